@@ -0,0 +1,210 @@
+//! `tlt`, the core parsers run against the live upstreams from the command line. Prints
+//! tab-separated lines for scripting, and the lines the parsers skipped on stderr so parser
+//! changes can be checked against the current data.
+//!
+//! The upstreams are overridden with the worker's `UPSTREAM_ROUTES_URL`, `UPSTREAM_STOPS_URL`
+//! and `UPSTREAM_ARRIVALS_URL`, and the timezone with `REGION_TIMEZONE`. HTTP upstreams are
+//! fetched with the system's `curl`, which has to be on `PATH`; `file://` URLs are read
+//! directly, so recorded data needs no curl.
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::process::{Command, ExitCode};
+use std::rc::Rc;
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures::executor::block_on;
+
+use tlt_stops_core::diagnostics::{MalformedLines, ParseDiagnostics};
+use tlt_stops_core::feeds;
+use tlt_stops_core::models::{RouteGroup, StopData, TransportType, flat_arrivals};
+use tlt_stops_core::str_utils::{
+    LastRouteData, ParseError, extract_arrival_stop_data_from_line, extract_route_data_from_buffer,
+    extract_stop_data_from_buffer, extract_type_from_buffer, split_arrival_by_stops,
+};
+
+const USAGE: &str = "\
+usage: tlt types
+       tlt routes <type>
+       tlt arrivals <stop id>[,<stop id>...]";
+
+fn url(var: &str, default: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| default.to_string())
+}
+
+const CURL_MISSING: &str = "\
+curl is required to fetch HTTP upstreams but was not found on PATH;
+install curl, or point the UPSTREAM_*_URL variables at file:// URLs";
+
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    if let Some(path) = url.strip_prefix("file://") {
+        // The arrivals URL carries the stop ids as a query, meaningless for a file
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        return std::fs::read(path).map_err(|err| format!("{url}: {err}"));
+    }
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--compressed",
+            url,
+        ])
+        .output()
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => CURL_MISSING.to_string(),
+            _ => format!("running curl: {err}"),
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "{url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn parse_error(err: ParseError) -> String {
+    match err {
+        ParseError::Utf8 => "invalid number or time field".to_string(),
+        ParseError::MissingColumn(msg) | ParseError::Malformed(msg) => msg,
+    }
+}
+
+fn route_map() -> Result<HashMap<String, HashMap<String, RouteGroup>>, String> {
+    let buf = fetch(&url("UPSTREAM_ROUTES_URL", feeds::ROUTES_URL))?;
+    let (route_map, _, _, _) = block_on(extract_route_data_from_buffer(
+        &buf,
+        HashMap::new(),
+        LastRouteData::default(),
+        0,
+        None,
+    ))
+    .map_err(parse_error)?;
+    Ok(route_map)
+}
+
+fn stop_map() -> Result<HashMap<String, Rc<StopData>>, String> {
+    let buf = fetch(&url("UPSTREAM_STOPS_URL", feeds::STOPS_URL))?;
+    let (stop_map, _, _, _) = block_on(extract_stop_data_from_buffer(
+        &buf,
+        HashMap::new(),
+        None,
+        0,
+        None,
+    ))
+    .map_err(parse_error)?;
+    Ok(stop_map)
+}
+
+fn types() -> Result<(), String> {
+    let buf = fetch(&url("UPSTREAM_ROUTES_URL", feeds::ROUTES_URL))?;
+    let (types, _, _) =
+        block_on(extract_type_from_buffer(&buf, HashSet::new(), 0, None)).map_err(parse_error)?;
+    let mut types = types.into_iter().collect::<Vec<String>>();
+    types.sort_unstable();
+    for route_type in types {
+        println!("{route_type}");
+    }
+    Ok(())
+}
+
+/// One `number, direction, stop count` line per direction of each route of the type.
+fn routes(route_type: &str) -> Result<(), String> {
+    let route_type = TransportType::parse(route_type);
+    let route_map = route_map()?;
+    let routes = route_map
+        .get(route_type.as_str())
+        .ok_or_else(|| format!("no routes of type {route_type}"))?;
+    let mut numbers = routes.keys().collect::<Vec<&String>>();
+    numbers.sort_unstable();
+    for number in numbers {
+        let mut directions = routes[number].directions.iter().collect::<Vec<_>>();
+        directions.sort_unstable_by_key(|(direction, _)| *direction);
+        for (direction, stops) in directions {
+            println!("{number}\t{direction}\t{}", stops.len());
+        }
+    }
+    Ok(())
+}
+
+/// One `stop id, type, number, time, destination` line per arrival, sorted by time per stop.
+fn arrivals(stop_ids: &str) -> Result<(), String> {
+    let stop_map = stop_map()?;
+    let mut siri_ids = Vec::new();
+    for stop_id in stop_ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+    {
+        let stop = stop_map
+            .get(stop_id)
+            .ok_or_else(|| format!("no stop {stop_id}"))?;
+        siri_ids.push(stop.siri_id.as_str());
+    }
+    if siri_ids.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let region_tz = match std::env::var("REGION_TIMEZONE") {
+        Ok(name) => name
+            .parse::<Tz>()
+            .map_err(|_| format!("invalid REGION_TIMEZONE: {name}"))?,
+        Err(_) => feeds::REGION_TIMEZONE,
+    };
+    let uri = format!(
+        "{}?stopid={}",
+        url("UPSTREAM_ARRIVALS_URL", feeds::ARRIVALS_URL),
+        siri_ids.join(",")
+    );
+    let raw = fetch(&uri)?;
+    let now = Utc::now();
+    for stop_raw in split_arrival_by_stops(&raw) {
+        for stop in extract_arrival_stop_data_from_line(stop_raw, &stop_map, region_tz) {
+            let stop = stop.map_err(parse_error)?;
+            for arrival in flat_arrivals(&stop.arrivals, now) {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    stop.id,
+                    arrival.r#type,
+                    arrival.number,
+                    arrival.time,
+                    arrival.destination.unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn report_malformed(file: &str, malformed: MalformedLines) {
+    if malformed.count == 0 {
+        return;
+    }
+    eprintln!("{} malformed lines in {file}", malformed.count);
+    for sample in malformed.samples {
+        eprintln!("  {sample}");
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let result = match args.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["types"] => types(),
+        ["routes", route_type] => routes(route_type),
+        ["arrivals", stop_ids] => arrivals(stop_ids),
+        _ => Err(USAGE.to_string()),
+    };
+    let report = ParseDiagnostics::get().report();
+    report_malformed("routes.txt", report.routes);
+    report_malformed("stops.txt", report.stops);
+    report_malformed("the arrivals", report.arrivals);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+use crate::str_utils::{fnv1a_64, non_utf8_field_count};
+
+thread_local! {
+    static DIAGNOSTICS: &'static ParseDiagnostics = Box::leak(Box::default());
+}
+
+/// Malformed lines sampled per file, the count keeps going past it.
+const MAX_SAMPLES: usize = 5;
+/// Sample lines are cut to this many characters.
+const MAX_SAMPLE_LEN: usize = 200;
+/// Distinct lines remembered per file, so re-parsing a cached file isn't counted twice.
+const MAX_TRACKED_LINES: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub enum ParsedFile {
+    Routes,
+    Stops,
+    Arrivals,
+    Vehicles,
+}
+
+#[derive(Default)]
+struct FileDiagnostics {
+    seen: HashSet<u64>,
+    samples: Vec<String>,
+    last_seen_at: Option<u32>,
+}
+
+/// Lines the parsers skipped or failed on since the isolate started.
+#[derive(Default)]
+pub struct ParseDiagnostics {
+    routes: RefCell<FileDiagnostics>,
+    stops: RefCell<FileDiagnostics>,
+    arrivals: RefCell<FileDiagnostics>,
+    vehicles: RefCell<FileDiagnostics>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MalformedLines {
+    /// Distinct malformed lines, stops growing once the tracking limit is reached
+    #[schema(example = 2)]
+    pub count: usize,
+    /// The first few of them, truncated
+    #[schema(example = json!(["42;;;;;;;;;;;;"]))]
+    pub samples: Vec<String>,
+    #[schema(example = 1761000000)]
+    pub last_seen_at: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ParseDiagnosticsReport {
+    pub routes: MalformedLines,
+    pub stops: MalformedLines,
+    pub arrivals: MalformedLines,
+    pub vehicles: MalformedLines,
+    /// Upstream fields that had to be decoded as Windows-1257
+    #[schema(example = 0)]
+    pub non_utf8_fields: u64,
+}
+
+impl ParseDiagnostics {
+    pub fn get() -> &'static ParseDiagnostics {
+        DIAGNOSTICS.with(|diagnostics| *diagnostics)
+    }
+
+    fn file(&self, file: ParsedFile) -> &RefCell<FileDiagnostics> {
+        match file {
+            ParsedFile::Routes => &self.routes,
+            ParsedFile::Stops => &self.stops,
+            ParsedFile::Arrivals => &self.arrivals,
+            ParsedFile::Vehicles => &self.vehicles,
+        }
+    }
+
+    pub fn record_malformed(&self, file: ParsedFile, line: &[u8]) {
+        let mut diagnostics = self.file(file).borrow_mut();
+        diagnostics.last_seen_at = Some(chrono::Utc::now().timestamp() as u32);
+        if diagnostics.seen.len() >= MAX_TRACKED_LINES || !diagnostics.seen.insert(fnv1a_64(line)) {
+            return;
+        }
+        if diagnostics.samples.len() < MAX_SAMPLES {
+            let sample = String::from_utf8_lossy(line)
+                .chars()
+                .take(MAX_SAMPLE_LEN)
+                .collect();
+            diagnostics.samples.push(sample);
+        }
+    }
+
+    pub fn report(&self) -> ParseDiagnosticsReport {
+        let malformed = |file| {
+            let diagnostics = self.file(file).borrow();
+            MalformedLines {
+                count: diagnostics.seen.len(),
+                samples: diagnostics.samples.clone(),
+                last_seen_at: diagnostics.last_seen_at,
+            }
+        };
+        ParseDiagnosticsReport {
+            routes: malformed(ParsedFile::Routes),
+            stops: malformed(ParsedFile::Stops),
+            arrivals: malformed(ParsedFile::Arrivals),
+            vehicles: malformed(ParsedFile::Vehicles),
+            non_utf8_fields: non_utf8_field_count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::str_utils::{extract_route_data_from_buffer, extract_stop_data_from_buffer};
+    use futures::executor::block_on;
+    use std::collections::HashMap;
+
+    fn parse_stops(buf: &[u8]) -> usize {
+        let parsed = extract_stop_data_from_buffer(buf, HashMap::new(), None, 0, None);
+        block_on(parsed).unwrap().0.len()
+    }
+
+    #[test]
+    fn records_skipped_stop_lines_once() {
+        let buf = b"ID;SiriID;Name\n1;101;Kopli\n2;;Pelguranna\n\n3;;\r\n";
+        assert_eq!(parse_stops(buf), 2);
+        // a cached copy parsed again doesn't count its lines twice
+        assert_eq!(parse_stops(buf), 2);
+        let report = ParseDiagnostics::get().report().stops;
+        assert_eq!(report.count, 2);
+        assert_eq!(report.samples, ["2;;Pelguranna", "3;;\r"]);
+        assert!(report.last_seen_at.is_some());
+        assert_eq!(ParseDiagnostics::get().report().routes.count, 0);
+    }
+
+    #[test]
+    fn records_route_lines_but_not_timetables() {
+        let buf = b"RouteNum;Transport;RouteName;RouteStops\n1;;Kopli;1001\n1;tram;Kopli;1001\n\
+            ,,960,1020\n2;bus;;1002\n";
+        let parsed =
+            extract_route_data_from_buffer(buf, HashMap::new(), Default::default(), 0, None);
+        let (route_map, ..) = block_on(parsed).unwrap();
+        assert_eq!(route_map["tram"].len(), 1);
+        let report = ParseDiagnostics::get().report().routes;
+        assert_eq!(report.samples, ["1;;Kopli;1001", "2;bus;;1002"]);
+    }
+
+    #[test]
+    fn keeps_counting_past_the_truncated_samples() {
+        let long = "x".repeat(MAX_SAMPLE_LEN + 50);
+        for n in 0..MAX_SAMPLES + 3 {
+            let line = format!("{};{}", n, long);
+            ParseDiagnostics::get().record_malformed(ParsedFile::Vehicles, line.as_bytes());
+        }
+        let report = ParseDiagnostics::get().report().vehicles;
+        assert_eq!(report.count, MAX_SAMPLES + 3);
+        assert_eq!(report.samples.len(), MAX_SAMPLES);
+        assert!(
+            report
+                .samples
+                .iter()
+                .all(|s| s.chars().count() == MAX_SAMPLE_LEN)
+        );
+        assert!(report.samples[0].starts_with("0;x"));
+    }
+}
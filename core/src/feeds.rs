@@ -0,0 +1,9 @@
+//! The public TLT upstreams, the defaults of the worker and the CLI.
+
+pub const ROUTES_URL: &str = "https://transport.tallinn.ee/data/routes.txt";
+pub const STOPS_URL: &str = "https://transport.tallinn.ee/data/stops.txt";
+/// SIRI departures, `?stopid=` taking comma-separated SIRI ids.
+pub const ARRIVALS_URL: &str = "https://transport.tallinn.ee/siri-stop-departures.php";
+pub const GPS_URL: &str = "https://transport.tallinn.ee/gps.txt";
+/// Times in the feeds are local to this timezone.
+pub const REGION_TIMEZONE: chrono_tz::Tz = chrono_tz::Europe::Tallinn;
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::models::StopData;
+
+/// Mean Earth radius used for the distances, in meters.
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Great-circle distance between two WGS84 points, in meters.
+pub fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// Index of the next stop a vehicle at `lat`/`lon` serves along `stops`: the nearest one, or the
+/// one after it once the vehicle is closer to that than the nearest stop is. Stops without
+/// coordinates are never picked.
+pub fn next_stop_index(stops: &[Option<(f64, f64)>], lat: f64, lon: f64) -> Option<usize> {
+    let (nearest, _) = stops
+        .iter()
+        .enumerate()
+        .filter_map(|(index, position)| {
+            position.map(|(stop_lat, stop_lon)| (index, haversine_m(lat, lon, stop_lat, stop_lon)))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let (Some((nearest_lat, nearest_lon)), Some(Some((next_lat, next_lon)))) =
+        (stops[nearest], stops.get(nearest + 1))
+    else {
+        return Some(nearest);
+    };
+    let passed = haversine_m(lat, lon, *next_lat, *next_lon)
+        < haversine_m(nearest_lat, nearest_lon, *next_lat, *next_lon);
+    Some(if passed { nearest + 1 } else { nearest })
+}
+
+/// Meters per degree of latitude, and of longitude at the equator.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+/// Grid cell size, about 1.1 km on both sides at Tallinn's latitude.
+const LAT_CELL_DEG: f64 = 0.01;
+const LON_CELL_DEG: f64 = 0.02;
+
+/// Stops with coordinates bucketed into a lat/lon grid, so geo lookups only visit the cells
+/// around the point instead of every stop.
+pub struct StopGrid {
+    /// The stop map the grid was built from, a different map means the grid is outdated
+    pub stop_map: Rc<HashMap<String, Rc<StopData>>>,
+    cells: HashMap<(i32, i32), Vec<Rc<StopData>>>,
+}
+
+fn cell_of(lat: f64, lon: f64) -> (i32, i32) {
+    (
+        (lat / LAT_CELL_DEG).floor() as i32,
+        (lon / LON_CELL_DEG).floor() as i32,
+    )
+}
+
+impl StopGrid {
+    pub fn build(stop_map: Rc<HashMap<String, Rc<StopData>>>) -> Self {
+        let mut cells: HashMap<(i32, i32), Vec<Rc<StopData>>> = HashMap::new();
+        for (key, stop) in stop_map.iter() {
+            // the map holds every stop under both of its ids
+            if *key != stop.id {
+                continue;
+            }
+            if let (Some(lat), Some(lon)) = (stop.lat, stop.lon) {
+                cells
+                    .entry(cell_of(lat, lon))
+                    .or_default()
+                    .push(Rc::clone(stop));
+            }
+        }
+        Self { stop_map, cells }
+    }
+
+    /// Stops inside the bounding box, in no particular order.
+    pub fn in_bbox(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> impl Iterator<Item = &Rc<StopData>> {
+        let (min_row, min_col) = cell_of(min_lat, min_lon);
+        let (max_row, max_col) = cell_of(max_lat, max_lon);
+        let visited = (max_row as i64 - min_row as i64 + 1) * (max_col as i64 - min_col as i64 + 1);
+        // a box wider than the data is cheaper to answer by walking the occupied cells
+        let cells: Box<dyn Iterator<Item = &Vec<Rc<StopData>>>> =
+            if visited > self.cells.len() as i64 {
+                Box::new(self.cells.values())
+            } else {
+                Box::new((min_row..=max_row).flat_map(move |row| {
+                    (min_col..=max_col).filter_map(move |col| self.cells.get(&(row, col)))
+                }))
+            };
+        cells.flatten().filter(move |stop| {
+            matches!((stop.lat, stop.lon), (Some(lat), Some(lon))
+                if (min_lat..=max_lat).contains(&lat) && (min_lon..=max_lon).contains(&lon))
+        })
+    }
+
+    /// Stops within `radius_m` meters of the point with their distance, in no particular order.
+    pub fn within_radius(
+        &self,
+        lat: f64,
+        lon: f64,
+        radius_m: f64,
+    ) -> impl Iterator<Item = (&Rc<StopData>, f64)> {
+        let d_lat = radius_m / METERS_PER_DEGREE;
+        let d_lon = radius_m / (METERS_PER_DEGREE * lat.to_radians().cos().max(0.01));
+        self.in_bbox(lat - d_lat, lon - d_lon, lat + d_lat, lon + d_lon)
+            .filter_map(move |stop| {
+                let distance_m = haversine_m(lat, lon, stop.lat?, stop.lon?);
+                (distance_m <= radius_m).then_some((stop, distance_m))
+            })
+    }
+}
@@ -0,0 +1,9 @@
+//! The TLT data behind tlt-stops without the Workers runtime: the models, the parsers of
+//! routes.txt, stops.txt, the SIRI arrivals and the GPS feed, and the stop lookups built on them.
+//! The worker crate serves them over HTTP with its caches and upstream access on top.
+
+pub mod diagnostics;
+pub mod feeds;
+pub mod geo;
+pub mod models;
+pub mod str_utils;
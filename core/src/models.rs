@@ -0,0 +1,1353 @@
+use chrono::{DateTime, Utc};
+use serde::ser::SerializeMap;
+use serde::{self, Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::str::FromStr;
+use utoipa::openapi::{ObjectBuilder, RefOr, Schema, Type};
+use utoipa::{PartialSchema, ToSchema};
+
+use crate::geo::haversine_m;
+use crate::str_utils::fnv1a_64;
+
+/// Transport type of the routes, arrivals and vehicles, written as its code. Codes are read
+/// case-insensitively, ignoring spaces, `-` and `_`, and from the aliases the feeds and clients
+/// use, so `Bus`, `trol` and `night-bus` all name a known type. Codes of other types are kept
+/// lowercased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TransportType {
+    Bus,
+    Tram,
+    Trolleybus,
+    NightBus,
+    RegionalBus,
+    CommercialBus,
+    Train,
+    Other(String),
+}
+
+impl TransportType {
+    pub fn parse(code: &str) -> Self {
+        let code = code.trim().to_lowercase();
+        let compact = code.replace([' ', '-', '_'], "");
+        match compact.as_str() {
+            "bus" => TransportType::Bus,
+            "tram" => TransportType::Tram,
+            "trolleybus" | "trolley" | "trol" => TransportType::Trolleybus,
+            "nightbus" | "night" => TransportType::NightBus,
+            "regionalbus" | "regional" => TransportType::RegionalBus,
+            "commercialbus" | "commercial" => TransportType::CommercialBus,
+            "train" => TransportType::Train,
+            _ => TransportType::Other(code),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            TransportType::Bus => "bus",
+            TransportType::Tram => "tram",
+            TransportType::Trolleybus => "trolleybus",
+            TransportType::NightBus => "nightbus",
+            TransportType::RegionalBus => "regionalbus",
+            TransportType::CommercialBus => "commercialbus",
+            TransportType::Train => "train",
+            TransportType::Other(code) => code,
+        }
+    }
+}
+
+impl From<&str> for TransportType {
+    fn from(code: &str) -> Self {
+        TransportType::parse(code)
+    }
+}
+
+impl FromStr for TransportType {
+    type Err = Infallible;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Ok(TransportType::parse(code))
+    }
+}
+
+impl fmt::Display for TransportType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for TransportType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransportType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(TransportType::parse(&String::deserialize(deserializer)?))
+    }
+}
+
+impl PartialSchema for TransportType {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some(
+                "Transport type code: bus, tram, trolleybus, nightbus, regionalbus, \
+                 commercialbus, train or another code of the feeds",
+            ))
+            .examples([serde_json::json!("bus")])
+            .into()
+    }
+}
+
+impl ToSchema for TransportType {}
+
+pub struct RouteData {
+    pub number: String,
+    pub route_type: TransportType,
+    pub directions: String,
+    pub stops: Vec<String>,
+}
+
+// #[derive(Debug, Clone, Serialize, Deserialize)]
+// pub struct RouteDirection {
+//     pub name: String,
+//     pub stops: Vec<String>,
+// }
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "number": "1",
+    "type": "bus",
+    "directions": {
+        "Kopli": ["1001", "1002"],
+        "Linnahall": ["2001", "2002"]
+    }
+}))]
+pub struct RouteGroup {
+    #[schema(example = "1")]
+    pub number: String,
+    pub r#type: TransportType,
+    #[schema(example = json!({"Kopli": ["1001", "1002"]}))]
+    pub directions: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, ToSchema)]
+pub struct RouteRef {
+    pub r#type: TransportType,
+    #[schema(example = "1")]
+    pub number: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopHub {
+    #[schema(example = "1001")]
+    pub id: String,
+    #[schema(example = "Balti jaam")]
+    pub name: String,
+    #[schema(example = 12)]
+    pub route_count: usize,
+    pub routes: Vec<RouteRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 59.44002)]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 24.73706)]
+    pub lon: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopSearchResult {
+    #[schema(example = "1001")]
+    pub id: String,
+    #[schema(example = "10901")]
+    pub siri_id: String,
+    #[schema(example = "Viru keskus")]
+    pub name: String,
+    /// Historical or alternative name the stop was found by, when not its current name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Viru väljak")]
+    pub matched_alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 59.43686)]
+    pub lat: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 24.75538)]
+    pub lon: Option<f64>,
+}
+
+/// A vehicle of the gps.txt feed.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VehiclePosition {
+    #[schema(example = "1359")]
+    pub id: String,
+    pub r#type: TransportType,
+    #[schema(example = "40")]
+    pub number: String,
+    /// Destination shown on the vehicle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Rocca al Mare")]
+    pub direction: Option<String>,
+    #[schema(example = 59.43859)]
+    pub lat: f64,
+    #[schema(example = 24.69879)]
+    pub lon: f64,
+    /// Degrees clockwise from north
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 255)]
+    pub bearing: Option<u16>,
+    /// km/h, the feed mostly leaves it out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 32.0)]
+    pub speed: Option<f32>,
+}
+
+/// The vehicles operating a route, by the direction they run.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteVehicles<'a> {
+    #[schema(example = "bus")]
+    pub r#type: &'a str,
+    #[schema(example = "40")]
+    pub number: &'a str,
+    /// Every direction of the route, empty when no vehicle runs it
+    pub directions: HashMap<&'a str, Vec<&'a VehiclePosition>>,
+    /// Vehicles whose destination matches none of the directions
+    pub unmatched: Vec<&'a VehiclePosition>,
+}
+
+/// A vehicle joined with the route it operates.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleDetails<'a> {
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub vehicle: &'a VehiclePosition,
+    /// Direction of the route the vehicle runs, absent when its destination matches none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Rocca al Mare")]
+    pub route_direction: Option<&'a str>,
+    /// The next stops along that direction, nearest first
+    pub next_stops: Vec<StopResponse>,
+}
+
+/// `ETag`/`Last-Modified` of the cached copy of an upstream file, sent back on refresh.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub struct StopAlias {
+    pub name: String,
+    pub stop_ids: Vec<String>,
+}
+
+/// Physical stops sharing one name, like the two sides of a street or the bays of a terminal.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopGroup {
+    #[schema(example = "Viru keskus")]
+    pub name: String,
+    /// Mean position of the stops that have coordinates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 59.43659)]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 24.75602)]
+    pub lon: Option<f64>,
+    pub stops: Vec<StopSearchResult>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopSuggestion {
+    #[schema(example = "Viru keskus")]
+    pub name: String,
+    /// Every stop with this name
+    #[schema(example = json!(["1001", "1002"]))]
+    pub stop_ids: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopPage {
+    /// Stops matching the filter, across all pages
+    #[schema(example = 2512)]
+    pub total: usize,
+    #[schema(example = 0)]
+    pub offset: usize,
+    #[schema(example = 50)]
+    pub limit: usize,
+    pub stops: Vec<StopSearchResult>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NearbyStop {
+    #[schema(example = "1001")]
+    pub id: String,
+    #[schema(example = "10901")]
+    pub siri_id: String,
+    #[schema(example = "Viru keskus")]
+    pub name: String,
+    #[schema(example = 59.43686)]
+    pub lat: f64,
+    #[schema(example = 24.75538)]
+    pub lon: f64,
+    /// Great-circle distance from the requested point, in meters
+    #[schema(example = 142.0)]
+    pub distance_m: f64,
+}
+
+/// GeoJSON Feature of a route direction drawn through its stops.
+#[derive(Serialize, ToSchema)]
+pub struct RouteShape {
+    #[serde(rename = "type")]
+    #[schema(example = "Feature")]
+    pub r#type: &'static str,
+    pub geometry: LineString,
+    pub properties: RouteShapeProperties,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LineString {
+    #[serde(rename = "type")]
+    #[schema(example = "LineString")]
+    pub r#type: &'static str,
+    /// `[lon, lat]` positions in stop order
+    #[schema(example = json!([[24.75538, 59.43686], [24.76114, 59.43601]]))]
+    pub coordinates: Vec<[f64; 2]>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Point {
+    #[serde(rename = "type")]
+    #[schema(example = "Point")]
+    pub r#type: &'static str,
+    /// `[lon, lat]`
+    #[schema(example = json!([24.69879, 59.43859]))]
+    pub coordinates: [f64; 2],
+}
+
+/// GeoJSON FeatureCollection of vehicle positions.
+#[derive(Serialize, ToSchema)]
+pub struct VehicleFeatureCollection<'a> {
+    #[serde(rename = "type")]
+    #[schema(example = "FeatureCollection")]
+    pub r#type: &'static str,
+    pub features: Vec<VehicleFeature<'a>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VehicleFeature<'a> {
+    #[serde(rename = "type")]
+    #[schema(example = "Feature")]
+    pub r#type: &'static str,
+    pub geometry: Point,
+    pub properties: VehicleProperties<'a>,
+}
+
+/// [`VehiclePosition`] without its coordinates.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleProperties<'a> {
+    #[schema(example = "1359")]
+    pub id: &'a str,
+    #[serde(rename = "type")]
+    #[schema(example = "bus")]
+    pub r#type: &'a str,
+    #[schema(example = "40")]
+    pub number: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Rocca al Mare")]
+    pub direction: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 255)]
+    pub bearing: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 32.0)]
+    pub speed: Option<f32>,
+}
+
+impl<'a> FromIterator<&'a VehiclePosition> for VehicleFeatureCollection<'a> {
+    fn from_iter<I: IntoIterator<Item = &'a VehiclePosition>>(vehicles: I) -> Self {
+        Self {
+            r#type: "FeatureCollection",
+            features: vehicles
+                .into_iter()
+                .map(|vehicle| VehicleFeature {
+                    r#type: "Feature",
+                    geometry: Point {
+                        r#type: "Point",
+                        coordinates: [vehicle.lon, vehicle.lat],
+                    },
+                    properties: VehicleProperties {
+                        id: &vehicle.id,
+                        r#type: vehicle.r#type.as_str(),
+                        number: &vehicle.number,
+                        direction: vehicle.direction.as_deref(),
+                        bearing: vehicle.bearing,
+                        speed: vehicle.speed,
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteShapeProperties {
+    #[serde(rename = "type")]
+    #[schema(example = "bus")]
+    pub r#type: String,
+    #[schema(example = "1")]
+    pub number: String,
+    #[schema(example = "Kopli")]
+    pub direction: String,
+    /// Stops the line passes through, in order
+    #[schema(example = json!(["1001", "1002"]))]
+    pub stop_ids: Vec<String>,
+    /// Stops left out of the line for lacking coordinates
+    #[schema(example = json!([]))]
+    pub missing_coordinates: Vec<String>,
+}
+
+impl StopGroup {
+    pub fn from_stops<'a>(stops: impl IntoIterator<Item = &'a Rc<StopData>>) -> Option<Self> {
+        let stops = stops
+            .into_iter()
+            .map(|stop| StopSearchResult {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                matched_alias: None,
+                lat: stop.lat,
+                lon: stop.lon,
+            })
+            .collect::<Vec<StopSearchResult>>();
+        let positioned = stops
+            .iter()
+            .filter_map(|stop| Some((stop.lat?, stop.lon?)))
+            .collect::<Vec<(f64, f64)>>();
+        let mean = |coordinate: fn(&(f64, f64)) -> f64| {
+            (!positioned.is_empty())
+                .then(|| positioned.iter().map(coordinate).sum::<f64>() / positioned.len() as f64)
+        };
+        Some(Self {
+            name: stops.first()?.name.clone(),
+            lat: mean(|position| position.0),
+            lon: mean(|position| position.1),
+            stops,
+        })
+    }
+}
+
+pub struct StopData {
+    pub id: String,
+    pub siri_id: String,
+    pub name: Rc<String>,
+    /// WGS84 degrees, absent when stops.txt has no coordinates for the stop
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    /// Area of the city stops.txt places the stop in, absent when it has no Area for the stop
+    pub zone: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!(["1001", "Stop Name"]))]
+pub struct StopResponse(pub String, pub String);
+
+/// Stop of a route direction's stops as an object, the `?shape=object` and v2 form of
+/// [`StopResponse`]. The SIRI id and coordinates are absent for stops stops.txt doesn't list.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopObject<'a> {
+    /// Id of stops.txt, taken by the arrivals endpoints
+    #[schema(example = "1001")]
+    pub id: &'a str,
+    /// Id of the upstream SIRI feed, which the arrivals endpoints take as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "10901")]
+    pub siri_id: Option<&'a str>,
+    #[schema(example = "Viru keskus")]
+    pub name: &'a str,
+    /// Position along the direction, from 0 at its first stop
+    #[schema(example = 0)]
+    pub sequence: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 59.43686)]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 24.75538)]
+    pub lon: Option<f64>,
+}
+
+/// Stops of a route direction as objects, the `?shape=object` and v2 listing.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopListing<'a> {
+    pub stops: Vec<StopObject<'a>>,
+    /// Ids of the stops whose name couldn't be resolved, named "Can't resolve stop name" in
+    /// `stops`
+    #[schema(example = json!(["1003"]))]
+    pub missing_names: Vec<&'a str>,
+    /// Members of a stop the arrivals endpoints take as its id, [`ARRIVALS_STOP_IDS`]
+    #[schema(example = json!(["id", "siriId"]))]
+    pub arrivals_accept: [&'static str; 2],
+}
+
+/// The stop object members the arrivals endpoints look a stop up by, either one.
+pub const ARRIVALS_STOP_IDS: [&str; 2] = ["id", "siriId"];
+
+/// A route direction's stops in the shape the request asked for.
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum RouteStopsListing<'a> {
+    /// `[id, name]` pairs, v1's default
+    Pairs(Vec<StopResponse>),
+    /// With `?shape=object` and in v2
+    Objects(StopListing<'a>),
+    /// With `?links=true`
+    Linked(Vec<LinkedStop<'a>>),
+}
+
+/// Transport type of the types listing with `?links=true`.
+#[derive(Serialize, ToSchema)]
+pub struct LinkedType<'a> {
+    #[schema(example = "bus")]
+    pub r#type: &'a str,
+    pub links: TypeLinks,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TypeLinks {
+    #[schema(example = "/api/v1/types/bus/routes")]
+    pub routes: String,
+}
+
+impl<'a> LinkedType<'a> {
+    pub fn new(route_type: &'a str) -> Self {
+        Self {
+            r#type: route_type,
+            links: TypeLinks {
+                routes: format!("/api/v1/types/{}/routes", urlencoding::encode(route_type)),
+            },
+        }
+    }
+}
+
+/// Route of the routes listing with `?links=true`.
+#[derive(Serialize, ToSchema)]
+pub struct LinkedRoute<'a> {
+    #[schema(example = "1")]
+    pub number: &'a str,
+    pub links: RouteLinks,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RouteLinks {
+    #[schema(example = "/api/v1/types/bus/routes/1/directions")]
+    pub directions: String,
+    #[schema(example = "/api/v1/types/bus/routes/1/vehicles")]
+    pub vehicles: String,
+}
+
+impl<'a> LinkedRoute<'a> {
+    pub fn new(route_type: &str, number: &'a str) -> Self {
+        let route = format!(
+            "/api/v1/types/{}/routes/{}",
+            urlencoding::encode(route_type),
+            urlencoding::encode(number)
+        );
+        Self {
+            number,
+            links: RouteLinks {
+                directions: format!("{}/directions", route),
+                vehicles: format!("{}/vehicles", route),
+            },
+        }
+    }
+}
+
+/// Direction of the directions listing with `?links=true`.
+#[derive(Serialize, ToSchema)]
+pub struct LinkedDirection<'a> {
+    /// Position in the sorted directions, taken in place of the name in the direction's URLs
+    #[schema(example = 0)]
+    pub index: usize,
+    #[schema(example = "Kopli")]
+    pub name: &'a str,
+    pub links: DirectionLinks,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DirectionLinks {
+    #[schema(example = "/api/v1/types/bus/routes/1/directions/Kopli/stops")]
+    pub stops: String,
+    #[schema(example = "/api/v1/types/bus/routes/1/directions/Kopli/shape")]
+    pub shape: String,
+}
+
+impl<'a> LinkedDirection<'a> {
+    pub fn new(route_type: &str, number: &str, index: usize, name: &'a str) -> Self {
+        let direction = format!(
+            "/api/v1/types/{}/routes/{}/directions/{}",
+            urlencoding::encode(route_type),
+            urlencoding::encode(number),
+            urlencoding::encode(name)
+        );
+        Self {
+            index,
+            name,
+            links: DirectionLinks {
+                stops: format!("{}/stops", direction),
+                shape: format!("{}/shape", direction),
+            },
+        }
+    }
+}
+
+/// Direction of the directions listing with `?detail=true` and in v2, summarizing its stops.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectionDetails<'a> {
+    /// Position in the sorted directions, taken in place of the name in the direction's URLs
+    #[schema(example = 0)]
+    pub index: usize,
+    #[schema(example = "Kopli")]
+    pub name: &'a str,
+    /// Name of the last stop, absent when stops.txt doesn't list it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Kopli")]
+    pub terminus_stop: Option<&'a str>,
+    #[schema(example = 18)]
+    pub stop_count: usize,
+    pub first_stop: Option<StopObject<'a>>,
+    pub last_stop: Option<StopObject<'a>>,
+}
+
+/// Stop of a route direction's stops with `?links=true`.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkedStop<'a> {
+    /// Id of stops.txt, taken by the arrivals endpoints
+    #[schema(example = "1001")]
+    pub id: &'a str,
+    /// Id of the upstream SIRI feed, which the arrivals endpoints take as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "10901")]
+    pub siri_id: Option<&'a str>,
+    #[schema(example = "Stop Name")]
+    pub name: &'a str,
+    /// Position along the direction, from 0 at its first stop
+    #[schema(example = 0)]
+    pub sequence: usize,
+    pub links: StopLinks,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StopLinks {
+    #[schema(example = "/api/v1/stops/1001/arrivals")]
+    pub arrivals: String,
+    #[schema(example = "/board/1001")]
+    pub board: String,
+}
+
+impl<'a> From<&StopObject<'a>> for LinkedStop<'a> {
+    fn from(stop: &StopObject<'a>) -> Self {
+        let id = stop.id;
+        Self {
+            id,
+            siri_id: stop.siri_id,
+            name: stop.name,
+            sequence: stop.sequence,
+            links: StopLinks {
+                arrivals: format!("/api/v1/stops/{}/arrivals", urlencoding::encode(id)),
+                board: format!("/board/{}", urlencoding::encode(id)),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Arrival {
+    /// Expected time as ISO 8601 UTC
+    #[schema(example = "2025-10-14T08:15:00Z")]
+    pub time: String,
+    #[serde(default)]
+    pub is_low_entry: bool,
+    /// Expected time projected from the vehicle position rather than the timetable
+    #[serde(default)]
+    pub is_realtime: bool,
+    /// Headsign of the trip, absent when the feed leaves it empty
+    #[serde(default)]
+    #[schema(example = "Äigrumäe")]
+    pub destination: Option<String>,
+    /// Vehicle or block serving the trip, for matching against vehicle positions
+    #[serde(default)]
+    #[schema(example = "1234")]
+    pub vehicle_id: Option<String>,
+    /// Straight-line meters from the vehicle's live position to the stop, only with
+    /// `include=vehicle_distance`
+    #[serde(default)]
+    #[schema(example = 850.0)]
+    pub vehicle_distance_m: Option<f64>,
+}
+
+impl Arrival {
+    /// Expected ISO 8601 UTC time, sortable as text.
+    pub fn time(&self) -> &str {
+        &self.time
+    }
+
+    pub fn expected_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(self.time())
+            .ok()
+            .map(|time| time.with_timezone(&Utc))
+    }
+
+    /// Seconds from `now` until the expected time, 0 once it has passed.
+    pub fn in_seconds(&self, now: DateTime<Utc>) -> Option<i64> {
+        self.expected_at()
+            .map(|expected_at| (expected_at - now).num_seconds().max(0))
+    }
+}
+
+/// Response-time narrowing of the arrivals, leaving the cached arrivals untouched.
+#[derive(Clone, Copy, Default)]
+pub struct ArrivalsFilter {
+    /// Drop arrivals further away than this many minutes
+    pub max_minutes: Option<u32>,
+    /// Keep only the next this many arrivals of each type/number pair
+    pub per_route: Option<usize>,
+}
+
+impl ArrivalsFilter {
+    pub fn is_noop(&self) -> bool {
+        self.max_minutes.is_none() && self.per_route.is_none()
+    }
+
+    fn keeps(&self, arrival: &Arrival, now: DateTime<Utc>) -> bool {
+        match (self.max_minutes, arrival.expected_at()) {
+            (Some(max_minutes), Some(expected_at)) => {
+                expected_at <= now + chrono::Duration::minutes(i64::from(max_minutes))
+            }
+            _ => true,
+        }
+    }
+
+    /// The filtered copy of a `type -> number -> arrivals` map, dropping routes left empty.
+    pub fn filter_routes(
+        &self,
+        arrivals: &HashMap<String, HashMap<String, Vec<Arrival>>>,
+    ) -> HashMap<String, HashMap<String, Vec<Arrival>>> {
+        let now = Utc::now();
+        arrivals
+            .iter()
+            .map(|(route_type, routes)| {
+                let routes = routes
+                    .iter()
+                    .map(|(number, times)| {
+                        let mut times = times
+                            .iter()
+                            .filter(|arrival| self.keeps(arrival, now))
+                            .cloned()
+                            .collect::<Vec<Arrival>>();
+                        if let Some(per_route) = self.per_route {
+                            times.sort_by(|a, b| a.time().cmp(b.time()));
+                            times.truncate(per_route);
+                        }
+                        (number.clone(), times)
+                    })
+                    .filter(|(_, times)| !times.is_empty())
+                    .collect::<HashMap<String, Vec<Arrival>>>();
+                (route_type.clone(), routes)
+            })
+            .filter(|(_, routes)| !routes.is_empty())
+            .collect()
+    }
+
+    pub fn apply(&self, stop: Rc<StopArrivals>) -> Rc<StopArrivals> {
+        if self.is_noop() {
+            return stop;
+        }
+        Rc::new(StopArrivals {
+            id: stop.id.clone(),
+            name: stop.name.clone(),
+            lat: stop.lat,
+            lon: stop.lon,
+            arrivals: self.filter_routes(&stop.arrivals),
+        })
+    }
+
+    pub fn apply_result(&self, result: StopArrivalsResult) -> StopArrivalsResult {
+        StopArrivalsResult {
+            data: result.data.map(|stop| self.apply(stop)),
+            ..result
+        }
+    }
+}
+
+impl Serialize for Arrival {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("time", &self.time)?;
+        if self.is_low_entry {
+            map.serialize_entry("isLowEntry", &true)?;
+        }
+        map.serialize_entry("isRealtime", &self.is_realtime)?;
+        if let Some(destination) = &self.destination {
+            map.serialize_entry("destination", destination)?;
+        }
+        if let Some(vehicle_id) = &self.vehicle_id {
+            map.serialize_entry("vehicleId", vehicle_id)?;
+        }
+        if let Some(vehicle_distance_m) = self.vehicle_distance_m {
+            map.serialize_entry("vehicleDistanceM", &vehicle_distance_m)?;
+        }
+        // computed on every serialization so cached arrivals still count down
+        if let Some(in_seconds) = self.in_seconds(Utc::now()) {
+            map.serialize_entry("inSeconds", &in_seconds)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StopArrival {
+    pub number: String,
+    pub r#type: TransportType,
+    pub arrivals: Arrival,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct StopArrivals {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 59.43686)]
+    pub lat: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 24.75538)]
+    pub lon: Option<f64>,
+    pub arrivals: HashMap<String, HashMap<String, Vec<Arrival>>>,
+    // pub arrivals: HashMap<String, HashMap<String, Vec<StopArrival>>>,
+}
+
+impl StopArrivals {
+    /// A copy with the distance from each arrival's vehicle to the stop, left out for vehicles
+    /// missing from `vehicles` or when the stop has no coordinates.
+    pub fn with_vehicle_distances(&self, vehicles: &HashMap<&str, &VehiclePosition>) -> Self {
+        let mut stop = self.clone();
+        let Some((lat, lon)) = self.lat.zip(self.lon) else {
+            return stop;
+        };
+        for arrival in stop
+            .arrivals
+            .values_mut()
+            .flat_map(|routes| routes.values_mut())
+            .flatten()
+        {
+            arrival.vehicle_distance_m = arrival
+                .vehicle_id
+                .as_deref()
+                .and_then(|vehicle_id| vehicles.get(vehicle_id))
+                .map(|vehicle| haversine_m(vehicle.lat, vehicle.lon, lat, lon).round());
+        }
+        stop
+    }
+
+    /// The route's earliest arrival at most `minutes` away at `now`.
+    pub fn next_arrival_within(
+        &self,
+        route_type: &str,
+        number: &str,
+        minutes: u32,
+        now: DateTime<Utc>,
+    ) -> Option<&Arrival> {
+        self.arrivals
+            .get(route_type)?
+            .get(number)?
+            .iter()
+            .filter(|arrival| {
+                arrival
+                    .in_seconds(now)
+                    .is_some_and(|seconds| seconds <= i64::from(minutes) * 60)
+            })
+            .min_by(|a, b| a.time().cmp(b.time()))
+    }
+
+    /// Hash of the stop's arrivals independent of map order and the countdown, so it only
+    /// changes with the upstream data.
+    pub fn fingerprint(&self) -> u64 {
+        let mut entries = self
+            .arrivals
+            .iter()
+            .flat_map(|(route_type, routes)| {
+                routes.iter().flat_map(move |(number, times)| {
+                    times.iter().map(move |arrival| {
+                        format!(
+                            "{};{};{};{};{};{};{}",
+                            route_type,
+                            number,
+                            arrival.time,
+                            arrival.is_low_entry,
+                            arrival.is_realtime,
+                            arrival.destination.as_deref().unwrap_or_default(),
+                            arrival.vehicle_id.as_deref().unwrap_or_default()
+                        )
+                    })
+                })
+            })
+            .collect::<Vec<String>>();
+        entries.sort_unstable();
+        fnv1a_64(format!("{}\n{}\n{}", self.id, self.name, entries.join("\n")).as_bytes())
+    }
+}
+
+/// Arrivals of every stop in a stop group merged, in time order per route.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StopGroupArrivals {
+    #[schema(example = "Viru keskus")]
+    pub name: String,
+    /// SIRI ids of the stops merged
+    #[schema(example = json!(["10901", "10902"]))]
+    pub stop_ids: Vec<String>,
+    pub arrivals: HashMap<String, HashMap<String, Vec<Arrival>>>,
+}
+
+impl StopGroupArrivals {
+    pub fn merge(name: String, stops: &[Rc<StopArrivals>]) -> Self {
+        let mut arrivals: HashMap<String, HashMap<String, Vec<Arrival>>> = HashMap::new();
+        for stop in stops {
+            for (route_type, routes) in &stop.arrivals {
+                let merged_routes = arrivals.entry(route_type.clone()).or_default();
+                for (number, times) in routes {
+                    merged_routes
+                        .entry(number.clone())
+                        .or_default()
+                        .extend(times.iter().cloned());
+                }
+            }
+        }
+        for times in arrivals.values_mut().flat_map(HashMap::values_mut) {
+            times.sort_by(|a, b| a.time().cmp(b.time()));
+        }
+        Self {
+            name,
+            stop_ids: stops.iter().map(|stop| stop.id.clone()).collect(),
+            arrivals,
+        }
+    }
+}
+
+/// `?format=` shape of the arrivals endpoints, the encoding aside.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum ArrivalsFormat {
+    /// `type -> number -> arrivals`
+    #[default]
+    Nested,
+    /// One time-sorted list of [`FlatArrival`]
+    Flat,
+}
+
+/// One arrival of the flat format, carrying its route.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FlatArrival<'a> {
+    #[schema(example = "bus")]
+    pub r#type: &'a str,
+    #[schema(example = "8")]
+    pub number: &'a str,
+    #[schema(example = "2025-10-14T08:15:00Z")]
+    pub time: &'a str,
+    pub is_low_entry: bool,
+    pub is_realtime: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Äigrumäe")]
+    pub destination: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "1234")]
+    pub vehicle_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 850.0)]
+    pub vehicle_distance_m: Option<f64>,
+    /// Seconds until `time` when the response was made, 0 once it has passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 240)]
+    pub in_seconds: Option<i64>,
+}
+
+/// A `type -> number -> arrivals` map as a single list sorted by time.
+pub fn flat_arrivals(
+    arrivals: &HashMap<String, HashMap<String, Vec<Arrival>>>,
+    now: DateTime<Utc>,
+) -> Vec<FlatArrival<'_>> {
+    let mut flat = arrivals
+        .iter()
+        .flat_map(|(route_type, routes)| {
+            routes.iter().flat_map(move |(number, times)| {
+                times.iter().map(move |arrival| FlatArrival {
+                    r#type: route_type,
+                    number,
+                    time: arrival.time(),
+                    is_low_entry: arrival.is_low_entry,
+                    is_realtime: arrival.is_realtime,
+                    destination: arrival.destination.as_deref(),
+                    vehicle_id: arrival.vehicle_id.as_deref(),
+                    vehicle_distance_m: arrival.vehicle_distance_m,
+                    in_seconds: arrival.in_seconds(now),
+                })
+            })
+        })
+        .collect::<Vec<FlatArrival>>();
+    flat.sort_by(|a, b| (a.time, a.r#type, a.number).cmp(&(b.time, b.r#type, b.number)));
+    flat
+}
+
+fn serialize_flat_arrivals<S>(
+    arrivals: &HashMap<String, HashMap<String, Vec<Arrival>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(flat_arrivals(arrivals, Utc::now()))
+}
+
+/// [`StopArrivals`] with the arrivals in the flat format.
+#[derive(Serialize)]
+pub struct FlatStopArrivals<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+    #[serde(serialize_with = "serialize_flat_arrivals")]
+    pub arrivals: &'a HashMap<String, HashMap<String, Vec<Arrival>>>,
+}
+
+impl<'a> From<&'a StopArrivals> for FlatStopArrivals<'a> {
+    fn from(stop: &'a StopArrivals) -> Self {
+        Self {
+            id: &stop.id,
+            name: &stop.name,
+            lat: stop.lat,
+            lon: stop.lon,
+            arrivals: &stop.arrivals,
+        }
+    }
+}
+
+/// [`StopGroupArrivals`] with the arrivals in the flat format.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlatStopGroupArrivals<'a> {
+    pub name: &'a str,
+    pub stop_ids: &'a [String],
+    #[serde(serialize_with = "serialize_flat_arrivals")]
+    pub arrivals: &'a HashMap<String, HashMap<String, Vec<Arrival>>>,
+}
+
+impl<'a> From<&'a StopGroupArrivals> for FlatStopGroupArrivals<'a> {
+    fn from(group: &'a StopGroupArrivals) -> Self {
+        Self {
+            name: &group.name,
+            stop_ids: &group.stop_ids,
+            arrivals: &group.arrivals,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StopArrivalsStatus {
+    Ok,
+    /// No stop has the requested id
+    UnknownStop,
+    /// Fetching the stop's arrivals failed and there are no earlier ones to fall back to
+    UpstreamError,
+}
+
+/// Outcome for one requested stop of an arrivals request.
+#[derive(Serialize, ToSchema)]
+pub struct StopArrivalsResult {
+    /// The id as requested, stop id or SIRI id
+    #[schema(example = "1001")]
+    pub id: String,
+    pub status: StopArrivalsStatus,
+    /// Present when `status` is `ok`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<StopArrivals>)]
+    pub data: Option<Rc<StopArrivals>>,
+}
+
+impl StopArrivalsResult {
+    pub fn ok(id: String, data: Rc<StopArrivals>) -> Self {
+        Self {
+            id,
+            status: StopArrivalsStatus::Ok,
+            data: Some(data),
+        }
+    }
+
+    pub fn failed(id: String, status: StopArrivalsStatus) -> Self {
+        Self {
+            id,
+            status,
+            data: None,
+        }
+    }
+}
+
+/// [`StopArrivalsResult`] with the arrivals in the flat format.
+#[derive(Serialize)]
+pub struct FlatStopArrivalsResult<'a> {
+    pub id: &'a str,
+    pub status: StopArrivalsStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<FlatStopArrivals<'a>>,
+}
+
+impl<'a> From<&'a StopArrivalsResult> for FlatStopArrivalsResult<'a> {
+    fn from(result: &'a StopArrivalsResult) -> Self {
+        Self {
+            id: &result.id,
+            status: result.status,
+            data: result.data.as_deref().map(FlatStopArrivals::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FlatPostArrivalsResponse<'a> {
+    pub stops: Vec<FlatStopArrivalsResult<'a>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PostArrivalsResponse {
+    pub stops: Vec<StopArrivalsResult>,
+}
+
+/// Stable identifier of an error, for clients to branch on instead of the English `detail`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    MissingParameter,
+    InvalidParameter,
+    TooManyStops,
+    UnknownType,
+    UnknownRoute,
+    UnknownDirection,
+    UnknownStop,
+    UnknownStopGroup,
+    UnknownVehicle,
+    NotFound,
+    MethodNotAllowed,
+    Unauthorized,
+    UpgradeRequired,
+    RateLimited,
+    QuotaExceeded,
+    UpstreamError,
+    UpstreamTimeout,
+    UpstreamUnavailable,
+    /// The routes and stops aren't loaded yet, see `/api/ready`
+    NotReady,
+    /// Answered only by the worker, `tlt-serve` lacking the Cloudflare bindings it needs
+    NotImplemented,
+    InternalError,
+}
+
+impl ErrorCode {
+    /// The code of errors with nothing more specific to say than their status.
+    pub fn for_status(status: u16) -> Self {
+        match status {
+            400 | 422 => ErrorCode::InvalidParameter,
+            401 | 403 => ErrorCode::Unauthorized,
+            404 => ErrorCode::NotFound,
+            405 => ErrorCode::MethodNotAllowed,
+            426 => ErrorCode::UpgradeRequired,
+            429 => ErrorCode::RateLimited,
+            501 => ErrorCode::NotImplemented,
+            502 => ErrorCode::UpstreamError,
+            503 => ErrorCode::UpstreamUnavailable,
+            504 => ErrorCode::UpstreamTimeout,
+            _ => ErrorCode::InternalError,
+        }
+    }
+}
+
+/// RFC 7807 problem details, the `application/problem+json` body of the error responses.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    pub r#type: String,
+    /// Reason phrase of the status
+    #[schema(example = "Not Found")]
+    pub title: String,
+    #[schema(example = 404)]
+    pub status: u16,
+    #[schema(example = "type not found")]
+    pub detail: String,
+    /// What went wrong, for clients to branch on
+    pub code: ErrorCode,
+    /// Path of the failed request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "/api/v1/types/boat/routes")]
+    pub instance: Option<String>,
+    /// `X-Request-Id` of the failed request, to quote when reporting it
+    #[serde(rename = "requestId", default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "q1w2e3r4t5y6u7i8o9p0aA")]
+    pub request_id: Option<String>,
+    /// W3C trace id of the failed request, the client's `traceparent` one when it sent one
+    #[serde(rename = "traceId", default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "4bf92f3577b34da6a3ce929d0e0e4736")]
+    pub trace_id: Option<String>,
+}
+
+impl Problem {
+    pub fn new(code: ErrorCode, status: u16, detail: impl Into<String>) -> Self {
+        let title = match status {
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            409 => "Conflict",
+            413 => "Content Too Large",
+            422 => "Unprocessable Content",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Error",
+        };
+        Self {
+            r#type: "about:blank".to_string(),
+            title: title.to_string(),
+            status,
+            detail: detail.into(),
+            code,
+            instance: None,
+            request_id: None,
+            trace_id: None,
+        }
+    }
+}
+
+/// What a deep health check found of one upstream dependency.
+#[derive(Serialize, ToSchema)]
+pub struct DependencyHealth {
+    #[schema(example = "routes")]
+    pub name: &'static str,
+    /// Answered without a 5xx within the upstream timeout
+    pub healthy: bool,
+    /// Status of the upstream's answer, none when it failed or timed out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 200)]
+    pub status: Option<u16>,
+    #[serde(rename = "latencyMs")]
+    #[schema(example = 84)]
+    pub latency_ms: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "timed out")]
+    pub error: Option<String>,
+}
+
+/// 422 body of a `strict` arrivals request naming stops that don't exist.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InvalidStopsResponse {
+    #[serde(flatten)]
+    pub problem: Problem,
+    /// The requested ids matching no stop id or SIRI id, in request order
+    #[schema(example = json!(["1O01"]))]
+    pub invalid_stop_ids: Vec<String>,
+}
+
+/// 404 body of a route direction request naming a direction the route doesn't have.
+#[derive(Serialize, ToSchema)]
+pub struct UnknownDirectionResponse {
+    #[serde(flatten)]
+    pub problem: Problem,
+    /// The route's direction names, sorted so each one's position is its index
+    #[schema(example = json!(["Kopli", "Linnahall"]))]
+    pub directions: Vec<String>,
+}
+
+pub struct StopId(pub String);
+impl Deref for StopId {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+pub struct ValidStop {
+    pub data: Rc<StopData>,
+}
+pub struct ReadyStopArrivals(pub Rc<StopArrivals>);
+
+pub enum StopArrivalState {
+    StopId(StopId),
+    Invalid,
+    Valid(ValidStop),
+    Ready(ReadyStopArrivals),
+}
+
+impl StopId {
+    pub fn validate(self, stop_map: &HashMap<String, Rc<StopData>>) -> StopArrivalState {
+        let id: String = self.to_string();
+        if let Some(data) = stop_map.get(&id) {
+            StopArrivalState::Valid(ValidStop {
+                data: Rc::clone(data),
+            })
+        } else {
+            StopArrivalState::Invalid
+        }
+    }
+}
+
+impl ValidStop {
+    /// Ready once `cached` has the arrivals of the stop's SIRI id.
+    pub fn fetch_arrivals_from_cache(
+        self,
+        cached: impl FnOnce(&String) -> Option<Rc<StopArrivals>>,
+    ) -> StopArrivalState {
+        let from_cache = cached(&self.data.siri_id);
+        if let Some(arrivals) = from_cache {
+            StopArrivalState::Ready(ReadyStopArrivals(arrivals))
+        } else {
+            StopArrivalState::Valid(self)
+        }
+    }
+
+    /// Like `fetch_arrivals_from_cache`, with `cached` accepting arrivals past their TTL.
+    pub fn fetch_stale_arrivals_from_cache(
+        self,
+        cached: impl FnOnce(&String) -> Option<Rc<StopArrivals>>,
+    ) -> StopArrivalState {
+        match cached(&self.data.siri_id) {
+            Some(arrivals) => StopArrivalState::Ready(ReadyStopArrivals(arrivals)),
+            None => StopArrivalState::Valid(self),
+        }
+    }
+}
@@ -0,0 +1,1417 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::offset::LocalResult;
+use chrono::{NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use memchr::{memchr_iter, memmem};
+
+use crate::diagnostics::{ParseDiagnostics, ParsedFile};
+use crate::models::*;
+
+/// Upstream data the parsers couldn't make sense of.
+#[derive(Debug)]
+pub enum ParseError {
+    /// A number or time field that isn't one
+    Utf8,
+    /// A header line without a column the parser needs
+    MissingColumn(String),
+    Malformed(String),
+}
+
+impl From<FromUtf8Error> for ParseError {
+    fn from(_err: FromUtf8Error) -> Self {
+        ParseError::Utf8
+    }
+}
+
+type Result<T, E = ParseError> = core::result::Result<T, E>;
+
+/// Seconds from midnight in `region_tz`, past 86400 for the next day, as a UTC RFC 3339 time.
+pub fn seconds_from_midnight_to_utc_iso(
+    seconds_from_midnight: u32,
+    region_tz: Tz,
+) -> Result<String, &'static str> {
+    let is_next_day = seconds_from_midnight >= 86400;
+    let seconds_from_midnight = if is_next_day {
+        seconds_from_midnight - 86400
+    } else {
+        seconds_from_midnight
+    };
+    let time = NaiveTime::from_num_seconds_from_midnight_opt(seconds_from_midnight, 0)
+        .ok_or("seconds_from_midnight must be in 0..=86399")?;
+
+    let today_region = Utc::now().with_timezone(&region_tz).date_naive();
+    let mut naive_dt = NaiveDateTime::new(today_region, time);
+    if is_next_day {
+        naive_dt = naive_dt
+            .checked_add_days(chrono::Days::new(1))
+            .ok_or("date overflow")?;
+    }
+
+    match region_tz.from_local_datetime(&naive_dt) {
+        LocalResult::Single(dt_region) => Ok(dt_region.with_timezone(&Utc).to_rfc3339()),
+        // If local time is ambiguous (fall-back), pick the earlier occurrence.
+        LocalResult::Ambiguous(earliest, _latest) => Ok(earliest.with_timezone(&Utc).to_rfc3339()),
+        // If local time doesn't exist (spring-forward gap), surface an error.
+        LocalResult::None => Err("Local time does not exist in region timezone today (DST gap)"),
+    }
+}
+
+/// 64-bit FNV-1a, a cheap stable content hash for change detection.
+pub fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Windows-1257 (Baltic) code points of 0x80..=0xFF, U+FFFD where the code page has none.
+const WINDOWS_1257_HIGH: [char; 128] = [
+    '\u{20AC}', '\u{FFFD}', '\u{201A}', '\u{FFFD}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{FFFD}', '\u{2030}', '\u{FFFD}', '\u{2039}', '\u{FFFD}', '\u{00A8}', '\u{02C7}', '\u{00B8}',
+    '\u{FFFD}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{FFFD}', '\u{2122}', '\u{FFFD}', '\u{203A}', '\u{FFFD}', '\u{00AF}', '\u{02DB}', '\u{FFFD}',
+    '\u{00A0}', '\u{FFFD}', '\u{00A2}', '\u{00A3}', '\u{00A4}', '\u{FFFD}', '\u{00A6}', '\u{00A7}',
+    '\u{00D8}', '\u{00A9}', '\u{0156}', '\u{00AB}', '\u{00AC}', '\u{00AD}', '\u{00AE}', '\u{00C6}',
+    '\u{00B0}', '\u{00B1}', '\u{00B2}', '\u{00B3}', '\u{00B4}', '\u{00B5}', '\u{00B6}', '\u{00B7}',
+    '\u{00F8}', '\u{00B9}', '\u{0157}', '\u{00BB}', '\u{00BC}', '\u{00BD}', '\u{00BE}', '\u{00E6}',
+    '\u{0104}', '\u{012E}', '\u{0100}', '\u{0106}', '\u{00C4}', '\u{00C5}', '\u{0118}', '\u{0112}',
+    '\u{010C}', '\u{00C9}', '\u{0179}', '\u{0116}', '\u{0122}', '\u{0136}', '\u{012A}', '\u{013B}',
+    '\u{0160}', '\u{0143}', '\u{0145}', '\u{00D3}', '\u{014C}', '\u{00D5}', '\u{00D6}', '\u{00D7}',
+    '\u{0172}', '\u{0141}', '\u{015A}', '\u{016A}', '\u{00DC}', '\u{017B}', '\u{017D}', '\u{00DF}',
+    '\u{0105}', '\u{012F}', '\u{0101}', '\u{0107}', '\u{00E4}', '\u{00E5}', '\u{0119}', '\u{0113}',
+    '\u{010D}', '\u{00E9}', '\u{017A}', '\u{0117}', '\u{0123}', '\u{0137}', '\u{012B}', '\u{013C}',
+    '\u{0161}', '\u{0144}', '\u{0146}', '\u{00F3}', '\u{014D}', '\u{00F5}', '\u{00F6}', '\u{00F7}',
+    '\u{0173}', '\u{0142}', '\u{015B}', '\u{016B}', '\u{00FC}', '\u{017C}', '\u{017E}', '\u{02D9}',
+];
+
+static NON_UTF8_FIELDS: AtomicU64 = AtomicU64::new(0);
+
+/// Upstream fields decoded as Windows-1257 since the isolate started.
+pub fn non_utf8_field_count() -> u64 {
+    NON_UTF8_FIELDS.load(Ordering::Relaxed)
+}
+
+/// Decodes an upstream field as UTF-8, falling back to Windows-1257, the legacy encoding the
+/// files have shipped in, so names like "Väike-Õismäe" survive either way.
+pub fn decode_field(bytes: &[u8]) -> Cow<'_, str> {
+    match str::from_utf8(bytes) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => {
+            NON_UTF8_FIELDS.fetch_add(1, Ordering::Relaxed);
+            Cow::Owned(
+                bytes
+                    .iter()
+                    .map(|&byte| match byte {
+                        0..=0x7f => byte as char,
+                        _ => WINDOWS_1257_HIGH[(byte - 0x80) as usize],
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// Column positions of a header line, by name, compared case-insensitively.
+fn header_columns(header: &[u8]) -> HashMap<String, usize> {
+    delimited_fields(header, b';')
+        .enumerate()
+        .map(|(col, name)| {
+            let name = decode_field(&name)
+                .trim()
+                .trim_start_matches('\u{FEFF}')
+                .to_ascii_lowercase();
+            (name, col)
+        })
+        .collect()
+}
+
+fn required_column(columns: &HashMap<String, usize>, file: &str, name: &str) -> Result<usize> {
+    columns
+        .get(&name.to_ascii_lowercase())
+        .copied()
+        .ok_or_else(|| ParseError::MissingColumn(format!("{} header is missing {}", file, name)))
+}
+
+/// Where the fields used from routes.txt are, resolved from its header line.
+#[derive(Clone, Copy)]
+pub struct RouteColumns {
+    number: usize,
+    route_type: usize,
+    direction: usize,
+    stops: usize,
+}
+
+impl RouteColumns {
+    pub fn from_header(header: &[u8]) -> Result<Self> {
+        let columns = header_columns(header);
+        Ok(Self {
+            number: required_column(&columns, "routes.txt", "RouteNum")?,
+            route_type: required_column(&columns, "routes.txt", "Transport")?,
+            direction: required_column(&columns, "routes.txt", "RouteName")?,
+            stops: required_column(&columns, "routes.txt", "RouteStops")?,
+        })
+    }
+
+    fn last(&self) -> usize {
+        self.number
+            .max(self.route_type)
+            .max(self.direction)
+            .max(self.stops)
+    }
+}
+
+/// Where the fields used from stops.txt are, resolved from its header line.
+#[derive(Clone, Copy)]
+pub struct StopColumns {
+    id: usize,
+    siri_id: usize,
+    name: usize,
+    lat: Option<usize>,
+    lon: Option<usize>,
+    zone: Option<usize>,
+}
+
+impl StopColumns {
+    pub fn from_header(header: &[u8]) -> Result<Self> {
+        let columns = header_columns(header);
+        Ok(Self {
+            id: required_column(&columns, "stops.txt", "ID")?,
+            siri_id: required_column(&columns, "stops.txt", "SiriID")?,
+            name: required_column(&columns, "stops.txt", "Name")?,
+            lat: columns.get("lat").copied(),
+            lon: columns.get("lng").copied(),
+            zone: columns.get("area").copied(),
+        })
+    }
+
+    fn last(&self) -> usize {
+        self.id
+            .max(self.siri_id)
+            .max(self.name)
+            .max(self.lat.unwrap_or(0))
+            .max(self.lon.unwrap_or(0))
+            .max(self.zone.unwrap_or(0))
+    }
+}
+
+/// RFC 4180 style fields of one line: a field opening with `"` runs to the matching closing
+/// quote, delimiters inside it are kept and `""` stands for a literal quote. Unquoted fields are
+/// borrowed as is. Quoted newlines aren't supported, lines are split before reaching here.
+pub struct DelimitedFields<'a> {
+    line: &'a [u8],
+    delimiter: u8,
+    pos: usize,
+    done: bool,
+}
+
+pub fn delimited_fields(line: &[u8], delimiter: u8) -> DelimitedFields<'_> {
+    DelimitedFields {
+        line,
+        delimiter,
+        pos: 0,
+        done: false,
+    }
+}
+
+impl<'a> Iterator for DelimitedFields<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let rest = &self.line[self.pos..];
+        let (field, consumed) = if rest.first() == Some(&b'"') {
+            let mut unquoted: Option<Vec<u8>> = None;
+            let mut chunk_start = 1usize;
+            let mut close = rest.len();
+            let mut search = 1usize;
+            while let Some(quote) = memchr::memchr(b'"', &rest[search..]).map(|q| q + search) {
+                if rest.get(quote + 1) == Some(&b'"') {
+                    // escaped quote, keep one of the pair
+                    unquoted
+                        .get_or_insert_with(Vec::new)
+                        .extend_from_slice(&rest[chunk_start..=quote]);
+                    chunk_start = quote + 2;
+                    search = quote + 2;
+                } else {
+                    close = quote;
+                    break;
+                }
+            }
+            let field = match unquoted {
+                Some(mut unquoted) => {
+                    unquoted.extend_from_slice(&rest[chunk_start.min(close)..close]);
+                    Cow::Owned(unquoted)
+                }
+                None => Cow::Borrowed(&rest[chunk_start.min(close)..close]),
+            };
+            // anything between the closing quote and the delimiter is dropped
+            let end = memchr::memchr(self.delimiter, &rest[close.min(rest.len())..])
+                .map_or(rest.len(), |d| d + close);
+            (field, end)
+        } else {
+            let end = memchr::memchr(self.delimiter, rest).unwrap_or(rest.len());
+            (Cow::Borrowed(&rest[..end]), end)
+        };
+        if consumed >= rest.len() {
+            self.done = true;
+        } else {
+            self.pos += consumed + 1;
+        }
+        Some(field)
+    }
+}
+
+#[inline(always)]
+pub fn splits_commas(input: &[u8]) -> core::result::Result<Vec<String>, FromUtf8Error> {
+    let count = memchr_iter(b',', input).count();
+    let mut parts = Vec::with_capacity(count + 1);
+    let mut start = 0usize;
+    for i in memchr_iter(b',', input).chain(std::iter::once(input.len())) {
+        parts.push(String::from_utf8(input[start..i].to_owned())?);
+        start = i + 1;
+    }
+    Ok(parts)
+}
+
+/// Strips the diacritics of Latin letters, so "Õismäe" and "oismae" compare equal.
+fn fold_diacritic(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => 'a',
+        'ç' | 'ć' | 'č' => 'c',
+        'ď' | 'đ' => 'd',
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => 'e',
+        'ğ' | 'ģ' => 'g',
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' => 'i',
+        'ķ' => 'k',
+        'ĺ' | 'ļ' | 'ľ' | 'ł' => 'l',
+        'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => 'o',
+        'ŕ' | 'ř' => 'r',
+        'ś' | 'ş' | 'š' => 's',
+        'ţ' | 'ť' => 't',
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' | 'ų' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        _ => c,
+    }
+}
+
+/// Lowercase, diacritic-free and trimmed form names are compared in.
+pub fn normalize_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(fold_diacritic)
+        .collect()
+}
+
+/// Stops by normalized name, built from one stop map and cached alongside it.
+pub struct StopNameIndex {
+    /// The stop map the index was built from, a different map means the index is outdated
+    pub stop_map: Rc<HashMap<String, Rc<StopData>>>,
+    /// One entry per stop, sorted by normalized name then id
+    pub entries: Vec<(String, Rc<StopData>)>,
+    /// `(entry, byte offset)` of every word start in the names, sorted by the name from there
+    word_starts: Vec<(usize, usize)>,
+}
+
+impl StopNameIndex {
+    pub fn build(stop_map: Rc<HashMap<String, Rc<StopData>>>) -> Self {
+        let mut entries = stop_map
+            .iter()
+            .filter(|(key, stop)| **key == stop.id)
+            .map(|(_, stop)| (normalize_name(&stop.name), Rc::clone(stop)))
+            .collect::<Vec<(String, Rc<StopData>)>>();
+        entries.sort_unstable_by(|(a_name, a), (b_name, b)| {
+            a_name.cmp(b_name).then_with(|| a.id.cmp(&b.id))
+        });
+        let mut word_starts = entries
+            .iter()
+            .enumerate()
+            .flat_map(|(entry, (name, _))| {
+                name.char_indices()
+                    .filter(|&(offset, c)| {
+                        c.is_alphanumeric()
+                            && name[..offset]
+                                .chars()
+                                .next_back()
+                                .is_none_or(|prev| !prev.is_alphanumeric())
+                    })
+                    .map(move |(offset, _)| (entry, offset))
+            })
+            .collect::<Vec<(usize, usize)>>();
+        word_starts.sort_unstable_by(|&(a, a_offset), &(b, b_offset)| {
+            entries[a].0[a_offset..].cmp(&entries[b].0[b_offset..])
+        });
+        Self {
+            stop_map,
+            entries,
+            word_starts,
+        }
+    }
+
+    /// Entries whose normalized name is exactly `name`, next to each other since sorted.
+    pub fn named(&self, name: &str) -> &[(String, Rc<StopData>)] {
+        let first = self
+            .entries
+            .partition_point(|(entry, _)| entry.as_str() < name);
+        let count = self.entries[first..]
+            .iter()
+            .take_while(|(entry, _)| entry == name)
+            .count();
+        &self.entries[first..first + count]
+    }
+
+    /// Entries grouped by normalized name, in name order.
+    pub fn groups(&self) -> impl Iterator<Item = &[(String, Rc<StopData>)]> {
+        self.entries.chunk_by(|(a, _), (b, _)| a == b)
+    }
+
+    /// Entries with a word starting with `prefix`, which is expected normalized, each once, by
+    /// binary search over the word starts.
+    pub fn with_word_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = &'a (String, Rc<StopData>)> {
+        let suffix = |&(entry, offset): &(usize, usize)| &self.entries[entry].0[offset..];
+        let first = self
+            .word_starts
+            .partition_point(|word_start| suffix(word_start) < prefix);
+        let mut seen = HashSet::new();
+        self.word_starts[first..]
+            .iter()
+            .take_while(move |word_start| suffix(word_start).starts_with(prefix))
+            .filter(move |(entry, _)| seen.insert(*entry))
+            .map(|(entry, _)| &self.entries[*entry])
+    }
+}
+
+/// The stored direction matching the requested one: exactly, else ignoring case, surrounding
+/// whitespace and diacritics, the first in sorted order when several match that way.
+pub fn resolve_direction<'a>(
+    directions: impl IntoIterator<Item = &'a String>,
+    requested: &str,
+) -> Option<&'a String> {
+    let normalized = normalize_name(requested);
+    let mut lenient: Option<&String> = None;
+    for direction in directions {
+        if direction == requested {
+            return Some(direction);
+        }
+        if lenient.is_none_or(|lenient| direction < lenient)
+            && normalize_name(direction) == normalized
+        {
+            lenient = Some(direction);
+        }
+    }
+    lenient
+}
+
+/// The direction `requested` addresses: by name as [`resolve_direction`] matches it, else by its
+/// index among the sorted names, the order the directions listings are in.
+pub fn resolve_direction_or_index<'a>(
+    directions: impl IntoIterator<Item = &'a String>,
+    requested: &str,
+) -> Option<&'a String> {
+    let mut directions = directions.into_iter().collect::<Vec<&String>>();
+    if let Some(direction) = resolve_direction(directions.iter().copied(), requested) {
+        return Some(direction);
+    }
+    if requested.is_empty() || !requested.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let index = requested.parse::<usize>().ok()?;
+    directions.sort_unstable();
+    directions.get(index).copied()
+}
+
+/// The direction a vehicle showing `destination` runs. gps.txt shows the terminus rather than the
+/// direction name, so after the name itself this tries the name of each direction's last stop,
+/// then the direction names ending with it ("Kopli - Linnahall"). Directions are tried in sorted
+/// order so the same vehicle always lands on the same one.
+pub fn resolve_vehicle_direction<'a>(
+    directions: &'a HashMap<String, Vec<String>>,
+    destination: &str,
+    stop_map: &HashMap<String, Rc<StopData>>,
+) -> Option<&'a String> {
+    if let Some(direction) = resolve_direction(directions.keys(), destination) {
+        return Some(direction);
+    }
+    let destination = normalize_name(destination);
+    let mut sorted = directions.iter().collect::<Vec<(&String, &Vec<String>)>>();
+    sorted.sort_unstable_by_key(|(direction, _)| *direction);
+    sorted
+        .iter()
+        .find(|(_, stops)| {
+            stops
+                .last()
+                .and_then(|stop_id| stop_map.get(stop_id))
+                .is_some_and(|stop| normalize_name(&stop.name) == destination)
+        })
+        .or_else(|| {
+            sorted
+                .iter()
+                .find(|(direction, _)| normalize_name(direction).ends_with(&destination))
+        })
+        .map(|(direction, _)| *direction)
+}
+
+/// Edits a fuzzy stop search tolerates for a query of `len` characters.
+pub fn max_typos(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Fewest single-character edits turning `needle` into some part of `haystack` (approximate
+/// substring matching), `None` when that takes more than `max_edits`. Both are expected
+/// normalized.
+pub fn fuzzy_substring_distance(
+    needle: &[char],
+    haystack: &str,
+    max_edits: usize,
+) -> Option<usize> {
+    // column j holds the best distance of needle[..j] ending at the current haystack char;
+    // row 0 is free so the match may start anywhere
+    let mut prev = (0..=needle.len()).collect::<Vec<usize>>();
+    let mut cur = vec![0usize; needle.len() + 1];
+    let mut best = prev[needle.len()];
+    for ch in haystack.chars() {
+        for j in 1..=needle.len() {
+            let substitution = prev[j - 1] + usize::from(needle[j - 1] != ch);
+            cur[j] = substitution.min(prev[j] + 1).min(cur[j - 1] + 1);
+        }
+        best = best.min(cur[needle.len()]);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    (best <= max_edits).then_some(best)
+}
+
+/// How well `name` matches a search for `needle`, lower is better: the whole name, its start,
+/// the start of one of its words, anywhere in it. Both are expected normalized.
+pub fn name_match_rank(name: &str, needle: &str) -> u8 {
+    if name == needle {
+        0
+    } else if name.starts_with(needle) {
+        1
+    } else if name
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.starts_with(needle))
+    {
+        2
+    } else {
+        3
+    }
+}
+
+#[derive(Default)]
+pub struct LastRouteData {
+    pub last_type: Option<String>,
+    pub last_number: Option<String>,
+}
+
+#[inline(always)]
+fn split_stops_field(stops_raw: &[u8]) -> Vec<String> {
+    let count = memchr_iter(b',', stops_raw).count();
+    let mut stops = Vec::with_capacity(count + 1);
+    let mut start = 0usize;
+    for i in memchr_iter(b',', stops_raw) {
+        stops.push(decode_field(&stops_raw[start..i]).into_owned());
+        start = i + 1;
+    }
+    if start < stops_raw.len() {
+        stops.push(decode_field(&stops_raw[start..]).into_owned());
+    }
+    stops
+}
+
+pub fn split_arrival_by_stops(arrival: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut start = 0usize;
+    memmem::find_iter(arrival, b"\nstop,")
+        .chain(std::iter::once(arrival.len()))
+        .map(move |i| {
+            let part = &arrival[start..i];
+            start = i + 1;
+            part
+        })
+        .skip(1)
+}
+
+pub fn remove_trailing_newline(input: &[u8]) -> &[u8] {
+    if let Some(last_byte) = input.last()
+        && *last_byte == b'\n'
+    {
+        &input[..input.len() - 1]
+    } else {
+        input
+    }
+}
+
+fn remove_trailing_cr(input: &[u8]) -> &[u8] {
+    input.strip_suffix(b"\r").unwrap_or(input)
+}
+
+pub fn extract_arrival_data(arrival_line: &[u8], region_tz: Tz) -> Result<StopArrival> {
+    let mut start = 0usize;
+
+    let mut route_number = None;
+    let mut route_type = None;
+    let mut expected_time = None;
+    let mut expected_seconds = None;
+    let mut is_realtime = false;
+    let mut destination = None;
+    let mut vehicle_id = None;
+    let mut arrival_type = None;
+
+    for (col, i) in memchr_iter(b',', arrival_line)
+        .chain(std::iter::once(arrival_line.len()))
+        .enumerate()
+    {
+        let current = unsafe { str::from_utf8_unchecked(&arrival_line[start..i]) };
+        match col {
+            0 => {
+                route_type = Some(current);
+            }
+            1 => {
+                route_number = Some(current);
+            }
+            2 => {
+                let seconds = current.parse::<u32>().map_err(|_| ParseError::Utf8)?;
+                expected_seconds = Some(seconds);
+                expected_time = Some(
+                    seconds_from_midnight_to_utc_iso(seconds, region_tz)
+                        .map_err(|_| ParseError::Utf8)?,
+                );
+            }
+            3 => {
+                // the feed repeats the timetable time as the expected one unless a vehicle
+                // position moved it
+                is_realtime = current
+                    .parse::<u32>()
+                    .is_ok_and(|scheduled| Some(scheduled) != expected_seconds);
+            }
+            4 => {
+                destination = Some(current.trim()).filter(|destination| !destination.is_empty());
+            }
+            5 => {
+                vehicle_id = Some(current.trim()).filter(|vehicle_id| !vehicle_id.is_empty());
+            }
+            6 => {
+                let expected_time = expected_time.ok_or(ParseError::Malformed(String::from(
+                    "incorrect arrival time",
+                )))?;
+                arrival_type = Some(Arrival {
+                    time: expected_time,
+                    is_low_entry: current == "Z",
+                    is_realtime,
+                    destination: destination.map(str::to_string),
+                    vehicle_id: vehicle_id.map(str::to_string),
+                    vehicle_distance_m: None,
+                });
+                break; // early exit after the last needed column
+            }
+            _ => {}
+        }
+        start = i.saturating_add(1);
+    }
+
+    Ok(StopArrival {
+        number: route_number
+            .ok_or(ParseError::Malformed(String::from("invalid arrival data1")))?
+            .to_string(),
+        r#type: TransportType::parse(
+            route_type.ok_or(ParseError::Malformed(String::from("invalid arrival data2")))?,
+        ),
+        arrivals: arrival_type
+            .ok_or(ParseError::Malformed(String::from("invalid arrival data3")))?,
+    })
+}
+
+pub fn extract_arrival_list_data(
+    arrival_lines: &[u8],
+    region_tz: Tz,
+) -> impl Iterator<Item = Result<StopArrival>> {
+    let mut start = 0usize;
+    memchr_iter(b'\n', arrival_lines)
+        .chain(std::iter::once(arrival_lines.len()))
+        .map(move |i| {
+            let part = &arrival_lines[start..i];
+            start = i + 1;
+            part
+        })
+        .filter(|line| !line.is_empty())
+        .map(move |line| {
+            let arrival = extract_arrival_data(line, region_tz);
+            if arrival.is_err() {
+                ParseDiagnostics::get().record_malformed(ParsedFile::Arrivals, line);
+            }
+            arrival
+        })
+}
+
+pub fn extract_stop_arrival_list_data(
+    stop_lines: &[u8],
+    stop_map: &HashMap<String, Rc<StopData>>,
+    region_tz: Tz,
+) -> Result<StopArrivals> {
+    let first_new_line_pos = memchr::memchr(b'\n', stop_lines)
+        .ok_or(ParseError::Malformed(String::from("invalid arrival data4")))?;
+    let stop_id = {
+        let first_line = remove_trailing_newline(&stop_lines[..=first_new_line_pos]);
+        let stop_id_comma_pos = memchr::memchr_iter(b',', first_line)
+            .next()
+            .ok_or(ParseError::Malformed(String::from("invalid arrival data5")))?;
+        &first_line[stop_id_comma_pos + 1..]
+    };
+
+    let mut arrivals = HashMap::new();
+    let arrival_lines = &stop_lines[first_new_line_pos + 1..];
+
+    for arrival in extract_arrival_list_data(arrival_lines, region_tz) {
+        let arrival = arrival?;
+        arrivals
+            .entry(arrival.r#type.to_string())
+            .or_insert_with(HashMap::new)
+            .entry(arrival.number.clone())
+            .or_insert_with(Vec::new)
+            .push(arrival.arrivals);
+    }
+
+    let stop_id = unsafe { str::from_utf8_unchecked(stop_id) };
+    let stop = stop_map.get(stop_id);
+    Ok(StopArrivals {
+        id: stop_id.to_string(),
+        lat: stop.and_then(|stop| stop.lat),
+        lon: stop.and_then(|stop| stop.lon),
+        name: stop
+            .map(|stop| stop.name.to_string())
+            .ok_or(ParseError::Malformed(String::from("invalid arrival data6")))?,
+        arrivals,
+    })
+}
+
+pub fn extract_arrival_stop_data_from_line(
+    line: &[u8],
+    stop_map: &HashMap<String, Rc<StopData>>,
+    region_tz: Tz,
+) -> impl Iterator<Item = Result<StopArrivals>> {
+    let mut start = 0usize;
+    memmem::find_iter(line, b"\nstop,")
+        .chain(std::iter::once(line.len()))
+        .map(move |i| {
+            let part = &line[start..i];
+            start = i + 1;
+            part
+        })
+        .filter(|s| memchr::memchr(b'\n', s).is_some())
+        .map(move |s| {
+            let stop_arrivals = extract_stop_arrival_list_data(s, stop_map, region_tz);
+            if stop_arrivals.is_err() {
+                let stop_line = memchr::memchr(b'\n', s).map_or(s, |pos| &s[..pos]);
+                ParseDiagnostics::get().record_malformed(ParsedFile::Arrivals, stop_line);
+            }
+            stop_arrivals
+        })
+}
+
+pub fn extract_route_data_from_line(
+    line: &[u8],
+    columns: &RouteColumns,
+    last_data: &mut LastRouteData,
+) -> Option<RouteData> {
+    let mut raw_num = None;
+    let mut raw_type = None;
+    let mut direction = None;
+    let mut stops = Vec::new();
+
+    for (col, field) in delimited_fields(line, b';').enumerate() {
+        if col == columns.number {
+            // skip validation here, just take whatever is present
+            raw_num = Some(decode_field(&field).into_owned());
+        }
+        if col == columns.route_type {
+            raw_type = Some(decode_field(&field).into_owned());
+        }
+        if col == columns.direction {
+            direction = Some(decode_field(&field).trim().to_string()).filter(|s| !s.is_empty());
+        }
+        if col == columns.stops {
+            stops = split_stops_field(&field);
+        }
+        if col == columns.last() {
+            break; // early exit after the last needed column
+        }
+    }
+
+    // start validating, or fail fast if absent
+    let route_type = Some(raw_type?.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .or(last_data.last_type.clone())?;
+    last_data.last_type = Some(route_type.clone());
+    let route_num = raw_num
+        .as_deref()
+        .map(str::trim)
+        .map(str::to_string)
+        .filter(|s| !s.is_empty())
+        .or(last_data.last_number.clone())?;
+    last_data.last_number = Some(route_num.clone());
+    Some(RouteData {
+        number: route_num,
+        route_type: TransportType::parse(&route_type),
+        directions: direction?,
+        stops,
+    })
+}
+
+#[allow(clippy::type_complexity)]
+pub async fn extract_route_data_from_buffer_fold<E: From<ParseError>>(
+    (mut buf, route_map, last_data, last_processed, columns): (
+        Vec<u8>,
+        HashMap<String, HashMap<String, RouteGroup>>,
+        LastRouteData,
+        usize,
+        Option<RouteColumns>,
+    ),
+    chunk: Vec<u8>,
+) -> Result<
+    (
+        Vec<u8>,
+        HashMap<String, HashMap<String, RouteGroup>>,
+        LastRouteData,
+        usize,
+        Option<RouteColumns>,
+    ),
+    E,
+> {
+    buf.extend_from_slice(&chunk);
+    let (route_map, last_data, last_processed, columns) =
+        extract_route_data_from_buffer(&buf, route_map, last_data, last_processed, columns).await?;
+    Ok((buf, route_map, last_data, last_processed, columns))
+}
+
+pub async fn extract_route_data_from_buffer(
+    buf: &[u8],
+    mut route_map: HashMap<String, HashMap<String, RouteGroup>>,
+    mut last_data: LastRouteData,
+    mut last_processed: usize,
+    mut columns: Option<RouteColumns>,
+) -> Result<(
+    HashMap<String, HashMap<String, RouteGroup>>,
+    LastRouteData,
+    usize,
+    Option<RouteColumns>,
+)> {
+    let search_start = last_processed;
+
+    for newline_pos in
+        memchr::memchr_iter(b'\n', &buf[search_start..]).map(|pos| pos + search_start)
+    {
+        let line = &buf[last_processed..newline_pos];
+        let Some(line_columns) = &columns else {
+            columns = Some(RouteColumns::from_header(line)?);
+            last_processed = newline_pos + 1;
+            continue;
+        };
+
+        if let Some(route_data) = extract_route_data_from_line(line, line_columns, &mut last_data) {
+            let type_entry = route_map
+                .entry(route_data.route_type.to_string())
+                .or_default();
+            type_entry
+                .entry(route_data.number.clone())
+                .and_modify(|group| {
+                    group
+                        .directions
+                        .insert(route_data.directions.clone(), route_data.stops.clone());
+                })
+                .or_insert({
+                    let mut directions = HashMap::with_capacity(2);
+                    directions.insert(route_data.directions, route_data.stops);
+                    RouteGroup {
+                        number: route_data.number,
+                        r#type: route_data.route_type,
+                        directions,
+                    }
+                });
+        } else if memchr::memchr(b';', line).is_some() {
+            // lines without any `;` are the timetables following each route, not routes
+            ParseDiagnostics::get().record_malformed(ParsedFile::Routes, line);
+        }
+        last_processed = newline_pos + 1;
+    }
+
+    Ok((route_map, last_data, last_processed, columns))
+}
+
+pub async fn extract_type_from_buffer_fold<E: From<ParseError>>(
+    (mut buf, type_set, last_processed, columns): (
+        Vec<u8>,
+        HashSet<String>,
+        usize,
+        Option<RouteColumns>,
+    ),
+    chunk: Vec<u8>,
+) -> Result<
+    (
+        Vec<u8>,
+        HashSet<std::string::String>,
+        usize,
+        Option<RouteColumns>,
+    ),
+    E,
+> {
+    buf.extend_from_slice(&chunk);
+    let (type_set, last_processed, columns) =
+        extract_type_from_buffer(&buf, type_set, last_processed, columns).await?;
+    Ok((buf, type_set, last_processed, columns))
+}
+
+pub async fn extract_type_from_buffer(
+    buf: &[u8],
+    mut type_set: HashSet<String>,
+    mut last_processed: usize,
+    mut columns: Option<RouteColumns>,
+) -> Result<(HashSet<std::string::String>, usize, Option<RouteColumns>)> {
+    let search_start = last_processed;
+
+    for newline_pos in
+        memchr::memchr_iter(b'\n', &buf[search_start..]).map(|pos| pos + search_start)
+    {
+        let line = &buf[last_processed..newline_pos];
+        let Some(line_columns) = &columns else {
+            columns = Some(RouteColumns::from_header(line)?);
+            last_processed = newline_pos + 1;
+            continue;
+        };
+
+        if let Some(transport_type_bytes) =
+            delimited_fields(line, b';').nth(line_columns.route_type)
+            && !transport_type_bytes.is_empty()
+        {
+            type_set.insert(TransportType::parse(&decode_field(&transport_type_bytes)).to_string());
+        }
+        last_processed = newline_pos + 1;
+    }
+
+    Ok((type_set, last_processed, columns))
+}
+
+/// stops.txt writes coordinates as degrees scaled by 10^5 ("5943686"), plain decimal degrees
+/// are accepted too. Out of range values are dropped rather than placing a stop off the map.
+fn parse_coordinate(field: &[u8], max_degrees: f64) -> Option<f64> {
+    let field = str::from_utf8(field).ok()?.trim();
+    let degrees = if field.contains('.') {
+        field.parse::<f64>().ok()?
+    } else {
+        field.parse::<i64>().ok()? as f64 / 100_000.0
+    };
+    (degrees.is_finite() && degrees.abs() <= max_degrees && degrees != 0.0).then_some(degrees)
+}
+
+/// gps.txt codes of the transport types, unknown codes are kept as they are.
+fn vehicle_type(code: &str) -> &str {
+    match code {
+        "1" => "trol",
+        "2" => "bus",
+        "3" => "tram",
+        code => code,
+    }
+}
+
+/// gps.txt writes coordinates as degrees scaled by 10^6 ("59438590").
+fn parse_gps_coordinate(field: &[u8], max_degrees: f64) -> Option<f64> {
+    let degrees = str::from_utf8(field).ok()?.trim().parse::<i64>().ok()? as f64 / 1_000_000.0;
+    (degrees.abs() <= max_degrees && degrees != 0.0).then_some(degrees)
+}
+
+/// One gps.txt line, `type,number,lon,lat,speed,bearing,vehicle id,...`, with the destination in
+/// the tenth column when the feed has one.
+pub fn extract_vehicle_position_from_line(line: &[u8]) -> Option<VehiclePosition> {
+    let fields = remove_trailing_cr(line)
+        .split(|&byte| byte == b',')
+        .collect::<Vec<&[u8]>>();
+    let text = |col: usize| {
+        fields
+            .get(col)
+            .map(|field| decode_field(field).trim().to_string())
+            .filter(|field| !field.is_empty())
+    };
+    let r#type = TransportType::parse(vehicle_type(&text(0)?));
+    let number = text(1)?;
+    let lon = parse_gps_coordinate(fields.get(2)?, 180.0)?;
+    let lat = parse_gps_coordinate(fields.get(3)?, 90.0)?;
+    let speed = text(4).and_then(|speed| speed.parse::<f32>().ok());
+    let bearing = text(5)
+        .and_then(|bearing| bearing.parse::<u16>().ok())
+        .filter(|bearing| *bearing < 360);
+    Some(VehiclePosition {
+        id: text(6)?,
+        r#type,
+        number,
+        direction: text(9),
+        lat,
+        lon,
+        bearing,
+        speed,
+    })
+}
+
+pub fn extract_vehicle_positions(buf: &[u8]) -> Vec<VehiclePosition> {
+    buf.split(|&byte| byte == b'\n')
+        .filter(|line| !remove_trailing_cr(line).is_empty())
+        .filter_map(|line| {
+            let vehicle = extract_vehicle_position_from_line(line);
+            if vehicle.is_none() {
+                ParseDiagnostics::get().record_malformed(ParsedFile::Vehicles, line);
+            }
+            vehicle
+        })
+        .collect()
+}
+
+pub fn extract_stop_data_from_line(
+    line: &[u8],
+    columns: &StopColumns,
+    last_name: &Option<Rc<String>>,
+) -> Option<Rc<StopData>> {
+    let mut id = None;
+    let mut siri_id = None;
+    let mut name = None;
+    let mut lat = None;
+    let mut lon = None;
+    let mut zone = None;
+
+    for (col, field) in delimited_fields(line, b';').enumerate() {
+        if col == columns.id {
+            id = Some(decode_field(&field).into_owned());
+        }
+        if col == columns.siri_id {
+            siri_id = Some(decode_field(&field).into_owned());
+        }
+        if col == columns.name {
+            name = Some(decode_field(&field).into_owned());
+        }
+        if Some(col) == columns.lat {
+            lat = parse_coordinate(&field, 90.0);
+        }
+        if Some(col) == columns.lon {
+            lon = parse_coordinate(&field, 180.0);
+        }
+        if Some(col) == columns.zone {
+            zone = Some(decode_field(&field).trim().to_string()).filter(|s| !s.is_empty());
+        }
+        if col == columns.last() {
+            break; // early exit after the last needed column
+        }
+    }
+
+    let name = name
+        .as_deref()
+        .map(str::trim)
+        .map(str::to_string)
+        .filter(|s| !s.is_empty())
+        .map(Rc::new)
+        .or_else(|| last_name.as_ref().map(Rc::clone))?;
+    let siri_id = siri_id
+        .as_deref()
+        .map(str::trim)
+        .map(str::to_string)
+        .filter(|s| !s.is_empty())?;
+    let id = id
+        .as_deref()
+        .map(str::trim)
+        .map(str::to_string)
+        .filter(|s| !s.is_empty())?;
+
+    Some(Rc::new(StopData {
+        id,
+        siri_id,
+        name,
+        lat,
+        lon,
+        zone,
+    }))
+}
+
+#[allow(clippy::type_complexity)]
+pub async fn extract_stop_data_from_buffer_fold<E: From<ParseError>>(
+    (mut buf, stop_map, last_name, last_processed, columns): (
+        Vec<u8>,
+        HashMap<String, Rc<StopData>>,
+        Option<Rc<String>>,
+        usize,
+        Option<StopColumns>,
+    ),
+    chunk: Vec<u8>,
+) -> Result<
+    (
+        Vec<u8>,
+        HashMap<String, Rc<StopData>>,
+        Option<Rc<String>>,
+        usize,
+        Option<StopColumns>,
+    ),
+    E,
+> {
+    buf.extend_from_slice(&chunk);
+    let (stop_map, last_name, last_processed, columns) =
+        extract_stop_data_from_buffer(&buf, stop_map, last_name, last_processed, columns).await?;
+    Ok((buf, stop_map, last_name, last_processed, columns))
+}
+
+pub async fn extract_stop_data_from_buffer(
+    buf: &[u8],
+    mut stop_map: HashMap<String, Rc<StopData>>,
+    mut last_name: Option<Rc<String>>,
+    mut last_processed: usize,
+    mut columns: Option<StopColumns>,
+) -> Result<(
+    HashMap<String, Rc<StopData>>,
+    Option<Rc<String>>,
+    usize,
+    Option<StopColumns>,
+)> {
+    let search_start = last_processed;
+
+    for newline_pos in
+        memchr::memchr_iter(b'\n', &buf[search_start..]).map(|pos| pos + search_start)
+    {
+        let line = &buf[last_processed..newline_pos];
+        let Some(line_columns) = &columns else {
+            columns = Some(StopColumns::from_header(line)?);
+            last_processed = newline_pos + 1;
+            continue;
+        };
+
+        if let Some(stop_data) = extract_stop_data_from_line(line, line_columns, &last_name) {
+            last_name = Some(Rc::clone(&stop_data.name));
+            stop_map.insert(stop_data.id.clone(), Rc::clone(&stop_data));
+            stop_map.insert(stop_data.siri_id.clone(), stop_data);
+        } else if !remove_trailing_cr(line).is_empty() {
+            ParseDiagnostics::get().record_malformed(ParsedFile::Stops, line);
+        }
+        last_processed = newline_pos + 1;
+    }
+
+    Ok((stop_map, last_name, last_processed, columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(id: &str, name: &str) -> (String, Rc<StopData>) {
+        let stop = StopData {
+            id: id.to_string(),
+            siri_id: format!("s{}", id),
+            name: Rc::new(name.to_string()),
+            lat: None,
+            lon: None,
+            zone: None,
+        };
+        (id.to_string(), Rc::new(stop))
+    }
+
+    fn directions(entries: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, stops)| {
+                let stops = stops.iter().map(|stop| stop.to_string()).collect();
+                (name.to_string(), stops)
+            })
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn direction_matches_leniently() {
+        let directions = names(&["Kopli", "Männiku", "Pääsküla - Balti jaam"]);
+        let resolve = |requested| resolve_direction(&directions, requested).map(String::as_str);
+        assert_eq!(resolve("Kopli"), Some("Kopli"));
+        assert_eq!(resolve("kOPLI"), Some("Kopli"));
+        assert_eq!(resolve("  Kopli \t"), Some("Kopli"));
+        assert_eq!(resolve("manniku"), Some("Männiku"));
+        assert_eq!(
+            resolve("PAASKULA - balti JAAM"),
+            Some("Pääsküla - Balti jaam")
+        );
+        assert_eq!(resolve("Kop"), None);
+        assert_eq!(resolve(""), None);
+    }
+
+    #[test]
+    fn direction_prefers_exact_then_sorted_lenient() {
+        let directions = names(&["Mannik", "männik", "Männik"]);
+        let resolve = |requested| resolve_direction(&directions, requested).map(String::as_str);
+        assert_eq!(resolve("männik"), Some("männik"));
+        assert_eq!(resolve("MANNIK"), Some("Mannik"));
+        let reversed = names(&["männik", "Männik"]);
+        assert_eq!(resolve_direction(&reversed, "MANNIK").unwrap(), "Männik");
+    }
+
+    #[test]
+    fn direction_by_index_of_sorted_names() {
+        let directions = names(&["Linnahall", "Kopli", "7"]);
+        let resolve =
+            |requested| resolve_direction_or_index(&directions, requested).map(String::as_str);
+        assert_eq!(resolve("0"), Some("7"));
+        assert_eq!(resolve("1"), Some("Kopli"));
+        assert_eq!(resolve("2"), Some("Linnahall"));
+        assert_eq!(resolve("7"), Some("7"));
+        assert_eq!(resolve("3"), None);
+        assert_eq!(resolve("+1"), None);
+        assert_eq!(resolve("kopli"), Some("Kopli"));
+    }
+
+    #[test]
+    fn vehicle_direction_by_name_then_terminus_then_suffix() {
+        let stop_map = HashMap::from([stop("1", "Kopli"), stop("2", "Männiku")]);
+        let directions = directions(&[
+            ("Kopli - Linnahall", &["2", "1"]),
+            ("Linnahall - Männiku", &["1", "2"]),
+        ]);
+        let resolve = |destination| resolve_vehicle_direction(&directions, destination, &stop_map);
+        assert_eq!(
+            resolve("linnahall - männiku").unwrap(),
+            "Linnahall - Männiku"
+        );
+        assert_eq!(resolve("Kopli").unwrap(), "Kopli - Linnahall");
+        assert_eq!(resolve("Manniku").unwrap(), "Linnahall - Männiku");
+        assert_eq!(resolve("Pirita"), None);
+    }
+
+    #[test]
+    fn vehicle_direction_ties_resolve_in_sorted_order() {
+        let stop_map = HashMap::from([stop("1", "Kopli")]);
+        let terminating = directions(&[("B", &["1"]), ("C", &["1"]), ("A", &["1"])]);
+        for _ in 0..10 {
+            let direction = resolve_vehicle_direction(&terminating, "Kopli", &stop_map);
+            assert_eq!(direction.unwrap(), "A");
+        }
+        let suffixed = directions(&[("Z - Kopli", &[]), ("A - Kopli", &[])]);
+        let direction = resolve_vehicle_direction(&suffixed, "kopli", &stop_map);
+        assert_eq!(direction.unwrap(), "A - Kopli");
+    }
+
+    #[test]
+    fn decodes_utf8_fields_as_is() {
+        let field = decode_field("Väike-Õismäe".as_bytes());
+        assert!(matches!(field, Cow::Borrowed("Väike-Õismäe")));
+        assert!(matches!(decode_field(b""), Cow::Borrowed("")));
+    }
+
+    #[test]
+    fn decodes_non_utf8_fields_as_windows_1257() {
+        let before = non_utf8_field_count();
+        let field = decode_field(b"V\xe4ike-\xd5ism\xe4e");
+        assert!(matches!(field, Cow::Owned(_)));
+        assert_eq!(field, "Väike-Õismäe");
+        assert_eq!(decode_field(b"\xd0\xf0 \xde\xfe \x80"), "Šš Žž €");
+        assert_eq!(decode_field(b"a\x81b"), "a\u{FFFD}b");
+        // other tests decode concurrently, so only a lower bound holds
+        assert!(non_utf8_field_count() >= before + 3);
+    }
+
+    fn fields(line: &[u8]) -> Vec<String> {
+        delimited_fields(line, b';')
+            .map(|field| String::from_utf8(field.into_owned()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn splits_unquoted_fields() {
+        assert_eq!(fields(b"1;Kopli;;x"), ["1", "Kopli", "", "x"]);
+        assert_eq!(fields(b"a;"), ["a", ""]);
+        assert_eq!(fields(b""), [""]);
+        let borrowed = delimited_fields(b"a;b", b';').all(|f| matches!(f, Cow::Borrowed(_)));
+        assert!(borrowed);
+    }
+
+    #[test]
+    fn keeps_delimiters_and_escaped_quotes_inside_quotes() {
+        assert_eq!(
+            fields(br#""Kopli; Linnahall";2"#),
+            ["Kopli; Linnahall", "2"]
+        );
+        assert_eq!(
+            fields(br#"1;"Balti ""jaam""";3"#),
+            ["1", r#"Balti "jaam""#, "3"]
+        );
+        assert_eq!(fields(br#""";"""""#), ["", "\""]);
+        let mut quoted = delimited_fields(br#""a;b";c"#, b';');
+        assert!(matches!(quoted.next(), Some(Cow::Borrowed(b"a;b"))));
+    }
+
+    #[test]
+    fn tolerates_malformed_quoting() {
+        // unterminated quote runs to the end of the line
+        assert_eq!(fields(br#"1;"Kopli;2"#), ["1", "Kopli;2"]);
+        // text after the closing quote is dropped
+        assert_eq!(fields(br#""Kopli"x;2"#), ["Kopli", "2"]);
+        assert_eq!(fields(br#"""#), [""]);
+        assert_eq!(fields(br#"a"b;c"#), [r#"a"b"#, "c"]);
+    }
+
+    #[test]
+    fn route_columns_follow_the_header() {
+        let columns = RouteColumns::from_header(
+            "\u{FEFF}RouteStops; routenum ;Extra;TRANSPORT;RouteName".as_bytes(),
+        )
+        .unwrap();
+        let line = b"1001,1002;1;x;tram;Kopli - Linnahall";
+        let route = extract_route_data_from_line(line, &columns, &mut LastRouteData::default());
+        let route = route.unwrap();
+        assert_eq!(route.number, "1");
+        assert_eq!(route.route_type, TransportType::Tram);
+        assert_eq!(route.directions, "Kopli - Linnahall");
+        assert_eq!(route.stops, ["1001", "1002"]);
+    }
+
+    #[test]
+    fn stop_columns_follow_the_header_with_optional_coordinates() {
+        let columns = StopColumns::from_header(b"Name;Lng;SiriID;Lat;ID").unwrap();
+        let stop = extract_stop_data_from_line(b"Kopli;2470000;123;5940000;1", &columns, &None);
+        let stop = stop.unwrap();
+        assert_eq!((stop.id.as_str(), stop.siri_id.as_str()), ("1", "123"));
+        assert_eq!(*stop.name, "Kopli");
+        assert_eq!((stop.lat, stop.lon), (Some(59.4), Some(24.7)));
+
+        let columns = StopColumns::from_header(b"ID;SiriID;Name").unwrap();
+        let stop = extract_stop_data_from_line(b"1;123;Kopli;59.4;24.7", &columns, &None);
+        let stop = stop.unwrap();
+        assert_eq!(
+            (stop.lat, stop.lon, stop.zone.as_deref()),
+            (None, None, None)
+        );
+
+        let columns = StopColumns::from_header(b"ID;SiriID;Name;Area").unwrap();
+        let stop = extract_stop_data_from_line(b"1;123;Kopli; Kesklinn ", &columns, &None);
+        assert_eq!(stop.unwrap().zone.as_deref(), Some("Kesklinn"));
+        let stop = extract_stop_data_from_line(b"1;123;Kopli;", &columns, &None);
+        assert!(stop.unwrap().zone.is_none());
+    }
+
+    #[test]
+    fn missing_header_columns_are_named() {
+        let missing = |result: Result<()>| match result {
+            Err(ParseError::MissingColumn(message)) => message,
+            other => panic!("expected a missing column, got {:?}", other),
+        };
+        let routes = RouteColumns::from_header(b"RouteNum;Transport;RouteStops").map(|_| ());
+        assert_eq!(missing(routes), "routes.txt header is missing RouteName");
+        let stops = StopColumns::from_header(b"ID;Name;Lat;Lng").map(|_| ());
+        assert_eq!(missing(stops), "stops.txt header is missing SiriID");
+        let empty = StopColumns::from_header(b"").map(|_| ());
+        assert_eq!(missing(empty), "stops.txt header is missing ID");
+    }
+
+    fn distance(needle: &str, haystack: &str, max_edits: usize) -> Option<usize> {
+        let needle = needle.chars().collect::<Vec<char>>();
+        fuzzy_substring_distance(&needle, haystack, max_edits)
+    }
+
+    #[test]
+    fn typos_allowed_grow_with_the_query() {
+        let allowed = [0, 3, 4, 7, 8, 30].map(max_typos);
+        assert_eq!(allowed, [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn fuzzy_distance_matches_anywhere_in_the_name() {
+        assert_eq!(distance("kopli", "kopli", 0), Some(0));
+        assert_eq!(distance("balti", "balti jaam", 0), Some(0));
+        assert_eq!(distance("jaam", "balti jaam", 0), Some(0));
+        assert_eq!(distance("kolpi", "kopli", 2), Some(2));
+        assert_eq!(distance("kopi", "kopli", 1), Some(1));
+        assert_eq!(distance("kopplli", "kopli", 2), Some(2));
+        assert_eq!(distance("oismae", "väike-õismäe", 2), Some(2));
+        assert_eq!(
+            distance("oismae", &normalize_name("Väike-Õismäe"), 0),
+            Some(0)
+        );
+        assert_eq!(distance("kolpi", "kopli", 1), None);
+        assert_eq!(distance("", "kopli", 0), Some(0));
+        assert_eq!(distance("kopli", "", 5), Some(5));
+    }
+
+    #[test]
+    fn match_rank_prefers_whole_then_start_then_word() {
+        let rank = |name| name_match_rank(name, "kop");
+        assert_eq!(name_match_rank("kopli", "kopli"), 0);
+        assert_eq!(rank("kopli"), 1);
+        assert_eq!(rank("suur-kopli"), 2);
+        assert_eq!(rank("vana kopli"), 2);
+        assert_eq!(rank("ekopli"), 3);
+    }
+
+    fn name_index(stops: &[(&str, &str)]) -> StopNameIndex {
+        let stop_map = stops
+            .iter()
+            .flat_map(|&(id, name)| {
+                let (id, stop) = stop(id, name);
+                [(stop.siri_id.clone(), Rc::clone(&stop)), (id, stop)]
+            })
+            .collect::<HashMap<String, Rc<StopData>>>();
+        StopNameIndex::build(Rc::new(stop_map))
+    }
+
+    fn ids<'a>(entries: impl IntoIterator<Item = &'a (String, Rc<StopData>)>) -> Vec<&'a str> {
+        entries
+            .into_iter()
+            .map(|(_, stop)| stop.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_case_whitespace_and_diacritics() {
+        assert_eq!(normalize_name("  Väike-Õismäe\t"), "vaike-oismae");
+        assert_eq!(normalize_name("ŠÕÄÖÜŽ šõäöüž"), "soaouz soaouz");
+        assert_eq!(normalize_name("Łódź"), "lodz");
+        assert_eq!(normalize_name("Balti jaam"), "balti jaam");
+    }
+
+    #[test]
+    fn name_index_groups_stops_by_normalized_name() {
+        let index = name_index(&[
+            ("2", "Kopli"),
+            ("1", "kopli "),
+            ("3", "Männiku"),
+            ("4", "Vana-Kopli"),
+        ]);
+        // siri ids alias the same stops and aren't indexed again
+        assert_eq!(index.entries.len(), 4);
+        assert_eq!(ids(index.named("kopli")), ["1", "2"]);
+        assert_eq!(ids(index.named("manniku")), ["3"]);
+        assert!(index.named("kop").is_empty());
+        let groups = index.groups().map(|group| group.len()).collect::<Vec<_>>();
+        assert_eq!(groups, [2, 1, 1]);
+    }
+
+    #[test]
+    fn name_index_finds_word_prefixes_once_per_stop() {
+        let index = name_index(&[
+            ("1", "Kopli"),
+            ("2", "Vana-Kopli"),
+            ("3", "Kopli kopli"),
+            ("4", "Ekopli"),
+            ("5", "Õismäe"),
+        ]);
+        let mut found = ids(index.with_word_prefix("kop"));
+        found.sort_unstable();
+        assert_eq!(found, ["1", "2", "3"]);
+        assert_eq!(ids(index.with_word_prefix("oism")), ["5"]);
+        assert_eq!(ids(index.with_word_prefix("vana-k")), ["2"]);
+        assert!(ids(index.with_word_prefix("pli")).is_empty());
+    }
+}
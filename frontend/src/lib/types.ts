@@ -25,6 +25,12 @@ export interface RawStopArrival {
   };
 }
 
+export interface StopArrivalsResult {
+  id: string;
+  status: 'ok' | 'unknown_stop' | 'upstream_error';
+  data?: RawStopArrival; // Present when status is 'ok'
+}
+
 export interface FavoriteStop {
   id: string;
   name: string;
@@ -0,0 +1,146 @@
+//! Optional API keys sent as `X-API-Key`, stored in KV as `apikey:<key>` JSON with the key's
+//! daily quota and per-minute rate limit, which the [`ApiKeyQuotaObject`] named by the key
+//! counts.
+//!
+//! [`ApiKeyQuotaObject`]: crate::quota_object::ApiKeyQuotaObject
+
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::{Env, KvStore, Method, ObjectNamespace, Request, RequestInit, Response, Result};
+
+use crate::caches::Caches;
+use crate::config::Config;
+use crate::logging::log_error;
+use crate::models::ErrorCode;
+use crate::quota_object::QuotaOutcome;
+
+/// KV namespace of the API keys, optional. While unbound no key is checked.
+pub const API_KEYS_BINDING: &str = "API_KEYS";
+/// Durable Object namespace counting the requests of each key. Without it keys are checked but
+/// not limited.
+pub const API_KEY_QUOTAS_BINDING: &str = "API_KEY_QUOTAS";
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ApiKey {
+    /// Requests per UTC day
+    pub daily_quota: Option<u32>,
+    /// Requests per minute
+    pub per_minute: Option<u32>,
+}
+
+impl ApiKey {
+    /// Loads `key` from KV, caching hits and misses for a few minutes per isolate.
+    async fn load(kv: &KvStore, key: &str) -> Result<Rc<Option<ApiKey>>> {
+        let cache = &Caches::get_cache().api_keys;
+        let cache_key = key.to_string();
+        if let Some(api_key) = cache.get(&cache_key) {
+            return Ok(api_key);
+        }
+        let api_key = kv
+            .get(&format!("apikey:{}", key))
+            .json::<ApiKey>()
+            .await
+            .map_err(worker::Error::from)?;
+        let api_key = Rc::new(api_key);
+        cache.set(cache_key, Rc::clone(&api_key)).ok();
+        Ok(api_key)
+    }
+}
+
+/// How a request may reach the routes.
+pub enum Access {
+    /// No key configured or sent, limited by the client address instead
+    Anonymous,
+    /// A known key within its limits, with the requests it has left when it has any
+    Keyed { remaining: Option<u32> },
+    /// The response to answer instead of the route
+    Denied(Response),
+}
+
+/// The health check, the spec and its docs page stay reachable without a key.
+fn is_public(path: &str) -> bool {
+    let path = crate::unversioned_path(path);
+    !path.starts_with("/api/")
+        || matches!(
+            path.as_str(),
+            "/api/health"
+                | "/api/ready"
+                | "/api/version"
+                | "/api/openapi.json"
+                | "/api/openapi.yaml"
+                | "/api/docs"
+        )
+}
+
+/// Checks the request's `X-API-Key` and counts it against the key's limits. A missing key is
+/// refused only with `API_KEYS_REQUIRED`.
+pub async fn check(env: &Env, req: &Request) -> Result<Access> {
+    let Ok(kv) = env.kv(API_KEYS_BINDING) else {
+        return Ok(Access::Anonymous);
+    };
+    if req.method() == Method::Options || is_public(req.url()?.path()) {
+        return Ok(Access::Anonymous);
+    }
+    let Some(key) = req.headers().get("X-API-Key")? else {
+        if Config::get().api_keys_required {
+            return denied(ErrorCode::Unauthorized, "missing API key", 401);
+        }
+        return Ok(Access::Anonymous);
+    };
+    let api_key = ApiKey::load(&kv, &key).await?;
+    let Some(api_key) = api_key.as_ref() else {
+        return denied(ErrorCode::Unauthorized, "invalid API key", 401);
+    };
+    let unlimited = api_key.daily_quota.is_none() && api_key.per_minute.is_none();
+    let Ok(namespace) = env.durable_object(API_KEY_QUOTAS_BINDING) else {
+        return Ok(Access::Keyed { remaining: None });
+    };
+    if unlimited {
+        return Ok(Access::Keyed { remaining: None });
+    }
+    // A failing counter lets the request through rather than failing every keyed request.
+    let outcome = match consume(&namespace, &key, api_key).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            log_error!("API key quota check failed: {}", err);
+            return Ok(Access::Keyed { remaining: None });
+        }
+    };
+    let Some(retry_after_secs) = outcome.retry_after_secs else {
+        return Ok(Access::Keyed {
+            remaining: Some(outcome.remaining),
+        });
+    };
+    let (code, detail) = match outcome.daily {
+        true => (ErrorCode::QuotaExceeded, "daily quota exceeded"),
+        false => (ErrorCode::RateLimited, "too many requests"),
+    };
+    let mut response = crate::coded_error_response(code, detail, 429)?;
+    let headers = response.headers_mut();
+    headers.set("Retry-After", &retry_after_secs.to_string())?;
+    headers.set("X-RateLimit-Remaining", "0")?;
+    Ok(Access::Denied(response))
+}
+
+fn denied(code: ErrorCode, detail: &str, status: u16) -> Result<Access> {
+    crate::coded_error_response(code, detail, status).map(Access::Denied)
+}
+
+async fn consume(namespace: &ObjectNamespace, key: &str, api_key: &ApiKey) -> Result<QuotaOutcome> {
+    let stub = namespace.get_by_name(key)?;
+    let mut uri = "https://api-key-quota/consume?".to_string();
+    if let Some(daily_quota) = api_key.daily_quota {
+        uri.push_str(&format!("dailyQuota={}&", daily_quota));
+    }
+    if let Some(per_minute) = api_key.per_minute {
+        uri.push_str(&format!("perMinute={}", per_minute));
+    }
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    let mut res = stub
+        .fetch_with_request(Request::new_with_init(&uri, &init)?)
+        .await?;
+    res.json().await
+}
@@ -0,0 +1,104 @@
+use std::rc::Rc;
+
+use worker::*;
+
+use crate::caches::CacheDataWithKeys;
+use crate::config::Config;
+use crate::logging::log_error;
+use crate::models::StopArrivals;
+use crate::services::{ParsingUpstreamError, TransportService};
+use crate::str_utils::splits_commas;
+
+/// Single Durable Object owning the arrivals cache, so concurrent isolates coalesce on one
+/// SIRI request per stop instead of each hammering the upstream.
+///
+/// - `GET /get?stops=…` returns the still fresh cached arrivals only.
+/// - `POST /refresh?stops=…` fetches the stops missing from the cache, then returns all of them.
+/// - `POST /purge` drops every cached stop.
+#[durable_object]
+pub struct ArrivalsCacheObject {
+    arrivals: CacheDataWithKeys<String, StopArrivals>,
+    env: Env,
+}
+
+impl ArrivalsCacheObject {
+    fn cached(&self, stop_siri_ids: &[String]) -> Vec<Rc<StopArrivals>> {
+        stop_siri_ids
+            .iter()
+            .filter_map(|id| self.arrivals.get(id))
+            .collect()
+    }
+
+    async fn refresh(
+        &self,
+        stop_siri_ids: &[String],
+    ) -> core::result::Result<Vec<Rc<StopArrivals>>, ParsingUpstreamError> {
+        let missing = stop_siri_ids
+            .iter()
+            .filter(|id| self.arrivals.get(id).is_none())
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .join(",");
+        if !missing.is_empty() {
+            let service = TransportService::get_service();
+            for stop_arrival in service.fetch_stops_arrivals(&self.env, &missing).await? {
+                let stop_arrival = Rc::new(stop_arrival);
+                self.arrivals
+                    .set(stop_arrival.id.clone(), stop_arrival)
+                    .ok();
+            }
+        }
+        Ok(self.cached(stop_siri_ids))
+    }
+}
+
+impl DurableObject for ArrivalsCacheObject {
+    fn new(_state: State, env: Env) -> Self {
+        if let Err(err) = Config::configure(&env) {
+            log_error!("{}", err);
+        }
+        Self {
+            arrivals: CacheDataWithKeys::with_max_entries(
+                Config::get().arrivals_cache_ttl_secs,
+                Config::get().arrivals_cache_max_entries,
+            ),
+            env,
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let stops_param = url
+            .query_pairs()
+            .find_map(|(k, v)| (k == "stops").then_some(v))
+            .unwrap_or_default();
+        let stop_siri_ids = splits_commas(stops_param.as_bytes())
+            .map_err(|_| worker::Error::RustError("invalid stops query parameter".to_string()))?
+            .into_iter()
+            .filter(|id| !id.is_empty())
+            .collect::<Vec<String>>();
+        match (req.method(), url.path()) {
+            (Method::Get, "/get") => Response::from_json(&self.cached(&stop_siri_ids)),
+            (Method::Post, "/refresh") => match self.refresh(&stop_siri_ids).await {
+                Ok(arrivals) => Response::from_json(&arrivals),
+                Err(ParsingUpstreamError::Unavailable(retry_after)) => {
+                    let mut response = crate::error_response("upstream unavailable", 503)?;
+                    response
+                        .headers_mut()
+                        .set("Retry-After", &retry_after.to_string())?;
+                    Ok(response)
+                }
+                // Keep the upstream status (504 on timeout) for the calling worker.
+                Err(err) => match Error::from(err) {
+                    Error::Json((message, status)) => crate::json_error_response(message, status),
+                    err => Err(err),
+                },
+            },
+            (Method::Post, "/purge") => {
+                self.arrivals.clear();
+                Response::empty()
+            }
+            _ => crate::error_response("not found", 404),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::config::Config;
+use crate::logging::log_error;
+use crate::models::StopArrivals;
+use crate::services::{ParsingUpstreamError, TransportService};
+use crate::str_utils::splits_commas;
+
+/// Single Durable Object holding the live arrivals WebSockets. It polls SIRI once per arrivals
+/// cache TTL for all subscribed stops together and sends each socket only the stops that changed.
+///
+/// - `GET /?stops=…` with `Upgrade: websocket` accepts a socket subscribed to the stops.
+/// - Sockets send `{"subscribe": [ids]}` or `{"unsubscribe": [ids]}`, stop ids or SIRI ids.
+/// - The object sends `{"type": "arrivals", "stops": [..]}` with the changed stops,
+///   `{"type": "unavailable", "retryAfter": secs}` while the upstream is paused and
+///   `{"type": "error", "message": ".."}` for rejected messages.
+#[durable_object]
+pub struct ArrivalsSocketObject {
+    state: State,
+    env: Env,
+    /// Arrivals last sent per SIRI id with their fingerprint, lost when the object hibernates
+    /// which only means resending them
+    latest: RefCell<HashMap<String, (u64, Rc<StopArrivals>)>>,
+}
+
+/// SIRI ids a socket is subscribed to, kept as its attachment so it survives hibernation.
+#[derive(Default, Serialize, Deserialize)]
+struct Subscription {
+    stops: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ClientMessage {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ServerMessage {
+    Arrivals {
+        stops: Vec<Rc<StopArrivals>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unavailable {
+        retry_after: u32,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ArrivalsSocketObject {
+    fn subscription(ws: &WebSocket) -> Subscription {
+        ws.deserialize_attachment()
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn send_error(ws: &WebSocket, message: String) {
+        ws.send(&ServerMessage::Error { message }).ok();
+    }
+
+    /// SIRI ids of the given stop ids or SIRI ids, failing on the first unknown one.
+    async fn resolve(&self, ids: &[String]) -> Result<Vec<String>> {
+        let stop_map = TransportService::get_service()
+            .get_stop_map(&self.env)
+            .await?;
+        ids.iter()
+            .map(|id| {
+                stop_map
+                    .get(id)
+                    .map(|stop| stop.siri_id.clone())
+                    .ok_or_else(|| Error::RustError(format!("unknown stop id {}", id)))
+            })
+            .collect()
+    }
+
+    async fn subscribe(&self, ws: &WebSocket, ids: &[String]) -> Result<()> {
+        let mut subscription = Self::subscription(ws);
+        let added = self
+            .resolve(ids)
+            .await?
+            .into_iter()
+            .filter(|id| !subscription.stops.contains(id))
+            .collect::<HashSet<String>>();
+        subscription.stops.extend(added.iter().cloned());
+        let max_stops = Config::get().max_arrivals_stops;
+        if subscription.stops.len() > max_stops {
+            return Err(Error::RustError(format!(
+                "too many stops subscribed (max {})",
+                max_stops
+            )));
+        }
+        ws.serialize_attachment(&subscription)?;
+        // Stops another socket already follows are sent now, the rest with the next poll.
+        let known = {
+            let latest = self.latest.borrow();
+            added
+                .iter()
+                .filter_map(|id| latest.get(id).map(|(_, stop)| Rc::clone(stop)))
+                .collect::<Vec<Rc<StopArrivals>>>()
+        };
+        if !known.is_empty() {
+            ws.send(&ServerMessage::Arrivals { stops: known })?;
+        }
+        let storage = self.state.storage();
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(Duration::ZERO).await?;
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, ws: &WebSocket, ids: &[String]) -> Result<()> {
+        let removed = self.resolve(ids).await?;
+        let mut subscription = Self::subscription(ws);
+        subscription.stops.retain(|id| !removed.contains(id));
+        ws.serialize_attachment(&subscription)
+    }
+
+    /// Fetches the stops in batches, answering those whose arrivals changed since last sent and
+    /// the first failure among the batches.
+    async fn poll(
+        &self,
+        stop_ids: &[String],
+    ) -> (Vec<Rc<StopArrivals>>, Option<ParsingUpstreamError>) {
+        let service = TransportService::get_service();
+        let batches = stop_ids
+            .chunks(Config::get().arrivals_batch_size)
+            .map(|batch| batch.join(","))
+            .collect::<Vec<String>>();
+        let results = futures::future::join_all(
+            batches
+                .iter()
+                .map(|batch| service.fetch_stops_arrivals(&self.env, batch)),
+        )
+        .await;
+        let mut latest = self.latest.borrow_mut();
+        latest.retain(|id, _| stop_ids.contains(id));
+        let mut changed = Vec::new();
+        let mut first_err = None;
+        for result in results {
+            match result {
+                Ok(stops) => {
+                    for stop in stops {
+                        let fingerprint = stop.fingerprint();
+                        if latest
+                            .get(&stop.id)
+                            .is_some_and(|(last, _)| *last == fingerprint)
+                        {
+                            continue;
+                        }
+                        let stop = Rc::new(stop);
+                        latest.insert(stop.id.clone(), (fingerprint, Rc::clone(&stop)));
+                        changed.push(stop);
+                    }
+                }
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        (changed, first_err)
+    }
+}
+
+impl DurableObject for ArrivalsSocketObject {
+    fn new(state: State, env: Env) -> Self {
+        if let Err(err) = Config::configure(&env) {
+            log_error!("{}", err);
+        }
+        Self {
+            state,
+            env,
+            latest: RefCell::new(HashMap::new()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        if req.headers().get("Upgrade")?.as_deref() != Some("websocket") {
+            return crate::error_response("expected a WebSocket upgrade", 426);
+        }
+        let url = req.url()?;
+        let stops_param = url
+            .query_pairs()
+            .find_map(|(k, v)| (k == "stops").then_some(v))
+            .unwrap_or_default();
+        let stop_ids = splits_commas(stops_param.as_bytes())
+            .map_err(|_| Error::RustError("invalid stops query parameter".to_string()))?
+            .into_iter()
+            .filter(|id| !id.is_empty())
+            .collect::<Vec<String>>();
+        let pair = WebSocketPair::new()?;
+        self.state.accept_web_socket(&pair.server);
+        pair.server.serialize_attachment(Subscription::default())?;
+        if !stop_ids.is_empty()
+            && let Err(err) = self.subscribe(&pair.server, &stop_ids).await
+        {
+            Self::send_error(&pair.server, err.to_string());
+        }
+        Response::from_websocket(pair.client)
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let subscriptions = self
+            .state
+            .get_websockets()
+            .into_iter()
+            .map(|ws| {
+                let subscription = Self::subscription(&ws);
+                (ws, subscription)
+            })
+            .collect::<Vec<(WebSocket, Subscription)>>();
+        let stop_ids = subscriptions
+            .iter()
+            .flat_map(|(_, subscription)| subscription.stops.iter().cloned())
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
+        if stop_ids.is_empty() {
+            // nothing to poll, the next subscription schedules the alarm again
+            self.latest.borrow_mut().clear();
+            return Response::empty();
+        }
+        let (changed, err) = self.poll(&stop_ids).await;
+        for (ws, subscription) in &subscriptions {
+            let stops = changed
+                .iter()
+                .filter(|stop| subscription.stops.contains(&stop.id))
+                .cloned()
+                .collect::<Vec<Rc<StopArrivals>>>();
+            if !stops.is_empty() {
+                ws.send(&ServerMessage::Arrivals { stops }).ok();
+            }
+        }
+        match err {
+            Some(ParsingUpstreamError::Unavailable(retry_after)) => {
+                for (ws, _) in &subscriptions {
+                    ws.send(&ServerMessage::Unavailable { retry_after }).ok();
+                }
+            }
+            Some(err) => log_error!("live arrivals poll failed: {:?}", err),
+            None => {}
+        }
+        let interval_secs = Config::get().arrivals_cache_ttl_secs.max(1);
+        self.state
+            .storage()
+            .set_alarm(Duration::from_secs(u64::from(interval_secs)))
+            .await?;
+        Response::empty()
+    }
+
+    async fn websocket_message(
+        &self,
+        ws: WebSocket,
+        message: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        let WebSocketIncomingMessage::String(message) = message else {
+            Self::send_error(&ws, "expected a JSON text message".to_string());
+            return Ok(());
+        };
+        let handled = match serde_json::from_str::<ClientMessage>(&message) {
+            Ok(ClientMessage::Subscribe(ids)) => self.subscribe(&ws, &ids).await,
+            Ok(ClientMessage::Unsubscribe(ids)) => self.unsubscribe(&ws, &ids).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = handled {
+            Self::send_error(&ws, err.to_string());
+        }
+        Ok(())
+    }
+
+    async fn websocket_close(
+        &self,
+        ws: WebSocket,
+        _code: usize,
+        _reason: String,
+        _was_clean: bool,
+    ) -> Result<()> {
+        ws.close(Some(1000), Some("closing")).ok();
+        Ok(())
+    }
+
+    async fn websocket_error(&self, _ws: WebSocket, _error: Error) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+//! `tlt-serve [address]`, the read-only API on the host, listening on `127.0.0.1:8787` unless
+//! given another address. Reads the worker's vars, `UPSTREAM_ROUTES_URL` and the others, from the
+//! environment and fetches HTTP upstreams with the system's `curl`.
+
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8787";
+
+fn main() -> ExitCode {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("binding {address}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        eprintln!("listening on http://{address}");
+    }
+    match tlt_stops::native::serve(listener) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
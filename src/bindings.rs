@@ -0,0 +1,51 @@
+//! [`Bindings`], what the handlers `tlt-serve` shares with the worker reach through the worker's
+//! [`Env`]: the service, the D1 store, the arrivals Durable Object and the response profiles.
+//! The host has none of the Cloudflare bindings and answers as the worker does without them.
+
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use worker::{Env, ObjectNamespace};
+
+use crate::profiles::ResponseProfile;
+use crate::services::{
+    ARRIVALS_CACHE_BINDING, ParsingUpstreamError, StopsRouteStore, TransportService,
+};
+use crate::snapshots::SnapshotStore;
+
+pub trait Bindings: SnapshotStore {
+    fn service(&self) -> &TransportService;
+
+    /// The D1 store once it's known to be in sync, see [`TransportService::get_synced_store`].
+    fn synced_store(&self) -> Option<StopsRouteStore>;
+
+    /// The Durable Object sharing the arrivals between isolates, `None` fetches them directly.
+    fn arrivals_object(&self) -> Option<ObjectNamespace>;
+
+    /// The response profile of `?profile=`, `None` when there's no such profile.
+    fn profile<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Rc<Option<ResponseProfile>>, ParsingUpstreamError>>;
+}
+
+impl Bindings for Env {
+    fn service(&self) -> &TransportService {
+        TransportService::get_service()
+    }
+
+    fn synced_store(&self) -> Option<StopsRouteStore> {
+        TransportService::get_service().get_synced_store(self)
+    }
+
+    fn arrivals_object(&self) -> Option<ObjectNamespace> {
+        self.durable_object(ARRIVALS_CACHE_BINDING).ok()
+    }
+
+    fn profile<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Rc<Option<ResponseProfile>>, ParsingUpstreamError>> {
+        Box::pin(ResponseProfile::load(self, name))
+    }
+}
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+
+use crate::config::Config;
+use crate::models::{StopArrivals, flat_arrivals};
+
+/// Rows shown on a board, a kiosk screen doesn't fit more.
+const MAX_BOARD_ROWS: usize = 16;
+
+const BOARD_STYLE: &str = "body{margin:0;padding:2vw;background:#000;color:#fc0;\
+font:5vh/1.3 system-ui,sans-serif}h1{margin:0 0 2vh;font-size:7vh}\
+table{width:100%;border-collapse:collapse}td{padding:.5vh 1vw;border-bottom:1px solid #333}\
+.number{width:12%;font-weight:bold}.due{width:18%;text-align:right}\
+.notice,footer{color:#999;font-size:3.5vh}";
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// The departure board page of a stop reloading itself every `refresh_secs`, showing `notice`
+/// instead of the arrivals when there are none to show.
+pub fn render_board(
+    name: &str,
+    stop: Option<&StopArrivals>,
+    notice: Option<&str>,
+    refresh_secs: u32,
+    now: DateTime<Utc>,
+) -> String {
+    let region_tz = Config::get().region_timezone;
+    let mut rows = String::new();
+    if let Some(stop) = stop {
+        for arrival in flat_arrivals(&stop.arrivals, now)
+            .into_iter()
+            .take(MAX_BOARD_ROWS)
+        {
+            let due = match arrival.in_seconds {
+                Some(seconds) if seconds < 60 => "now".to_string(),
+                Some(seconds) => format!("{} min", seconds / 60),
+                None => String::new(),
+            };
+            let clock = DateTime::parse_from_rfc3339(arrival.time)
+                .map(|time| time.with_timezone(&region_tz).format("%H:%M").to_string())
+                .unwrap_or_default();
+            rows.push_str(&format!(
+                "<tr><td class=\"number\">{}</td><td>{}</td><td class=\"due\">{}</td>\
+                 <td class=\"due\">{}{}</td></tr>",
+                escape_html(arrival.number),
+                escape_html(arrival.destination.unwrap_or(arrival.r#type)),
+                due,
+                clock,
+                if arrival.is_realtime { "" } else { "*" },
+            ));
+        }
+    }
+    let notice = match (notice, rows.is_empty()) {
+        (Some(notice), _) => format!("<p class=\"notice\">{}</p>", escape_html(notice)),
+        (None, true) => "<p class=\"notice\">No upcoming arrivals</p>".to_string(),
+        (None, false) => String::new(),
+    };
+    let updated = now.with_timezone(&region_tz).format("%H:%M:%S");
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+         <meta http-equiv=\"refresh\" content=\"{refresh_secs}\">\
+         <title>{name}</title><style>{BOARD_STYLE}</style></head><body>\
+         <h1>{name}</h1>{notice}<table>{rows}</table>\
+         <footer>Updated {updated}, * scheduled time</footer></body></html>",
+        name = escape_html(name),
+    )
+}
@@ -1,30 +1,111 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
-use std::sync::OnceLock;
-use worker::send::SendWrapper;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::{Rc, Weak};
+use utoipa::ToSchema;
 
+use crate::api_keys::ApiKey;
+use crate::clock;
+use crate::config::Config;
+use crate::geo::StopGrid;
+use crate::logging;
 use crate::models::*;
+use crate::profiles::ResponseProfile;
+use crate::str_utils::{StopNameIndex, fnv1a_64};
 
-pub static CACHE: OnceLock<SendWrapper<Caches>> = OnceLock::new();
+thread_local! {
+    // per thread rather than a `SendWrapper` static so each host test gets its own
+    static CACHE: &'static Caches = Box::leak(Box::new(Caches::new()));
+}
 
-fn now_secs() -> u32 {
-    (js_sys::Date::now() / 1000.0) as u32
+pub fn now_secs() -> u32 {
+    (clock::now_ms() / 1000.0) as u32
 }
 
 struct CacheRecord<T> {
     data: Rc<T>,
     expires_at: u32,
+    /// Tick of the last read or write, for the LRU eviction of the keyed caches
+    last_used: Cell<u64>,
+}
+
+impl<T> CacheRecord<T> {
+    fn age_secs(&self, ttl_secs: u32) -> u32 {
+        now_secs().saturating_sub(self.expires_at.saturating_sub(ttl_secs))
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    expirations: Cell<u64>,
+    stale_hits: Cell<u64>,
+    evictions: Cell<u64>,
+}
+impl CacheCounters {
+    fn bump(counter: &Cell<u64>) {
+        counter.set(counter.get().saturating_add(1));
+    }
+
+    /// Snapshot of the counters plus the ages of `expiry_times`, the records currently held.
+    fn stats(&self, ttl_secs: u32, expiry_times: impl Iterator<Item = u32>) -> CacheStats {
+        let now = now_secs();
+        let ages = expiry_times
+            .map(|expires_at| now.saturating_sub(expires_at.saturating_sub(ttl_secs)))
+            .collect::<Vec<u32>>();
+        let (hits, misses, expirations) =
+            (self.hits.get(), self.misses.get(), self.expirations.get());
+        let lookups = hits + misses + expirations;
+        CacheStats {
+            hits,
+            misses,
+            expirations,
+            stale_hits: self.stale_hits.get(),
+            evictions: self.evictions.get(),
+            hit_ratio: (lookups > 0).then(|| hits as f64 / lookups as f64),
+            entries: ages.len(),
+            ttl_secs,
+            oldest_age_secs: ages.iter().max().copied(),
+            newest_age_secs: ages.iter().min().copied(),
+        }
+    }
+}
+
+/// Counters of one cache since the isolate started.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Lookups finding nothing stored
+    pub misses: u64,
+    /// Lookups finding only an expired record
+    pub expirations: u64,
+    /// Expired records still served while being refreshed
+    pub stale_hits: u64,
+    /// Least recently used records dropped to stay within the entry limit
+    pub evictions: u64,
+    /// Hits over all lookups, `null` before the first lookup
+    #[schema(example = 0.93)]
+    pub hit_ratio: Option<f64>,
+    pub entries: usize,
+    pub ttl_secs: u32,
+    pub oldest_age_secs: Option<u32>,
+    pub newest_age_secs: Option<u32>,
 }
 
 pub struct CacheData<T> {
     record: RefCell<Option<CacheRecord<T>>>,
+    revalidating: Cell<bool>,
+    counters: CacheCounters,
     ttl_secs: u32,
 }
 impl<T> CacheData<T> {
     pub fn new(ttl_secs: u32) -> Self {
         CacheData::<T> {
             record: RefCell::new(None),
+            revalidating: Cell::new(false),
+            counters: CacheCounters::default(),
             ttl_secs,
         }
     }
@@ -32,26 +113,109 @@ impl<T> CacheData<T> {
     pub fn set(&self, data: Rc<T>) -> Result<(), ()> {
         let expires_at = now_secs().saturating_add(self.ttl_secs);
         let mut record = self.record.try_borrow_mut().map_err(|_| ())?;
-        record.replace(CacheRecord { data, expires_at });
+        record.replace(CacheRecord {
+            data,
+            expires_at,
+            last_used: Cell::new(0),
+        });
+        self.revalidating.set(false);
         Ok(())
     }
 
+    /// Cached data even past its expiry; expired records are kept until replaced.
+    pub fn get_stale(&self) -> Option<Rc<T>> {
+        let record = self.record.try_borrow().ok()?;
+        let Some(record_ref) = (*record).as_ref() else {
+            CacheCounters::bump(&self.counters.misses);
+            logging::record_cache_lookup(false);
+            return None;
+        };
+        if now_secs() > record_ref.expires_at {
+            CacheCounters::bump(&self.counters.stale_hits);
+            logging::record_cache_lookup(true);
+        } else {
+            CacheCounters::bump(&self.counters.hits);
+            logging::record_cache_lookup(true);
+        }
+        Some(Rc::clone(&record_ref.data))
+    }
+
+    /// Whether the record has expired and no refresh is running yet, marking one as running.
+    /// `set` or `end_revalidation` ends it.
+    pub fn begin_revalidation(&self) -> bool {
+        let Ok(record) = self.record.try_borrow() else {
+            return false;
+        };
+        let expired = (*record)
+            .as_ref()
+            .is_some_and(|record| now_secs() > record.expires_at);
+        if !expired || self.revalidating.get() {
+            return false;
+        }
+        self.revalidating.set(true);
+        true
+    }
+
+    pub fn end_revalidation(&self) {
+        self.revalidating.set(false);
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut record) = self.record.try_borrow_mut() {
+            record.take();
+        }
+    }
+
+    /// The data, expired or not, without counting a lookup.
+    pub fn peek(&self) -> Option<Rc<T>> {
+        let record = self.record.try_borrow().ok()?;
+        (*record).as_ref().map(|record| Rc::clone(&record.data))
+    }
+
+    /// Seconds since the record was set, expired or not.
+    pub fn age_secs(&self) -> Option<u32> {
+        let record = self.record.try_borrow().ok()?;
+        (*record)
+            .as_ref()
+            .map(|record| record.age_secs(self.ttl_secs))
+    }
+
     pub fn get(&self) -> Option<Rc<T>> {
         let record = self.record.try_borrow().ok()?;
-        let record_ref = (*record).as_ref()?;
+        let Some(record_ref) = (*record).as_ref() else {
+            CacheCounters::bump(&self.counters.misses);
+            logging::record_cache_lookup(false);
+            return None;
+        };
         if now_secs() > record_ref.expires_at {
             drop(record);
+            CacheCounters::bump(&self.counters.expirations);
+            logging::record_cache_lookup(false);
             let _ = self.record.try_borrow_mut().ok().map(|mut rec| rec.take());
             None
         } else {
+            CacheCounters::bump(&self.counters.hits);
+            logging::record_cache_lookup(true);
             Some(Rc::clone(&record_ref.data))
         }
     }
+
+    pub fn stats(&self) -> CacheStats {
+        let expires_at = self
+            .record
+            .try_borrow()
+            .ok()
+            .and_then(|record| (*record).as_ref().map(|record| record.expires_at));
+        self.counters.stats(self.ttl_secs, expires_at.into_iter())
+    }
 }
 
 pub struct CacheDataWithKeys<K, T> {
     record: RefCell<HashMap<K, CacheRecord<T>>>,
+    counters: CacheCounters,
     ttl_secs: u32,
+    max_entries: Option<usize>,
+    tick: Cell<u64>,
 }
 impl<K, T> CacheDataWithKeys<K, T>
 where
@@ -60,58 +224,550 @@ where
     pub fn new(ttl_secs: u32) -> Self {
         CacheDataWithKeys::<K, T> {
             record: RefCell::new(HashMap::new()),
+            counters: CacheCounters::default(),
             ttl_secs,
+            max_entries: None,
+            tick: Cell::new(0),
+        }
+    }
+
+    /// Keeps at most `max_entries` records: once full, an insert first drops the expired ones,
+    /// then the least recently used, so caches keyed by client input can't grow without bound.
+    pub fn with_max_entries(ttl_secs: u32, max_entries: usize) -> Self {
+        CacheDataWithKeys::<K, T> {
+            max_entries: Some(max_entries.max(1)),
+            ..Self::new(ttl_secs)
         }
     }
 
+    fn next_tick(&self) -> u64 {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        tick
+    }
+
     pub fn set(&self, key: K, data: Rc<T>) -> Result<(), ()> {
-        let expires_at = now_secs().saturating_add(self.ttl_secs);
+        let now = now_secs();
         let mut record = self.record.try_borrow_mut().map_err(|_| ())?;
-        record.insert(key, CacheRecord { data, expires_at });
+        if let Some(max_entries) = self.max_entries
+            && record.len() >= max_entries
+            && !record.contains_key(&key)
+        {
+            record.retain(|_, rec| now <= rec.expires_at);
+            while record.len() >= max_entries {
+                let Some(lru_key) = record
+                    .iter()
+                    .min_by_key(|(_, rec)| rec.last_used.get())
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                record.remove(&lru_key);
+                CacheCounters::bump(&self.counters.evictions);
+            }
+        }
+        let expires_at = now.saturating_add(self.ttl_secs);
+        record.insert(
+            key,
+            CacheRecord {
+                data,
+                expires_at,
+                last_used: Cell::new(self.next_tick()),
+            },
+        );
         Ok(())
     }
 
     pub fn get(&self, key: &K) -> Option<Rc<T>> {
         let record = self.record.try_borrow().ok()?;
-        let record_ref = record.get(key)?;
+        let Some(record_ref) = record.get(key) else {
+            CacheCounters::bump(&self.counters.misses);
+            logging::record_cache_lookup(false);
+            return None;
+        };
         if now_secs() > record_ref.expires_at {
             drop(record);
-            let _ = self
-                .record
-                .try_borrow_mut()
-                .ok()
-                .map(|mut rec| rec.remove(key));
+            CacheCounters::bump(&self.counters.expirations);
+            logging::record_cache_lookup(false);
+            // Capped caches keep expired records for `get_stale` until an insert sweeps them.
+            if self.max_entries.is_none() {
+                let _ = self
+                    .record
+                    .try_borrow_mut()
+                    .ok()
+                    .map(|mut rec| rec.remove(key));
+            }
             None
         } else {
+            CacheCounters::bump(&self.counters.hits);
+            logging::record_cache_lookup(true);
+            record_ref.last_used.set(self.next_tick());
             Some(Rc::clone(&record_ref.data))
         }
     }
+
+    /// The record for `key` even past its expiry, as long as it hasn't been evicted.
+    pub fn get_stale(&self, key: &K) -> Option<Rc<T>> {
+        let record = self.record.try_borrow().ok()?;
+        let record_ref = record.get(key)?;
+        if now_secs() > record_ref.expires_at {
+            CacheCounters::bump(&self.counters.stale_hits);
+            logging::record_cache_lookup(true);
+        }
+        Some(Rc::clone(&record_ref.data))
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut record) = self.record.try_borrow_mut() {
+            record.clear();
+        }
+    }
+
+    /// Seconds since the record for `key` was set, expired or not.
+    pub fn age_secs(&self, key: &K) -> Option<u32> {
+        let record = self.record.try_borrow().ok()?;
+        record.get(key).map(|record| record.age_secs(self.ttl_secs))
+    }
+
+    /// Stats over the held records, expired ones not yet evicted included.
+    pub fn stats(&self) -> CacheStats {
+        match self.record.try_borrow() {
+            Ok(record) => self
+                .counters
+                .stats(self.ttl_secs, record.values().map(|rec| rec.expires_at)),
+            Err(_) => self.counters.stats(self.ttl_secs, std::iter::empty()),
+        }
+    }
+}
+
+/// Stops calling an upstream for `cooldown_secs` once `threshold` calls in a row failed. Then it
+/// is half-open: the first call after the cool-down goes through as a probe while the others keep
+/// being turned away until the probe records its outcome, closing the breaker on success and
+/// reopening it on failure. A probe that never reports back, its request dropped, is replaced by
+/// another one after a further `cooldown_secs`.
+pub struct CircuitBreaker {
+    failures: Cell<u32>,
+    open_until: Cell<u32>,
+    /// When the probe still out was let through
+    probe_started_at: Cell<Option<u32>>,
+    threshold: u32,
+    cooldown_secs: u32,
+}
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown_secs: u32) -> Self {
+        Self {
+            failures: Cell::new(0),
+            open_until: Cell::new(0),
+            probe_started_at: Cell::new(None),
+            threshold: threshold.max(1),
+            cooldown_secs,
+        }
+    }
+
+    /// Seconds to wait before calling, `None` when the call may go through. Half-open, the caller
+    /// getting `None` is the probe and must record how its call went.
+    pub fn retry_after(&self) -> Option<u32> {
+        let now = now_secs();
+        let open_until = self.open_until.get();
+        if now < open_until {
+            return Some(open_until - now);
+        }
+        if self.failures.get() < self.threshold {
+            return None;
+        }
+        if let Some(started_at) = self.probe_started_at.get()
+            && now < started_at.saturating_add(self.cooldown_secs)
+        {
+            // the probe is bounded by the upstream timeout, ask to come back shortly
+            return Some(1);
+        }
+        self.probe_started_at.set(Some(now));
+        None
+    }
+
+    pub fn record_success(&self) {
+        self.failures.set(0);
+        self.open_until.set(0);
+        self.probe_started_at.set(None);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.failures.get().saturating_add(1);
+        self.failures.set(failures);
+        self.probe_started_at.set(None);
+        if failures >= self.threshold {
+            self.open_until
+                .set(now_secs().saturating_add(self.cooldown_secs));
+        }
+    }
+}
+
+/// Names accepted by `Caches::purge`.
+pub const PURGEABLE_CACHES: [&str; 7] = [
+    "routes_raw",
+    "stops_raw",
+    "stop_map",
+    "types",
+    "stop_arrival",
+    "vehicles",
+    "raw_siri",
+];
+
+/// Hash of the routes.txt and stops.txt copies it was computed from, held weakly so a replaced
+/// copy never matches.
+struct DataHash {
+    routes: Weak<Vec<u8>>,
+    stops: Weak<Vec<u8>>,
+    hash: u64,
 }
 
 pub struct Caches {
+    pub api_keys: CacheDataWithKeys<String, Option<ApiKey>>,
+    pub arrivals_breaker: CircuitBreaker,
+    data_hash: RefCell<Option<DataHash>>,
+    /// A warm-up started by `/api/ready` is running
+    warming: Cell<bool>,
+    store_syncing: Cell<bool>,
+    /// Upstream SIRI bodies served by the pass-through, keyed by the sorted SIRI ids
+    pub raw_siri: CacheDataWithKeys<String, String>,
+    pub response_profiles: CacheDataWithKeys<String, Option<ResponseProfile>>,
     pub routes_raw: CacheData<Vec<u8>>,
+    pub routes_validators: CacheData<UpstreamValidators>,
+    pub stop_aliases: CacheData<HashMap<String, StopAlias>>,
     pub stop_arrival: CacheDataWithKeys<String, StopArrivals>,
+    pub stop_grid: CacheData<StopGrid>,
     pub stop_map: CacheData<HashMap<String, Rc<StopData>>>,
+    pub stop_names: CacheData<StopNameIndex>,
+    pub stop_routes: CacheData<HashMap<String, Vec<RouteRef>>>,
     pub stops_raw: CacheData<Vec<u8>>,
+    pub stops_validators: CacheData<UpstreamValidators>,
+    pub store_synced: CacheData<()>,
     pub types: CacheData<Vec<String>>,
+    /// 404 message for recently requested unknown `type` and `type/number` keys
+    pub unknown_routes: CacheDataWithKeys<String, ErrorCode>,
+    pub vehicles: CacheData<Vec<VehiclePosition>>,
 }
 impl Caches {
-    pub fn get_cache() -> &'static SendWrapper<Caches> {
-        CACHE.get_or_init(|| SendWrapper::new(Caches::new()))
+    pub fn get_cache() -> &'static Caches {
+        CACHE.with(|cache| *cache)
     }
 
     pub fn new() -> Self {
-        let routes_raw = CacheData::new(60 * 60 * 3);
-        let stop_arrival = CacheDataWithKeys::new(9);
-        let stop_map = CacheData::new(60 * 60 * 3);
-        let stops_raw = CacheData::new(60 * 60 * 3);
-        let types = CacheData::new(60 * 60 * 24);
+        let config = Config::get();
+        let api_keys = CacheDataWithKeys::with_max_entries(60 * 5, 1000);
+        let arrivals_breaker = CircuitBreaker::new(
+            config.arrivals_breaker_threshold,
+            config.arrivals_breaker_cooldown_secs,
+        );
+        let raw_siri = CacheDataWithKeys::with_max_entries(config.arrivals_cache_ttl_secs, 200);
+        let response_profiles = CacheDataWithKeys::with_max_entries(60 * 5, 100);
+        let routes_raw = CacheData::new(config.data_cache_ttl_secs);
+        let routes_validators = CacheData::new(config.data_cache_ttl_secs);
+        let stop_aliases = CacheData::new(60 * 5);
+        let stop_arrival = CacheDataWithKeys::with_max_entries(
+            config.arrivals_cache_ttl_secs,
+            config.arrivals_cache_max_entries,
+        );
+        let stop_grid = CacheData::new(config.data_cache_ttl_secs);
+        let stop_map = CacheData::new(config.data_cache_ttl_secs);
+        let stop_names = CacheData::new(config.data_cache_ttl_secs);
+        let stop_routes = CacheData::new(config.data_cache_ttl_secs);
+        let stops_raw = CacheData::new(config.data_cache_ttl_secs);
+        let stops_validators = CacheData::new(config.data_cache_ttl_secs);
+        let store_synced = CacheData::new(60 * 10);
+        let types = CacheData::new(config.types_cache_ttl_secs);
+        let unknown_routes = CacheDataWithKeys::with_max_entries(60, 1000);
+        let vehicles = CacheData::new(config.vehicles_cache_ttl_secs);
         Self {
+            api_keys,
+            arrivals_breaker,
+            data_hash: RefCell::new(None),
+            warming: Cell::new(false),
+            store_syncing: Cell::new(false),
+            raw_siri,
+            response_profiles,
             routes_raw,
+            routes_validators,
+            stop_aliases,
             stop_arrival,
+            stop_grid,
             stop_map,
+            stop_names,
+            stop_routes,
             stops_raw,
+            stops_validators,
+            store_synced,
             types,
+            unknown_routes,
+            vehicles,
+        }
+    }
+
+    /// Drops one of the `PURGEABLE_CACHES` of this isolate, `false` for any other name, along
+    /// with everything derived from it so nothing outlives its source: `routes_raw` takes the
+    /// types, stop routes index, unknown route answers and D1 sync marker with it, `stops_raw`
+    /// the stop map and `stop_map` the spatial grid and name index.
+    pub fn purge(&self, name: &str) -> bool {
+        match name {
+            "routes_raw" => {
+                self.routes_raw.clear();
+                self.routes_validators.clear();
+                self.types.clear();
+                self.stop_routes.clear();
+                self.store_synced.clear();
+                self.unknown_routes.clear();
+            }
+            "stops_raw" => {
+                self.stops_raw.clear();
+                self.stops_validators.clear();
+                self.purge("stop_map");
+                self.store_synced.clear();
+            }
+            "stop_map" => {
+                self.stop_map.clear();
+                self.stop_grid.clear();
+                self.stop_names.clear();
+            }
+            "types" => self.types.clear(),
+            "stop_arrival" => self.stop_arrival.clear(),
+            "vehicles" => self.vehicles.clear(),
+            "raw_siri" => self.raw_siri.clear(),
+            _ => return false,
         }
+        true
+    }
+
+    /// Whether routes.txt and stops.txt and the types and stop map derived from them are
+    /// loaded, expired or not.
+    pub fn is_ready(&self) -> bool {
+        self.routes_raw.peek().is_some()
+            && self.stops_raw.peek().is_some()
+            && self.types.peek().is_some()
+            && self.stop_map.peek().is_some()
+    }
+
+    /// Whether no warm-up is running yet, marking one as running. `end_warm_up` ends it.
+    pub fn begin_warm_up(&self) -> bool {
+        !self.warming.replace(true)
+    }
+
+    pub fn end_warm_up(&self) {
+        self.warming.set(false);
+    }
+
+    /// Whether no D1 store sync is running yet, marking one as running. `end_store_sync` ends it.
+    pub fn begin_store_sync(&self) -> bool {
+        !self.store_syncing.replace(true)
+    }
+
+    pub fn end_store_sync(&self) {
+        self.store_syncing.set(false);
+    }
+
+    /// Seconds since the older of routes.txt and stops.txt was fetched, which the route and stop
+    /// listings are derived from.
+    pub fn data_age_secs(&self) -> Option<u32> {
+        self.routes_raw.age_secs().max(self.stops_raw.age_secs())
+    }
+
+    /// Content hash of the cached routes.txt and stops.txt, `None` until both are loaded. Hashed
+    /// once per copy, renewing an unchanged copy keeps it.
+    pub fn data_hash(&self) -> Option<u64> {
+        let (routes, stops) = (self.routes_raw.peek()?, self.stops_raw.peek()?);
+        let mut data_hash = self.data_hash.try_borrow_mut().ok()?;
+        if let Some(data_hash) = &*data_hash
+            && data_hash.routes.as_ptr() == Rc::as_ptr(&routes)
+            && data_hash.stops.as_ptr() == Rc::as_ptr(&stops)
+        {
+            return Some(data_hash.hash);
+        }
+        let hashes = format!("{:016x}{:016x}", fnv1a_64(&routes), fnv1a_64(&stops));
+        let hash = fnv1a_64(hashes.as_bytes());
+        data_hash.replace(DataHash {
+            routes: Rc::downgrade(&routes),
+            stops: Rc::downgrade(&stops),
+            hash,
+        });
+        Some(hash)
+    }
+
+    pub fn stats(&self) -> BTreeMap<&'static str, CacheStats> {
+        BTreeMap::from([
+            ("apiKeys", self.api_keys.stats()),
+            ("rawSiri", self.raw_siri.stats()),
+            ("responseProfiles", self.response_profiles.stats()),
+            ("routesRaw", self.routes_raw.stats()),
+            ("stopAliases", self.stop_aliases.stats()),
+            ("stopArrival", self.stop_arrival.stats()),
+            ("stopGrid", self.stop_grid.stats()),
+            ("stopMap", self.stop_map.stats()),
+            ("stopNames", self.stop_names.stats()),
+            ("stopRoutes", self.stop_routes.stats()),
+            ("stopsRaw", self.stops_raw.stats()),
+            ("storeSynced", self.store_synced.stats()),
+            ("types", self.types.stats()),
+            ("unknownRoutes", self.unknown_routes.stats()),
+            ("vehicles", self.vehicles.stats()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, ManualClock};
+    use std::time::Duration;
+
+    fn manual_clock() -> ManualClock {
+        let clock = ManualClock::new(1_760_000_000_000.0);
+        clock::install(clock.clone());
+        clock
+    }
+
+    fn keys(cache: &CacheDataWithKeys<&'static str, u32>) -> Vec<&'static str> {
+        let mut keys = cache.record.borrow().keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn keyed_cache_evicts_the_least_recently_used() {
+        manual_clock();
+        let cache = CacheDataWithKeys::with_max_entries(60, 2);
+        cache.set("a", Rc::new(1)).unwrap();
+        cache.set("b", Rc::new(2)).unwrap();
+        assert_eq!(cache.get(&"a").as_deref(), Some(&1));
+        cache.set("c", Rc::new(3)).unwrap();
+        assert_eq!(keys(&cache), ["a", "c"]);
+        // replacing a held key evicts nothing
+        cache.set("a", Rc::new(4)).unwrap();
+        assert_eq!(keys(&cache), ["a", "c"]);
+        cache.set("d", Rc::new(5)).unwrap();
+        assert_eq!(keys(&cache), ["a", "d"]);
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[test]
+    fn keyed_cache_sweeps_expired_records_before_evicting() {
+        let clock = manual_clock();
+        let cache = CacheDataWithKeys::with_max_entries(10, 2);
+        cache.set("a", Rc::new(1)).unwrap();
+        clock.advance(Duration::from_secs(5));
+        cache.set("b", Rc::new(2)).unwrap();
+        clock.advance(Duration::from_secs(6));
+        // expired but still held for get_stale until an insert sweeps it
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get_stale(&"a").as_deref(), Some(&1));
+        cache.set("c", Rc::new(3)).unwrap();
+        assert_eq!(keys(&cache), ["b", "c"]);
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn uncapped_keyed_cache_keeps_growing() {
+        manual_clock();
+        let cache = CacheDataWithKeys::new(60);
+        for (n, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            cache.set(key, Rc::new(n as u32)).unwrap();
+        }
+        assert_eq!(keys(&cache), ["a", "b", "c", "d"]);
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn breaker_opens_at_the_threshold() {
+        manual_clock();
+        let breaker = CircuitBreaker::new(3, 30);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.retry_after(), None);
+        breaker.record_failure();
+        assert_eq!(breaker.retry_after(), Some(30));
+    }
+
+    #[test]
+    fn breaker_success_resets_the_failure_count() {
+        manual_clock();
+        let breaker = CircuitBreaker::new(2, 30);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.retry_after(), None);
+        breaker.record_failure();
+        assert_eq!(breaker.retry_after(), Some(30));
+    }
+
+    #[test]
+    fn breaker_lets_one_probe_through_after_the_cooldown() {
+        let clock = manual_clock();
+        let breaker = CircuitBreaker::new(1, 30);
+        breaker.record_failure();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(breaker.retry_after(), Some(10));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(breaker.retry_after(), None);
+        assert_eq!(breaker.retry_after(), Some(1));
+        assert_eq!(breaker.retry_after(), Some(1));
+
+        // a failed probe reopens for a whole cool-down
+        breaker.record_failure();
+        assert_eq!(breaker.retry_after(), Some(30));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(breaker.retry_after(), None);
+        assert_eq!(breaker.retry_after(), Some(1));
+
+        // a successful one closes it
+        breaker.record_success();
+        assert_eq!(breaker.retry_after(), None);
+        assert_eq!(breaker.retry_after(), None);
+    }
+
+    #[test]
+    fn breaker_replaces_a_probe_that_never_reports_back() {
+        let clock = manual_clock();
+        let breaker = CircuitBreaker::new(1, 30);
+        breaker.record_failure();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(breaker.retry_after(), None);
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(breaker.retry_after(), Some(1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(breaker.retry_after(), None);
+        assert_eq!(breaker.retry_after(), Some(1));
+    }
+
+    #[test]
+    fn purge_drops_what_was_derived_from_the_source() {
+        manual_clock();
+        let cache = Caches::new();
+        let fill = |cache: &Caches| {
+            cache.routes_raw.set(Rc::new(b"routes".to_vec())).unwrap();
+            cache.types.set(Rc::new(vec!["bus".to_string()])).unwrap();
+            cache.stop_routes.set(Rc::new(HashMap::new())).unwrap();
+            cache.stops_raw.set(Rc::new(b"stops".to_vec())).unwrap();
+            cache.stop_map.set(Rc::new(HashMap::new())).unwrap();
+            cache.store_synced.set(Rc::new(())).unwrap();
+            let code = Rc::new(ErrorCode::UnknownRoute);
+            cache
+                .unknown_routes
+                .set("bus/99".to_string(), code)
+                .unwrap();
+        };
+
+        fill(&cache);
+        assert!(cache.purge("stops_raw"));
+        assert!(cache.stops_raw.peek().is_none() && cache.stop_map.peek().is_none());
+        assert!(cache.store_synced.peek().is_none());
+        assert!(cache.routes_raw.peek().is_some() && cache.types.peek().is_some());
+
+        fill(&cache);
+        assert!(cache.purge("routes_raw"));
+        assert!(cache.routes_raw.peek().is_none() && cache.types.peek().is_none());
+        assert!(cache.stop_routes.peek().is_none() && cache.store_synced.peek().is_none());
+        assert_eq!(cache.unknown_routes.get_stale(&"bus/99".to_string()), None);
+        assert!(cache.stops_raw.peek().is_some() && cache.stop_map.peek().is_some());
+
+        assert!(PURGEABLE_CACHES.iter().all(|name| cache.purge(name)));
+        assert!(!cache.purge("stopArrival"));
     }
 }
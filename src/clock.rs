@@ -0,0 +1,80 @@
+//! Time behind [`Clock`], the sibling of [`UpstreamClient`](crate::upstream::UpstreamClient).
+//! The worker reads the runtime's `Date.now()` and waits on its timers through [`RuntimeClock`],
+//! while [`ManualClock`] is set by hand and finishes every delay at once, jumping past it, so the
+//! caches, breaker and timeouts can be driven on the host without the Workers runtime.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+
+thread_local! {
+    static CLOCK: RefCell<Rc<dyn Clock>> = RefCell::new(Rc::new(RuntimeClock));
+}
+
+/// Reads the time and waits, object-safe so any clock can be installed.
+pub trait Clock {
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> f64;
+
+    /// Resolves once `duration` has passed.
+    fn delay(&self, duration: Duration) -> LocalBoxFuture<'static, ()>;
+}
+
+/// The runtime's `Date.now()` and timers.
+pub struct RuntimeClock;
+
+impl Clock for RuntimeClock {
+    fn now_ms(&self) -> f64 {
+        js_sys::Date::now()
+    }
+
+    fn delay(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        Box::pin(worker::Delay::from(duration))
+    }
+}
+
+/// A clock standing still until advanced. A delay doesn't wait, it moves the time past itself
+/// when first polled, so a delay raced against a pending future wins as if it had elapsed.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    now_ms: Rc<Cell<f64>>,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: f64) -> Self {
+        Self {
+            now_ms: Rc::new(Cell::new(now_ms)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_ms
+            .set(self.now_ms.get() + duration.as_secs_f64() * 1000.0);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> f64 {
+        self.now_ms.get()
+    }
+
+    fn delay(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        let clock = self.clone();
+        Box::pin(async move { clock.advance(duration) })
+    }
+}
+
+/// Makes `clock` the time of the current thread, the isolate's only one in the worker.
+pub fn install(clock: impl Clock + 'static) {
+    CLOCK.with(|current| *current.borrow_mut() = Rc::new(clock));
+}
+
+pub fn now_ms() -> f64 {
+    CLOCK.with(|clock| clock.borrow().now_ms())
+}
+
+pub fn delay(duration: Duration) -> LocalBoxFuture<'static, ()> {
+    CLOCK.with(|clock| clock.borrow().delay(duration))
+}
@@ -0,0 +1,207 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use chrono_tz::Tz;
+use tlt_stops_core::feeds;
+use worker::{Env, Url};
+
+use crate::logging::Level;
+
+pub static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Deployment settings read from the wrangler vars, each falling back to the value the service
+/// was built around when unset.
+pub struct Config {
+    /// `UPSTREAM_ROUTES_URL`
+    pub routes_url: String,
+    /// `UPSTREAM_STOPS_URL`
+    pub stops_url: String,
+    /// `UPSTREAM_ARRIVALS_URL`, the stop ids are appended as `?stopid=`
+    pub arrivals_url: String,
+    /// `UPSTREAM_GPS_URL`, live vehicle positions
+    pub gps_url: String,
+    /// `UPSTREAM_MIRROR_URL`, base URL serving the same paths, tried when the primary fails
+    pub mirror_base_url: Option<String>,
+    /// `REGION_TIMEZONE`, IANA timezone of the upstream schedule times
+    pub region_timezone: Tz,
+    /// `MAX_ARRIVALS_STOPS`, stops accepted by one arrivals request
+    pub max_arrivals_stops: usize,
+    /// `ARRIVALS_UPSTREAM_BATCH_SIZE`, stops per SIRI request, larger requests are split and
+    /// fetched concurrently
+    pub arrivals_batch_size: usize,
+    /// `DATA_CACHE_TTL_SECS`, isolate cache of routes.txt/stops.txt and what's derived from them
+    pub data_cache_ttl_secs: u32,
+    /// `TYPES_CACHE_TTL_SECS`
+    pub types_cache_ttl_secs: u32,
+    /// `ARRIVALS_CACHE_TTL_SECS`
+    pub arrivals_cache_ttl_secs: u32,
+    /// `VEHICLES_CACHE_TTL_SECS`
+    pub vehicles_cache_ttl_secs: u32,
+    /// `ARRIVALS_CACHE_MAX_ENTRIES`, stops kept before the least recently used are evicted
+    pub arrivals_cache_max_entries: usize,
+    /// `ARRIVALS_BREAKER_THRESHOLD`, consecutive SIRI failures before pausing the calls
+    pub arrivals_breaker_threshold: u32,
+    /// `ARRIVALS_BREAKER_COOLDOWN_SECS`
+    pub arrivals_breaker_cooldown_secs: u32,
+    /// `UPSTREAM_TIMEOUT_MS`, limit for one upstream request including reading its body
+    pub upstream_timeout_ms: u64,
+    /// `DATA_CF_CACHE_TTL_SECS`, Cloudflare edge cache of routes.txt/stops.txt
+    pub data_cf_cache_ttl_secs: u32,
+    /// `ARRIVALS_CF_CACHE_TTL_SECS`, Cloudflare edge cache of the SIRI responses
+    pub arrivals_cf_cache_ttl_secs: u32,
+    /// `RATE_LIMIT_PERIOD_SECS`, period of the `RATE_LIMITER` binding's limit, sent as the
+    /// `Retry-After` of its 429s
+    pub rate_limit_period_secs: u32,
+    /// `API_KEYS_REQUIRED`, refuse the API requests without an `X-API-Key` while the `API_KEYS`
+    /// namespace is bound
+    pub api_keys_required: bool,
+    /// `LOG_LEVEL`, `debug`, `info`, `warn` or `error`, lines below it are dropped
+    pub log_level: Level,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            routes_url: feeds::ROUTES_URL.to_string(),
+            stops_url: feeds::STOPS_URL.to_string(),
+            arrivals_url: feeds::ARRIVALS_URL.to_string(),
+            gps_url: feeds::GPS_URL.to_string(),
+            mirror_base_url: None,
+            region_timezone: feeds::REGION_TIMEZONE,
+            max_arrivals_stops: 25,
+            arrivals_batch_size: 5,
+            data_cache_ttl_secs: 60 * 60 * 3,
+            types_cache_ttl_secs: 60 * 60 * 24,
+            arrivals_cache_ttl_secs: 9,
+            vehicles_cache_ttl_secs: 5,
+            arrivals_cache_max_entries: 2000,
+            arrivals_breaker_threshold: 5,
+            arrivals_breaker_cooldown_secs: 30,
+            upstream_timeout_ms: 10_000,
+            data_cf_cache_ttl_secs: 3600,
+            arrivals_cf_cache_ttl_secs: 120,
+            rate_limit_period_secs: 60,
+            api_keys_required: false,
+            log_level: Level::Info,
+        }
+    }
+}
+
+/// Where the vars are read from, the worker's [`Env`] or the process environment on the host.
+pub trait Vars {
+    fn get(&self, name: &str) -> Option<String>;
+}
+
+impl Vars for Env {
+    fn get(&self, name: &str) -> Option<String> {
+        self.var(name).ok().map(|var| var.to_string())
+    }
+}
+
+/// The environment variables of the process, the vars of the `native` server.
+#[cfg(feature = "native")]
+pub struct ProcessVars;
+
+#[cfg(feature = "native")]
+impl Vars for ProcessVars {
+    fn get(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+}
+
+fn var(env: &dyn Vars, name: &str) -> Option<String> {
+    env.get(name)
+        .map(|var| var.trim().to_string())
+        .filter(|var| !var.is_empty())
+}
+
+fn parsed_var<T: FromStr>(env: &dyn Vars, name: &str) -> worker::Result<Option<T>> {
+    var(env, name)
+        .map(|var| {
+            var.parse::<T>()
+                .map_err(|_| worker::Error::RustError(format!("invalid {name}: {var}")))
+        })
+        .transpose()
+}
+
+impl Config {
+    /// The configuration of this isolate, the defaults until `configure` ran.
+    pub fn get() -> &'static Config {
+        CONFIG.get_or_init(Config::default)
+    }
+
+    /// Reads the vars once per isolate, rejecting values that don't parse so a typo can't
+    /// silently fall back to a default. Must run before the caches are first touched.
+    pub fn configure(env: &dyn Vars) -> worker::Result<()> {
+        if CONFIG.get().is_some() {
+            return Ok(());
+        }
+        CONFIG.set(Self::from_env(env)?).ok();
+        Ok(())
+    }
+
+    /// `uri` with its origin swapped for the mirror's, `None` without a mirror.
+    pub fn mirror_url(&self, uri: &str) -> Option<String> {
+        let mirror = self.mirror_base_url.as_deref()?;
+        let uri = Url::parse(uri).ok()?;
+        Some(match uri.query() {
+            Some(query) => format!("{}{}?{}", mirror, uri.path(), query),
+            None => format!("{}{}", mirror, uri.path()),
+        })
+    }
+
+    fn from_env(env: &dyn Vars) -> worker::Result<Self> {
+        let defaults = Self::default();
+        let max_arrivals_stops =
+            parsed_var(env, "MAX_ARRIVALS_STOPS")?.unwrap_or(defaults.max_arrivals_stops);
+        if max_arrivals_stops == 0 {
+            return Err(worker::Error::RustError(
+                "invalid MAX_ARRIVALS_STOPS: 0".to_string(),
+            ));
+        }
+        let arrivals_batch_size = parsed_var(env, "ARRIVALS_UPSTREAM_BATCH_SIZE")?
+            .unwrap_or(defaults.arrivals_batch_size);
+        if arrivals_batch_size == 0 {
+            return Err(worker::Error::RustError(
+                "invalid ARRIVALS_UPSTREAM_BATCH_SIZE: 0".to_string(),
+            ));
+        }
+        Ok(Self {
+            routes_url: var(env, "UPSTREAM_ROUTES_URL").unwrap_or(defaults.routes_url),
+            stops_url: var(env, "UPSTREAM_STOPS_URL").unwrap_or(defaults.stops_url),
+            arrivals_url: var(env, "UPSTREAM_ARRIVALS_URL").unwrap_or(defaults.arrivals_url),
+            gps_url: var(env, "UPSTREAM_GPS_URL").unwrap_or(defaults.gps_url),
+            mirror_base_url: var(env, "UPSTREAM_MIRROR_URL")
+                .map(|url| url.trim_end_matches('/').to_string()),
+            region_timezone: parsed_var(env, "REGION_TIMEZONE")?
+                .unwrap_or(defaults.region_timezone),
+            max_arrivals_stops,
+            arrivals_batch_size,
+            data_cache_ttl_secs: parsed_var(env, "DATA_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.data_cache_ttl_secs),
+            types_cache_ttl_secs: parsed_var(env, "TYPES_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.types_cache_ttl_secs),
+            arrivals_cache_ttl_secs: parsed_var(env, "ARRIVALS_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.arrivals_cache_ttl_secs),
+            vehicles_cache_ttl_secs: parsed_var(env, "VEHICLES_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.vehicles_cache_ttl_secs),
+            arrivals_cache_max_entries: parsed_var(env, "ARRIVALS_CACHE_MAX_ENTRIES")?
+                .unwrap_or(defaults.arrivals_cache_max_entries),
+            arrivals_breaker_threshold: parsed_var(env, "ARRIVALS_BREAKER_THRESHOLD")?
+                .unwrap_or(defaults.arrivals_breaker_threshold),
+            arrivals_breaker_cooldown_secs: parsed_var(env, "ARRIVALS_BREAKER_COOLDOWN_SECS")?
+                .unwrap_or(defaults.arrivals_breaker_cooldown_secs),
+            upstream_timeout_ms: parsed_var(env, "UPSTREAM_TIMEOUT_MS")?
+                .unwrap_or(defaults.upstream_timeout_ms),
+            data_cf_cache_ttl_secs: parsed_var(env, "DATA_CF_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.data_cf_cache_ttl_secs),
+            arrivals_cf_cache_ttl_secs: parsed_var(env, "ARRIVALS_CF_CACHE_TTL_SECS")?
+                .unwrap_or(defaults.arrivals_cf_cache_ttl_secs),
+            rate_limit_period_secs: parsed_var(env, "RATE_LIMIT_PERIOD_SECS")?
+                .unwrap_or(defaults.rate_limit_period_secs),
+            api_keys_required: parsed_var(env, "API_KEYS_REQUIRED")?
+                .unwrap_or(defaults.api_keys_required),
+            log_level: parsed_var(env, "LOG_LEVEL")?.unwrap_or(defaults.log_level),
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use js_sys::{Object, Uint8Array};
+use serde_json::{Value, json};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{CryptoKey, SubtleCrypto, WorkerGlobalScope};
+use worker::{Error, Result, wasm_bindgen, wasm_bindgen_futures};
+
+const BASE64URL_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Unpadded base64url, as used by JWTs and the Web Push keys.
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |acc, (i, byte)| {
+            acc | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            let index = (triple >> (18 - 6 * i)) & 0x3f;
+            encoded.push(BASE64URL_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Decodes base64url with or without padding, `None` on any other character.
+pub fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = BASE64URL_ALPHABET.iter().position(|c| *c == byte)? as u32;
+        buffer = buffer << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn subtle() -> Result<SubtleCrypto> {
+    let scope = js_sys::global().unchecked_into::<WorkerGlobalScope>();
+    Ok(scope.crypto()?.subtle())
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let scope = js_sys::global().unchecked_into::<WorkerGlobalScope>();
+    let mut bytes = [0u8; N];
+    scope
+        .crypto()?
+        .get_random_values_with_u8_array(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// 128 random bits from the runtime's CSPRNG as base64url, for unguessable ids.
+pub fn random_id() -> Result<String> {
+    Ok(base64url_encode(&random_bytes::<16>()?))
+}
+
+/// `N` random bytes as lowercase hex, for the ids of trace contexts.
+pub fn random_hex<const N: usize>() -> Result<String> {
+    Ok(random_bytes::<N>()?
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+fn js_object(value: Value) -> Result<Object> {
+    Ok(js_sys::JSON::parse(&value.to_string())?.unchecked_into())
+}
+
+async fn sign(algorithm: Value, key: &CryptoKey, data: &[u8]) -> Result<Vec<u8>> {
+    let promise = subtle()?.sign_with_object_and_u8_array(&js_object(algorithm)?, key, data)?;
+    let signature = JsFuture::from(promise).await?;
+    Ok(Uint8Array::new(&signature).to_vec())
+}
+
+async fn import_key(format: &str, key_data: &Object, algorithm: Value) -> Result<CryptoKey> {
+    let usages = js_sys::Array::of1(&"sign".into());
+    let promise = subtle()?.import_key_with_object(
+        format,
+        key_data,
+        &js_object(algorithm)?,
+        false,
+        &usages,
+    )?;
+    Ok(JsFuture::from(promise).await?.unchecked_into())
+}
+
+/// ES256 signature (raw `r || s`, the JWS form) of `data`, with the P-256 key given as its
+/// base64url private scalar and uncompressed public point.
+pub async fn sign_es256(private_key: &str, public_key: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let point = base64url_decode(public_key)
+        .filter(|point| point.len() == 65 && point[0] == 4)
+        .ok_or_else(|| Error::RustError("invalid P-256 public key".to_string()))?;
+    let jwk = js_object(json!({
+        "kty": "EC",
+        "crv": "P-256",
+        "d": private_key,
+        "x": base64url_encode(&point[1..33]),
+        "y": base64url_encode(&point[33..]),
+    }))?;
+    let key = import_key("jwk", &jwk, json!({"name": "ECDSA", "namedCurve": "P-256"})).await?;
+    sign(json!({"name": "ECDSA", "hash": "SHA-256"}), &key, data).await
+}
+
+/// HMAC-SHA256 of `data` under `key`.
+pub async fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = import_key(
+        "raw",
+        &Uint8Array::from(key),
+        json!({"name": "HMAC", "hash": "SHA-256"}),
+    )
+    .await?;
+    sign(json!({"name": "HMAC"}), &key, data).await
+}
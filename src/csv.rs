@@ -0,0 +1,123 @@
+//! CSV bodies of the stop listing and arrivals endpoints, for piping the API into spreadsheets.
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use worker::{Response, Result};
+
+use crate::models::{Arrival, StopArrivalsStatus, StopSearchResult, flat_arrivals};
+use crate::reply::Reply;
+
+const ARRIVALS_HEADER: [&str; 12] = [
+    "stopId",
+    "stopName",
+    "status",
+    "type",
+    "number",
+    "time",
+    "inSeconds",
+    "isRealtime",
+    "isLowEntry",
+    "destination",
+    "vehicleId",
+    "vehicleDistanceM",
+];
+
+const STOPS_HEADER: [&str; 6] = ["id", "siriId", "name", "matchedAlias", "lat", "lon"];
+
+/// RFC 4180 rows, fields quoted only when they need to be.
+pub struct CsvWriter(String);
+
+impl CsvWriter {
+    pub fn new(header: &[&str]) -> Self {
+        let mut writer = Self(String::new());
+        writer.row(header);
+        writer
+    }
+
+    pub fn row<S: AsRef<str>>(&mut self, fields: &[S]) {
+        for (index, field) in fields.iter().enumerate() {
+            if index > 0 {
+                self.0.push(',');
+            }
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                self.0.push('"');
+                self.0.push_str(&field.replace('"', "\"\""));
+                self.0.push('"');
+            } else {
+                self.0.push_str(field);
+            }
+        }
+        self.0.push_str("\r\n");
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        self.into_reply().into_response()
+    }
+
+    pub fn into_reply(self) -> Reply {
+        Reply::new("text/csv; charset=utf-8", self.0.into_bytes())
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+pub fn stops_csv(stops: &[StopSearchResult]) -> CsvWriter {
+    let mut writer = CsvWriter::new(&STOPS_HEADER);
+    for stop in stops {
+        writer.row(&[
+            stop.id.clone(),
+            stop.siri_id.clone(),
+            stop.name.clone(),
+            optional(stop.matched_alias.as_ref()),
+            optional(stop.lat),
+            optional(stop.lon),
+        ]);
+    }
+    writer
+}
+
+pub fn arrivals_csv() -> CsvWriter {
+    CsvWriter::new(&ARRIVALS_HEADER)
+}
+
+/// One row per arrival in time order, or a single row carrying the status of a stop whose
+/// arrivals are missing.
+pub fn push_stop_arrivals(
+    writer: &mut CsvWriter,
+    stop_id: &str,
+    stop_name: &str,
+    status: StopArrivalsStatus,
+    arrivals: Option<&HashMap<String, HashMap<String, Vec<Arrival>>>>,
+) {
+    let status = match status {
+        StopArrivalsStatus::Ok => "ok",
+        StopArrivalsStatus::UnknownStop => "unknown_stop",
+        StopArrivalsStatus::UpstreamError => "upstream_error",
+    };
+    let Some(arrivals) = arrivals else {
+        let mut row = [""; ARRIVALS_HEADER.len()];
+        row[..3].copy_from_slice(&[stop_id, stop_name, status]);
+        writer.row(&row);
+        return;
+    };
+    for arrival in flat_arrivals(arrivals, Utc::now()) {
+        writer.row(&[
+            stop_id.to_string(),
+            stop_name.to_string(),
+            status.to_string(),
+            arrival.r#type.to_string(),
+            arrival.number.to_string(),
+            arrival.time.to_string(),
+            optional(arrival.in_seconds),
+            arrival.is_realtime.to_string(),
+            arrival.is_low_entry.to_string(),
+            optional(arrival.destination),
+            optional(arrival.vehicle_id),
+            optional(arrival.vehicle_distance_m),
+        ]);
+    }
+}
@@ -0,0 +1,512 @@
+//! Typed extractors of the path and query parameters. A handler names the parameters it takes
+//! as a struct and gets them deserialized and validated at once, or the 400 problem naming the
+//! parameter that is missing or invalid.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::value::BorrowedStrDeserializer;
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use worker::{Request, Result, RouteContext, Url};
+
+use crate::config::Config;
+use crate::models::{ArrivalsFilter, TransportType};
+use crate::str_utils::splits_commas;
+
+pub enum RequestError {
+    MissingParameter(String),
+    InvalidParameter(String),
+    TooManyStops(String),
+}
+
+/// What the handlers read of a request, the worker's [`Request`] or the host's in `tlt-serve`.
+pub trait Incoming {
+    fn url(&self) -> Result<Url>;
+
+    /// The value of the header, `None` when it's absent.
+    fn header(&self, name: &str) -> Result<Option<String>>;
+}
+
+impl Incoming for Request {
+    fn url(&self) -> Result<Url> {
+        Request::url(self)
+    }
+
+    fn header(&self, name: &str) -> Result<Option<String>> {
+        self.headers().get(name)
+    }
+}
+
+/// The raw path parameters of a matched route, the worker router's or `tlt-serve`'s.
+pub trait PathParams {
+    fn param(&self, name: &str) -> Option<&str>;
+}
+
+impl PathParams for RouteContext<()> {
+    fn param(&self, name: &str) -> Option<&str> {
+        RouteContext::param(self, name).map(String::as_str)
+    }
+}
+
+/// The query parameters of a request. Empty values count as absent and only the first value
+/// of a repeated parameter is read.
+pub struct Query<T>(pub T);
+
+/// The path parameters of a matched route.
+pub struct Path<T>(pub T);
+
+/// Checks across the parameters, or against the config, that deserializing them can't make.
+pub trait Validate {
+    fn validate(&self) -> core::result::Result<(), RequestError> {
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned + Validate> Query<T> {
+    pub fn from_request(req: &dyn Incoming) -> Result<Self> {
+        let mut pairs = Vec::<(String, String)>::new();
+        for (name, value) in req.url()?.query_pairs() {
+            if !value.is_empty() && !pairs.iter().any(|(seen, _)| *seen == name) {
+                pairs.push((name.into_owned(), value.into_owned()));
+            }
+        }
+        let params =
+            T::deserialize(Params::Query(&pairs)).map_err(|err| err.into_request_error("query"))?;
+        params.validate()?;
+        Ok(Query(params))
+    }
+}
+
+impl<'a, T: Deserialize<'a> + Validate> Path<T> {
+    pub fn from_context(ctx: &'a dyn PathParams) -> Result<Self> {
+        let params =
+            T::deserialize(Params::Path(ctx)).map_err(|err| err.into_request_error("path"))?;
+        params.validate()?;
+        Ok(Path(params))
+    }
+}
+
+#[derive(Debug)]
+enum ParamError {
+    Missing(String),
+    Invalid(String),
+    /// What a valid value looks like, from a parameter type not knowing the parameter's name
+    Hint(String),
+}
+
+impl ParamError {
+    fn into_request_error(self, kind: &str) -> RequestError {
+        match self {
+            ParamError::Missing(name) => {
+                RequestError::MissingParameter(format!("missing {} {} parameter", name, kind))
+            }
+            ParamError::Invalid(message) | ParamError::Hint(message) => {
+                RequestError::InvalidParameter(message)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Missing(name) => write!(f, "missing {}", name),
+            ParamError::Invalid(message) | ParamError::Hint(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+impl de::Error for ParamError {
+    fn custom<T: fmt::Display>(hint: T) -> Self {
+        ParamError::Hint(hint.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ParamError::Missing(field.to_string())
+    }
+}
+
+/// The parameters deserialized as a struct, the path ones looked up by the struct's fields.
+enum Params<'de> {
+    Query(&'de [(String, String)]),
+    Path(&'de dyn PathParams),
+}
+
+impl<'de> Deserializer<'de> for Params<'de> {
+    type Error = ParamError;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        Err(ParamError::Invalid(
+            "parameters are read as a struct".to_string(),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        let pairs = match self {
+            Params::Query(pairs) => pairs
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect::<Vec<(&str, &str)>>(),
+            Params::Path(ctx) => fields
+                .iter()
+                .filter_map(|field| {
+                    let value = ctx.param(field).filter(|value| !value.is_empty())?;
+                    Some((*field, value))
+                })
+                .collect(),
+        };
+        visitor.visit_map(ParamsAccess {
+            pairs: pairs.into_iter(),
+            value: None,
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct ParamsAccess<'de> {
+    pairs: std::vec::IntoIter<(&'de str, &'de str)>,
+    value: Option<(&'de str, &'de str)>,
+}
+
+impl<'de> MapAccess<'de> for ParamsAccess<'de> {
+    type Error = ParamError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> core::result::Result<Option<K::Value>, ParamError> {
+        let Some((name, value)) = self.pairs.next() else {
+            return Ok(None);
+        };
+        self.value = Some((name, value));
+        seed.deserialize(BorrowedStrDeserializer::new(name))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        let (name, value) = self
+            .value
+            .take()
+            .ok_or_else(|| ParamError::Invalid("value without a parameter".to_string()))?;
+        seed.deserialize(Param { name, value })
+            .map_err(|err| match err {
+                // A hint from the value's type, completed with the parameter's name.
+                ParamError::Hint(hint) => {
+                    ParamError::Invalid(format!("invalid {} provided ({})", name, hint))
+                }
+                err => err,
+            })
+    }
+}
+
+/// One parameter's value, parsed as the type its field asks for.
+struct Param<'de> {
+    name: &'de str,
+    value: &'de str,
+}
+
+impl Param<'_> {
+    fn parse<T: FromStr>(&self) -> core::result::Result<T, ParamError> {
+        self.value
+            .trim()
+            .parse()
+            .map_err(|_| ParamError::Invalid(format!("invalid {} provided", self.name)))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> core::result::Result<V::Value, ParamError> {
+                let value = self.parse()?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Param<'de> {
+    type Error = ParamError;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        match self.value {
+            "true" => visitor.visit_bool(true),
+            "false" => visitor.visit_bool(false),
+            _ => Err(ParamError::Hint("true, false".to_string())),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        visitor
+            .visit_enum(BorrowedStrDeserializer::<ParamError>::new(self.value))
+            .map_err(|_| ParamError::Hint(variants.join(", ")))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> core::result::Result<V::Value, ParamError> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier
+    }
+}
+
+/// An integer parameter within `MIN..=MAX`.
+#[derive(Clone, Copy)]
+pub struct Bounded<const MIN: u32, const MAX: u32>(pub u32);
+
+impl<'de, const MIN: u32, const MAX: u32> Deserialize<'de> for Bounded<MIN, MAX> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|value| (MIN..=MAX).contains(value))
+            .map(Bounded)
+            .ok_or_else(|| de::Error::custom(format!("{}-{}", MIN, MAX)))
+    }
+}
+
+/// A number parameter within `MIN..=MAX`.
+#[derive(Clone, Copy)]
+pub struct BoundedFloat<const MIN: i32, const MAX: i32>(pub f64);
+
+impl<'de, const MIN: i32, const MAX: i32> Deserialize<'de> for BoundedFloat<MIN, MAX> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|value| (f64::from(MIN)..=f64::from(MAX)).contains(value))
+            .map(BoundedFloat)
+            .ok_or_else(|| de::Error::custom(format!("{}-{}", MIN, MAX)))
+    }
+}
+
+/// A comma-separated list, parts kept as they are.
+pub struct CommaSeparated(pub Vec<String>);
+
+impl<'de> Deserialize<'de> for CommaSeparated {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        splits_commas(value.as_bytes())
+            .map(CommaSeparated)
+            .map_err(|_| de::Error::custom("comma-separated values"))
+    }
+}
+
+/// `?limit=`, at most 100 items of a list.
+#[derive(Deserialize)]
+pub struct LimitQuery {
+    limit: Option<Bounded<1, 100>>,
+}
+
+impl LimitQuery {
+    pub fn limit_or(&self, default: usize) -> usize {
+        self.limit.map_or(default, |limit| limit.0 as usize)
+    }
+}
+
+impl Validate for LimitQuery {}
+
+/// `?format=`, the encoding or shape of the response.
+#[derive(Deserialize)]
+pub struct FormatQuery {
+    pub format: Option<String>,
+}
+
+impl Validate for FormatQuery {}
+
+/// `?links=true`, the list items with the URLs they lead to.
+#[derive(Deserialize)]
+pub struct LinksQuery {
+    #[serde(default)]
+    pub links: bool,
+}
+
+impl Validate for LinksQuery {}
+
+/// `?stops=`, the stop ids of the arrivals endpoints within the configured stop limit.
+#[derive(Deserialize)]
+pub struct StopsQuery {
+    stops: CommaSeparated,
+}
+
+impl StopsQuery {
+    pub fn into_stop_ids(self) -> Vec<String> {
+        self.stops.0
+    }
+}
+
+impl Validate for StopsQuery {
+    fn validate(&self) -> core::result::Result<(), RequestError> {
+        check_stop_count(&self.stops.0)
+    }
+}
+
+/// Whether the arrivals of this many stops may be asked for at once.
+pub fn check_stop_count(stop_ids: &[String]) -> core::result::Result<(), RequestError> {
+    let max_stops = Config::get().max_arrivals_stops;
+    if !(1..=max_stops).contains(&stop_ids.len()) {
+        return Err(RequestError::TooManyStops(format!(
+            "invalid number of stops provided (1-{})",
+            max_stops
+        )));
+    }
+    Ok(())
+}
+
+/// `?max_minutes=` and `?per_route=`, read as an [`ArrivalsFilter`].
+#[derive(Deserialize)]
+pub struct ArrivalsFilterQuery {
+    max_minutes: Option<Bounded<1, 1440>>,
+    per_route: Option<Bounded<1, 100>>,
+}
+
+impl From<ArrivalsFilterQuery> for ArrivalsFilter {
+    fn from(query: ArrivalsFilterQuery) -> Self {
+        ArrivalsFilter {
+            max_minutes: query.max_minutes.map(|max_minutes| max_minutes.0),
+            per_route: query.per_route.map(|per_route| per_route.0 as usize),
+        }
+    }
+}
+
+impl Validate for ArrivalsFilterQuery {}
+
+/// The extras `?include=` asks for, the vehicle distances being the only one.
+#[derive(Default)]
+pub struct Includes {
+    pub vehicle_distance: bool,
+}
+
+impl<'de> Deserialize<'de> for Includes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let mut includes = Includes::default();
+        for part in value
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            match part {
+                "vehicle_distance" => includes.vehicle_distance = true,
+                _ => return Err(de::Error::custom("vehicle_distance")),
+            }
+        }
+        Ok(includes)
+    }
+}
+
+/// `{type}` of the route paths.
+#[derive(Deserialize)]
+pub struct TypeSelector {
+    #[serde(rename = "type")]
+    pub route_type: TransportType,
+}
+
+impl Validate for TypeSelector {}
+
+/// `{type}/routes/{number}` of the route paths.
+#[derive(Deserialize)]
+pub struct RouteSelector<'a> {
+    #[serde(rename = "type")]
+    pub route_type: TransportType,
+    pub number: &'a str,
+}
+
+impl Validate for RouteSelector<'_> {}
+
+/// `{type}/routes/{number}/directions/{direction}` of the route paths.
+#[derive(Deserialize)]
+pub struct DirectionSelector<'a> {
+    #[serde(rename = "type")]
+    pub route_type: TransportType,
+    pub number: &'a str,
+    pub direction: &'a str,
+}
+
+impl Validate for DirectionSelector<'_> {}
+
+/// `{id}` of the stop, vehicle, alert and webhook paths.
+#[derive(Deserialize)]
+pub struct IdParam<'a> {
+    pub id: &'a str,
+}
+
+impl Validate for IdParam<'_> {}
+
+/// `{name}` of the stop group paths, still percent-encoded.
+#[derive(Deserialize)]
+pub struct NameParam<'a> {
+    pub name: &'a str,
+}
+
+impl Validate for NameParam<'_> {}
@@ -0,0 +1,937 @@
+//! GraphQL over the routes, stops and arrivals, so a client gets a route with its stops and live
+//! arrivals in one request. Executes the part of the query language nested fetches need:
+//! selection sets, aliases, arguments and variables. Fragments, directives, mutations and
+//! introspection are answered with an error. Kept hand-written rather than built on
+//! async-graphql, which would add a schema derive layer and its parser to the worker's size
+//! budget for a handful of read-only fields.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use utoipa::ToSchema;
+use worker::Env;
+
+use crate::config::Config;
+use crate::handlers::arrivals::{ArrivalsLookup, lookup_stop_arrivals};
+use crate::models::{
+    ArrivalsFilter, RouteGroup, StopArrivals, StopArrivalsStatus, StopData, TransportType,
+    flat_arrivals,
+};
+use crate::services::TransportService;
+use crate::str_utils::resolve_direction;
+
+/// Longest query accepted, in bytes.
+const MAX_QUERY_LEN: usize = 8 * 1024;
+/// Deepest selection set nesting accepted.
+const MAX_DEPTH: usize = 8;
+
+/// Fields of `Arrival`, the `FlatArrival` keys.
+const ARRIVAL_FIELDS: [&str; 9] = [
+    "type",
+    "number",
+    "time",
+    "isLowEntry",
+    "isRealtime",
+    "destination",
+    "vehicleId",
+    "vehicleDistanceM",
+    "inSeconds",
+];
+
+#[derive(Deserialize, ToSchema)]
+pub struct GraphQlRequest {
+    #[schema(
+        example = "{ route(type: \"bus\", number: \"1\") { directions { name stops { name arrivals(limit: 2) { time inSeconds } } } } }"
+    )]
+    pub query: String,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub variables: Option<Map<String, Value>>,
+}
+
+struct Field {
+    alias: Option<String>,
+    name: String,
+    /// With the variables already substituted
+    arguments: Vec<(String, Value)>,
+    selections: Vec<Field>,
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    fn argument(&self, name: &str) -> Option<&Value> {
+        self.arguments
+            .iter()
+            .find(|(argument, _)| argument == name)
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_null())
+    }
+
+    fn string_argument(&self, name: &str) -> Result<&str, String> {
+        match self.argument(name) {
+            Some(Value::String(value)) => Ok(value),
+            Some(_) => Err(format!(
+                "argument {} of {} must be a String",
+                name, self.name
+            )),
+            None => Err(format!("missing argument {} of {}", name, self.name)),
+        }
+    }
+
+    fn u32_argument(&self, name: &str) -> Result<Option<u32>, String> {
+        self.argument(name)
+            .map(|value| {
+                value
+                    .as_u64()
+                    .and_then(|value| u32::try_from(value).ok())
+                    .ok_or(format!(
+                        "argument {} of {} must be a positive Int",
+                        name, self.name
+                    ))
+            })
+            .transpose()
+    }
+
+    fn scalar(&self, value: Value) -> Result<Value, String> {
+        if !self.selections.is_empty() {
+            return Err(format!("{} is a scalar and takes no selection", self.name));
+        }
+        Ok(value)
+    }
+
+    fn selections(&self) -> Result<&[Field], String> {
+        if self.selections.is_empty() {
+            return Err(format!("{} needs a selection of its fields", self.name));
+        }
+        Ok(&self.selections)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    variables: &'a Map<String, Value>,
+}
+
+impl Parser<'_> {
+    fn skip_ignored(&mut self) {
+        while let Some(&byte) = self.src.get(self.pos) {
+            match byte {
+                b' ' | b'\t' | b'\n' | b'\r' | b',' => self.pos += 1,
+                b'#' => {
+                    while self.src.get(self.pos).is_some_and(|&byte| byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ignored();
+        self.src.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        match self.peek() {
+            Some(byte) if byte == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(byte) => Err(format!(
+                "expected '{}' but found '{}' at {}",
+                expected as char, byte as char, self.pos
+            )),
+            None => Err(format!(
+                "expected '{}' but the query ended",
+                expected as char
+            )),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        self.skip_ignored();
+        let start = self.pos;
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos || self.src[start].is_ascii_digit() {
+            return Err(format!("expected a name at {}", start));
+        }
+        Ok(String::from_utf8_lossy(&self.src[start..self.pos]).into_owned())
+    }
+
+    fn document(&mut self) -> Result<Vec<Field>, String> {
+        if self.peek() != Some(b'{') {
+            match self.name()?.as_str() {
+                "query" => {}
+                "fragment" => return Err("fragments are not supported".to_string()),
+                _ => return Err("only queries are supported".to_string()),
+            }
+            if self
+                .peek()
+                .is_some_and(|byte| byte.is_ascii_alphabetic() || byte == b'_')
+            {
+                self.name()?;
+            }
+            if self.peek() == Some(b'(') {
+                self.skip_variable_definitions()?;
+            }
+            if self.peek() == Some(b'@') {
+                return Err("directives are not supported".to_string());
+            }
+        }
+        let fields = self.selection_set(1)?;
+        match self.peek() {
+            None => Ok(fields),
+            Some(_) => Err("only one operation per request is supported".to_string()),
+        }
+    }
+
+    /// The values come from the request's `variables`, so the definitions are only skipped.
+    fn skip_variable_definitions(&mut self) -> Result<(), String> {
+        let mut depth = 0usize;
+        while let Some(&byte) = self.src.get(self.pos) {
+            self.pos += 1;
+            match byte {
+                b'(' => depth += 1,
+                b')' if depth == 1 => return Ok(()),
+                b')' => depth -= 1,
+                b'"' => {
+                    self.pos -= 1;
+                    self.string()?;
+                }
+                _ => {}
+            }
+        }
+        Err("unterminated variable definitions".to_string())
+    }
+
+    fn selection_set(&mut self, depth: usize) -> Result<Vec<Field>, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("selections nest deeper than {}", MAX_DEPTH));
+        }
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'}') => break,
+                Some(b'.') => return Err("fragments are not supported".to_string()),
+                Some(_) => fields.push(self.field(depth)?),
+                None => return Err("unterminated selection set".to_string()),
+            }
+        }
+        self.expect(b'}')?;
+        if fields.is_empty() {
+            return Err("empty selection set".to_string());
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self, depth: usize) -> Result<Field, String> {
+        let mut alias = None;
+        let mut name = self.name()?;
+        if self.peek() == Some(b':') {
+            self.expect(b':')?;
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let mut arguments = Vec::new();
+        if self.peek() == Some(b'(') {
+            self.expect(b'(')?;
+            while self.peek() != Some(b')') {
+                let argument = self.name()?;
+                self.expect(b':')?;
+                arguments.push((argument, self.value()?));
+            }
+            self.expect(b')')?;
+        }
+        if self.peek() == Some(b'@') {
+            return Err("directives are not supported".to_string());
+        }
+        let selections = if self.peek() == Some(b'{') {
+            self.selection_set(depth + 1)?
+        } else {
+            Vec::new()
+        };
+        Ok(Field {
+            alias,
+            name,
+            arguments,
+            selections,
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'$') => {
+                self.pos += 1;
+                let name = self.name()?;
+                Ok(self.variables.get(&name).cloned().unwrap_or(Value::Null))
+            }
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.expect(b'[')?;
+                let mut items = Vec::new();
+                while self.peek() != Some(b']') {
+                    if self.peek().is_none() {
+                        return Err("unterminated list".to_string());
+                    }
+                    items.push(self.value()?);
+                }
+                self.expect(b']')?;
+                Ok(Value::Array(items))
+            }
+            Some(b'{') => {
+                self.expect(b'{')?;
+                let mut object = Map::new();
+                while self.peek() != Some(b'}') {
+                    let key = self.name()?;
+                    self.expect(b':')?;
+                    object.insert(key, self.value()?);
+                }
+                self.expect(b'}')?;
+                Ok(Value::Object(object))
+            }
+            Some(byte) if byte == b'-' || byte.is_ascii_digit() => {
+                let start = self.pos;
+                while self.src.get(self.pos).is_some_and(|byte| {
+                    matches!(byte, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                }) {
+                    self.pos += 1;
+                }
+                let number = String::from_utf8_lossy(&self.src[start..self.pos]);
+                number
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .or_else(|_| number.parse::<f64>().map(Value::from))
+                    .map_err(|_| format!("invalid number {}", number))
+            }
+            Some(_) => Ok(match self.name()?.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values, compared as strings
+                name => Value::String(name.to_string()),
+            }),
+            None => Err("expected a value but the query ended".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        if self.src[self.pos..].starts_with(b"\"\"") {
+            return Err("block strings are not supported".to_string());
+        }
+        let mut string = Vec::new();
+        loop {
+            let Some(&byte) = self.src.get(self.pos) else {
+                return Err("unterminated string".to_string());
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.src.get(self.pos).copied();
+                    self.pos += 1;
+                    let unescaped = match escaped {
+                        Some(b'u') => {
+                            let hex = self
+                                .src
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or("invalid unicode escape")?;
+                            self.pos += 4;
+                            hex
+                        }
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(byte @ (b'"' | b'\\' | b'/')) => byte as char,
+                        _ => return Err("invalid string escape".to_string()),
+                    };
+                    string.extend_from_slice(unescaped.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b'\n' => return Err("unterminated string".to_string()),
+                byte => string.push(byte),
+            }
+        }
+        String::from_utf8(string).map_err(|_| "invalid UTF-8 in string".to_string())
+    }
+}
+
+struct Executor<'a> {
+    route_map: &'a HashMap<String, HashMap<String, RouteGroup>>,
+    stop_map: &'a HashMap<String, Rc<StopData>>,
+    types: &'a [String],
+    /// Arrivals by stop id, `None` on the first pass collecting the stops they're selected for
+    arrivals: Option<HashMap<String, Rc<StopArrivals>>>,
+    wanted_arrivals: RefCell<HashSet<String>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Executor<'_> {
+    /// The object of `fields`, a field failing to resolve is reported and answered as null.
+    fn object(
+        &self,
+        typename: &str,
+        fields: &[Field],
+        resolve: impl Fn(&Field) -> Result<Value, String>,
+    ) -> Value {
+        let mut object = Map::new();
+        for field in fields {
+            let value = if field.name == "__typename" {
+                Ok(Value::from(typename))
+            } else {
+                resolve(field)
+            };
+            let value = value.unwrap_or_else(|message| {
+                self.errors.borrow_mut().push(message);
+                Value::Null
+            });
+            object.insert(field.key().to_string(), value);
+        }
+        Value::Object(object)
+    }
+
+    fn query(&self, fields: &[Field]) -> Value {
+        self.object("Query", fields, |field| match field.name.as_str() {
+            "types" => field.scalar(json!(self.types)),
+            "routes" => {
+                let route_type = TransportType::parse(field.string_argument("type")?);
+                let selections = field.selections()?;
+                let Some(routes) = self.route_map.get(route_type.as_str()) else {
+                    return Ok(Value::Array(Vec::new()));
+                };
+                let mut routes = routes.values().collect::<Vec<&RouteGroup>>();
+                routes.sort_unstable_by(|a, b| a.number.cmp(&b.number));
+                Ok(routes
+                    .into_iter()
+                    .map(|route| self.route(route, selections))
+                    .collect())
+            }
+            "route" => {
+                let route = self
+                    .route_map
+                    .get(field.string_argument("type")?)
+                    .and_then(|routes| routes.get(field.string_argument("number").ok()?));
+                let selections = field.selections()?;
+                Ok(route.map_or(Value::Null, |route| self.route(route, selections)))
+            }
+            "stop" => {
+                let stop = self.stop_map.get(field.string_argument("id")?);
+                let selections = field.selections()?;
+                Ok(stop.map_or(Value::Null, |stop| self.stop(stop, selections)))
+            }
+            "stops" => {
+                let Some(Value::Array(ids)) = field.argument("ids") else {
+                    return Err("argument ids of stops must be a list of String".to_string());
+                };
+                let selections = field.selections()?;
+                Ok(ids
+                    .iter()
+                    .map(|id| {
+                        id.as_str()
+                            .and_then(|id| self.stop_map.get(id))
+                            .map_or(Value::Null, |stop| self.stop(stop, selections))
+                    })
+                    .collect())
+            }
+            _ => Err(format!("unknown field {} on Query", field.name)),
+        })
+    }
+
+    fn route(&self, route: &RouteGroup, fields: &[Field]) -> Value {
+        self.object("Route", fields, |field| match field.name.as_str() {
+            "type" => field.scalar(Value::from(route.r#type.as_str())),
+            "number" => field.scalar(Value::from(route.number.as_str())),
+            "directions" => {
+                let selections = field.selections()?;
+                let mut directions = route.directions.keys().collect::<Vec<&String>>();
+                directions.sort_unstable();
+                Ok(directions
+                    .into_iter()
+                    .map(|direction| {
+                        self.direction(direction, &route.directions[direction], selections)
+                    })
+                    .collect())
+            }
+            "direction" => {
+                let direction =
+                    resolve_direction(route.directions.keys(), field.string_argument("name")?);
+                let selections = field.selections()?;
+                Ok(direction.map_or(Value::Null, |direction| {
+                    self.direction(direction, &route.directions[direction], selections)
+                }))
+            }
+            _ => Err(format!("unknown field {} on Route", field.name)),
+        })
+    }
+
+    fn direction(&self, name: &str, stop_ids: &[String], fields: &[Field]) -> Value {
+        self.object("Direction", fields, |field| match field.name.as_str() {
+            "name" => field.scalar(Value::from(name)),
+            "stops" => {
+                let selections = field.selections()?;
+                Ok(stop_ids
+                    .iter()
+                    .map(|stop_id| {
+                        self.stop_map
+                            .get(stop_id)
+                            .map_or(Value::Null, |stop| self.stop(stop, selections))
+                    })
+                    .collect())
+            }
+            _ => Err(format!("unknown field {} on Direction", field.name)),
+        })
+    }
+
+    fn stop(&self, stop: &StopData, fields: &[Field]) -> Value {
+        self.object("Stop", fields, |field| match field.name.as_str() {
+            "id" => field.scalar(Value::from(stop.id.as_str())),
+            "siriId" => field.scalar(Value::from(stop.siri_id.as_str())),
+            "name" => field.scalar(Value::from(stop.name.as_str())),
+            "lat" => field.scalar(json!(stop.lat)),
+            "lon" => field.scalar(json!(stop.lon)),
+            "arrivals" => self.arrivals(stop, field),
+            _ => Err(format!("unknown field {} on Stop", field.name)),
+        })
+    }
+
+    fn arrivals(&self, stop: &StopData, field: &Field) -> Result<Value, String> {
+        let filter = ArrivalsFilter {
+            max_minutes: field.u32_argument("maxMinutes")?,
+            per_route: None,
+        };
+        let limit = field
+            .u32_argument("limit")?
+            .map_or(usize::MAX, |limit| limit as usize);
+        let selections = field.selections()?;
+        if let Some(selection) = selections.iter().find(|selection| {
+            selection.name != "__typename" && !ARRIVAL_FIELDS.contains(&selection.name.as_str())
+        }) {
+            return Err(format!("unknown field {} on Arrival", selection.name));
+        }
+        let Some(arrivals) = &self.arrivals else {
+            self.wanted_arrivals.borrow_mut().insert(stop.id.clone());
+            return Ok(Value::Array(Vec::new()));
+        };
+        let Some(stop_arrivals) = arrivals.get(&stop.id) else {
+            return Err(format!("arrivals of stop {} are unavailable", stop.id));
+        };
+        let routes = filter.filter_routes(&stop_arrivals.arrivals);
+        Ok(flat_arrivals(&routes, Utc::now())
+            .into_iter()
+            .take(limit)
+            .map(|arrival| {
+                let arrival = serde_json::to_value(&arrival).unwrap_or_default();
+                self.object("Arrival", selections, |field| {
+                    field.scalar(arrival.get(&field.name).cloned().unwrap_or(Value::Null))
+                })
+            })
+            .collect())
+    }
+}
+
+fn errors_response(errors: Vec<String>) -> Value {
+    let errors = errors
+        .into_iter()
+        .map(|message| json!({ "message": message }))
+        .collect::<Vec<Value>>();
+    json!({ "errors": errors })
+}
+
+/// The GraphQL response of the request, `{"data", "errors"}`, the field errors not failing it.
+pub async fn execute(env: &Env, request: GraphQlRequest) -> worker::Result<Value> {
+    if request.query.len() > MAX_QUERY_LEN {
+        return Ok(errors_response(vec![format!(
+            "query longer than {} bytes",
+            MAX_QUERY_LEN
+        )]));
+    }
+    let variables = request.variables.unwrap_or_default();
+    let mut parser = Parser {
+        src: request.query.as_bytes(),
+        pos: 0,
+        variables: &variables,
+    };
+    let fields = match parser.document() {
+        Ok(fields) => fields,
+        Err(message) => return Ok(errors_response(vec![message])),
+    };
+    let service = TransportService::get_service();
+    let route_map = service.get_route_map(env).await?;
+    let stop_map = service.get_stop_map(env).await?;
+    let types = service.get_sorted_types(env).await?;
+    let mut executor = Executor {
+        route_map: &route_map,
+        stop_map: &stop_map,
+        types: &types,
+        arrivals: None,
+        wanted_arrivals: RefCell::new(HashSet::new()),
+        errors: RefCell::new(Vec::new()),
+    };
+    let mut data = executor.query(&fields);
+    let wanted = executor.wanted_arrivals.take();
+    if !wanted.is_empty() {
+        let max_stops = Config::get().max_arrivals_stops;
+        if wanted.len() > max_stops {
+            return Ok(errors_response(vec![format!(
+                "arrivals selected for {} stops, at most {} allowed",
+                wanted.len(),
+                max_stops
+            )]));
+        }
+        let arrivals = match lookup_stop_arrivals(env, wanted.into_iter().collect()).await? {
+            ArrivalsLookup::Found { stops, .. } => stops
+                .into_iter()
+                .filter(|stop| stop.status == StopArrivalsStatus::Ok)
+                .filter_map(|stop| Some((stop.id, stop.data?)))
+                .collect(),
+            ArrivalsLookup::Unavailable(_) => HashMap::new(),
+        };
+        executor.arrivals = Some(arrivals);
+        executor.errors.borrow_mut().clear();
+        data = executor.query(&fields);
+    }
+    let errors = executor.errors.take();
+    if errors.is_empty() {
+        return Ok(json!({ "data": data }));
+    }
+    let mut response = errors_response(errors);
+    response["data"] = data;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Arrival;
+    use chrono::{Duration, SecondsFormat};
+
+    fn parse(query: &str, variables: Value) -> Result<Vec<Field>, String> {
+        let Value::Object(variables) = variables else {
+            panic!("variables must be an object");
+        };
+        Parser {
+            src: query.as_bytes(),
+            pos: 0,
+            variables: &variables,
+        }
+        .document()
+    }
+
+    fn parse_error(query: &str) -> String {
+        parse(query, json!({}))
+            .err()
+            .expect("query should be rejected")
+    }
+
+    struct Fixtures {
+        route_map: HashMap<String, HashMap<String, RouteGroup>>,
+        stop_map: HashMap<String, Rc<StopData>>,
+        types: Vec<String>,
+    }
+
+    fn fixtures() -> Fixtures {
+        let stop = |id: &str, name: &str| {
+            let stop = StopData {
+                id: id.to_string(),
+                siri_id: format!("9{}", id),
+                name: Rc::new(name.to_string()),
+                lat: Some(59.44),
+                lon: None,
+                zone: None,
+            };
+            (id.to_string(), Rc::new(stop))
+        };
+        let route = RouteGroup {
+            number: "1".to_string(),
+            r#type: TransportType::Bus,
+            directions: HashMap::from([
+                (
+                    "Kopli".to_string(),
+                    vec!["1001".to_string(), "1002".to_string()],
+                ),
+                (
+                    "Viru".to_string(),
+                    vec!["1002".to_string(), "404".to_string()],
+                ),
+            ]),
+        };
+        Fixtures {
+            route_map: HashMap::from([(
+                "bus".to_string(),
+                HashMap::from([("1".to_string(), route)]),
+            )]),
+            stop_map: HashMap::from([stop("1001", "Kopli"), stop("1002", "Sitsi")]),
+            types: vec!["bus".to_string(), "tram".to_string()],
+        }
+    }
+
+    fn stop_arrivals(id: &str, minutes: &[i64]) -> (String, Rc<StopArrivals>) {
+        let times = minutes
+            .iter()
+            .map(|minutes| Arrival {
+                time: (Utc::now() + Duration::minutes(*minutes))
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+                is_low_entry: true,
+                is_realtime: false,
+                destination: Some("Kopli".to_string()),
+                vehicle_id: None,
+                vehicle_distance_m: None,
+            })
+            .collect();
+        let stop = StopArrivals {
+            id: id.to_string(),
+            name: "Kopli".to_string(),
+            lat: None,
+            lon: None,
+            arrivals: HashMap::from([(
+                "bus".to_string(),
+                HashMap::from([("1".to_string(), times)]),
+            )]),
+        };
+        (id.to_string(), Rc::new(stop))
+    }
+
+    /// Runs `query` the way [`execute`] does, the second pass answered from `arrivals`.
+    fn run(
+        query: &str,
+        variables: Value,
+        arrivals: Vec<(String, Rc<StopArrivals>)>,
+    ) -> (Value, Vec<String>, HashSet<String>) {
+        let fixtures = fixtures();
+        let fields = parse(query, variables).expect("query should parse");
+        let mut executor = Executor {
+            route_map: &fixtures.route_map,
+            stop_map: &fixtures.stop_map,
+            types: &fixtures.types,
+            arrivals: None,
+            wanted_arrivals: RefCell::new(HashSet::new()),
+            errors: RefCell::new(Vec::new()),
+        };
+        let mut data = executor.query(&fields);
+        let wanted = executor.wanted_arrivals.take();
+        if !wanted.is_empty() {
+            executor.arrivals = Some(arrivals.into_iter().collect());
+            executor.errors.borrow_mut().clear();
+            data = executor.query(&fields);
+        }
+        (data, executor.errors.take(), wanted)
+    }
+
+    #[test]
+    fn parses_aliases_arguments_and_nesting() {
+        let fields = parse(
+            "query Board($n: String = \"1\") {
+                # the route and its first direction
+                main: route(type: bus, number: $n) { directions { name } }
+                types
+            }",
+            json!({ "n": "7" }),
+        )
+        .unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].key(), "main");
+        assert_eq!(fields[0].name, "route");
+        assert_eq!(fields[0].argument("type"), Some(&json!("bus")));
+        assert_eq!(fields[0].argument("number"), Some(&json!("7")));
+        assert_eq!(fields[0].selections[0].selections[0].name, "name");
+        assert_eq!(fields[1].key(), "types");
+        assert!(fields[1].selections.is_empty());
+    }
+
+    #[test]
+    fn parses_argument_values() {
+        let fields = parse(
+            r#"{ f(s: "a\"\u00e4\n", i: -12, x: 1.5e2, b: true, n: null, l: [1 "2"], o: {k: false}, v: $missing) }"#,
+            json!({}),
+        )
+        .unwrap();
+        let field = &fields[0];
+        assert_eq!(field.argument("s"), Some(&json!("a\"ä\n")));
+        assert_eq!(field.argument("i"), Some(&json!(-12)));
+        assert_eq!(field.argument("x"), Some(&json!(150.0)));
+        assert_eq!(field.argument("b"), Some(&json!(true)));
+        assert_eq!(field.argument("n"), None);
+        assert_eq!(field.argument("l"), Some(&json!([1, "2"])));
+        assert_eq!(field.argument("o"), Some(&json!({ "k": false })));
+        assert_eq!(field.argument("v"), None);
+    }
+
+    #[test]
+    fn rejects_unsupported_syntax() {
+        assert_eq!(
+            parse_error("mutation { types }"),
+            "only queries are supported"
+        );
+        assert_eq!(
+            parse_error("fragment F on Stop { id }"),
+            "fragments are not supported"
+        );
+        assert_eq!(
+            parse_error("{ stop(id: \"1\") { ...F } }"),
+            "fragments are not supported"
+        );
+        assert_eq!(
+            parse_error("{ types @skip(if: true) }"),
+            "directives are not supported"
+        );
+        assert_eq!(
+            parse_error("{ types } { types }"),
+            "only one operation per request is supported"
+        );
+        assert_eq!(
+            parse_error("{ f(s: \"\"\"x\"\"\") }"),
+            "block strings are not supported"
+        );
+        let nested = format!(
+            "{}x{}",
+            "{ a ".repeat(MAX_DEPTH + 1),
+            " }".repeat(MAX_DEPTH + 1)
+        );
+        assert_eq!(parse_error(&nested), "selections nest deeper than 8");
+    }
+
+    #[test]
+    fn malformed_queries_are_errors() {
+        for query in [
+            "",
+            "{",
+            "{}",
+            "}",
+            "{ 1a }",
+            "{ f(a: \"x) }",
+            "{ f(a: \"\\q\") }",
+            "{ f(a: \"\\u12\") }",
+            "{ f(a: [1, 2) }",
+            "{ f(a: -) }",
+            "{ f(a: 1e) }",
+            "query Q($a: [String!] { x }",
+            "\u{0}",
+        ] {
+            assert!(
+                parse(query, json!({})).is_err(),
+                "{:?} should be rejected",
+                query
+            );
+        }
+        let query = r#"query Q($id: String) { a: stop(id: "1001") { name arrivals(limit: 2) { time } } stops(ids: ["1", "2"]) { id } }"#;
+        assert!(parse(query, json!({})).is_ok());
+        for end in 0..query.len() {
+            assert!(
+                parse(&query[..end], json!({})).is_err(),
+                "{:?}",
+                &query[..end]
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_nested_selections_and_aliases() {
+        let (data, errors, wanted) = run(
+            "{ types kinds: routes(type: \"bus\") { number __typename }
+               route(type: \"bus\", number: \"1\") {
+                 type
+                 k: direction(name: \"kopli\") { name stops { id name lat lon } }
+               }
+               stop(id: \"404\") { id } }",
+            json!({}),
+            Vec::new(),
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(wanted.is_empty());
+        assert_eq!(
+            data,
+            json!({
+                "types": ["bus", "tram"],
+                "kinds": [{ "number": "1", "__typename": "Route" }],
+                "route": {
+                    "type": "bus",
+                    "k": {
+                        "name": "Kopli",
+                        "stops": [
+                            { "id": "1001", "name": "Kopli", "lat": 59.44, "lon": null },
+                            { "id": "1002", "name": "Sitsi", "lat": 59.44, "lon": null },
+                        ],
+                    },
+                },
+                "stop": null,
+            })
+        );
+    }
+
+    #[test]
+    fn resolves_arrivals_on_the_second_pass() {
+        let (data, errors, wanted) = run(
+            "query($ids: [String], $limit: Int) {
+               stops(ids: $ids) { id arrivals(limit: $limit) { number isLowEntry } }
+             }",
+            json!({ "ids": ["1001", "1002", "404"], "limit": 1 }),
+            vec![stop_arrivals("1001", &[10, 5])],
+        );
+        assert_eq!(
+            wanted,
+            HashSet::from(["1001".to_string(), "1002".to_string()])
+        );
+        assert_eq!(errors, ["arrivals of stop 1002 are unavailable"]);
+        assert_eq!(
+            data,
+            json!({
+                "stops": [
+                    { "id": "1001", "arrivals": [{ "number": "1", "isLowEntry": true }] },
+                    { "id": "1002", "arrivals": null },
+                    null,
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn field_errors_null_only_their_field() {
+        let (data, errors, _) = run(
+            "{ types { name } route(type: \"bus\") { number } nope
+               stop(id: \"1001\") { name { x } arrivals(limit: -1) { time } }
+               routes(type: \"bus\") { directions } }",
+            json!({}),
+            Vec::new(),
+        );
+        assert_eq!(
+            errors,
+            [
+                "types is a scalar and takes no selection",
+                "unknown field nope on Query",
+                "name is a scalar and takes no selection",
+                "argument limit of arrivals must be a positive Int",
+                "directions needs a selection of its fields",
+            ]
+        );
+        assert_eq!(
+            data,
+            json!({
+                "types": null,
+                "route": null,
+                "nope": null,
+                "stop": { "name": null, "arrivals": null },
+                "routes": [{ "directions": null }],
+            })
+        );
+    }
+}
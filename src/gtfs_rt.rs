@@ -0,0 +1,457 @@
+//! GTFS-Realtime feed encoding, written by hand as the feed only needs a handful of fields of
+//! the `gtfs-realtime.proto` messages.
+
+use chrono::{DateTime, Utc};
+
+use crate::models::{StopArrivals, VehiclePosition};
+
+/// Protobuf wire encoding of one message.
+#[derive(Default)]
+struct ProtoWriter(Vec<u8>);
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field << 3 | u32::from(wire_type)));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    /// `int64` fields, negative values take ten bytes as in the reference encoders.
+    fn int(&mut self, field: u32, value: i64) {
+        self.uint(field, value as u64);
+    }
+
+    fn float(&mut self, field: u32, value: f32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut ProtoWriter)) {
+        let mut message = ProtoWriter::default();
+        build(&mut message);
+        self.bytes(field, &message.0);
+    }
+}
+
+/// `FeedMessage` holding only its `FeedHeader`: gtfs_realtime_version, incrementality
+/// FULL_DATASET and timestamp.
+fn feed_with_header(timestamp: u64) -> ProtoWriter {
+    let mut feed = ProtoWriter::default();
+    feed.message(1, |header| {
+        header.string(1, "2.0");
+        header.uint(2, 0);
+        header.uint(3, timestamp);
+    });
+    feed
+}
+
+/// `FeedMessage` with a `TripUpdate` per arrival of the stops, given with their GTFS stop id.
+///
+/// SIRI carries no trip ids, so each trip is described only by its `route_id` (`<type>_<number>`)
+/// and the single stop it's predicted at.
+pub fn trip_updates_feed(stops: &[(&str, &StopArrivals)], now: DateTime<Utc>) -> Vec<u8> {
+    let timestamp = now.timestamp().max(0) as u64;
+    let mut feed = feed_with_header(timestamp);
+    for (stop_id, stop) in stops {
+        for (route_type, routes) in &stop.arrivals {
+            for (number, arrivals) in routes {
+                let route_id = format!("{}_{}", route_type, number);
+                for arrival in arrivals {
+                    let Some(expected_at) = arrival.expected_at() else {
+                        continue;
+                    };
+                    // FeedEntity: id, trip_update
+                    feed.message(2, |entity| {
+                        entity.string(1, &format!("{}:{}:{}", stop_id, route_id, arrival.time()));
+                        entity.message(3, |trip_update| {
+                            // TripDescriptor: route_id
+                            trip_update.message(1, |trip| trip.string(5, &route_id));
+                            // StopTimeUpdate: stop_id, arrival StopTimeEvent time
+                            trip_update.message(2, |update| {
+                                update.string(4, stop_id);
+                                update.message(2, |event| event.int(2, expected_at.timestamp()));
+                            });
+                            // VehicleDescriptor: id
+                            if let Some(vehicle_id) = &arrival.vehicle_id {
+                                trip_update.message(3, |vehicle| vehicle.string(1, vehicle_id));
+                            }
+                            trip_update.uint(4, timestamp);
+                        });
+                    });
+                }
+            }
+        }
+    }
+    feed.0
+}
+
+/// `FeedMessage` with a `VehiclePosition` per vehicle, its trip described by the `route_id` as in
+/// [`trip_updates_feed`].
+pub fn vehicle_positions_feed(vehicles: &[VehiclePosition], now: DateTime<Utc>) -> Vec<u8> {
+    let timestamp = now.timestamp().max(0) as u64;
+    let mut feed = feed_with_header(timestamp);
+    for vehicle in vehicles {
+        // FeedEntity: id, vehicle
+        feed.message(2, |entity| {
+            entity.string(1, &vehicle.id);
+            entity.message(4, |position| {
+                // TripDescriptor: route_id
+                position.message(1, |trip| {
+                    trip.string(5, &format!("{}_{}", vehicle.r#type, vehicle.number))
+                });
+                // Position: latitude, longitude, bearing, speed in m/s
+                position.message(2, |coordinates| {
+                    coordinates.float(1, vehicle.lat as f32);
+                    coordinates.float(2, vehicle.lon as f32);
+                    if let Some(bearing) = vehicle.bearing {
+                        coordinates.float(3, f32::from(bearing));
+                    }
+                    if let Some(speed) = vehicle.speed {
+                        coordinates.float(5, speed / 3.6);
+                    }
+                });
+                position.uint(5, timestamp);
+                // VehicleDescriptor: id, label
+                position.message(8, |descriptor| {
+                    descriptor.string(1, &vehicle.id);
+                    if let Some(direction) = &vehicle.direction {
+                        descriptor.string(2, direction);
+                    }
+                });
+            });
+        });
+    }
+    feed.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Arrival, TransportType};
+    use std::collections::HashMap;
+
+    // field numbers of the gtfs-realtime.proto messages the feeds are checked against
+    const FEED_HEADER: u32 = 1;
+    const FEED_ENTITY: u32 = 2;
+    const HEADER_VERSION: u32 = 1;
+    const HEADER_INCREMENTALITY: u32 = 2;
+    const HEADER_TIMESTAMP: u32 = 3;
+    const ENTITY_ID: u32 = 1;
+    const ENTITY_TRIP_UPDATE: u32 = 3;
+    const ENTITY_VEHICLE: u32 = 4;
+    const TRIP_UPDATE_TRIP: u32 = 1;
+    const TRIP_UPDATE_STOP_TIME_UPDATE: u32 = 2;
+    const TRIP_UPDATE_VEHICLE: u32 = 3;
+    const TRIP_UPDATE_TIMESTAMP: u32 = 4;
+    const TRIP_ROUTE_ID: u32 = 5;
+    const STOP_TIME_UPDATE_ARRIVAL: u32 = 2;
+    const STOP_TIME_UPDATE_STOP_ID: u32 = 4;
+    const STOP_TIME_EVENT_TIME: u32 = 2;
+    const VEHICLE_DESCRIPTOR_ID: u32 = 1;
+    const VEHICLE_DESCRIPTOR_LABEL: u32 = 2;
+    const POSITION_TRIP: u32 = 1;
+    const POSITION_POSITION: u32 = 2;
+    const POSITION_TIMESTAMP: u32 = 5;
+    const POSITION_VEHICLE: u32 = 8;
+    const LATITUDE: u32 = 1;
+    const LONGITUDE: u32 = 2;
+    const BEARING: u32 = 3;
+    const SPEED: u32 = 5;
+
+    enum Wire {
+        Varint(u64),
+        Fixed64,
+        Bytes(Vec<u8>),
+        Fixed32([u8; 4]),
+    }
+
+    /// A decoded message: its fields in wire order, panicking on anything a protobuf parser
+    /// would reject.
+    struct Message(Vec<(u32, Wire)>);
+
+    fn varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *buf.get(*pos).expect("truncated varint");
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+        panic!("varint longer than ten bytes")
+    }
+
+    fn take<const N: usize>(buf: &[u8], pos: &mut usize) -> [u8; N] {
+        let bytes = buf[*pos..].get(..N).expect("truncated field");
+        *pos += N;
+        bytes.try_into().unwrap()
+    }
+
+    impl Message {
+        fn decode(buf: &[u8]) -> Self {
+            let mut fields = Vec::new();
+            let mut pos = 0;
+            while pos < buf.len() {
+                let key = varint(buf, &mut pos);
+                let field = u32::try_from(key >> 3).unwrap();
+                assert_ne!(field, 0, "field number 0");
+                let value = match key & 7 {
+                    0 => Wire::Varint(varint(buf, &mut pos)),
+                    1 => {
+                        take::<8>(buf, &mut pos);
+                        Wire::Fixed64
+                    }
+                    2 => {
+                        let len = varint(buf, &mut pos) as usize;
+                        let bytes = buf[pos..].get(..len).expect("length past the end");
+                        pos += len;
+                        Wire::Bytes(bytes.to_vec())
+                    }
+                    5 => Wire::Fixed32(take(buf, &mut pos)),
+                    wire_type => panic!("wire type {} of field {}", wire_type, field),
+                };
+                fields.push((field, value));
+            }
+            Message(fields)
+        }
+
+        /// Fails unless all fields are among the message's `known` ones.
+        fn only(self, known: &[u32]) -> Self {
+            for (field, _) in &self.0 {
+                assert!(known.contains(field), "unexpected field {}", field);
+            }
+            self
+        }
+
+        fn values(&self, field: u32) -> impl Iterator<Item = &Wire> {
+            self.0
+                .iter()
+                .filter(move |(number, _)| *number == field)
+                .map(|(_, value)| value)
+        }
+
+        /// The last value of a singular field, as protobuf parsers keep it.
+        fn value(&self, field: u32) -> Option<&Wire> {
+            self.values(field).last()
+        }
+
+        fn messages(&self, field: u32) -> Vec<Message> {
+            self.values(field)
+                .map(|value| match value {
+                    Wire::Bytes(bytes) => Message::decode(bytes),
+                    _ => panic!("field {} isn't a message", field),
+                })
+                .collect()
+        }
+
+        fn message(&self, field: u32) -> Message {
+            let mut messages = self.messages(field);
+            messages
+                .pop()
+                .unwrap_or_else(|| panic!("missing field {}", field))
+        }
+
+        fn string(&self, field: u32) -> Option<String> {
+            self.value(field).map(|value| match value {
+                Wire::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                _ => panic!("field {} isn't a string", field),
+            })
+        }
+
+        fn uint(&self, field: u32) -> Option<u64> {
+            self.value(field).map(|value| match value {
+                Wire::Varint(value) => *value,
+                _ => panic!("field {} isn't a varint", field),
+            })
+        }
+
+        fn float(&self, field: u32) -> Option<f32> {
+            self.value(field).map(|value| match value {
+                Wire::Fixed32(bytes) => f32::from_le_bytes(*bytes),
+                _ => panic!("field {} isn't a float", field),
+            })
+        }
+    }
+
+    /// `FeedMessage`, checking its `FeedHeader` on the way.
+    fn decode_feed(buf: &[u8], timestamp: u64) -> Vec<Message> {
+        let feed = Message::decode(buf).only(&[FEED_HEADER, FEED_ENTITY]);
+        let header = feed.message(FEED_HEADER).only(&[
+            HEADER_VERSION,
+            HEADER_INCREMENTALITY,
+            HEADER_TIMESTAMP,
+        ]);
+        assert_eq!(header.string(HEADER_VERSION).as_deref(), Some("2.0"));
+        assert_eq!(header.uint(HEADER_INCREMENTALITY), Some(0));
+        assert_eq!(header.uint(HEADER_TIMESTAMP), Some(timestamp));
+        feed.messages(FEED_ENTITY)
+    }
+
+    fn arrival(time: &str, vehicle_id: Option<&str>) -> Arrival {
+        Arrival {
+            time: time.to_string(),
+            is_low_entry: false,
+            is_realtime: true,
+            destination: None,
+            vehicle_id: vehicle_id.map(str::to_string),
+            vehicle_distance_m: None,
+        }
+    }
+
+    #[test]
+    fn header_only_feed_has_the_reference_bytes() {
+        let header = [0x0a, 0x03, b'2', b'.', b'0', 0x10, 0x00, 0x18, 0x96, 0x01];
+        assert_eq!(feed_with_header(150).0, [&[0x0a, 10][..], &header].concat());
+    }
+
+    #[test]
+    fn negative_int64_takes_ten_bytes() {
+        let mut writer = ProtoWriter::default();
+        writer.int(STOP_TIME_EVENT_TIME, -1);
+        assert_eq!(writer.0, [&[0x10][..], &[0xff; 9], &[0x01]].concat());
+        let event = Message::decode(&writer.0);
+        assert_eq!(
+            event.uint(STOP_TIME_EVENT_TIME).map(|time| time as i64),
+            Some(-1)
+        );
+    }
+
+    #[test]
+    fn trip_updates_decode_as_a_feed_message() {
+        let now = DateTime::from_timestamp(1_760_000_000, 0).unwrap();
+        let stop = StopArrivals {
+            id: "101".to_string(),
+            name: "Kopli".to_string(),
+            lat: None,
+            lon: None,
+            arrivals: HashMap::from([(
+                "tram".to_string(),
+                HashMap::from([(
+                    "1".to_string(),
+                    vec![
+                        arrival("2025-10-09T08:55:00Z", Some("3101")),
+                        arrival("not a time", None),
+                        arrival("2025-10-09T09:05:00Z", None),
+                    ],
+                )]),
+            )]),
+        };
+        let entities = decode_feed(&trip_updates_feed(&[("1001", &stop)], now), 1_760_000_000);
+        let Ok([entity, last]) = <[Message; 2]>::try_from(entities) else {
+            panic!("expected two entities");
+        };
+
+        let entity = entity.only(&[ENTITY_ID, ENTITY_TRIP_UPDATE]);
+        let id = entity.string(ENTITY_ID).unwrap();
+        assert_eq!(id, "1001:tram_1:2025-10-09T08:55:00Z");
+        let trip_update = entity.message(ENTITY_TRIP_UPDATE).only(&[
+            TRIP_UPDATE_TRIP,
+            TRIP_UPDATE_STOP_TIME_UPDATE,
+            TRIP_UPDATE_VEHICLE,
+            TRIP_UPDATE_TIMESTAMP,
+        ]);
+        let trip = trip_update.message(TRIP_UPDATE_TRIP).only(&[TRIP_ROUTE_ID]);
+        assert_eq!(trip.string(TRIP_ROUTE_ID).as_deref(), Some("tram_1"));
+        let update = trip_update
+            .message(TRIP_UPDATE_STOP_TIME_UPDATE)
+            .only(&[STOP_TIME_UPDATE_ARRIVAL, STOP_TIME_UPDATE_STOP_ID]);
+        assert_eq!(
+            update.string(STOP_TIME_UPDATE_STOP_ID).as_deref(),
+            Some("1001")
+        );
+        let event = update
+            .message(STOP_TIME_UPDATE_ARRIVAL)
+            .only(&[STOP_TIME_EVENT_TIME]);
+        assert_eq!(event.uint(STOP_TIME_EVENT_TIME), Some(1_760_000_100));
+        let vehicle = trip_update.message(TRIP_UPDATE_VEHICLE);
+        assert_eq!(
+            vehicle.string(VEHICLE_DESCRIPTOR_ID).as_deref(),
+            Some("3101")
+        );
+        assert_eq!(trip_update.uint(TRIP_UPDATE_TIMESTAMP), Some(1_760_000_000));
+
+        let trip_update = last.message(ENTITY_TRIP_UPDATE);
+        assert!(trip_update.value(TRIP_UPDATE_VEHICLE).is_none());
+    }
+
+    #[test]
+    fn vehicle_positions_decode_as_a_feed_message() {
+        let now = DateTime::from_timestamp(1_760_000_000, 0).unwrap();
+        let vehicle = |id: &str, bearing, speed, direction: Option<&str>| VehiclePosition {
+            id: id.to_string(),
+            r#type: TransportType::Bus,
+            number: "40".to_string(),
+            direction: direction.map(str::to_string),
+            lat: 59.43859,
+            lon: 24.69879,
+            bearing,
+            speed,
+        };
+        let vehicles = [
+            vehicle("1359", Some(255), Some(36.0), Some("Rocca al Mare")),
+            vehicle("1360", None, None, None),
+        ];
+        let entities = decode_feed(&vehicle_positions_feed(&vehicles, now), 1_760_000_000);
+        let Ok([entity, last]) = <[Message; 2]>::try_from(entities) else {
+            panic!("expected two entities");
+        };
+
+        let entity = entity.only(&[ENTITY_ID, ENTITY_VEHICLE]);
+        assert_eq!(entity.string(ENTITY_ID).as_deref(), Some("1359"));
+        let position = entity.message(ENTITY_VEHICLE).only(&[
+            POSITION_TRIP,
+            POSITION_POSITION,
+            POSITION_TIMESTAMP,
+            POSITION_VEHICLE,
+        ]);
+        let trip = position.message(POSITION_TRIP);
+        assert_eq!(trip.string(TRIP_ROUTE_ID).as_deref(), Some("bus_40"));
+        let coordinates = position
+            .message(POSITION_POSITION)
+            .only(&[LATITUDE, LONGITUDE, BEARING, SPEED]);
+        assert_eq!(coordinates.float(LATITUDE), Some(59.43859));
+        assert_eq!(coordinates.float(LONGITUDE), Some(24.69879));
+        assert_eq!(coordinates.float(BEARING), Some(255.0));
+        assert_eq!(coordinates.float(SPEED), Some(10.0));
+        assert_eq!(position.uint(POSITION_TIMESTAMP), Some(1_760_000_000));
+        let descriptor = position
+            .message(POSITION_VEHICLE)
+            .only(&[VEHICLE_DESCRIPTOR_ID, VEHICLE_DESCRIPTOR_LABEL]);
+        assert_eq!(
+            descriptor.string(VEHICLE_DESCRIPTOR_ID).as_deref(),
+            Some("1359")
+        );
+        assert_eq!(
+            descriptor.string(VEHICLE_DESCRIPTOR_LABEL).as_deref(),
+            Some("Rocca al Mare")
+        );
+
+        let position = last.message(ENTITY_VEHICLE);
+        let coordinates = position.message(POSITION_POSITION);
+        assert!(coordinates.float(BEARING).is_none() && coordinates.float(SPEED).is_none());
+        let descriptor = position.message(POSITION_VEHICLE);
+        assert!(descriptor.string(VEHICLE_DESCRIPTOR_LABEL).is_none());
+    }
+}
@@ -0,0 +1,251 @@
+//! Operator endpoints: cache and parse statistics, metrics, and the token-guarded cache purge,
+//! replay and webhook registration.
+
+use crate::caches::*;
+use crate::diagnostics::{ParseDiagnostics, ParseDiagnosticsReport};
+use crate::extract::{CommaSeparated, IdParam, LimitQuery, Path, Query, Validate};
+use crate::metrics::Metrics;
+use crate::models::*;
+use crate::replay::ReplayReport;
+use crate::services::*;
+use crate::webhooks::{WebhookCondition, WebhookCreated};
+use crate::{coded_error_response, error_response, replay, router, webhooks};
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// Get cache statistics
+///
+/// Returns per-cache lookup counters, hit ratios, entry counts and ages of this isolate
+#[utoipa::path(
+    get,
+    path = "/api/cache/stats",
+    responses(
+        (status = 200, description = "Stats keyed by cache name", body = HashMap<String, CacheStats>)
+    ),
+    tag = "Health"
+)]
+pub(crate) fn get_cache_stats(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    Response::from_json(&Caches::get_cache().stats())
+}
+
+/// Get metrics
+///
+/// Request, upstream and cache metrics of this isolate in the Prometheus text exposition format
+#[utoipa::path(
+    get,
+    path = "/api/metrics",
+    responses(
+        (status = 200, description = "Counters and histograms since the isolate started", body = String, content_type = "text/plain; version=0.0.4")
+    ),
+    tag = "Health"
+)]
+pub(crate) fn get_metrics(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let metrics = Metrics::get().render(&Caches::get_cache().stats());
+    let mut response = Response::ok(metrics)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "text/plain; version=0.0.4; charset=utf-8")?;
+    Ok(response)
+}
+
+/// Get parser diagnostics
+///
+/// Returns the malformed routes.txt, stops.txt and arrivals lines this isolate skipped, with a
+/// few samples of each
+#[utoipa::path(
+    get,
+    path = "/api/diagnostics/parse",
+    responses(
+        (status = 200, description = "Malformed lines per upstream file", body = ParseDiagnosticsReport)
+    ),
+    tag = "Health"
+)]
+pub(crate) fn get_parse_diagnostics(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    Response::from_json(&ParseDiagnostics::get().report())
+}
+
+/// Secret the `Authorization: Bearer` token of the admin endpoints is checked against.
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+/// `None` when the request carries the admin token, otherwise the response to answer with:
+/// 404 while no token is configured, 401 for a missing or wrong one.
+fn check_admin_token(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let Ok(secret) = env.secret(ADMIN_TOKEN_SECRET) else {
+        return error_response("Not Found", 404).map(Some);
+    };
+    let secret = secret.to_string();
+    let token = req.headers().get("Authorization")?;
+    let token = token
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compare every byte so the response time doesn't leak the matching prefix length.
+    let matches = token.len() == secret.len()
+        && token
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if secret.is_empty() || !matches {
+        return error_response("Unauthorized", 401).map(Some);
+    }
+    Ok(None)
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub(crate) struct CachePurgeResult {
+    #[schema(example = json!(["routes_raw", "types"]))]
+    purged: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    caches: Option<CommaSeparated>,
+}
+
+impl Validate for PurgeQuery {}
+
+/// Purge caches
+///
+/// Clears the selected caches, each with the ones derived from it, authenticated with `Authorization: Bearer <ADMIN_TOKEN>`.
+/// Caches are per isolate, so only the isolate handling the request is purged and the others keep theirs until they expire. `stop_arrival` also clears the arrivals shared by the `ArrivalsCacheObject` Durable Object.
+#[utoipa::path(
+    post,
+    path = "/api/admin/cache/purge",
+    params(
+        ("caches" = Option<String>, Query, description = "Comma-separated caches to clear: routes_raw, stops_raw, stop_map, types, stop_arrival, vehicles, raw_siri (default all)", example = "routes_raw,types"),
+    ),
+    responses(
+        (status = 200, description = "Caches cleared", body = CachePurgeResult),
+        (status = 400, description = "Unknown cache name", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or wrong admin token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No admin token configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Admin"
+)]
+pub(crate) async fn purge_caches(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if let Some(response) = check_admin_token(&req, &ctx.env)? {
+        return Ok(response);
+    }
+    let Query(PurgeQuery { caches }) = Query::from_request(&req)?;
+    let names = match caches {
+        Some(names) => names
+            .0
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<String>>(),
+        None => PURGEABLE_CACHES
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+    };
+    if let Some(unknown) = names
+        .iter()
+        .find(|name| !PURGEABLE_CACHES.contains(&name.as_str()))
+    {
+        return error_response(format!("unknown cache {}", unknown), 400);
+    }
+    let cache = Caches::get_cache();
+    for name in &names {
+        cache.purge(name);
+    }
+    if names.iter().any(|name| name == "stop_arrival") {
+        TransportService::purge_arrivals_object(&ctx.env).await?;
+    }
+    Response::from_json(&CachePurgeResult { purged: names })
+}
+
+/// Replay recorded requests
+///
+/// Re-runs sampled production requests against this build and reports the responses that differ
+#[utoipa::path(
+    post,
+    path = "/api/admin/replay",
+    params(
+        ("limit" = Option<usize>, Query, description = "Number of recordings to replay (1-100, default 20)", example = 20),
+    ),
+    responses(
+        (status = 200, description = "Replay results", body = ReplayReport),
+        (status = 400, description = "Invalid limit parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Replay is not enabled on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Admin"
+)]
+pub(crate) async fn replay_recordings(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if !replay::replay_enabled(&ctx.env) {
+        return error_response("Not Found", 404);
+    }
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(20);
+    let report = replay::replay(&ctx.env, limit, router).await?;
+    Response::from_json(&report)
+}
+
+/// Register a webhook
+///
+/// Calls the URL whenever an arrival of the route gets within the minutes of the stop, checked every minute. Authenticated with `Authorization: Bearer <ADMIN_TOKEN>`
+#[utoipa::path(
+    post,
+    path = "/api/admin/webhooks",
+    request_body = WebhookCondition,
+    responses(
+        (status = 201, description = "Webhook registered, calls are signed with the returned secret", body = WebhookCreated),
+        (status = 400, description = "Invalid webhook", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or wrong admin token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown stop, or no admin token or webhooks store configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Admin"
+)]
+pub(crate) async fn create_webhook(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if let Some(response) = check_admin_token(&req, &ctx.env)? {
+        return Ok(response);
+    }
+    let Some(kv) = webhooks::webhooks_store(&ctx.env) else {
+        return error_response("Not Found", 404);
+    };
+    let Ok(condition) = req.json::<WebhookCondition>().await else {
+        return error_response("invalid webhook body", 400);
+    };
+    if !(1..=60).contains(&condition.within_minutes) {
+        return error_response("invalid withinMinutes provided (1-60)", 400);
+    }
+    if !condition.url.starts_with("https://") || Url::parse(&condition.url).is_err() {
+        return error_response("invalid webhook url, must be https", 400);
+    }
+    let stop_map = TransportService::get_service()
+        .get_stop_map(&ctx.env)
+        .await?;
+    if !stop_map.contains_key(&condition.stop_id) {
+        return coded_error_response(ErrorCode::UnknownStop, "stop not found", 404);
+    }
+    let created = webhooks::create_webhook(&kv, condition).await?;
+    Ok(Response::from_json(&created)?.with_status(201))
+}
+
+/// Remove a webhook
+///
+/// Stops calling a registered webhook, authenticated with `Authorization: Bearer <ADMIN_TOKEN>`
+#[utoipa::path(
+    delete,
+    path = "/api/admin/webhooks/{id}",
+    params(
+        ("id" = String, Path, description = "Webhook ID returned when it was registered"),
+    ),
+    responses(
+        (status = 204, description = "Webhook removed"),
+        (status = 401, description = "Missing or wrong admin token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown webhook, or no admin token or webhooks store configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Admin"
+)]
+pub(crate) async fn delete_webhook(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if let Some(response) = check_admin_token(&req, &ctx.env)? {
+        return Ok(response);
+    }
+    let Some(kv) = webhooks::webhooks_store(&ctx.env) else {
+        return error_response("Not Found", 404);
+    };
+    if !webhooks::delete_webhook(&kv, Path::<IdParam>::from_context(&ctx)?.0.id).await? {
+        return error_response("webhook not found", 404);
+    }
+    Ok(Response::empty()?.with_status(204))
+}
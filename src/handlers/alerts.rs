@@ -0,0 +1,92 @@
+//! Web Push arrival alerts, subscribed to with the deployment's VAPID key.
+
+use crate::extract::{IdParam, Path};
+use crate::models::*;
+use crate::push::{ArrivalAlertCreated, ArrivalAlertRequest, Vapid, VapidPublicKey};
+use crate::services::*;
+use crate::{coded_error_response, error_response, push};
+use worker::*;
+
+/// Get the Web Push public key
+///
+/// Returns the VAPID public key to pass as `applicationServerKey` when subscribing to pushes
+#[utoipa::path(
+    get,
+    path = "/api/push/vapid-public-key",
+    responses(
+        (status = 200, description = "VAPID public key", body = VapidPublicKey),
+        (status = 404, description = "Push notifications are not configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Notifications"
+)]
+pub(crate) fn get_vapid_public_key(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    match Vapid::from_env(&ctx.env) {
+        Some(vapid) => Response::from_json(&VapidPublicKey {
+            public_key: vapid.public_key,
+        }),
+        None => error_response("Not Found", 404),
+    }
+}
+
+/// Create an arrival alert
+///
+/// Sends a Web Push once the route is at most the threshold away from the stop, checked every minute. The push carries no payload, the service worker fetches the arrivals
+#[utoipa::path(
+    post,
+    path = "/api/push/alerts",
+    request_body = ArrivalAlertRequest,
+    responses(
+        (status = 201, description = "Alert created", body = ArrivalAlertCreated),
+        (status = 400, description = "Invalid alert", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown stop, or push notifications are not configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Notifications"
+)]
+pub(crate) async fn create_arrival_alert(
+    mut req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    let (Some(kv), Some(_)) = (push::alerts_store(&ctx.env), Vapid::from_env(&ctx.env)) else {
+        return error_response("Not Found", 404);
+    };
+    let Ok(alert) = req.json::<ArrivalAlertRequest>().await else {
+        return error_response("invalid alert body", 400);
+    };
+    if !(1..=60).contains(&alert.threshold_minutes) {
+        return error_response("invalid thresholdMinutes provided (1-60)", 400);
+    }
+    if !alert.subscription.endpoint.starts_with("https://") {
+        return error_response("invalid subscription endpoint", 400);
+    }
+    let stop_map = TransportService::get_service()
+        .get_stop_map(&ctx.env)
+        .await?;
+    if !stop_map.contains_key(&alert.stop_id) {
+        return coded_error_response(ErrorCode::UnknownStop, "stop not found", 404);
+    }
+    let id = push::create_alert(&kv, alert).await?;
+    Ok(Response::from_json(&ArrivalAlertCreated { id })?.with_status(201))
+}
+
+/// Cancel an arrival alert
+///
+/// Removes an alert that hasn't fired yet
+#[utoipa::path(
+    delete,
+    path = "/api/push/alerts/{id}",
+    params(
+        ("id" = String, Path, description = "Alert ID returned when it was created"),
+    ),
+    responses(
+        (status = 204, description = "Alert removed, or it had already fired"),
+        (status = 404, description = "Push notifications are not configured on this deployment", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Notifications"
+)]
+pub(crate) async fn delete_arrival_alert(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Some(kv) = push::alerts_store(&ctx.env) else {
+        return error_response("Not Found", 404);
+    };
+    push::delete_alert(&kv, Path::<IdParam>::from_context(&ctx)?.0.id).await?;
+    Ok(Response::empty()?.with_status(204))
+}
@@ -0,0 +1,793 @@
+//! Arrivals of stops, as JSON, CSV, GTFS-RT and the board page, polled, streamed or over a
+//! WebSocket, and the lookup through the arrivals cache the other handlers share.
+
+use crate::bindings::Bindings;
+use crate::caches::*;
+use crate::clock;
+use crate::config::Config;
+use crate::extract::{
+    ArrivalsFilterQuery, Bounded, CommaSeparated, IdParam, Includes, Incoming, Path, PathParams,
+    Query, RequestError, StopsQuery, Validate, check_stop_count,
+};
+use crate::logging::log_warn;
+use crate::models::*;
+use crate::profiles::reply_with_age;
+use crate::reply::Reply;
+use crate::services::*;
+use crate::str_utils::fnv1a_64;
+use crate::{
+    ARRIVALS_ENCODINGS, Encoding, board, coded_error_reply, coded_error_response, csv, error_reply,
+    error_response, etag_matches, get_arrivals_format, gtfs_rt, jsonapi, not_modified,
+    problem_reply,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+use worker::*;
+
+/// The stops with their vehicle distances joined from gps.txt, unchanged when it can't be
+/// loaded as the arrivals are still worth answering without them.
+async fn with_vehicle_distances(
+    service: &TransportService,
+    stops: Vec<StopArrivalsResult>,
+) -> Vec<StopArrivalsResult> {
+    let vehicles = match service.get_vehicle_positions().await {
+        Ok(vehicles) => vehicles,
+        Err(err) => {
+            log_warn!("vehicle distances left out: {:?}", err);
+            return stops;
+        }
+    };
+    let vehicles = vehicles
+        .iter()
+        .map(|vehicle| (vehicle.id.as_str(), vehicle))
+        .collect::<HashMap<&str, &VehiclePosition>>();
+    stops
+        .into_iter()
+        .map(|result| StopArrivalsResult {
+            data: result
+                .data
+                .map(|stop| Rc::new(stop.with_vehicle_distances(&vehicles))),
+            ..result
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ArrivalsQuery {
+    stops: CommaSeparated,
+    #[serde(default)]
+    include: Includes,
+    #[serde(default)]
+    strict: bool,
+    wait: Option<Bounded<1, MAX_ARRIVALS_WAIT_SECS>>,
+}
+
+impl Validate for ArrivalsQuery {
+    fn validate(&self) -> core::result::Result<(), RequestError> {
+        check_stop_count(&self.stops.0)
+    }
+}
+
+/// Get arrival times for specific stops
+///
+/// Returns real-time arrival information for the requested stops
+#[utoipa::path(
+    get,
+    path = "/api/arrivals",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("fields[arrivals]" = Option<String>, Query, description = "Comma-separated members kept in each arrival object", example = "time,inSeconds"),
+        ("stops" = String, Query, description = "Comma-separated list of stop IDs or SIRI IDs (max 25 unless MAX_ARRIVALS_STOPS is set)", example = "1001,1002,1003"),
+        ("strict" = Option<bool>, Query, description = "`true` to answer 422 when any stop ID is unknown instead of an `unknown_stop` entry", example = true),
+        ("wait" = Option<u32>, Query, description = "With `If-None-Match`, seconds to hold the request until the arrivals change before answering 304 (1-25)", example = 20),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the arrivals are unchanged"),
+        ("max_minutes" = Option<u32>, Query, description = "Only arrivals within this many minutes (1-1440)", example = 30),
+        ("per_route" = Option<usize>, Query, description = "Only the next N arrivals of each route (1-100)", example = 2),
+        ("format" = Option<String>, Query, description = "`flat` for one time-sorted list of FlatArrival per stop instead of the type/number map, `csv` (or `Accept: text/csv`) for a CSV row per arrival, `msgpack` (or `Accept: application/msgpack`) for MessagePack, `jsonapi` (or `Accept: application/vnd.api+json`) for a JSON:API document of the stops with their arrivals included", example = "flat"),
+        ("include" = Option<String>, Query, description = "`vehicle_distance` to add the straight-line distance from each arrival's vehicle to the stop, from the live vehicle positions", example = "vehicle_distance"),
+    ),
+    responses(
+        (status = 200, description = "Arrival times or the reason they are missing for each requested stop", body = PostArrivalsResponse,
+         headers(
+             ("X-Arrivals-Stale" = String, description = "`true` when served from expired arrivals while the upstream is paused"),
+             ("ETag" = String, description = "Weak validator of the arrivals, ignoring the countdowns")
+         )),
+        (status = 304, description = "Arrivals unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Invalid request - no stops provided, too many stops or an invalid filter", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Some stop IDs are unknown, only with `strict=true`", body = InvalidStopsResponse, content_type = "application/problem+json"),
+        (status = 503, description = "Arrivals upstream paused after repeated failures and no cached arrivals", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until the upstream is tried again"))),
+        (status = 500, description = "Internal server error", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_stop_arrivals(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    stop_arrivals(&req, &ctx.env).await?.into_response()
+}
+
+/// [`get_stop_arrivals`], also served by `tlt-serve`.
+pub(crate) async fn stop_arrivals(req: &dyn Incoming, env: &dyn Bindings) -> Result<Reply> {
+    let Query(ArrivalsQuery {
+        stops,
+        include,
+        strict,
+        wait,
+    }) = Query::from_request(req)?;
+    let stops_request = stops.0;
+    let filter = ArrivalsFilter::from(Query::<ArrivalsFilterQuery>::from_request(req)?.0);
+    let (format, encoding) = get_arrivals_format(req, &ARRIVALS_ENCODINGS)?;
+    let include_vehicle_distance = include.vehicle_distance;
+    if strict {
+        let stop_map = env.service().get_stop_map(env).await?;
+        let invalid_stop_ids = stops_request
+            .iter()
+            .filter(|id| !stop_map.contains_key(id.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+        if !invalid_stop_ids.is_empty() {
+            return problem_reply(&InvalidStopsResponse {
+                problem: Problem::new(ErrorCode::UnknownStop, 422, "unknown stop ids"),
+                invalid_stop_ids,
+            });
+        }
+    }
+    let if_none_match = req.header("If-None-Match")?;
+    let wait_secs = wait.map_or(0, |wait| wait.0);
+    let deadline = now_secs() + wait_secs;
+    // The encoding may have been picked by the Accept header, keep the ETags of each apart.
+    let query = format!(
+        "{}&encoding={}",
+        req.url()?.query().unwrap_or_default(),
+        encoding.name()
+    );
+    loop {
+        let (stops, stale) = match lookup_stop_arrivals(env, stops_request.clone()).await? {
+            ArrivalsLookup::Found { stops, stale } => (stops, stale),
+            ArrivalsLookup::Unavailable(retry_after) => return arrivals_unavailable(retry_after),
+        };
+        let stops = stops
+            .into_iter()
+            .map(|stop| filter.apply_result(stop))
+            .collect::<Vec<StopArrivalsResult>>();
+        let etag = arrivals_etag(&query, &stops);
+        if if_none_match
+            .as_deref()
+            .is_some_and(|tags| etag_matches(tags, &etag))
+        {
+            // Long-poll: hold the request until the arrivals change or the wait runs out.
+            if now_secs() < deadline {
+                let interval_secs = Config::get().arrivals_cache_ttl_secs.clamp(1, wait_secs);
+                clock::delay(Duration::from_secs(u64::from(interval_secs))).await;
+                continue;
+            }
+            return Ok(not_modified(&etag));
+        }
+        let stops = if include_vehicle_distance {
+            with_vehicle_distances(env.service(), stops).await
+        } else {
+            stops
+        };
+        let cache_age_secs =
+            arrivals_age_secs(stops.iter().filter_map(|stop| stop.data.as_deref()));
+        let mut reply = match format {
+            _ if encoding == Encoding::Csv => {
+                let mut writer = csv::arrivals_csv();
+                for stop in &stops {
+                    let data = stop.data.as_deref();
+                    csv::push_stop_arrivals(
+                        &mut writer,
+                        &stop.id,
+                        data.map_or("", |data| data.name.as_str()),
+                        stop.status,
+                        data.map(|data| &data.arrivals),
+                    );
+                }
+                writer.into_reply()
+            }
+            _ if encoding == Encoding::JsonApi => {
+                let document = jsonapi::arrivals_document(&stops);
+                reply_with_age(req, env, &document, encoding, cache_age_secs).await?
+            }
+            ArrivalsFormat::Nested => {
+                let response = PostArrivalsResponse { stops };
+                reply_with_age(req, env, &response, encoding, cache_age_secs).await?
+            }
+            ArrivalsFormat::Flat => {
+                let stops = stops.iter().map(FlatStopArrivalsResult::from).collect();
+                let flat = FlatPostArrivalsResponse { stops };
+                reply_with_age(req, env, &flat, encoding, cache_age_secs).await?
+            }
+        };
+        reply.set_header("ETag", etag);
+        if stale {
+            reply.set_header("X-Arrivals-Stale", "true");
+        }
+        reply.set_freshness(Config::get().arrivals_cache_ttl_secs, cache_age_secs);
+        return Ok(reply);
+    }
+}
+
+/// Longest `wait` of a long-polling arrivals request.
+const MAX_ARRIVALS_WAIT_SECS: u32 = 25;
+
+/// Weak validator of an arrivals response: the countdowns change with every response while the
+/// arrivals themselves don't.
+fn arrivals_etag(query: &str, stops: &[StopArrivalsResult]) -> String {
+    let fingerprints = stops
+        .iter()
+        .map(|stop| {
+            let fingerprint = stop.data.as_ref().map_or(0, |data| data.fingerprint());
+            format!("{}:{:?}:{:016x}", stop.id, stop.status, fingerprint)
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    format!(
+        "W/\"{:016x}\"",
+        fnv1a_64(format!("{}|{}", query, fingerprints).as_bytes())
+    )
+}
+
+/// Get arrival times for one stop
+///
+/// Returns the same real-time arrival information as `/api/arrivals` for a single stop
+#[utoipa::path(
+    get,
+    path = "/api/stops/{id}/arrivals",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("fields[arrivals]" = Option<String>, Query, description = "Comma-separated members kept in each arrival object", example = "time,inSeconds"),
+        ("id" = String, Path, description = "Stop ID or SIRI ID", example = "1001"),
+        ("max_minutes" = Option<u32>, Query, description = "Only arrivals within this many minutes (1-1440)", example = 30),
+        ("per_route" = Option<usize>, Query, description = "Only the next N arrivals of each route (1-100)", example = 2),
+        ("format" = Option<String>, Query, description = "`flat` for one time-sorted list of FlatArrival per stop instead of the type/number map, `csv` (or `Accept: text/csv`) for a CSV row per arrival, `msgpack` (or `Accept: application/msgpack`) for MessagePack, `jsonapi` (or `Accept: application/vnd.api+json`) for a JSON:API document of the stops with their arrivals included", example = "flat"),
+    ),
+    responses(
+        (status = 200, description = "Arrival times for the stop", body = StopArrivals,
+         headers(("X-Arrivals-Stale" = String, description = "`true` when served from expired arrivals while the upstream is paused"))),
+        (status = 400, description = "Invalid filter parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Stop not found", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching the stop's arrivals failed with no earlier ones cached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Arrivals upstream paused after repeated failures and no cached arrivals", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until the upstream is tried again")))
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_single_stop_arrivals(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    single_stop_arrivals(&req, &ctx, &ctx.env)
+        .await?
+        .into_response()
+}
+
+/// [`get_single_stop_arrivals`], also served by `tlt-serve`.
+pub(crate) async fn single_stop_arrivals(
+    req: &dyn Incoming,
+    params: &dyn PathParams,
+    env: &dyn Bindings,
+) -> Result<Reply> {
+    let Path(IdParam { id }) = Path::from_context(params)?;
+    let stop_id = id.to_string();
+    let filter = ArrivalsFilter::from(Query::<ArrivalsFilterQuery>::from_request(req)?.0);
+    let (format, encoding) = get_arrivals_format(req, &ARRIVALS_ENCODINGS)?;
+    match lookup_stop_arrivals(env, vec![stop_id]).await? {
+        ArrivalsLookup::Found { stops, stale } => {
+            let Some(result) = stops.into_iter().next() else {
+                return coded_error_reply(ErrorCode::UnknownStop, "stop not found", 404);
+            };
+            let stop = match (result.status, result.data) {
+                (StopArrivalsStatus::Ok, Some(stop)) => stop,
+                (StopArrivalsStatus::UpstreamError, _) => {
+                    return error_reply("arrivals upstream failed", 502);
+                }
+                _ => return coded_error_reply(ErrorCode::UnknownStop, "stop not found", 404),
+            };
+            let cache_age_secs = arrivals_age_secs([stop.as_ref()]);
+            let stop = filter.apply(stop);
+            let mut reply = match format {
+                _ if encoding == Encoding::Csv => {
+                    let mut writer = csv::arrivals_csv();
+                    csv::push_stop_arrivals(
+                        &mut writer,
+                        &stop.id,
+                        &stop.name,
+                        StopArrivalsStatus::Ok,
+                        Some(&stop.arrivals),
+                    );
+                    writer.into_reply()
+                }
+                _ if encoding == Encoding::JsonApi => {
+                    let document = jsonapi::stop_arrivals_document(&stop);
+                    reply_with_age(req, env, &document, encoding, cache_age_secs).await?
+                }
+                ArrivalsFormat::Nested => {
+                    reply_with_age(req, env, &stop, encoding, cache_age_secs).await?
+                }
+                ArrivalsFormat::Flat => {
+                    let flat = FlatStopArrivals::from(stop.as_ref());
+                    reply_with_age(req, env, &flat, encoding, cache_age_secs).await?
+                }
+            };
+            if stale {
+                reply.set_header("X-Arrivals-Stale", "true");
+            }
+            reply.set_freshness(Config::get().arrivals_cache_ttl_secs, cache_age_secs);
+            Ok(reply)
+        }
+        ArrivalsLookup::Unavailable(retry_after) => arrivals_unavailable(retry_after),
+    }
+}
+
+/// Departure board page
+///
+/// Returns the stop's arrivals as a self-refreshing HTML page for kiosk screens, times marked `*` are scheduled rather than realtime
+#[utoipa::path(
+    get,
+    path = "/board/{id}",
+    params(
+        ("id" = String, Path, description = "Stop ID or SIRI ID", example = "1001"),
+        ("max_minutes" = Option<u32>, Query, description = "Only arrivals within this many minutes (1-1440)", example = 30),
+        ("per_route" = Option<usize>, Query, description = "Only the next N arrivals of each route (1-100)", example = 2),
+    ),
+    responses(
+        (status = 200, description = "Departure board reloading itself with the arrivals cache", content_type = "text/html", body = String),
+        (status = 400, description = "Invalid filter parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Stop not found", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Board without arrivals, fetching them failed", content_type = "text/html", body = String),
+        (status = 503, description = "Board without arrivals, the upstream is paused", content_type = "text/html", body = String)
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_stop_board(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Path(IdParam { id }) = Path::from_context(&ctx)?;
+    let stop_id = id.to_string();
+    let filter = ArrivalsFilter::from(Query::<ArrivalsFilterQuery>::from_request(&req)?.0);
+    let refresh_secs = Config::get().arrivals_cache_ttl_secs.max(5);
+    let now = chrono::Utc::now();
+    // Failures still answer a board, so the kiosk keeps reloading until arrivals are back.
+    let (html, status) = match lookup_stop_arrivals(&ctx.env, vec![stop_id.clone()]).await? {
+        ArrivalsLookup::Found { stops, .. } => {
+            let Some(result) = stops.into_iter().next() else {
+                return coded_error_response(ErrorCode::UnknownStop, "stop not found", 404);
+            };
+            match (result.status, result.data) {
+                (StopArrivalsStatus::Ok, Some(stop)) => {
+                    let stop = filter.apply(stop);
+                    let html =
+                        board::render_board(&stop.name, Some(&stop), None, refresh_secs, now);
+                    (html, 200)
+                }
+                (StopArrivalsStatus::UpstreamError, _) => {
+                    let notice = Some("Arrivals are temporarily unavailable");
+                    (
+                        board::render_board(&stop_id, None, notice, refresh_secs, now),
+                        502,
+                    )
+                }
+                _ => return coded_error_response(ErrorCode::UnknownStop, "stop not found", 404),
+            }
+        }
+        ArrivalsLookup::Unavailable(retry_after) => {
+            let notice = Some("Arrivals are temporarily unavailable");
+            let html =
+                board::render_board(&stop_id, None, notice, retry_after.max(refresh_secs), now);
+            (html, 503)
+        }
+    };
+    let mut response = Response::from_html(html)?.with_status(status);
+    response.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(response)
+}
+
+/// GTFS-Realtime trip updates
+///
+/// Returns the stops' arrivals as a GTFS-RT `FeedMessage` protobuf, with one `TripUpdate` per arrival identified by its stop and `route_id` (`<type>_<number>`) as the upstream has no trip ids
+#[utoipa::path(
+    get,
+    path = "/api/gtfs-rt/trip-updates",
+    params(
+        ("stops" = String, Query, description = "Comma-separated list of stop IDs or SIRI IDs", example = "1001,1002"),
+    ),
+    responses(
+        (status = 200, description = "GTFS-RT feed, unknown stops left out", content_type = "application/x-protobuf", body = Vec<u8>),
+        (status = 400, description = "Missing or invalid stops parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Arrivals upstream paused after repeated failures and no cached arrivals", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until the upstream is tried again")))
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_gtfs_rt_trip_updates(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    let stop_ids = Query::<StopsQuery>::from_request(&req)?.0.into_stop_ids();
+    let stops = match lookup_stop_arrivals(&ctx.env, stop_ids).await? {
+        ArrivalsLookup::Found { stops, .. } => stops,
+        ArrivalsLookup::Unavailable(retry_after) => {
+            return arrivals_unavailable(retry_after)?.into_response();
+        }
+    };
+    let stop_map = TransportService::get_service()
+        .get_stop_map(&ctx.env)
+        .await?;
+    // The feed refers to stops by their stops.txt id whichever id they were requested by.
+    let feed_stops = stops
+        .iter()
+        .filter_map(|result| {
+            let stop = result.data.as_deref()?;
+            let stop_id = stop_map
+                .get(&result.id)
+                .map_or(result.id.as_str(), |data| data.id.as_str());
+            Some((stop_id, stop))
+        })
+        .collect::<Vec<(&str, &StopArrivals)>>();
+    let feed = gtfs_rt::trip_updates_feed(&feed_stops, chrono::Utc::now());
+    let mut response = Response::from_bytes(feed)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/x-protobuf")?;
+    Ok(response)
+}
+
+/// GTFS-Realtime vehicle positions
+///
+/// Returns the live positions of the upstream gps.txt feed as a GTFS-RT `FeedMessage` protobuf, trips identified by `route_id` (`<type>_<number>`)
+#[utoipa::path(
+    get,
+    path = "/api/gtfs-rt/vehicle-positions",
+    responses(
+        (status = 200, description = "GTFS-RT feed of every vehicle", content_type = "application/x-protobuf", body = Vec<u8>),
+        (status = 502, description = "Fetching gps.txt failed", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "gps.txt didn't load within the upstream timeout", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Vehicles"
+)]
+pub(crate) async fn get_gtfs_rt_vehicle_positions(
+    _req: Request,
+    _ctx: RouteContext<()>,
+) -> Result<Response> {
+    let vehicles = TransportService::get_service()
+        .get_vehicle_positions()
+        .await?;
+    let feed = gtfs_rt::vehicle_positions_feed(&vehicles, chrono::Utc::now());
+    let mut response = Response::from_bytes(feed)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/x-protobuf")?;
+    Ok(response)
+}
+
+/// Raw SIRI departures
+///
+/// Returns the upstream siri-stop-departures response for the stops unchanged, cached at the edge and open to any origin for clients with their own SIRI parser
+#[utoipa::path(
+    get,
+    path = "/api/raw/siri",
+    params(
+        ("stops" = String, Query, description = "Comma-separated list of stop IDs or SIRI IDs", example = "1001,1002"),
+    ),
+    responses(
+        (status = 200, description = "The upstream response body", content_type = "text/plain", body = String),
+        (status = 400, description = "Missing or invalid stops parameter, or unknown stop IDs", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching the departures failed", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Arrivals upstream paused after repeated failures", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until the upstream is tried again"))),
+        (status = 504, description = "The upstream didn't answer within the upstream timeout", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_raw_siri(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let stop_ids = Query::<StopsQuery>::from_request(&req)?.0.into_stop_ids();
+    let service = TransportService::get_service();
+    let stop_map = service.get_stop_map(&ctx.env).await?;
+    let mut unknown = Vec::new();
+    let mut siri_ids = stop_ids
+        .iter()
+        .filter_map(|stop_id| match stop_map.get(stop_id) {
+            Some(stop) => Some(stop.siri_id.as_str()),
+            None => {
+                unknown.push(stop_id.as_str());
+                None
+            }
+        })
+        .collect::<Vec<&str>>();
+    if !unknown.is_empty() {
+        return coded_error_response(
+            ErrorCode::UnknownStop,
+            format!("unknown stop ids: {}", unknown.join(",")),
+            400,
+        );
+    }
+    siri_ids.sort_unstable();
+    siri_ids.dedup();
+    let raw = match service.get_raw_stops_arrivals(&siri_ids.join(",")).await {
+        Ok(raw) => raw,
+        Err(ParsingUpstreamError::Unavailable(retry_after)) => {
+            return arrivals_unavailable(retry_after)?.into_response();
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut response = Response::ok(raw.as_str())?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", "text/plain; charset=utf-8")?;
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set(
+        "Cache-Control",
+        &format!("public, max-age={}", Config::get().arrivals_cache_ttl_secs),
+    )?;
+    Ok(response)
+}
+
+/// Never recorded for replay, the body doesn't end.
+pub(crate) const ARRIVALS_STREAM_PATH: &str = "/api/arrivals/stream";
+pub(crate) const ARRIVALS_SOCKET_PATH: &str = "/api/arrivals/ws";
+/// Durable Object binding holding the live arrivals WebSockets.
+const ARRIVALS_SOCKETS_BINDING: &str = "ARRIVALS_SOCKETS";
+/// Seconds an arrivals stream stays open, `EventSource` reconnects by itself after.
+const ARRIVALS_STREAM_SECS: u32 = 300;
+
+/// Stream arrival times
+///
+/// Pushes the `/api/arrivals` response as Server-Sent Events whenever it changes, checked every arrivals cache TTL
+#[utoipa::path(
+    get,
+    path = "/api/arrivals/stream",
+    params(
+        ("stops" = String, Query, description = "Comma-separated list of stop IDs or SIRI IDs (max 25 unless MAX_ARRIVALS_STOPS is set)", example = "1001,1002,1003"),
+        ("max_minutes" = Option<u32>, Query, description = "Only arrivals within this many minutes (1-1440)", example = 30),
+        ("per_route" = Option<usize>, Query, description = "Only the next N arrivals of each route (1-100)", example = 2),
+    ),
+    responses(
+        (status = 200, description = "`arrivals` events carrying a PostArrivalsResponse, `unavailable` events carrying the seconds until the paused upstream is retried; closed after 5 minutes", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid request - no stops provided, too many stops or an invalid filter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn stream_stop_arrivals(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let stop_ids = Query::<StopsQuery>::from_request(&req)?.0.into_stop_ids();
+    let filter = ArrivalsFilter::from(Query::<ArrivalsFilterQuery>::from_request(&req)?.0);
+    let interval_secs = Config::get().arrivals_cache_ttl_secs.max(1);
+    let last_tick = ARRIVALS_STREAM_SECS / interval_secs;
+    let env = ctx.env;
+    let events = futures::stream::unfold((0u32, None::<String>), move |(tick, last)| {
+        let (env, stop_ids) = (env.clone(), stop_ids.clone());
+        async move {
+            if tick > last_tick {
+                return None;
+            }
+            if tick > 0 {
+                Delay::from(Duration::from_secs(u64::from(interval_secs))).await;
+            }
+            let (event, last) = match lookup_stop_arrivals(&env, stop_ids).await {
+                Ok(ArrivalsLookup::Found { stops, .. }) => {
+                    let stops = stops
+                        .into_iter()
+                        .map(|stop| filter.apply_result(stop))
+                        .collect();
+                    match serde_json::to_string(&PostArrivalsResponse { stops }) {
+                        // a comment keeps idle proxies from closing the connection
+                        Ok(data) if last.as_ref() == Some(&data) => {
+                            (String::from(": unchanged\n\n"), last)
+                        }
+                        Ok(data) => (format!("event: arrivals\ndata: {}\n\n", data), Some(data)),
+                        Err(err) => (format!("event: error\ndata: {}\n\n", err), last),
+                    }
+                }
+                Ok(ArrivalsLookup::Unavailable(retry_after)) => (
+                    format!("event: unavailable\ndata: {}\n\n", retry_after),
+                    last,
+                ),
+                Err(err) => (format!("event: error\ndata: {}\n\n", err), last),
+            };
+            Some((Ok::<_, Error>(event.into_bytes()), (tick + 1, last)))
+        }
+    });
+    let mut response = Response::from_stream(events)?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", "text/event-stream")?;
+    headers.set("Cache-Control", "no-cache")?;
+    Ok(response)
+}
+
+/// The stops a WebSocket subscribes to from the start, optional.
+#[derive(Deserialize)]
+struct SubscribeQuery {
+    stops: Option<CommaSeparated>,
+}
+
+impl Validate for SubscribeQuery {
+    fn validate(&self) -> core::result::Result<(), RequestError> {
+        match &self.stops {
+            Some(stops) => check_stop_count(&stops.0),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Live arrival times over a WebSocket
+///
+/// Upgrades to a WebSocket receiving the arrivals of the subscribed stops whenever they change, polled once for all connected clients
+#[utoipa::path(
+    get,
+    path = "/api/arrivals/ws",
+    params(
+        ("stops" = Option<String>, Query, description = "Comma-separated list of stop IDs or SIRI IDs to subscribe to right away (max 25 unless MAX_ARRIVALS_STOPS is set)", example = "1001,1002"),
+    ),
+    responses(
+        (status = 101, description = "Switched to a WebSocket. Send `{\"subscribe\": [ids]}` or `{\"unsubscribe\": [ids]}`; receive `arrivals` messages with the changed StopArrivals, `unavailable` while the upstream is paused and `error` for rejected messages"),
+        (status = 400, description = "Too many stops", body = Problem, content_type = "application/problem+json"),
+        (status = 426, description = "Not a WebSocket upgrade request", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn arrivals_socket(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if req.headers().get("Upgrade")?.as_deref() != Some("websocket") {
+        return error_response("expected a WebSocket upgrade", 426);
+    }
+    Query::<SubscribeQuery>::from_request(&req)?;
+    let Ok(namespace) = ctx.env.durable_object(ARRIVALS_SOCKETS_BINDING) else {
+        return error_response("live arrivals not configured", 404);
+    };
+    namespace
+        .get_by_name("arrivals-sockets")?
+        .fetch_with_request(req)
+        .await
+}
+
+pub(crate) enum ArrivalsLookup {
+    /// One result per requested stop in request order; `stale` when some were served from
+    /// expired arrivals because their upstream fetch failed or is paused
+    Found {
+        stops: Vec<StopArrivalsResult>,
+        stale: bool,
+    },
+    /// Upstream paused with some stop lacking even stale arrivals, seconds until it's retried
+    Unavailable(u32),
+}
+
+/// Seconds since the oldest of the stops' arrivals was fetched, for the `?envelope=true` metadata.
+pub(crate) fn arrivals_age_secs<'a>(
+    stops: impl IntoIterator<Item = &'a StopArrivals>,
+) -> Option<u32> {
+    let arrivals_cache = &Caches::get_cache().stop_arrival;
+    stops
+        .into_iter()
+        .filter_map(|stop| arrivals_cache.age_secs(&stop.id))
+        .max()
+}
+
+/// Arrivals of the given stop ids, answered from the arrivals cache and refreshing the stops
+/// missing from it from upstream together.
+pub(crate) async fn lookup_stop_arrivals(
+    env: &dyn Bindings,
+    stop_ids: Vec<String>,
+) -> Result<ArrivalsLookup> {
+    let service = env.service();
+    let stop_map = service.get_stop_map(env).await?;
+    let arrivals_cache = &Caches::get_cache().stop_arrival;
+    let mut stale_arrivals = false;
+    let mut stop_states: Vec<StopArrivalState> = stop_ids
+        .iter()
+        .cloned()
+        .map(StopId)
+        .map(StopArrivalState::StopId)
+        .map(|state| match state {
+            StopArrivalState::StopId(stop_id) => stop_id.validate(&stop_map),
+            other => other,
+        })
+        .map(|state| match state {
+            StopArrivalState::Valid(valid_stop_id) => {
+                valid_stop_id.fetch_arrivals_from_cache(|siri_id| arrivals_cache.get(siri_id))
+            }
+            other => other,
+        })
+        .collect();
+    let missing_caches = stop_states
+        .iter()
+        .filter_map(|state| match state {
+            StopArrivalState::Valid(stop) => Some(stop.data.siri_id.to_string()),
+            _ => None,
+        })
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .fold(String::new(), |mut acc, id| {
+            if !acc.is_empty() {
+                acc.push(',');
+            }
+            acc.push_str(&id);
+            acc
+        });
+    let mut failed_ids = HashSet::new();
+    if !missing_caches.is_empty() {
+        let retry_after = match service
+            .update_stops_arrival_cache(env, &missing_caches)
+            .await
+        {
+            Ok(failed) => {
+                failed_ids = failed;
+                None
+            }
+            Err(ParsingUpstreamError::Unavailable(retry_after)) => Some(retry_after),
+            Err(err) => return Err(err.into()),
+        };
+        stop_states = stop_states
+            .into_iter()
+            .map(|state| match state {
+                StopArrivalState::Valid(valid_stop_id)
+                    if retry_after.is_some()
+                        || failed_ids.contains(valid_stop_id.data.siri_id.as_str()) =>
+                {
+                    match valid_stop_id.fetch_stale_arrivals_from_cache(|siri_id| {
+                        arrivals_cache.get_stale(siri_id)
+                    }) {
+                        ready @ StopArrivalState::Ready(_) => {
+                            stale_arrivals = true;
+                            ready
+                        }
+                        other => other,
+                    }
+                }
+                StopArrivalState::Valid(valid_stop_id) => {
+                    valid_stop_id.fetch_arrivals_from_cache(|siri_id| arrivals_cache.get(siri_id))
+                }
+                other => other,
+            })
+            .collect();
+        // With upstream paused, answer from stale arrivals only when every stop has some.
+        if let Some(retry_after) = retry_after
+            && stop_states
+                .iter()
+                .any(|state| matches!(state, StopArrivalState::Valid(_)))
+        {
+            return Ok(ArrivalsLookup::Unavailable(retry_after));
+        }
+    }
+    let stop_arrivals = stop_ids
+        .into_iter()
+        .zip(stop_states)
+        .map(|(id, state)| match state {
+            StopArrivalState::Ready(ready_stop_arrivals) => {
+                Ok(StopArrivalsResult::ok(id, ready_stop_arrivals.0))
+            }
+            StopArrivalState::Valid(stop_data)
+                if failed_ids.contains(stop_data.data.siri_id.as_str()) =>
+            {
+                Ok(StopArrivalsResult::failed(
+                    id,
+                    StopArrivalsStatus::UpstreamError,
+                ))
+            }
+            StopArrivalState::Valid(stop_data) => {
+                let stop_arrival = StopArrivals {
+                    id: stop_data.data.siri_id.to_string(),
+                    name: stop_data.data.name.to_string(),
+                    lat: stop_data.data.lat,
+                    lon: stop_data.data.lon,
+                    arrivals: HashMap::new(),
+                };
+                Ok(StopArrivalsResult::ok(id, Rc::new(stop_arrival)))
+            }
+            StopArrivalState::Invalid => Ok(StopArrivalsResult::failed(
+                id,
+                StopArrivalsStatus::UnknownStop,
+            )),
+            StopArrivalState::StopId(_) => Err(ParsingUpstreamError::Error(
+                "unreachable state: StopId after validation".to_string(),
+            )),
+        })
+        .collect::<core::result::Result<Vec<StopArrivalsResult>, ParsingUpstreamError>>()?;
+    Ok(ArrivalsLookup::Found {
+        stops: stop_arrivals,
+        stale: stale_arrivals,
+    })
+}
+
+/// 503 telling the client when the paused arrivals upstream is tried again.
+pub(crate) fn arrivals_unavailable(retry_after: u32) -> Result<Reply> {
+    let mut reply = error_reply("arrivals upstream unavailable", 503)?;
+    reply.set_header("Retry-After", retry_after.to_string());
+    Ok(reply)
+}
@@ -0,0 +1,28 @@
+//! The GraphQL endpoint, executed by [`crate::graphql`].
+
+use crate::graphql::GraphQlRequest;
+use crate::models::*;
+use crate::{error_response, graphql};
+use worker::*;
+
+/// GraphQL query
+///
+/// Fetches types, routes, directions, stops and their live arrivals as one graph, e.g. a route with its stops and arrivals in a single request. Supports selection sets, aliases, arguments and variables, not fragments, directives, mutations or introspection. Field errors are listed in `errors` beside the partial `data`
+#[utoipa::path(
+    post,
+    path = "/api/graphql",
+    request_body = GraphQlRequest,
+    responses(
+        (status = 200, description = "GraphQL response with `data` and any `errors`", body = Object,
+         example = json!({"data": {"route": {"directions": [{"name": "A-B", "stops": [{"name": "Stop Name", "arrivals": [{"time": "2025-10-14T08:15:00Z", "inSeconds": 240}]}]}]}}})),
+        (status = 400, description = "Body isn't a GraphQL request", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching routes.txt or stops.txt failed", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Routes"
+)]
+pub(crate) async fn graphql_query(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Ok(request) = req.json::<GraphQlRequest>().await else {
+        return error_response("invalid GraphQL request body", 400);
+    };
+    Response::from_json(&graphql::execute(&ctx.env, request).await?)
+}
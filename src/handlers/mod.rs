@@ -0,0 +1,11 @@
+//! The endpoint handlers, grouped by the part of the API they serve. The router, the OpenAPI
+//! document and the response helpers they share stay in the crate root.
+
+pub(crate) mod admin;
+pub(crate) mod alerts;
+pub(crate) mod arrivals;
+pub(crate) mod graphql;
+pub(crate) mod routes;
+pub(crate) mod status;
+pub(crate) mod stops;
+pub(crate) mod vehicles;
@@ -0,0 +1,612 @@
+//! The route tree: transport types, their routes, the directions of a route and the stops and
+//! shape of a direction.
+
+use crate::bindings::Bindings;
+use crate::extract::{
+    DirectionSelector, Incoming, LinksQuery, Path, PathParams, Query, RouteSelector, TypeSelector,
+    Validate,
+};
+use crate::models::*;
+use crate::profiles::{reply, reply_as};
+use crate::reply::Reply;
+use crate::services::*;
+use crate::str_utils::resolve_direction_or_index;
+use crate::{
+    ApiVersion, Encoding, LISTING_ENCODINGS, cached_unknown_route, error_reply, jsonapi, negotiate,
+    unknown_direction, unknown_route, unknown_route_response,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::rc::Rc;
+use worker::*;
+
+/// Get all transport types
+///
+/// Returns a list of all available transport types (e.g., bus, tram, trolleybus)
+#[utoipa::path(
+    get,
+    path = "/api/types",
+    params(
+        ("links" = Option<bool>, Query, description = "`true` for LinkedType objects carrying the URL of each type's routes", example = true),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the routes and stops data is unchanged"),
+    ),
+    responses(
+        (status = 200, description = "List of transport types", body = Vec<String>,
+         headers(("ETag" = String, description = "Validator of the response, changing with the routes and stops data")),
+         example = json!(["bus", "tram", "trolleybus"])),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag")
+    ),
+    tag = "Routes"
+)]
+pub(crate) async fn get_types(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    types(&req, &ctx.env).await?.into_response()
+}
+
+/// [`get_types`], also served by `tlt-serve`.
+pub(crate) async fn types(req: &dyn Incoming, env: &dyn Bindings) -> Result<Reply> {
+    let types = env.service().get_sorted_types(env).await?;
+    if Query::<LinksQuery>::from_request(req)?.0.links {
+        let linked = types.iter().map(|route_type| LinkedType::new(route_type));
+        return reply(req, env, &linked.collect::<Vec<LinkedType>>()).await;
+    }
+    reply(req, env, &types).await
+}
+
+/// Get routes by transport type
+///
+/// Returns a list of all route numbers for the specified transport type
+#[utoipa::path(
+    get,
+    path = "/api/types/{type}/routes",
+    params(
+        ("type" = String, Path, description = "Transport type (e.g., bus, tram), case-insensitive and by alias such as trol", example = "bus"),
+        ("links" = Option<bool>, Query, description = "`true` for LinkedRoute objects carrying the URLs of each route's directions and vehicles", example = true),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the routes and stops data is unchanged"),
+    ),
+    responses(
+        (status = 200, description = "List of route numbers, with `Accept: application/vnd.api+json` a JSON:API document of route resources related to their stops", body = Vec<String>,
+         headers(("ETag" = String, description = "Validator of the response, changing with the routes and stops data")),
+         example = json!(["1", "2", "3"])),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 404, description = "Transport type not found", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Routes"
+)]
+pub(crate) async fn get_routes_by_type(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    routes_by_type(&req, &ctx, &ctx.env).await?.into_response()
+}
+
+/// [`get_routes_by_type`], also served by `tlt-serve`.
+pub(crate) async fn routes_by_type(
+    req: &dyn Incoming,
+    params: &dyn PathParams,
+    env: &dyn Bindings,
+) -> Result<Reply> {
+    let Path(TypeSelector { route_type }) = Path::from_context(params)?;
+    let route_type = route_type.as_str();
+    if let Some(code) = cached_unknown_route(route_type, None) {
+        return unknown_route_response(code);
+    }
+    let service = env.service();
+    if negotiate(req, None, &LISTING_ENCODINGS)? == Encoding::JsonApi {
+        // The route resources relate to their stops, which only the route map has at hand.
+        let route_map = service.get_route_map(env).await?;
+        let Some(routes) = route_map.get(route_type) else {
+            return unknown_route(route_type.to_string(), ErrorCode::UnknownType);
+        };
+        let mut routes = routes.values().collect::<Vec<&RouteGroup>>();
+        routes.sort_unstable_by(|a, b| a.number.cmp(&b.number));
+        let document = jsonapi::routes_document(&routes);
+        return reply_as(req, env, &document, Encoding::JsonApi).await;
+    }
+    if let Some(store) = env.synced_store() {
+        let routes = store.route_numbers(route_type).await?;
+        if routes.is_empty() {
+            return unknown_route(route_type.to_string(), ErrorCode::UnknownType);
+        }
+        return route_list_response(req, env, route_type, &routes).await;
+    }
+    let route_map = service.get_route_map(env).await?;
+    let routes = route_map.get(route_type);
+    match routes {
+        Some(routes) => {
+            let mut routes = routes.keys().collect::<Vec<&String>>();
+            routes.sort_unstable();
+            route_list_response(req, env, route_type, &routes).await
+        }
+        None => unknown_route(route_type.to_string(), ErrorCode::UnknownType),
+    }
+}
+
+#[derive(Deserialize)]
+struct DetailQuery {
+    detail: Option<bool>,
+}
+
+impl Validate for DetailQuery {}
+
+/// Get directions for a specific route
+///
+/// Returns a list of all direction names for the specified route, sorted so that each one's
+/// position is the index the stops and shape endpoints also take in place of the name. In v2
+/// each direction is an object summarizing its stops
+#[utoipa::path(
+    get,
+    path = "/api/types/{type}/routes/{number}/directions",
+    params(
+        ("type" = String, Path, description = "Transport type, case-insensitive and by alias such as trol", example = "bus"),
+        ("number" = String, Path, description = "Route number", example = "1"),
+        ("detail" = Option<bool>, Query, description = "`true` for DirectionDetails objects with each direction's terminus, stop count and first and last stops, by default `false` in v1 and `true` in v2", example = true),
+        ("links" = Option<bool>, Query, description = "`true` for LinkedDirection objects carrying each direction's index and the URLs of its stops and shape, taking precedence over `detail`", example = true),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the routes and stops data is unchanged"),
+    ),
+    responses(
+        (status = 200, description = "List of direction names, sorted, the position of each being its index, DirectionDetails objects with `detail=true` or in v2", body = Vec<String>,
+         headers(("ETag" = String, description = "Validator of the response, changing with the routes and stops data")),
+         example = json!(["Kopli", "Linnahall"])),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Invalid detail parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Transport type or route not found", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Routes"
+)]
+pub(crate) async fn get_directions_by_route_type_number(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    directions_by_route_type_number(&req, &ctx, &ctx.env)
+        .await?
+        .into_response()
+}
+
+/// [`get_directions_by_route_type_number`], also served by `tlt-serve`.
+pub(crate) async fn directions_by_route_type_number(
+    req: &dyn Incoming,
+    params: &dyn PathParams,
+    env: &dyn Bindings,
+) -> Result<Reply> {
+    let Path(RouteSelector {
+        route_type,
+        number: route_number,
+    }) = Path::from_context(params)?;
+    let route_type = route_type.as_str();
+    if let Some(code) = cached_unknown_route(route_type, Some(route_number)) {
+        return unknown_route_response(code);
+    }
+
+    let service = env.service();
+    if let Some(store) = env.synced_store() {
+        let directions = store.directions(route_type, route_number).await?;
+        if directions.is_empty() {
+            if !store.has_type(route_type).await? {
+                return unknown_route(route_type.to_string(), ErrorCode::UnknownType);
+            }
+            return unknown_route(
+                format!("{}/{}", route_type, route_number),
+                ErrorCode::UnknownRoute,
+            );
+        }
+        if wants_direction_details(req)? {
+            let mut details = Vec::with_capacity(directions.len());
+            for direction in directions {
+                let stops = store.stops(route_type, route_number, &direction).await?;
+                details.push((direction, stops));
+            }
+            return direction_details_response(req, env, &details).await;
+        }
+        return direction_list_response(req, env, route_type, route_number, &directions).await;
+    }
+    let route_map = service.get_route_map(env).await?;
+
+    let routes = match route_map.get(route_type) {
+        Some(routes) => routes,
+        None => return unknown_route(route_type.to_string(), ErrorCode::UnknownType),
+    };
+
+    let route = match routes.get(route_number) {
+        Some(route) => route,
+        None => {
+            return unknown_route(
+                format!("{}/{}", route_type, route_number),
+                ErrorCode::UnknownRoute,
+            );
+        }
+    };
+
+    let mut directions: Vec<&str> = route.directions.keys().map(|s| s.as_str()).collect();
+    directions.sort_unstable();
+
+    if wants_direction_details(req)? {
+        let stop_map = service.get_stop_map(env).await.ok();
+        let details = directions
+            .iter()
+            .map(|direction| {
+                let stops =
+                    route_direction_stops(&route.directions[*direction], stop_map.as_deref());
+                (*direction, stops)
+            })
+            .collect::<Vec<_>>();
+        return direction_details_response(req, env, &details).await;
+    }
+    direction_list_response(req, env, route_type, route_number, &directions).await
+}
+
+/// Whether the directions are answered as [`DirectionDetails`], by `?detail=` else the version,
+/// `?links=true` asking for [`LinkedDirection`]s instead.
+fn wants_direction_details(req: &dyn Incoming) -> Result<bool> {
+    if Query::<LinksQuery>::from_request(req)?.0.links {
+        return Ok(false);
+    }
+    let version = ApiVersion::of_path(req.url()?.path());
+    Ok(Query::<DetailQuery>::from_request(req)?
+        .0
+        .detail
+        .unwrap_or_else(|| version.direction_details()))
+}
+
+/// Serializes `(name, stops)` directions as [`DirectionDetails`] summarizing their stops.
+async fn direction_details_response<S: AsRef<str>>(
+    req: &dyn Incoming,
+    env: &dyn Bindings,
+    directions: &[(S, RouteStops)],
+) -> Result<Reply> {
+    let details = directions
+        .iter()
+        .enumerate()
+        .map(|(index, (name, stops))| {
+            let stop = |sequence: usize| {
+                let (id, stop) = stops.get(sequence)?;
+                Some(stop_object(sequence, id, stop.as_ref()))
+            };
+            DirectionDetails {
+                index,
+                name: name.as_ref(),
+                terminus_stop: stops
+                    .last()
+                    .and_then(|(_, stop)| stop.as_ref())
+                    .map(|stop| stop.name.as_str()),
+                stop_count: stops.len(),
+                first_stop: stop(0),
+                last_stop: stops.len().checked_sub(1).and_then(stop),
+            }
+        })
+        .collect::<Vec<DirectionDetails>>();
+    reply(req, env, &details).await
+}
+
+/// Serializes the route numbers, as [`LinkedRoute`]s with `?links=true`.
+async fn route_list_response<S: AsRef<str>>(
+    req: &dyn Incoming,
+    env: &dyn Bindings,
+    route_type: &str,
+    routes: &[S],
+) -> Result<Reply> {
+    if !Query::<LinksQuery>::from_request(req)?.0.links {
+        let routes = routes.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+        return reply(req, env, &routes).await;
+    }
+    let linked = routes
+        .iter()
+        .map(|number| LinkedRoute::new(route_type, number.as_ref()))
+        .collect::<Vec<LinkedRoute>>();
+    reply(req, env, &linked).await
+}
+
+/// Serializes the direction names, as [`LinkedDirection`]s with `?links=true`.
+async fn direction_list_response<S: AsRef<str>>(
+    req: &dyn Incoming,
+    env: &dyn Bindings,
+    route_type: &str,
+    route_number: &str,
+    directions: &[S],
+) -> Result<Reply> {
+    if !Query::<LinksQuery>::from_request(req)?.0.links {
+        let directions = directions.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+        return reply(req, env, &directions).await;
+    }
+    let linked = directions
+        .iter()
+        .enumerate()
+        .map(|(index, name)| LinkedDirection::new(route_type, route_number, index, name.as_ref()))
+        .collect::<Vec<LinkedDirection>>();
+    reply(req, env, &linked).await
+}
+
+/// `?shape=` of a stop listing.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StopShape {
+    /// `[id, name]` pairs, v1's default.
+    Tuple,
+    /// [`StopObject`]s, v2's default.
+    Object,
+}
+
+#[derive(Deserialize)]
+struct StopShapeQuery {
+    shape: Option<StopShape>,
+}
+
+impl Validate for StopShapeQuery {}
+
+/// Get stops for a specific route and direction
+///
+/// Returns the stop IDs and names of the specified route and direction, as `[id, name]` pairs in
+/// v1 and in v2 as objects also carrying the SIRI id, sequence and coordinates, listed with the
+/// stops whose name couldn't be resolved
+#[utoipa::path(
+    get,
+    path = "/api/types/{type}/routes/{number}/directions/{direction}/stops",
+    params(
+        ("type" = String, Path, description = "Transport type, case-insensitive and by alias such as trol", example = "bus"),
+        ("number" = String, Path, description = "Route number", example = "1"),
+        ("direction" = String, Path, description = "Direction name (URL encoded), matched ignoring case, surrounding whitespace and diacritics, or its index in the sorted directions listing", example = "Kopli"),
+        ("shape" = Option<String>, Query, description = "`tuple` for `[id, name]` pairs or `object` for a StopListing of StopObject objects, by default the version's shape: `tuple` in v1 and `object` in v2. The pairs carry only the stops.txt `id`, the SIRI id needs `shape=object` in v1", example = "object"),
+        ("links" = Option<bool>, Query, description = "`true` for LinkedStop objects carrying the URLs of each stop's arrivals and board, taking precedence over `shape`", example = true),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the routes and stops data is unchanged"),
+    ),
+    responses(
+        (status = 200, description = "`[id, name]` pairs in v1, a StopListing of StopObject objects and the ids of the stops whose name couldn't be resolved as `missingNames` with `shape=object` or in v2, LinkedStop objects with `links=true`, with `Accept: application/vnd.api+json` a JSON:API document of stop resources whose meta has the `missingNames`. The objects and JSON:API resources also carry the stop's `siriId`, which the arrivals endpoints take as well as the `id`, as the StopListing's and the JSON:API meta's `arrivalsAccept` say, and its `sequence` along the direction, the pairs being in that order. The v1 pairs carry only the `id`, ask for `shape=object` for the `siriId`", body = RouteStopsListing,
+         headers(("ETag" = String, description = "Validator of the response, changing with the routes and stops data"), ("X-Missing-Names" = String, description = "Comma-separated ids of the stops whose name couldn't be resolved, also in the pairs shape")),
+         example = json!([["1001", "Stop Name 1"], ["1002", "Stop Name 2"]])),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Invalid direction or shape parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Transport type, route, or direction not found, an unknown direction's body listing the route's directions", body = UnknownDirectionResponse, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn get_stops_by_route_type_number_direction(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    stops_by_route_type_number_direction(&req, &ctx, &ctx.env)
+        .await?
+        .into_response()
+}
+
+/// [`get_stops_by_route_type_number_direction`], also served by `tlt-serve`.
+pub(crate) async fn stops_by_route_type_number_direction(
+    req: &dyn Incoming,
+    params: &dyn PathParams,
+    env: &dyn Bindings,
+) -> Result<Reply> {
+    let Path(DirectionSelector {
+        route_type,
+        number: route_number,
+        direction: direction_raw,
+    }) = Path::from_context(params)?;
+    let route_type = route_type.as_str();
+    if let Some(code) = cached_unknown_route(route_type, Some(route_number)) {
+        return unknown_route_response(code);
+    }
+
+    let service = env.service();
+    if let Some(store) = env.synced_store() {
+        let direction = match urlencoding::decode(direction_raw) {
+            Ok(direction) if !direction.is_empty() => direction.to_string(),
+            _ => return error_reply("invalid direction", 400),
+        };
+        let mut stops = store.stops(route_type, route_number, &direction).await?;
+        if stops.is_empty() {
+            let directions = store.directions(route_type, route_number).await?;
+            if let Some(direction) = resolve_direction_or_index(&directions, &direction) {
+                stops = store.stops(route_type, route_number, direction).await?;
+            } else if !directions.is_empty() {
+                return unknown_direction(&directions);
+            }
+        }
+        if stops.is_empty() {
+            if !store.has_type(route_type).await? {
+                return unknown_route(route_type.to_string(), ErrorCode::UnknownType);
+            }
+            return unknown_route(
+                format!("{}/{}", route_type, route_number),
+                ErrorCode::UnknownRoute,
+            );
+        }
+        return stop_list_response(req, env, stops).await;
+    }
+    let route_map = service.get_route_map(env).await?;
+
+    let routes = match route_map.get(route_type) {
+        Some(routes) => routes,
+        None => return unknown_route(route_type.to_string(), ErrorCode::UnknownType),
+    };
+
+    let route = match routes.get(route_number) {
+        Some(route) => route,
+        None => {
+            return unknown_route(
+                format!("{}/{}", route_type, route_number),
+                ErrorCode::UnknownRoute,
+            );
+        }
+    };
+
+    let direction = match urlencoding::decode(direction_raw) {
+        Ok(direction) if !direction.is_empty() => direction.to_string(),
+        _ => return error_reply("invalid direction", 400),
+    };
+
+    let stops = match resolve_direction_or_index(route.directions.keys(), &direction) {
+        Some(direction) => &route.directions[direction],
+        None => return unknown_direction(route.directions.keys()),
+    };
+
+    // One snapshot of the stop map for the whole listing; if it can't be loaded the ids are
+    // still returned, just with every name reported missing.
+    let stop_map = service.get_stop_map(env).await.ok();
+    let stops_data = route_direction_stops(stops, stop_map.as_deref());
+
+    stop_list_response(req, env, stops_data).await
+}
+
+/// Get the shape of a route direction
+///
+/// Returns a GeoJSON LineString through the coordinates of the route's stops, in order
+#[utoipa::path(
+    get,
+    path = "/api/types/{type}/routes/{number}/directions/{direction}/shape",
+    params(
+        ("type" = String, Path, description = "Transport type, case-insensitive and by alias such as trol", example = "bus"),
+        ("number" = String, Path, description = "Route number", example = "1"),
+        ("direction" = String, Path, description = "Direction name (URL encoded), matched ignoring case, surrounding whitespace and diacritics, or its index in the sorted directions listing", example = "Kopli")
+    ),
+    responses(
+        (status = 200, description = "GeoJSON Feature with the route line", body = RouteShape, content_type = "application/geo+json"),
+        (status = 400, description = "Invalid direction parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Transport type, route, or direction not found, an unknown direction's body listing the route's directions", body = UnknownDirectionResponse, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn get_route_shape(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    route_shape(&req, &ctx, &ctx.env).await?.into_response()
+}
+
+/// [`get_route_shape`], also served by `tlt-serve`.
+pub(crate) async fn route_shape(
+    req: &dyn Incoming,
+    params: &dyn PathParams,
+    env: &dyn Bindings,
+) -> Result<Reply> {
+    let Path(DirectionSelector {
+        route_type,
+        number: route_number,
+        direction: direction_raw,
+    }) = Path::from_context(params)?;
+    let route_type = route_type.as_str();
+    if let Some(code) = cached_unknown_route(route_type, Some(route_number)) {
+        return unknown_route_response(code);
+    }
+    let direction = match urlencoding::decode(direction_raw) {
+        Ok(direction) if !direction.is_empty() => direction.to_string(),
+        _ => return error_reply("invalid direction", 400),
+    };
+
+    let service = env.service();
+    let route_map = service.get_route_map(env).await?;
+    let Some(routes) = route_map.get(route_type) else {
+        return unknown_route(route_type.to_string(), ErrorCode::UnknownType);
+    };
+    let Some(route) = routes.get(route_number) else {
+        return unknown_route(
+            format!("{}/{}", route_type, route_number),
+            ErrorCode::UnknownRoute,
+        );
+    };
+    let Some(direction) = resolve_direction_or_index(route.directions.keys(), &direction) else {
+        return unknown_direction(route.directions.keys());
+    };
+    let stops = &route.directions[direction];
+
+    let stop_map = service.get_stop_map(env).await?;
+    let mut coordinates = Vec::with_capacity(stops.len());
+    let mut missing_coordinates = Vec::new();
+    for stop_id in stops {
+        match stop_map
+            .get(stop_id)
+            .and_then(|stop| Some([stop.lon?, stop.lat?]))
+        {
+            Some(position) => coordinates.push(position),
+            None => missing_coordinates.push(stop_id.clone()),
+        }
+    }
+    let shape = RouteShape {
+        r#type: "Feature",
+        geometry: LineString {
+            r#type: "LineString",
+            coordinates,
+        },
+        properties: RouteShapeProperties {
+            r#type: route_type.to_string(),
+            number: route_number.to_string(),
+            direction: direction.clone(),
+            stop_ids: stops.clone(),
+            missing_coordinates,
+        },
+    };
+    reply_as(req, env, &shape, Encoding::GeoJson).await
+}
+
+pub(crate) const UNRESOLVED_STOP_NAME: &str = "Can't resolve stop name";
+
+/// The stops of a route direction's ids, `None` for those the stop map lacks or all of them
+/// without a stop map.
+pub(crate) fn route_direction_stops(
+    stop_ids: &[String],
+    stop_map: Option<&HashMap<String, Rc<StopData>>>,
+) -> RouteStops {
+    stop_ids
+        .iter()
+        .map(|stop_id| {
+            let stop = stop_map.and_then(|stop_map| stop_map.get(stop_id)).cloned();
+            (stop_id.clone(), stop)
+        })
+        .collect()
+}
+
+fn stop_object<'a>(sequence: usize, id: &'a str, stop: Option<&'a Rc<StopData>>) -> StopObject<'a> {
+    StopObject {
+        id,
+        siri_id: stop.map(|stop| stop.siri_id.as_str()),
+        name: stop.map_or(UNRESOLVED_STOP_NAME, |stop| stop.name.as_str()),
+        sequence,
+        lat: stop.and_then(|stop| stop.lat),
+        lon: stop.and_then(|stop| stop.lon),
+    }
+}
+
+/// Serializes the stops of a listing in the shape asked for. The ids whose name couldn't be
+/// resolved are the objects' `missingNames` and the JSON:API document's meta, and in every shape
+/// the `X-Missing-Names` header. Both also tell which of the stops' ids the arrivals take.
+async fn stop_list_response(
+    req: &dyn Incoming,
+    env: &dyn Bindings,
+    stops: RouteStops,
+) -> Result<Reply> {
+    let version = ApiVersion::of_path(req.url()?.path());
+    let shape = Query::<StopShapeQuery>::from_request(req)?
+        .0
+        .shape
+        .unwrap_or_else(|| version.stop_shape());
+    let missing_names = stops
+        .iter()
+        .filter(|(_, stop)| stop.is_none())
+        .map(|(id, _)| id.as_str())
+        .collect::<Vec<&str>>();
+    let objects = stops
+        .iter()
+        .enumerate()
+        .map(|(sequence, (id, stop))| stop_object(sequence, id, stop.as_ref()))
+        .collect::<Vec<StopObject>>();
+    let header = missing_names.join(",");
+    let mut reply = match negotiate(req, None, &LISTING_ENCODINGS)? {
+        Encoding::JsonApi => {
+            let document = jsonapi::stops_document(&objects, &missing_names);
+            reply_as(req, env, &document, Encoding::JsonApi).await?
+        }
+        _ => {
+            let listing = match shape {
+                _ if Query::<LinksQuery>::from_request(req)?.0.links => {
+                    RouteStopsListing::Linked(objects.iter().map(LinkedStop::from).collect())
+                }
+                StopShape::Tuple => RouteStopsListing::Pairs(
+                    objects
+                        .iter()
+                        .map(|stop| StopResponse(stop.id.to_string(), stop.name.to_string()))
+                        .collect(),
+                ),
+                StopShape::Object => RouteStopsListing::Objects(StopListing {
+                    stops: objects,
+                    missing_names,
+                    arrivals_accept: ARRIVALS_STOP_IDS,
+                }),
+            };
+            reply(req, env, &listing).await?
+        }
+    };
+    if !header.is_empty() {
+        reply.set_header("X-Missing-Names", header);
+    }
+    Ok(reply)
+}
@@ -0,0 +1,308 @@
+//! The API index and the health, readiness and version endpoints.
+
+use crate::caches::*;
+use crate::extract::{Query, Validate};
+use crate::models::*;
+use crate::services::*;
+use crate::{api_doc, coded_error_response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+#[derive(Serialize, utoipa::ToSchema)]
+struct ApiLink {
+    #[schema(example = "/api/v1/openapi.json")]
+    href: &'static str,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+struct ApiEndpoint {
+    /// Operation id from the OpenAPI spec
+    #[schema(example = "get_routes_by_type")]
+    rel: String,
+    #[schema(example = "GET")]
+    method: &'static str,
+    /// RFC 6570 URI template
+    #[schema(example = "/api/v1/types/{type}/routes")]
+    href: String,
+    templated: bool,
+    #[schema(example = "Get routes by transport type")]
+    title: Option<String>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ApiIndex {
+    #[schema(example = "TLT Stops API")]
+    name: String,
+    #[schema(example = "0.1.0")]
+    schema_version: String,
+    #[serde(rename = "_links")]
+    links: HashMap<&'static str, ApiLink>,
+    endpoints: Vec<ApiEndpoint>,
+}
+
+/// API index
+///
+/// Returns the available endpoints as templated links, derived from the OpenAPI spec
+#[utoipa::path(
+    get,
+    path = "/api",
+    responses(
+        (status = 200, description = "Index of the API endpoints", body = ApiIndex)
+    ),
+    tag = "Health"
+)]
+pub(crate) fn api_index(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let openapi = api_doc();
+    let endpoints = openapi
+        .paths
+        .paths
+        .iter()
+        .flat_map(|(path, item)| {
+            [
+                ("GET", &item.get),
+                ("POST", &item.post),
+                ("PUT", &item.put),
+                ("DELETE", &item.delete),
+            ]
+            .into_iter()
+            .filter_map(move |(method, operation)| {
+                let operation = operation.as_ref()?;
+                let query = operation
+                    .parameters
+                    .iter()
+                    .flatten()
+                    .filter(|param| {
+                        matches!(
+                            param.parameter_in,
+                            utoipa::openapi::path::ParameterIn::Query
+                        )
+                    })
+                    .map(|param| param.name.as_str())
+                    .collect::<Vec<&str>>();
+                let href = if query.is_empty() {
+                    path.clone()
+                } else {
+                    format!("{}{{?{}}}", path, query.join(","))
+                };
+                Some(ApiEndpoint {
+                    rel: operation
+                        .operation_id
+                        .clone()
+                        .unwrap_or_else(|| path.clone()),
+                    method,
+                    templated: href.contains('{'),
+                    href,
+                    title: operation.summary.clone(),
+                })
+            })
+        })
+        .collect();
+    let links = HashMap::from([
+        ("self", ApiLink { href: "/api/v1" }),
+        (
+            "openapi",
+            ApiLink {
+                href: "/api/v1/openapi.json",
+            },
+        ),
+        (
+            "openapiYaml",
+            ApiLink {
+                href: "/api/v1/openapi.yaml",
+            },
+        ),
+        (
+            "docs",
+            ApiLink {
+                href: "https://github.com/iNViTiON/tlt-stops#readme",
+            },
+        ),
+        (
+            "explorer",
+            ApiLink {
+                href: "/api/v1/docs",
+            },
+        ),
+    ]);
+    Response::from_json(&ApiIndex {
+        name: openapi.info.title,
+        schema_version: openapi.info.version,
+        links,
+        endpoints,
+    })
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[schema(example = json!({
+    "status": "healthy",
+    "timestamp": "2025-10-20T12:00:00Z",
+    "version": "0.1.0"
+}))]
+pub(crate) struct HealthStatus {
+    /// `degraded` when a deep check found an upstream unhealthy
+    #[schema(example = "healthy")]
+    status: &'static str,
+    #[schema(example = "2025-10-20T12:00:00Z")]
+    timestamp: String,
+    #[schema(example = "0.1.0")]
+    version: &'static str,
+    /// Upstream dependencies probed by a deep check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependencies: Option<Vec<DependencyHealth>>,
+}
+
+#[derive(Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    deep: bool,
+}
+
+impl Validate for HealthQuery {}
+
+/// Health check endpoint
+///
+/// Returns the current status of the API service. `deep=true` also probes routes.txt, stops.txt
+/// and SIRI with lightweight requests and reports each one's status and latency, the status
+/// being `degraded` while any of them is unhealthy.
+#[utoipa::path(
+    get,
+    path = "/api/health",
+    params(
+        ("deep" = Option<bool>, Query, description = "`true` to probe the upstream dependencies", example = true)
+    ),
+    responses(
+        (status = 200, description = "Service is up", body = HealthStatus)
+    ),
+    tag = "Health"
+)]
+pub(crate) async fn health_check(req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let Query(HealthQuery { deep }) = Query::from_request(&req)?;
+    let dependencies = match deep {
+        true => Some(TransportService::get_service().probe_upstreams().await),
+        false => None,
+    };
+    let healthy = dependencies
+        .iter()
+        .flatten()
+        .all(|dependency| dependency.healthy);
+    Response::from_json(&HealthStatus {
+        status: if healthy { "healthy" } else { "degraded" },
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        version: env!("CARGO_PKG_VERSION"),
+        dependencies,
+    })
+}
+
+pub(crate) const READY_PATH: &str = "/api/ready";
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub(crate) struct ReadyStatus {
+    #[schema(example = "ready")]
+    status: &'static str,
+    /// Seconds since the older of routes.txt and stops.txt was fetched
+    #[serde(rename = "dataAgeSecs")]
+    #[schema(example = 120)]
+    data_age_secs: Option<u32>,
+}
+
+/// Readiness check endpoint
+///
+/// 200 once the routes and stops are loaded and requests for them are answered without waiting
+/// on upstream, 503 while they aren't, which starts loading them in the background
+#[utoipa::path(
+    get,
+    path = "/api/ready",
+    responses(
+        (status = 200, description = "Routes and stops loaded", body = ReadyStatus),
+        (status = 503, description = "Still loading the routes and stops", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until checking again is worthwhile")))
+    ),
+    tag = "Health"
+)]
+pub(crate) fn get_ready(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let cache = Caches::get_cache();
+    if !cache.is_ready() {
+        let mut response =
+            coded_error_response(ErrorCode::NotReady, "routes and stops not loaded yet", 503)?;
+        response.headers_mut().set("Retry-After", "5")?;
+        return Ok(response);
+    }
+    Response::from_json(&ReadyStatus {
+        status: "ready",
+        data_age_secs: cache.data_age_secs(),
+    })
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub(crate) struct DataSnapshot {
+    /// When this isolate fetched its copy
+    #[serde(rename = "fetchedAt")]
+    #[schema(example = "2025-10-20T11:58:00Z")]
+    fetched_at: String,
+    /// Upstream's `Last-Modified` of the copy
+    #[serde(rename = "lastModified", skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Mon, 20 Oct 2025 03:00:00 GMT")]
+    last_modified: Option<String>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub(crate) struct VersionInfo {
+    #[schema(example = "0.1.0")]
+    version: &'static str,
+    /// Commit the worker was built from, `GIT_SHA` at build time or the checkout's `HEAD`
+    #[serde(rename = "gitSha", skip_serializing_if = "Option::is_none")]
+    #[schema(example = "f2c3c0e1a9b8d7c6e5f4a3b2c1d0e9f8a7b6c5d4")]
+    git_sha: Option<&'static str>,
+    /// `SOURCE_DATE_EPOCH` at build time or the time of the build
+    #[serde(rename = "builtAt", skip_serializing_if = "Option::is_none")]
+    #[schema(example = "2025-10-20T09:00:00Z")]
+    built_at: Option<String>,
+    /// The loaded routes.txt, none until loaded
+    routes: Option<DataSnapshot>,
+    /// The loaded stops.txt, none until loaded
+    stops: Option<DataSnapshot>,
+}
+
+/// The snapshot of an upstream file held by the isolate cache, `None` until loaded.
+fn data_snapshot(
+    raw: &CacheData<Vec<u8>>,
+    validators: &CacheData<UpstreamValidators>,
+) -> Option<DataSnapshot> {
+    let fetched_at = now_secs().saturating_sub(raw.age_secs()?);
+    Some(DataSnapshot {
+        fetched_at: chrono::DateTime::from_timestamp(fetched_at.into(), 0)?
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        last_modified: validators
+            .peek()
+            .and_then(|validators| validators.last_modified.clone()),
+    })
+}
+
+/// Build info endpoint
+///
+/// Returns the crate version, the commit and time of the build, and when the routes and stops
+/// served by the answering isolate were fetched
+#[utoipa::path(
+    get,
+    path = "/api/version",
+    responses(
+        (status = 200, description = "Build and data versions", body = VersionInfo)
+    ),
+    tag = "Health"
+)]
+pub(crate) fn get_version(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let cache = Caches::get_cache();
+    Response::from_json(&VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("TLT_GIT_SHA"),
+        built_at: env!("TLT_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|built_at| built_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        routes: data_snapshot(&cache.routes_raw, &cache.routes_validators),
+        stops: data_snapshot(&cache.stops_raw, &cache.stops_validators),
+    })
+}
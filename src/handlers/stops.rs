@@ -0,0 +1,463 @@
+//! Stop listings, search, nearby and zone queries, hubs and the named stop groups.
+
+use crate::config::Config;
+use crate::extract::{
+    ArrivalsFilterQuery, BoundedFloat, FormatQuery, LimitQuery, NameParam, Path, Query, Validate,
+};
+use crate::handlers::arrivals::{
+    ArrivalsLookup, arrivals_age_secs, arrivals_unavailable, lookup_stop_arrivals,
+};
+use crate::handlers::routes::UNRESOLVED_STOP_NAME;
+use crate::models::*;
+use crate::profiles::{respond, respond_as, respond_with_age};
+use crate::services::*;
+use crate::{
+    Encoding, GROUP_ARRIVALS_ENCODINGS, coded_error_response, csv, error_response,
+    get_arrivals_format, negotiate, set_freshness,
+};
+use serde::Deserialize;
+use std::rc::Rc;
+use worker::*;
+
+/// Get interchange hubs
+///
+/// Returns the stops served by the highest number of distinct routes
+#[utoipa::path(
+    get,
+    path = "/api/stats/hubs",
+    params(
+        ("limit" = Option<usize>, Query, description = "Number of stops to return (1-100, default 20)", example = 20),
+    ),
+    responses(
+        (status = 200, description = "Stops ordered by number of serving routes", body = Vec<StopHub>),
+        (status = 400, description = "Invalid limit parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stats"
+)]
+pub(crate) async fn get_stop_hubs(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(20);
+    let service = TransportService::get_service();
+    let index = service.get_stop_routes_index(&ctx.env).await?;
+    let stop_map = service.get_stop_map(&ctx.env).await?;
+
+    let mut ranked = index.iter().collect::<Vec<(&String, &Vec<RouteRef>)>>();
+    ranked.sort_unstable_by(|(a_id, a_routes), (b_id, b_routes)| {
+        b_routes
+            .len()
+            .cmp(&a_routes.len())
+            .then_with(|| a_id.cmp(b_id))
+    });
+    let hubs = ranked
+        .into_iter()
+        .take(limit)
+        .map(|(stop_id, routes)| StopHub {
+            id: stop_id.clone(),
+            lat: stop_map.get(stop_id).and_then(|stop| stop.lat),
+            lon: stop_map.get(stop_id).and_then(|stop| stop.lon),
+            name: TransportService::get_stop_name_by_id(stop_id, &stop_map)
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| UNRESOLVED_STOP_NAME.to_string()),
+            route_count: routes.len(),
+            routes: routes.clone(),
+        })
+        .collect::<Vec<StopHub>>();
+
+    respond(&req, &ctx.env, &hubs).await
+}
+
+#[derive(Deserialize)]
+struct ListStopsQuery {
+    name: Option<String>,
+    offset: Option<usize>,
+}
+
+impl Validate for ListStopsQuery {}
+
+/// List all stops
+///
+/// Returns one page of all stops ordered by name, optionally filtered by part of the name
+#[utoipa::path(
+    get,
+    path = "/api/stops",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("name" = Option<String>, Query, description = "Case-insensitive part of the stop name", example = "viru"),
+        ("offset" = Option<usize>, Query, description = "Stops to skip (default 0)", example = 0),
+        ("limit" = Option<usize>, Query, description = "Page size (1-100, default 50)", example = 50),
+        ("format" = Option<String>, Query, description = "`csv` (or `Accept: text/csv`) for a CSV row per stop, the total in `X-Total-Count`, `msgpack` (or `Accept: application/msgpack`) for MessagePack", example = "csv"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 while the routes and stops data is unchanged"),
+    ),
+    responses(
+        (status = 200, description = "Page of stops with the total count", body = StopPage,
+         headers(("ETag" = String, description = "Validator of the response, changing with the routes and stops data")),
+         content(
+            (StopPage = "application/json"),
+            (String = "text/csv")
+         )),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Invalid offset, limit or format parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn list_stops(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(ListStopsQuery { name, offset }) = Query::from_request(&req)?;
+    let offset = offset.unwrap_or_default();
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(50);
+    let encoding = negotiate(
+        &req,
+        Query::<FormatQuery>::from_request(&req)?
+            .0
+            .format
+            .as_deref(),
+        &[Encoding::Json, Encoding::Csv, Encoding::MessagePack],
+    )?;
+    let service = TransportService::get_service();
+    let page = service
+        .list_stops(&ctx.env, name.as_deref().map(str::trim), offset, limit)
+        .await?;
+    if encoding == Encoding::Csv {
+        let mut response = csv::stops_csv(&page.stops).into_response()?;
+        response
+            .headers_mut()
+            .set("X-Total-Count", &page.total.to_string())?;
+        return Ok(response);
+    }
+    respond_as(&req, &ctx.env, &page, encoding).await
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    name: String,
+    #[serde(rename = "type")]
+    route_type: Option<TransportType>,
+}
+
+impl Validate for SearchQuery {}
+
+/// Search stops by name
+///
+/// Returns stops whose name contains the query, or is within a couple of typos of it, optionally only those served by a transport type
+#[utoipa::path(
+    get,
+    path = "/api/stops/search",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("name" = String, Query, description = "Case-insensitive part of the stop name", example = "viru"),
+        ("type" = Option<String>, Query, description = "Only stops served by this transport type", example = "tram"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of results (1-100, default 20)", example = 20),
+    ),
+    responses(
+        (status = 200, description = "Matching stops, whole-name and prefix matches first, followed by stops matched by a historical name, then near misses by number of typos", body = Vec<StopSearchResult>),
+        (status = 400, description = "Missing name or invalid limit parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn search_stops(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(SearchQuery { name, route_type }) = Query::from_request(&req)?;
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(20);
+    let service = TransportService::get_service();
+    let results = service
+        .search_stops(
+            &ctx.env,
+            name.trim(),
+            route_type.as_ref().map(TransportType::as_str),
+            limit,
+        )
+        .await?;
+    respond(&req, &ctx.env, &results).await
+}
+
+#[derive(Deserialize)]
+struct ZoneQuery {
+    zone: String,
+    #[serde(rename = "type")]
+    route_type: Option<TransportType>,
+}
+
+impl Validate for ZoneQuery {}
+
+/// Get the stops of a zone
+///
+/// Returns the stops stops.txt places in an area of the city, optionally only those served by a transport type
+#[utoipa::path(
+    get,
+    path = "/api/stops/zone",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("zone" = String, Query, description = "Area of the stops, ignoring case and diacritics", example = "Kesklinn"),
+        ("type" = Option<String>, Query, description = "Only stops served by this transport type", example = "tram"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of results (1-100, default 20)", example = 20),
+    ),
+    responses(
+        (status = 200, description = "Stops of the zone ordered by name, empty for an unknown zone", body = Vec<StopSearchResult>),
+        (status = 400, description = "Missing zone or invalid limit parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn get_zone_stops(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(ZoneQuery { zone, route_type }) = Query::from_request(&req)?;
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(20);
+    let service = TransportService::get_service();
+    let results = service
+        .stops_in_zone(
+            &ctx.env,
+            zone.trim(),
+            route_type.as_ref().map(TransportType::as_str),
+            limit,
+        )
+        .await?;
+    respond(&req, &ctx.env, &results).await
+}
+
+#[derive(Deserialize)]
+struct AutocompleteQuery {
+    q: String,
+}
+
+impl Validate for AutocompleteQuery {}
+
+/// Autocomplete stop names
+///
+/// Returns distinct stop names with a word starting with the typed text, ignoring case and diacritics
+#[utoipa::path(
+    get,
+    path = "/api/stops/autocomplete",
+    params(
+        ("q" = String, Query, description = "Typed text", example = "vir"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of names (1-100, default 10)", example = 10),
+    ),
+    responses(
+        (status = 200, description = "Names starting with the text first, then names with a later word starting with it", body = Vec<StopSuggestion>),
+        (status = 400, description = "Missing q or invalid limit parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn autocomplete_stops(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(AutocompleteQuery { q: prefix }) = Query::from_request(&req)?;
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(10);
+    let service = TransportService::get_service();
+    let suggestions = service.autocomplete_stops(&ctx.env, &prefix, limit).await?;
+    respond(&req, &ctx.env, &suggestions).await
+}
+
+/// Largest search radius of the nearby stops, in meters.
+const MAX_NEARBY_RADIUS_M: i32 = 5000;
+
+#[derive(Deserialize)]
+struct NearbyQuery {
+    lat: BoundedFloat<-90, 90>,
+    lon: BoundedFloat<-180, 180>,
+    radius: Option<BoundedFloat<1, MAX_NEARBY_RADIUS_M>>,
+}
+
+impl Validate for NearbyQuery {}
+
+/// Find nearby stops
+///
+/// Returns the stops within a radius of a point, closest first
+#[utoipa::path(
+    get,
+    path = "/api/stops/nearby",
+    params(
+        ("fields" = Option<String>, Query, description = "Comma-separated members kept in each stop object, `id` always (also `fields[stops]`)", example = "id,name"),
+        ("lat" = f64, Query, description = "Latitude in WGS84 degrees", example = 59.437),
+        ("lon" = f64, Query, description = "Longitude in WGS84 degrees", example = 24.7536),
+        ("radius" = Option<f64>, Query, description = "Search radius in meters (1-5000, default 500)", example = 500),
+        ("limit" = Option<usize>, Query, description = "Maximum number of results (1-100, default 20)", example = 20),
+    ),
+    responses(
+        (status = 200, description = "Stops ordered by distance", body = Vec<NearbyStop>),
+        (status = 400, description = "Missing or invalid lat, lon, radius or limit parameter", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn nearby_stops(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(NearbyQuery { lat, lon, radius }) = Query::from_request(&req)?;
+    let (lat, lon) = (lat.0, lon.0);
+    let radius = radius.map_or(500.0, |radius| radius.0);
+    let limit = Query::<LimitQuery>::from_request(&req)?.0.limit_or(20);
+    let service = TransportService::get_service();
+    let results = service
+        .nearby_stops(&ctx.env, lat, lon, radius, limit)
+        .await?;
+    respond(&req, &ctx.env, &results).await
+}
+
+#[derive(Deserialize)]
+struct ResolveQuery {
+    name: String,
+}
+
+impl Validate for ResolveQuery {}
+
+/// Resolve a stop name
+///
+/// Returns the stops currently named, or formerly known as, exactly the given name
+#[utoipa::path(
+    get,
+    path = "/api/stops/resolve",
+    params(
+        ("name" = String, Query, description = "Current or historical stop name, case-insensitive", example = "Viru väljak"),
+    ),
+    responses(
+        (status = 200, description = "Stops with that name", body = Vec<StopSearchResult>),
+        (status = 400, description = "Missing name parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No stop known by that name", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn resolve_stop(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(ResolveQuery { name }) = Query::from_request(&req)?;
+    let service = TransportService::get_service();
+    let results = service.resolve_stop_name(&ctx.env, &name).await?;
+    if results.is_empty() {
+        return coded_error_response(ErrorCode::UnknownStop, "stop name not found", 404);
+    }
+    respond(&req, &ctx.env, &results).await
+}
+
+/// Get stop groups
+///
+/// Returns every stop name with the physical stops sharing it
+#[utoipa::path(
+    get,
+    path = "/api/stop-groups",
+    responses(
+        (status = 200, description = "Stop groups ordered by name", body = Vec<StopGroup>)
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn get_stop_groups(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let service = TransportService::get_service();
+    let groups = service.get_stop_groups(&ctx.env).await?;
+    respond(&req, &ctx.env, &groups).await
+}
+
+/// Get a stop group
+///
+/// Returns the physical stops sharing a name, ignoring case and diacritics
+#[utoipa::path(
+    get,
+    path = "/api/stop-groups/{name}",
+    params(
+        ("name" = String, Path, description = "Stop name (URL encoded)", example = "Viru keskus")
+    ),
+    responses(
+        (status = 200, description = "The stops with that name", body = StopGroup),
+        (status = 400, description = "Invalid name parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No stop with that name", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Stops"
+)]
+pub(crate) async fn get_stop_group(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Path(NameParam { name: name_raw }) = Path::from_context(&ctx)?;
+    let name = match urlencoding::decode(name_raw) {
+        Ok(name) if !name.trim().is_empty() => name.to_string(),
+        _ => return error_response("invalid name", 400),
+    };
+    let service = TransportService::get_service();
+    match service.get_stop_group(&ctx.env, &name).await? {
+        Some(group) => respond(&req, &ctx.env, &group).await,
+        None => coded_error_response(ErrorCode::UnknownStopGroup, "stop group not found", 404),
+    }
+}
+
+/// Get arrivals for a stop group
+///
+/// Returns the real-time arrivals of all stops sharing a name merged, so the side of the street doesn't matter
+#[utoipa::path(
+    get,
+    path = "/api/stop-groups/{name}/arrivals",
+    params(
+        ("fields[arrivals]" = Option<String>, Query, description = "Comma-separated members kept in each arrival object", example = "time,inSeconds"),
+        ("name" = String, Path, description = "Stop name (URL encoded)", example = "Viru keskus"),
+        ("max_minutes" = Option<u32>, Query, description = "Only arrivals within this many minutes (1-1440)", example = 30),
+        ("per_route" = Option<usize>, Query, description = "Only the next N arrivals of each route (1-100)", example = 2),
+        ("format" = Option<String>, Query, description = "`flat` for one time-sorted list of FlatArrival per stop instead of the type/number map, `csv` (or `Accept: text/csv`) for a CSV row per arrival, `msgpack` (or `Accept: application/msgpack`) for MessagePack", example = "flat"),
+    ),
+    responses(
+        (status = 200, description = "Merged arrivals of the stops with that name", body = StopGroupArrivals,
+         headers(("X-Arrivals-Stale" = String, description = "`true` when served from expired arrivals while the upstream is paused"))),
+        (status = 400, description = "Invalid name or filter parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No stop with that name", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching the group's arrivals failed with no earlier ones cached", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Arrivals upstream paused after repeated failures and no cached arrivals", body = Problem, content_type = "application/problem+json",
+         headers(("Retry-After" = u32, description = "Seconds until the upstream is tried again")))
+    ),
+    tag = "Arrivals"
+)]
+pub(crate) async fn get_stop_group_arrivals(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response> {
+    let Path(NameParam { name: name_raw }) = Path::from_context(&ctx)?;
+    let name = match urlencoding::decode(name_raw) {
+        Ok(name) if !name.trim().is_empty() => name.to_string(),
+        _ => return error_response("invalid name", 400),
+    };
+    let service = TransportService::get_service();
+    let Some(group) = service.get_stop_group(&ctx.env, &name).await? else {
+        return coded_error_response(ErrorCode::UnknownStopGroup, "stop group not found", 404);
+    };
+    let mut siri_ids = group
+        .stops
+        .iter()
+        .map(|stop| stop.siri_id.clone())
+        .collect::<Vec<String>>();
+    siri_ids.sort_unstable();
+    siri_ids.dedup();
+    let filter = ArrivalsFilter::from(Query::<ArrivalsFilterQuery>::from_request(&req)?.0);
+    let (format, encoding) = get_arrivals_format(&req, &GROUP_ARRIVALS_ENCODINGS)?;
+    match lookup_stop_arrivals(&ctx.env, siri_ids).await? {
+        ArrivalsLookup::Found { stops, stale } => {
+            if stops
+                .iter()
+                .all(|stop| stop.status == StopArrivalsStatus::UpstreamError)
+            {
+                return error_response("arrivals upstream failed", 502);
+            }
+            let stops = stops
+                .into_iter()
+                .filter_map(|stop| stop.data)
+                .collect::<Vec<Rc<StopArrivals>>>();
+            let cache_age_secs = arrivals_age_secs(stops.iter().map(Rc::as_ref));
+            let mut merged = StopGroupArrivals::merge(group.name, &stops);
+            if !filter.is_noop() {
+                // per_route counts across the whole group, so filter after merging
+                merged.arrivals = filter.filter_routes(&merged.arrivals);
+            }
+            let mut response = match format {
+                _ if encoding == Encoding::Csv => {
+                    let mut writer = csv::arrivals_csv();
+                    csv::push_stop_arrivals(
+                        &mut writer,
+                        &merged.stop_ids.join(" "),
+                        &merged.name,
+                        StopArrivalsStatus::Ok,
+                        Some(&merged.arrivals),
+                    );
+                    writer.into_response()?
+                }
+                ArrivalsFormat::Nested => {
+                    respond_with_age(&req, &ctx.env, &merged, encoding, cache_age_secs).await?
+                }
+                ArrivalsFormat::Flat => {
+                    let flat = FlatStopGroupArrivals::from(&merged);
+                    respond_with_age(&req, &ctx.env, &flat, encoding, cache_age_secs).await?
+                }
+            };
+            if stale {
+                response.headers_mut().set("X-Arrivals-Stale", "true")?;
+            }
+            set_freshness(
+                &mut response,
+                Config::get().arrivals_cache_ttl_secs,
+                cache_age_secs,
+            )?;
+            Ok(response)
+        }
+        ArrivalsLookup::Unavailable(retry_after) => {
+            arrivals_unavailable(retry_after)?.into_response()
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Live vehicle positions, of all routes, of one route and of a single vehicle.
+
+use tlt_stops_core::geo;
+
+use crate::caches::*;
+use crate::config::Config;
+use crate::extract::{FormatQuery, IdParam, Path, Query, RouteSelector, Validate};
+use crate::handlers::routes::UNRESOLVED_STOP_NAME;
+use crate::models::*;
+use crate::profiles::respond_with_age;
+use crate::services::*;
+use crate::str_utils::resolve_vehicle_direction;
+use crate::{
+    Encoding, cached_unknown_route, coded_error_response, negotiate, set_freshness, unknown_route,
+    unknown_route_response,
+};
+use serde::Deserialize;
+use worker::*;
+
+#[derive(Deserialize)]
+struct VehiclesQuery {
+    #[serde(rename = "type")]
+    route_type: Option<TransportType>,
+    route: Option<String>,
+}
+
+impl Validate for VehiclesQuery {}
+
+/// Get live vehicle positions
+///
+/// Returns the vehicles of the upstream gps.txt feed, refreshed every few seconds
+#[utoipa::path(
+    get,
+    path = "/api/vehicles",
+    params(
+        ("type" = Option<String>, Query, description = "Only vehicles of this transport type", example = "bus"),
+        ("route" = Option<String>, Query, description = "Only vehicles of this route number", example = "40"),
+        ("format" = Option<String>, Query, description = "`geojson` (or `Accept: application/geo+json`) for a FeatureCollection of Point features to overlay on a map, `msgpack` (or `Accept: application/msgpack`) for MessagePack", example = "geojson"),
+    ),
+    responses(
+        (status = 200, description = "Vehicle positions", body = Vec<VehiclePosition>,
+         content(
+            (Vec<VehiclePosition> = "application/json"),
+            (VehicleFeatureCollection = "application/geo+json")
+         )),
+        (status = 400, description = "Invalid format parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching gps.txt failed", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "gps.txt didn't load within the upstream timeout", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Vehicles"
+)]
+pub(crate) async fn get_vehicles(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Query(VehiclesQuery { route_type, route }) = Query::from_request(&req)?;
+    let encoding = negotiate(
+        &req,
+        Query::<FormatQuery>::from_request(&req)?
+            .0
+            .format
+            .as_deref(),
+        &[Encoding::Json, Encoding::GeoJson, Encoding::MessagePack],
+    )?;
+    let vehicles = TransportService::get_service()
+        .get_vehicle_positions()
+        .await?;
+    let vehicles = vehicles
+        .iter()
+        .filter(|vehicle| {
+            route_type
+                .as_ref()
+                .is_none_or(|route_type| &vehicle.r#type == route_type)
+        })
+        .filter(|vehicle| route.as_ref().is_none_or(|route| &vehicle.number == route));
+    if encoding == Encoding::GeoJson {
+        let features = vehicles.collect::<VehicleFeatureCollection>();
+        let response = respond_with_age(&req, &ctx.env, &features, encoding, vehicles_age_secs());
+        return with_vehicles_freshness(response.await?);
+    }
+    let vehicles = vehicles.collect::<Vec<&VehiclePosition>>();
+    let response = respond_with_age(&req, &ctx.env, &vehicles, encoding, vehicles_age_secs());
+    with_vehicles_freshness(response.await?)
+}
+
+/// Stops listed by the vehicle endpoints ahead of a vehicle.
+const MAX_NEXT_STOPS: usize = 5;
+
+/// Track a vehicle
+///
+/// Returns a vehicle's live position with the route direction it runs and its next stops, matched from its destination and position along the route
+#[utoipa::path(
+    get,
+    path = "/api/vehicles/{id}",
+    params(
+        ("id" = String, Path, description = "Vehicle ID", example = "1359"),
+    ),
+    responses(
+        (status = 200, description = "The vehicle and where it's heading", body = VehicleDetails),
+        (status = 404, description = "Vehicle not in the live feed", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching gps.txt failed", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "gps.txt didn't load within the upstream timeout", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Vehicles"
+)]
+pub(crate) async fn get_vehicle(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Path(IdParam { id: vehicle_id }) = Path::from_context(&ctx)?;
+    let service = TransportService::get_service();
+    let vehicles = service.get_vehicle_positions().await?;
+    let Some(vehicle) = vehicles.iter().find(|vehicle| vehicle.id == vehicle_id) else {
+        return coded_error_response(ErrorCode::UnknownVehicle, "vehicle not found", 404);
+    };
+    let route_map = service.get_route_map(&ctx.env).await?;
+    let stop_map = service.get_stop_map(&ctx.env).await?;
+    let route_direction = route_map
+        .get(vehicle.r#type.as_str())
+        .and_then(|routes| routes.get(&vehicle.number))
+        .zip(vehicle.direction.as_deref())
+        .and_then(|(route, destination)| {
+            let direction = resolve_vehicle_direction(&route.directions, destination, &stop_map)?;
+            Some((direction, &route.directions[direction]))
+        });
+    let next_stops = route_direction
+        .and_then(|(_, stops)| {
+            let positions = stops
+                .iter()
+                .map(|stop_id| {
+                    let stop = stop_map.get(stop_id)?;
+                    stop.lat.zip(stop.lon)
+                })
+                .collect::<Vec<Option<(f64, f64)>>>();
+            let next = geo::next_stop_index(&positions, vehicle.lat, vehicle.lon)?;
+            Some(&stops[next..])
+        })
+        .unwrap_or_default()
+        .iter()
+        .take(MAX_NEXT_STOPS)
+        .map(|stop_id| {
+            let name = TransportService::get_stop_name_by_id(stop_id, &stop_map)
+                .map_or_else(|| UNRESOLVED_STOP_NAME.to_string(), |name| name.to_string());
+            StopResponse(stop_id.clone(), name)
+        })
+        .collect();
+    let details = VehicleDetails {
+        vehicle,
+        route_direction: route_direction.map(|(direction, _)| direction.as_str()),
+        next_stops,
+    };
+    let encoding = negotiate(&req, None, &[Encoding::Json, Encoding::MessagePack])?;
+    let response = respond_with_age(&req, &ctx.env, &details, encoding, vehicles_age_secs());
+    with_vehicles_freshness(response.await?)
+}
+
+/// Get the vehicles of a route
+///
+/// Returns the vehicles currently operating a route grouped by the direction they run, so an empty route shows the line isn't running
+#[utoipa::path(
+    get,
+    path = "/api/types/{type}/routes/{number}/vehicles",
+    params(
+        ("type" = String, Path, description = "Transport type, case-insensitive and by alias such as trol", example = "bus"),
+        ("number" = String, Path, description = "Route number", example = "40"),
+    ),
+    responses(
+        (status = 200, description = "Live vehicles of the route per direction", body = RouteVehicles),
+        (status = 404, description = "Type or route number not found", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "Fetching gps.txt failed", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "gps.txt didn't load within the upstream timeout", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "Vehicles"
+)]
+pub(crate) async fn get_route_vehicles(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let Path(RouteSelector {
+        route_type,
+        number: route_number,
+    }) = Path::from_context(&ctx)?;
+    let route_type = route_type.as_str();
+    if let Some(code) = cached_unknown_route(route_type, Some(route_number)) {
+        return unknown_route_response(code)?.into_response();
+    }
+    let service = TransportService::get_service();
+    let route_map = service.get_route_map(&ctx.env).await?;
+    let Some(routes) = route_map.get(route_type) else {
+        return unknown_route(route_type.to_string(), ErrorCode::UnknownType)?.into_response();
+    };
+    let Some(route) = routes.get(route_number) else {
+        return unknown_route(
+            format!("{}/{}", route_type, route_number),
+            ErrorCode::UnknownRoute,
+        )?
+        .into_response();
+    };
+    let vehicles = service.get_vehicle_positions().await?;
+    let stop_map = service.get_stop_map(&ctx.env).await?;
+    let mut route_vehicles = RouteVehicles {
+        r#type: route_type,
+        number: route_number,
+        directions: route
+            .directions
+            .keys()
+            .map(|direction| (direction.as_str(), Vec::new()))
+            .collect(),
+        unmatched: Vec::new(),
+    };
+    for vehicle in vehicles
+        .iter()
+        .filter(|vehicle| vehicle.r#type.as_str() == route_type && vehicle.number == route_number)
+    {
+        let direction = vehicle.direction.as_deref().and_then(|destination| {
+            resolve_vehicle_direction(&route.directions, destination, &stop_map)
+        });
+        match direction.and_then(|direction| route_vehicles.directions.get_mut(direction.as_str()))
+        {
+            Some(direction_vehicles) => direction_vehicles.push(vehicle),
+            None => route_vehicles.unmatched.push(vehicle),
+        }
+    }
+    let encoding = negotiate(&req, None, &[Encoding::Json, Encoding::MessagePack])?;
+    let response = respond_with_age(
+        &req,
+        &ctx.env,
+        &route_vehicles,
+        encoding,
+        vehicles_age_secs(),
+    );
+    with_vehicles_freshness(response.await?)
+}
+
+/// Seconds since gps.txt was fetched, for the `?envelope=true` metadata.
+fn vehicles_age_secs() -> Option<u32> {
+    Caches::get_cache().vehicles.age_secs()
+}
+
+fn with_vehicles_freshness(mut response: Response) -> Result<Response> {
+    let ttl_secs = Config::get().vehicles_cache_ttl_secs;
+    set_freshness(&mut response, ttl_secs, vehicles_age_secs())?;
+    Ok(response)
+}
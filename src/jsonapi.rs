@@ -0,0 +1,193 @@
+//! JSON:API documents of the routes, stops and arrivals, answered instead of the plain JSON when
+//! `Accept` asks for `application/vnd.api+json`. Routes relate to their stops and stops to their
+//! arrivals, which are sent as `included` resources.
+
+use chrono::Utc;
+use serde_json::{Map, Value, json};
+
+use crate::models::{RouteGroup, StopArrivals, StopArrivalsResult, StopObject, flat_arrivals};
+
+fn document(data: Value, included: Vec<Value>) -> Value {
+    let mut document = json!({ "jsonapi": { "version": "1.1" }, "data": data });
+    if !included.is_empty() {
+        document["included"] = Value::Array(included);
+    }
+    document
+}
+
+fn identifier(resource_type: &str, id: &str) -> Value {
+    json!({ "type": resource_type, "id": id })
+}
+
+fn arrivals_link(stop_id: &str) -> Value {
+    json!({ "links": { "related": format!("/api/v1/stops/{}/arrivals", urlencoding::encode(stop_id)) } })
+}
+
+/// `type` and `id` are reserved member names, the transport type is `transportType` instead.
+fn attributes(value: Value) -> Map<String, Value> {
+    let Value::Object(mut attributes) = value else {
+        return Map::new();
+    };
+    if let Some(route_type) = attributes.remove("type") {
+        attributes.insert("transportType".to_string(), route_type);
+    }
+    attributes.remove("id");
+    attributes
+}
+
+/// Route resources, `<type>:<number>`, related to the stops of all their directions.
+pub fn routes_document(routes: &[&RouteGroup]) -> Value {
+    let data = routes
+        .iter()
+        .map(|route| {
+            let mut directions = route.directions.keys().collect::<Vec<&String>>();
+            directions.sort_unstable();
+            let mut stop_ids = Vec::new();
+            for direction in directions {
+                for stop_id in &route.directions[direction] {
+                    if !stop_ids.contains(&stop_id) {
+                        stop_ids.push(stop_id);
+                    }
+                }
+            }
+            json!({
+                "type": "routes",
+                "id": format!("{}:{}", route.r#type, route.number),
+                "attributes": { "transportType": route.r#type, "number": route.number },
+                "relationships": {
+                    "stops": {
+                        "data": stop_ids
+                            .into_iter()
+                            .map(|stop_id| identifier("stops", stop_id))
+                            .collect::<Vec<Value>>()
+                    }
+                },
+                "links": {
+                    "self": format!(
+                        "/api/v1/types/{}/routes/{}/directions",
+                        urlencoding::encode(route.r#type.as_str()),
+                        urlencoding::encode(&route.number)
+                    )
+                }
+            })
+        })
+        .collect();
+    document(Value::Array(data), Vec::new())
+}
+
+/// Stop resources of a stop listing, related to their arrivals by link, with the ids of those
+/// whose name couldn't be resolved as the meta's `missingNames` and the attributes the arrivals
+/// take as the stop's id as its `arrivalsAccept`.
+pub fn stops_document(stops: &[StopObject], missing_names: &[&str]) -> Value {
+    let data = stops
+        .iter()
+        .map(|stop| {
+            json!({
+                "type": "stops",
+                "id": stop.id,
+                "attributes": attributes(serde_json::to_value(stop).unwrap_or_default()),
+                "relationships": { "arrivals": arrivals_link(stop.id) }
+            })
+        })
+        .collect();
+    let mut document = document(Value::Array(data), Vec::new());
+    document["meta"] = json!({
+        "missingNames": missing_names,
+        "arrivalsAccept": ["id", "attributes.siriId"]
+    });
+    document
+}
+
+/// The stop resource with its arrivals appended to `included`, `status` is set for the
+/// results of an arrivals request.
+fn stop_with_arrivals(
+    stop: &StopArrivals,
+    status: Option<Value>,
+    included: &mut Vec<Value>,
+) -> Value {
+    let mut linkage = Vec::new();
+    for arrival in flat_arrivals(&stop.arrivals, Utc::now()) {
+        let id = format!(
+            "{}:{}:{}:{}",
+            stop.id, arrival.r#type, arrival.number, arrival.time
+        );
+        linkage.push(identifier("arrivals", &id));
+        included.push(json!({
+            "type": "arrivals",
+            "id": id,
+            "attributes": attributes(serde_json::to_value(&arrival).unwrap_or_default()),
+            "relationships": { "stop": { "data": identifier("stops", &stop.id) } }
+        }));
+    }
+    let mut attributes = attributes(json!({ "name": stop.name, "lat": stop.lat, "lon": stop.lon }));
+    if let Some(status) = status {
+        attributes.insert("status".to_string(), status);
+    }
+    let mut relationships = arrivals_link(&stop.id);
+    relationships["data"] = Value::Array(linkage);
+    json!({
+        "type": "stops",
+        "id": stop.id,
+        "attributes": attributes,
+        "relationships": { "arrivals": relationships }
+    })
+}
+
+/// One stop resource per arrivals result, those without arrivals carrying only their status.
+pub fn arrivals_document(stops: &[StopArrivalsResult]) -> Value {
+    let mut included = Vec::new();
+    let data = stops
+        .iter()
+        .map(|result| {
+            let status = serde_json::to_value(result.status).unwrap_or_default();
+            match &result.data {
+                Some(stop) => stop_with_arrivals(stop, Some(status), &mut included),
+                None => json!({
+                    "type": "stops",
+                    "id": result.id,
+                    "attributes": { "status": status }
+                }),
+            }
+        })
+        .collect();
+    document(Value::Array(data), included)
+}
+
+/// The stop resource of a single stop's arrivals.
+pub fn stop_arrivals_document(stop: &StopArrivals) -> Value {
+    let mut included = Vec::new();
+    let data = stop_with_arrivals(stop, None, &mut included);
+    document(data, included)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_unresolved_stops_in_the_meta() {
+        let stop = |id, name| StopObject {
+            id,
+            siri_id: None,
+            name,
+            sequence: 0,
+            lat: None,
+            lon: None,
+        };
+        let stops = [
+            stop("1001", "Kopli"),
+            stop("1003", "Can't resolve stop name"),
+        ];
+        let document = stops_document(&stops, &["1003"]);
+        assert_eq!(document["meta"]["missingNames"], json!(["1003"]));
+        assert_eq!(
+            document["meta"]["arrivalsAccept"],
+            json!(["id", "attributes.siriId"])
+        );
+        assert_eq!(document["data"][1]["id"], "1003");
+        assert_eq!(
+            stops_document(&stops[..1], &[])["meta"]["missingNames"],
+            json!([])
+        );
+    }
+}
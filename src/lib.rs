@@ -1,14 +1,56 @@
+mod api_keys;
+mod arrivals_object;
+mod arrivals_socket_object;
+mod bindings;
+mod board;
 mod caches;
-mod models;
+pub mod clock;
+mod config;
+mod crypto;
+mod csv;
+mod extract;
+mod graphql;
+mod gtfs_rt;
+mod handlers;
+mod jsonapi;
+mod logging;
+mod metrics;
+mod middleware;
+mod msgpack;
+#[cfg(feature = "native")]
+pub mod native;
+mod profiles;
+mod push;
+mod quota_object;
+mod rate_limit;
+mod replay;
+mod reply;
 mod services;
-mod str_utils;
+mod snapshots;
+pub mod upstream;
+mod webhooks;
+mod yaml;
+
+use tlt_stops_core::{diagnostics, geo, models, str_utils};
 
 use crate::caches::*;
+use crate::config::Config;
+use crate::diagnostics::{MalformedLines, ParseDiagnosticsReport};
+use crate::extract::{FormatQuery, Incoming, Query, RequestError};
+use crate::graphql::GraphQlRequest;
+use crate::handlers::{admin, alerts, arrivals, routes, status, stops, vehicles};
+use crate::logging::log_error;
 use crate::models::*;
+use crate::profiles::EnvelopeMeta;
+use crate::push::{
+    ArrivalAlertCreated, ArrivalAlertRequest, PushSubscription, PushSubscriptionKeys,
+    VapidPublicKey,
+};
+use crate::replay::{ReplayMismatch, ReplayReport};
+use crate::reply::Reply;
 use crate::services::*;
-use crate::str_utils::splits_commas;
+use crate::webhooks::{WebhookCondition, WebhookCreated, WebhookEvent};
 use serde::Serialize;
-use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use utoipa::OpenApi;
 use worker::*;
@@ -18,34 +60,286 @@ use worker::*;
     info(
         title = "TLT Stops API",
         version = "0.1.0",
-        description = "API for Tallinn public transport stops and routes information"
+        description = "API for Tallinn public transport stops and routes information. `?envelope=true` wraps the JSON responses as `{\"data\", \"meta\"}`, the meta being an EnvelopeMeta telling how current the data is. Errors are `application/problem+json` problems whose `code` is a stable ErrorCode to branch on. Deployments with API keys take them as `X-API-Key`, keyed responses carry `X-RateLimit-Remaining` and 429 with `Retry-After` once over the key's limits"
     ),
     paths(
-        health_check,
-        get_types,
-        get_routes_by_type,
-        get_directions_by_route_type_number,
-        get_stops_by_route_type_number_direction,
-        get_stop_arrivals,
+        status::api_index,
+        status::health_check,
+        status::get_ready,
+        status::get_version,
+        routes::get_types,
+        routes::get_routes_by_type,
+        routes::get_directions_by_route_type_number,
+        routes::get_stops_by_route_type_number_direction,
+        routes::get_route_shape,
+        arrivals::get_stop_arrivals,
+        arrivals::stream_stop_arrivals,
+        arrivals::arrivals_socket,
+        arrivals::get_single_stop_arrivals,
+        arrivals::get_stop_board,
+        arrivals::get_raw_siri,
+        arrivals::get_gtfs_rt_trip_updates,
+        arrivals::get_gtfs_rt_vehicle_positions,
+        vehicles::get_vehicles,
+        vehicles::get_vehicle,
+        vehicles::get_route_vehicles,
+        handlers::graphql::graphql_query,
+        stops::get_stop_hubs,
+        stops::list_stops,
+        stops::search_stops,
+        stops::get_zone_stops,
+        stops::autocomplete_stops,
+        stops::nearby_stops,
+        stops::resolve_stop,
+        stops::get_stop_groups,
+        stops::get_stop_group,
+        stops::get_stop_group_arrivals,
+        admin::get_cache_stats,
+        admin::get_metrics,
+        admin::get_parse_diagnostics,
+        admin::purge_caches,
+        admin::replay_recordings,
+        alerts::get_vapid_public_key,
+        alerts::create_arrival_alert,
+        alerts::delete_arrival_alert,
+        admin::create_webhook,
+        admin::delete_webhook,
     ),
     components(schemas(
-        HealthStatus,
+        StopSearchResult,
+        StopPage,
+        StopSuggestion,
+        StopGroup,
+        StopGroupArrivals,
+        NearbyStop,
+        status::HealthStatus,
+        status::ReadyStatus,
+        status::VersionInfo,
+        status::DataSnapshot,
+        DependencyHealth,
+        CacheStats,
+        admin::CachePurgeResult,
+        ParseDiagnosticsReport,
+        MalformedLines,
+        ReplayReport,
+        ReplayMismatch,
+        StopHub,
+        RouteRef,
         StopResponse,
+        StopObject,
+        StopListing,
+        RouteStopsListing,
+        LinkedType,
+        TypeLinks,
+        LinkedRoute,
+        RouteLinks,
+        LinkedDirection,
+        DirectionLinks,
+        DirectionDetails,
+        LinkedStop,
+        StopLinks,
+        RouteShape,
+        LineString,
+        RouteShapeProperties,
         PostArrivalsResponse,
+        Problem,
+        ErrorCode,
+        StopArrivalsResult,
+        StopArrivalsStatus,
+        InvalidStopsResponse,
+        UnknownDirectionResponse,
         StopArrivals,
         StopArrival,
-        Arrival
+        Arrival,
+        FlatArrival,
+        VehiclePosition,
+        VehicleDetails,
+        RouteVehicles,
+        VehicleFeatureCollection,
+        VehicleFeature,
+        VehicleProperties,
+        Point,
+        PushSubscription,
+        PushSubscriptionKeys,
+        ArrivalAlertRequest,
+        ArrivalAlertCreated,
+        VapidPublicKey,
+        EnvelopeMeta,
+        WebhookCondition,
+        WebhookCreated,
+        WebhookEvent,
+        GraphQlRequest
     ))
 )]
 struct ApiDoc;
 
-macro_rules! get_require_param {
-    ($ctx:expr, $name:literal) => {{
-        match $ctx.param($name) {
-            Some(s) if !s.is_empty() => s,
-            _ => return Response::error(concat!("missing ", $name, " query param"), 400),
+/// Response body encodings, picked per request by [`negotiate`] among those a handler supports.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+    Json,
+    MessagePack,
+    Csv,
+    GeoJson,
+    JsonApi,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 5] = [
+        Encoding::Json,
+        Encoding::MessagePack,
+        Encoding::Csv,
+        Encoding::GeoJson,
+        Encoding::JsonApi,
+    ];
+
+    /// `?format=` value selecting it.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Csv => "csv",
+            Encoding::GeoJson => "geojson",
+            Encoding::JsonApi => "jsonapi",
+        }
+    }
+
+    /// `Accept` media types selecting it, the first is the `Content-Type` it's answered with.
+    pub(crate) fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Encoding::Json => &["application/json"],
+            Encoding::MessagePack => &["application/msgpack", "application/x-msgpack"],
+            Encoding::Csv => &["text/csv"],
+            Encoding::GeoJson => &["application/geo+json"],
+            Encoding::JsonApi => &["application/vnd.api+json"],
         }
-    }};
+    }
+}
+
+/// The encoding named by `format` (the handler's `?format=`), else the `Accept` header's most
+/// preferred supported one, else the first of `supported`.
+pub(crate) fn negotiate(
+    req: &dyn Incoming,
+    format: Option<&str>,
+    supported: &[Encoding],
+) -> Result<Encoding> {
+    if let Some(format) = format {
+        return Encoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.name() == format && supported.contains(encoding))
+            .ok_or_else(|| {
+                let names = supported.iter().map(|encoding| encoding.name());
+                RequestError::InvalidParameter(format!(
+                    "invalid format provided ({})",
+                    names.collect::<Vec<&str>>().join(", ")
+                ))
+                .into()
+            });
+    }
+    let Some(accept) = req.header("Accept")? else {
+        return Ok(supported[0]);
+    };
+    let mut ranges = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next()?.trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q=")?.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (quality > 0.0).then_some((media_type, quality))
+        })
+        .collect::<Vec<(String, f32)>>();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (media_type, _) in &ranges {
+        if media_type == "*/*" {
+            return Ok(supported[0]);
+        }
+        if let Some(encoding) = supported
+            .iter()
+            .find(|encoding| encoding.media_types().contains(&media_type.as_str()))
+        {
+            return Ok(*encoding);
+        }
+    }
+    Ok(supported[0])
+}
+
+/// Encodings of the stop arrivals endpoints, the nested and flat shapes are given by `?format=`
+/// too.
+const ARRIVALS_ENCODINGS: [Encoding; 4] = [
+    Encoding::Json,
+    Encoding::Csv,
+    Encoding::MessagePack,
+    Encoding::JsonApi,
+];
+
+/// Encodings of the stop group arrivals, which aren't stop resources for JSON:API.
+const GROUP_ARRIVALS_ENCODINGS: [Encoding; 3] =
+    [Encoding::Json, Encoding::Csv, Encoding::MessagePack];
+
+/// Encodings of the route and stop listings.
+const LISTING_ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::JsonApi];
+
+/// `?format=` of the arrivals endpoints, nested by default, with the encoding negotiated among
+/// `supported`.
+fn get_arrivals_format(
+    req: &dyn Incoming,
+    supported: &[Encoding],
+) -> Result<(ArrivalsFormat, Encoding)> {
+    let Query(FormatQuery { format }) = Query::from_request(req)?;
+    match format.as_deref() {
+        None | Some("nested") => Ok((ArrivalsFormat::Nested, negotiate(req, None, supported)?)),
+        Some("flat") => Ok((ArrivalsFormat::Flat, negotiate(req, None, supported)?)),
+        Some(format) => match negotiate(req, Some(format), supported) {
+            Ok(encoding) => Ok((ArrivalsFormat::Nested, encoding)),
+            Err(_) => {
+                let names = supported.iter().map(|encoding| encoding.name());
+                Err(RequestError::InvalidParameter(format!(
+                    "invalid format provided (nested, flat, {})",
+                    names.collect::<Vec<&str>>().join(", ")
+                ))
+                .into())
+            }
+        },
+    }
+}
+
+/// The 404 code of a recent request for this unknown type, or route when a number is given.
+fn cached_unknown_route(route_type: &str, route_number: Option<&str>) -> Option<ErrorCode> {
+    let unknown_routes = &Caches::get_cache().unknown_routes;
+    if let Some(code) = unknown_routes.get(&route_type.to_string()) {
+        return Some(*code);
+    }
+    let key = format!("{}/{}", route_type, route_number?);
+    unknown_routes.get(&key).map(|code| *code)
+}
+
+/// Answers 404 for an unknown type or route, remembering it so repeats skip the route lookups.
+fn unknown_route(key: String, code: ErrorCode) -> Result<Reply> {
+    Caches::get_cache()
+        .unknown_routes
+        .set(key, Rc::new(code))
+        .ok();
+    unknown_route_response(code)
+}
+
+fn unknown_route_response(code: ErrorCode) -> Result<Reply> {
+    let detail = match code {
+        ErrorCode::UnknownType => "type not found",
+        _ => "route number not found",
+    };
+    coded_error_reply(code, detail, 404)
+}
+
+/// Answers 404 for a direction matching none of the route's, listing their names sorted as the
+/// directions endpoint does so the body also gives their indexes.
+fn unknown_direction<'a>(directions: impl IntoIterator<Item = &'a String>) -> Result<Reply> {
+    let mut directions = directions.into_iter().cloned().collect::<Vec<String>>();
+    directions.sort_unstable();
+    problem_reply(&UnknownDirectionResponse {
+        problem: Problem::new(ErrorCode::UnknownDirection, 404, "direction not found"),
+        directions,
+    })
 }
 
 pub enum HttpResponseError {
@@ -65,355 +359,508 @@ impl From<ParsingUpstreamError> for HttpResponseError {
 impl From<ParsingUpstreamError> for worker::Error {
     fn from(error: ParsingUpstreamError) -> Self {
         match error {
-            ParsingUpstreamError::Http(e) => worker::Error::Json((e.to_string(), 502)),
+            ParsingUpstreamError::Http(e) => {
+                problem_error(ErrorCode::UpstreamError, e.to_string(), 502)
+            }
             ParsingUpstreamError::Utf8 => {
                 worker::Error::RustError("UTF-8 parsing error".to_string())
             }
-            ParsingUpstreamError::Error(msg) => worker::Error::Json((msg, 500)),
+            ParsingUpstreamError::Timeout => {
+                problem_error(ErrorCode::UpstreamTimeout, "upstream timed out", 504)
+            }
+            ParsingUpstreamError::Unavailable(_) => {
+                problem_error(ErrorCode::UpstreamUnavailable, "upstream unavailable", 503)
+            }
+            ParsingUpstreamError::Error(msg) => problem_error(ErrorCode::InternalError, msg, 500),
         }
     }
 }
 
-impl From<RequestError> for worker::Error {
-    fn from(error: RequestError) -> Self {
-        match error {
-            RequestError::MissingParameter(msg) | RequestError::InvalidParameter(msg) => {
-                worker::Error::Json((msg, 400))
-            }
-        }
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// A [`Problem`] with the status and `detail`, what every error path answers. Its code is the
+/// status' default, see [`coded_error_response`] for a specific one.
+pub(crate) fn error_response(detail: impl Into<String>, status: u16) -> Result<Response> {
+    error_reply(detail, status)?.into_response()
+}
+
+/// [`error_response`] as a [`Reply`].
+pub(crate) fn error_reply(detail: impl Into<String>, status: u16) -> Result<Reply> {
+    coded_error_reply(ErrorCode::for_status(status), detail, status)
+}
+
+pub(crate) fn coded_error_response(
+    code: ErrorCode,
+    detail: impl Into<String>,
+    status: u16,
+) -> Result<Response> {
+    coded_error_reply(code, detail, status)?.into_response()
+}
+
+pub(crate) fn coded_error_reply(
+    code: ErrorCode,
+    detail: impl Into<String>,
+    status: u16,
+) -> Result<Reply> {
+    problem_reply(&Problem::new(code, status, detail))
+}
+
+/// The error raised with `?` for a problem, its message is the serialized problem which
+/// [`json_error_response`] answers.
+fn problem_error(code: ErrorCode, detail: impl Into<String>, status: u16) -> worker::Error {
+    let problem = Problem::new(code, status, detail);
+    worker::Error::Json((serde_json::to_string(&problem).unwrap_or_default(), status))
+}
+
+/// The response of a `worker::Error::Json`, the problem of [`problem_error`] or a bare message.
+pub(crate) fn json_error_response(message: String, status: u16) -> Result<Response> {
+    json_error_reply(message, status)?.into_response()
+}
+
+pub(crate) fn json_error_reply(message: String, status: u16) -> Result<Reply> {
+    match serde_json::from_str::<Problem>(&message) {
+        Ok(problem) => problem_reply(&problem),
+        Err(_) => error_reply(message, status),
     }
 }
 
-#[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    Router::new()
-        .get("/api/health", health_check)
-        .get("/api/openapi.json", openapi_spec)
-        .get_async("/api/types", get_types)
-        .get_async("/api/types/:type/routes", get_routes_by_type)
-        .get_async(
-            "/api/types/:type/routes/:number/directions",
-            get_directions_by_route_type_number,
-        )
-        .get_async(
-            "/api/types/:type/routes/:number/directions/:direction/stops",
-            get_stops_by_route_type_number_direction,
-        )
-        .get_async("/api/arrivals", get_stop_arrivals)
-        .run(req, env)
-        .await
+fn problem_reply<T: Serialize>(problem: &T) -> Result<Reply> {
+    let status = serde_json::to_value(problem)?["status"]
+        .as_u64()
+        .unwrap_or(500);
+    let body = serde_json::to_vec(problem)?;
+    Ok(Reply::new(PROBLEM_CONTENT_TYPE, body).with_status(status as u16))
 }
 
-/// Serves the OpenAPI specification
-fn openapi_spec(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    let openapi = ApiDoc::openapi();
-    Response::from_json(&openapi)
-}
-
-#[derive(Serialize, utoipa::ToSchema)]
-#[schema(example = json!({
-    "status": "healthy",
-    "timestamp": "2025-10-20T12:00:00Z",
-    "version": "0.1.0"
-}))]
-struct HealthStatus {
-    #[schema(example = "healthy")]
-    status: &'static str,
-    #[schema(example = "2025-10-20T12:00:00Z")]
-    timestamp: String,
-    #[schema(example = "0.1.0")]
-    version: &'static str,
-}
-
-/// Health check endpoint
-///
-/// Returns the current status of the API service
-#[utoipa::path(
-    get,
-    path = "/api/health",
-    responses(
-        (status = 200, description = "Service is healthy", body = HealthStatus)
-    ),
-    tag = "Health"
-)]
-fn health_check(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    Response::from_json(&HealthStatus {
-        status: "healthy",
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        version: env!("CARGO_PKG_VERSION"),
-    })
+/// The problem response with its `instance` set to the request path and its `requestId` and
+/// `traceId`, which the handlers answering it don't have at hand.
+pub(crate) async fn with_problem_context(
+    mut response: Response,
+    path: &str,
+    request_id: Option<&str>,
+) -> Result<Response> {
+    let is_problem = response
+        .headers()
+        .get("Content-Type")?
+        .is_some_and(|content_type| content_type.starts_with(PROBLEM_CONTENT_TYPE));
+    if !is_problem {
+        return Ok(response);
+    }
+    let (status, headers) = (response.status_code(), response.headers().clone());
+    let problem = response
+        .json::<serde_json::Map<String, serde_json::Value>>()
+        .await?;
+    let problem = problem_in_context(problem, path, request_id);
+    Ok(Response::from_json(&problem)?
+        .with_status(status)
+        .with_headers(headers))
 }
 
-/// Get all transport types
-///
-/// Returns a list of all available transport types (e.g., bus, tram, trolleybus)
-#[utoipa::path(
-    get,
-    path = "/api/types",
-    responses(
-        (status = 200, description = "List of transport types", body = Vec<String>,
-         example = json!(["bus", "tram", "trolleybus"]))
-    ),
-    tag = "Routes"
-)]
-async fn get_types(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    let cache = Caches::get_cache();
-    let from_cache = cache.types.get();
-    let types = match from_cache {
-        Some(types) => types,
-        None => {
-            let service = TransportService::get_service();
-            let mut types = service
-                .get_types()
-                .await?
-                .into_iter()
-                .collect::<Vec<String>>();
-            types.sort_unstable();
-            let types = Rc::new(types);
-            cache.types.set(types.clone()).ok();
-            types
+/// The members [`with_problem_context`] adds to a problem, `tlt-serve` having no request ids.
+pub(crate) fn problem_in_context(
+    mut problem: serde_json::Map<String, serde_json::Value>,
+    path: &str,
+    request_id: Option<&str>,
+) -> serde_json::Map<String, serde_json::Value> {
+    problem
+        .entry("instance")
+        .or_insert_with(|| serde_json::Value::from(path));
+    if let Some(request_id) = request_id {
+        problem.insert("requestId".to_string(), request_id.into());
+        if let Some(trace_id) = logging::trace_id() {
+            problem.insert("traceId".to_string(), trace_id.into());
         }
-    };
-    Response::from_json(&types)
+    }
+    problem
 }
 
-/// Get routes by transport type
-///
-/// Returns a list of all route numbers for the specified transport type
-#[utoipa::path(
-    get,
-    path = "/api/types/{type}/routes",
-    params(
-        ("type" = String, Path, description = "Transport type (e.g., bus, tram)", example = "bus")
-    ),
-    responses(
-        (status = 200, description = "List of route numbers", body = Vec<String>,
-         example = json!(["1", "2", "3"])),
-        (status = 404, description = "Transport type not found")
-    ),
-    tag = "Routes"
-)]
-async fn get_routes_by_type(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let route_type = get_require_param!(ctx, "type");
-    let service = TransportService::get_service();
-    let route_map = service.get_route_map().await?;
-    let routes = route_map.get(route_type);
-    match routes {
-        Some(routes) => {
-            let mut routes = routes.keys().collect::<Vec<&String>>();
-            routes.sort_unstable();
-            Response::from_json(&routes)
+impl From<RequestError> for worker::Error {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::MissingParameter(msg) => {
+                problem_error(ErrorCode::MissingParameter, msg, 400)
+            }
+            RequestError::InvalidParameter(msg) => {
+                problem_error(ErrorCode::InvalidParameter, msg, 400)
+            }
+            RequestError::TooManyStops(msg) => problem_error(ErrorCode::TooManyStops, msg, 400),
         }
-        None => Response::error("type not found", 404),
     }
 }
 
-/// Get directions for a specific route
-///
-/// Returns a list of all direction names for the specified route
-#[utoipa::path(
-    get,
-    path = "/api/types/{type}/routes/{number}/directions",
-    params(
-        ("type" = String, Path, description = "Transport type", example = "bus"),
-        ("number" = String, Path, description = "Route number", example = "1")
-    ),
-    responses(
-        (status = 200, description = "List of direction names", body = Vec<String>,
-         example = json!(["Kopli", "Linnahall"])),
-        (status = 404, description = "Transport type or route not found")
-    ),
-    tag = "Routes"
-)]
-async fn get_directions_by_route_type_number(
-    _req: Request,
-    ctx: RouteContext<()>,
-) -> Result<Response> {
-    let route_type = get_require_param!(ctx, "type");
-    let route_number = get_require_param!(ctx, "number");
+/// Versions of the API, served under `/api/<version>`. The unversioned `/api` paths are
+/// deprecated aliases of [`ApiVersion::CURRENT`].
+#[derive(Clone, Copy)]
+enum ApiVersion {
+    V1,
+    /// Stop listings as [`StopObject`]s rather than `[id, name]` pairs, and directions as
+    /// [`DirectionDetails`] rather than names.
+    V2,
+}
 
-    let service = TransportService::get_service();
-    let route_map = service.get_route_map().await?;
+impl ApiVersion {
+    const CURRENT: ApiVersion = ApiVersion::V1;
+    const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::V2];
 
-    let routes = match route_map.get(route_type) {
-        Some(routes) => routes,
-        None => return Response::error("type not found", 404),
-    };
+    fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/api/v1",
+            ApiVersion::V2 => "/api/v2",
+        }
+    }
 
-    let route = match routes.get(route_number) {
-        Some(route) => route,
-        None => return Response::error("route number not found", 404),
-    };
+    /// The version serving `path`, [`ApiVersion::CURRENT`] for the unversioned paths.
+    fn of_path(path: &str) -> ApiVersion {
+        Self::ALL
+            .into_iter()
+            .find(|version| {
+                path.strip_prefix(version.prefix())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .unwrap_or(Self::CURRENT)
+    }
 
-    let mut directions: Vec<&str> = route.directions.keys().map(|s| s.as_str()).collect();
-    directions.sort_unstable();
+    fn stop_shape(self) -> routes::StopShape {
+        match self {
+            ApiVersion::V1 => routes::StopShape::Tuple,
+            ApiVersion::V2 => routes::StopShape::Object,
+        }
+    }
 
-    Response::from_json(&directions)
+    fn direction_details(self) -> bool {
+        matches!(self, ApiVersion::V2)
+    }
 }
 
-/// Get stops for a specific route and direction
-///
-/// Returns a list of stop IDs and names for the specified route and direction
-#[utoipa::path(
-    get,
-    path = "/api/types/{type}/routes/{number}/directions/{direction}/stops",
-    params(
-        ("type" = String, Path, description = "Transport type", example = "bus"),
-        ("number" = String, Path, description = "Route number", example = "1"),
-        ("direction" = String, Path, description = "Direction name (URL encoded)", example = "Kopli")
-    ),
-    responses(
-        (status = 200, description = "List of stops with IDs and names", body = Vec<StopResponse>,
-         example = json!([["1001", "Stop Name 1"], ["1002", "Stop Name 2"]])),
-        (status = 400, description = "Invalid direction parameter"),
-        (status = 404, description = "Transport type, route, or direction not found")
-    ),
-    tag = "Stops"
-)]
-async fn get_stops_by_route_type_number_direction(
-    _req: Request,
-    ctx: RouteContext<()>,
-) -> Result<Response> {
-    let route_type = get_require_param!(ctx, "type");
-    let route_number = get_require_param!(ctx, "number");
-    let direction_raw = get_require_param!(ctx, "direction");
-
-    let service = TransportService::get_service();
-    let route_map = service.get_route_map().await?;
-
-    let routes = match route_map.get(route_type) {
-        Some(routes) => routes,
-        None => return Response::error("type not found", 404),
-    };
+/// The `/api/v1` path an unversioned `/api` path is an alias of, `None` for the versioned
+/// paths and those outside the API.
+pub(crate) fn successor_path(path: &str) -> Option<String> {
+    let rest = path.strip_prefix("/api")?;
+    if !(rest.is_empty() || rest.starts_with('/')) || versioned(rest) {
+        return None;
+    }
+    Some(format!("{}{}", ApiVersion::CURRENT.prefix(), rest))
+}
 
-    let route = match routes.get(route_number) {
-        Some(route) => route,
-        None => return Response::error("route number not found", 404),
+/// Whether the path after `/api` starts with a `/v<number>` segment.
+fn versioned(rest: &str) -> bool {
+    let Some(rest) = rest.strip_prefix('/') else {
+        return false;
     };
+    let version = rest.split('/').next().unwrap_or_default();
+    version.strip_prefix('v').is_some_and(|number| {
+        !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit())
+    })
+}
 
-    let direction = match urlencoding::decode(direction_raw) {
-        Ok(direction) if !direction.is_empty() => direction.to_string(),
-        _ => return Response::error("invalid direction", 400),
-    };
+/// `path` of the unversioned API, the form the route checks outside the router compare against.
+pub(crate) fn unversioned_path(path: &str) -> String {
+    match path.strip_prefix("/api") {
+        Some(rest) if versioned(rest) => {
+            let rest = &rest[1..];
+            format!("/api{}", &rest[rest.find('/').unwrap_or(rest.len())..])
+        }
+        _ => path.to_string(),
+    }
+}
 
-    let stops = match route.directions.get(&direction) {
-        Some(stops) => stops,
-        None => return Response::error("direction not found", 404),
-    };
+pub(crate) fn router() -> Router<'static, ()> {
+    let router = ApiVersion::ALL
+        .into_iter()
+        .fold(Router::new(), |router, version| {
+            api_routes(router, version.prefix())
+        });
+    api_routes(router, "/api")
+        .get_async("/board/:id", arrivals::get_stop_board)
+        .options("/*path", preflight)
+}
 
-    let mut stops_data = Vec::with_capacity(stops.len());
-    for stop_id in stops {
-        let stop_name = service
-            .get_stop_name_by_id_async(stop_id)
-            .await
-            .unwrap_or_else(|| Rc::new("Can't resolve stop name".to_string()));
-        stops_data.push((stop_id, stop_name));
-    }
+/// The routes of the API with their `/api` swapped for `prefix`. Every version is served by the
+/// same handlers: v2 only changes the default shape of the stop and direction listings, which
+/// their handlers read off the path with [`ApiVersion::of_path`].
+fn api_routes(router: Router<'static, ()>, prefix: &str) -> Router<'static, ()> {
+    let path = |path: &str| path.replacen("/api", prefix, 1);
+    router
+        .get(&path("/api"), status::api_index)
+        .get_async(&path("/api/health"), status::health_check)
+        .get(&path(status::READY_PATH), status::get_ready)
+        .get(&path("/api/version"), status::get_version)
+        .get(&path("/api/openapi.json"), openapi_spec)
+        .get(&path("/api/openapi.yaml"), openapi_spec_yaml)
+        .get(&path("/api/docs"), api_docs)
+        .get_async(&path("/api/types"), routes::get_types)
+        .get_async(&path("/api/types/:type/routes"), routes::get_routes_by_type)
+        .get_async(
+            &path("/api/types/:type/routes/:number/directions"),
+            routes::get_directions_by_route_type_number,
+        )
+        .get_async(
+            &path("/api/types/:type/routes/:number/directions/:direction/stops"),
+            routes::get_stops_by_route_type_number_direction,
+        )
+        .get_async(
+            &path("/api/types/:type/routes/:number/directions/:direction/shape"),
+            routes::get_route_shape,
+        )
+        .get_async(
+            &path("/api/types/:type/routes/:number/vehicles"),
+            vehicles::get_route_vehicles,
+        )
+        .get_async(&path("/api/arrivals"), arrivals::get_stop_arrivals)
+        .get_async(&path("/api/raw/siri"), arrivals::get_raw_siri)
+        .get_async(
+            &path("/api/gtfs-rt/trip-updates"),
+            arrivals::get_gtfs_rt_trip_updates,
+        )
+        .get_async(
+            &path("/api/gtfs-rt/vehicle-positions"),
+            arrivals::get_gtfs_rt_vehicle_positions,
+        )
+        .get_async(&path("/api/vehicles"), vehicles::get_vehicles)
+        .get_async(&path("/api/vehicles/:id"), vehicles::get_vehicle)
+        .post_async(&path("/api/graphql"), handlers::graphql::graphql_query)
+        .get_async(
+            &path(arrivals::ARRIVALS_STREAM_PATH),
+            arrivals::stream_stop_arrivals,
+        )
+        .get_async(
+            &path(arrivals::ARRIVALS_SOCKET_PATH),
+            arrivals::arrivals_socket,
+        )
+        .get_async(&path("/api/stats/hubs"), stops::get_stop_hubs)
+        .get_async(&path("/api/stops"), stops::list_stops)
+        .get_async(&path("/api/stops/search"), stops::search_stops)
+        .get_async(&path("/api/stops/zone"), stops::get_zone_stops)
+        .get_async(&path("/api/stops/autocomplete"), stops::autocomplete_stops)
+        .get_async(&path("/api/stops/nearby"), stops::nearby_stops)
+        .get_async(
+            &path("/api/stops/:id/arrivals"),
+            arrivals::get_single_stop_arrivals,
+        )
+        .get_async(&path("/api/stops/resolve"), stops::resolve_stop)
+        .get_async(&path("/api/stop-groups"), stops::get_stop_groups)
+        .get_async(&path("/api/stop-groups/:name"), stops::get_stop_group)
+        .get_async(
+            &path("/api/stop-groups/:name/arrivals"),
+            stops::get_stop_group_arrivals,
+        )
+        .get(&path("/api/cache/stats"), admin::get_cache_stats)
+        .get(&path("/api/metrics"), admin::get_metrics)
+        .get(
+            &path("/api/diagnostics/parse"),
+            admin::get_parse_diagnostics,
+        )
+        .post_async(&path("/api/admin/cache/purge"), admin::purge_caches)
+        .post_async(&path("/api/admin/replay"), admin::replay_recordings)
+        .get(
+            &path("/api/push/vapid-public-key"),
+            alerts::get_vapid_public_key,
+        )
+        .post_async(&path("/api/push/alerts"), alerts::create_arrival_alert)
+        .delete_async(&path("/api/push/alerts/:id"), alerts::delete_arrival_alert)
+        .post_async(&path("/api/admin/webhooks"), admin::create_webhook)
+        .delete_async(&path("/api/admin/webhooks/:id"), admin::delete_webhook)
+}
 
-    Response::from_json(&stops_data)
+#[event(fetch)]
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    middleware::run(req, env, ctx).await
 }
 
-/// Get arrival times for specific stops
-///
-/// Returns real-time arrival information for the requested stops
-#[utoipa::path(
-    get,
-    path = "/api/arrivals",
-    params(
-        ("stops" = String, Query, description = "Comma-separated list of stop IDs (max 5)", example = "1001,1002,1003"),
-    ),
-    responses(
-        (status = 200, description = "Arrival times for requested stops", body = PostArrivalsResponse),
-        (status = 400, description = "Invalid request - no stops provided or too many stops (max 5)"),
-        (status = 500, description = "Internal server error")
-    ),
-    tag = "Arrivals"
-)]
-async fn get_stop_arrivals(req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    let stops_param = req.url()?;
-    let stops_param = stops_param
-        .query_pairs()
-        .find_map(|(k, v)| {
-            if k == "stops" && !v.is_empty() {
-                return Some(v);
-            }
-            None
-        })
-        .ok_or(RequestError::MissingParameter(String::from(
-            "missing stops query parameter",
-        )))?;
-    let stops_request = splits_commas(stops_param.as_bytes()).map_err(|_| {
-        RequestError::InvalidParameter(String::from("invalid stops query parameter"))
-    })?;
-    {
-        let stop_count = stops_request.len();
-        if !(1..=5).contains(&stop_count) {
-            return Response::error("invalid number of stops provided (1-5)", 400);
+/// Re-fetches and parses the upstream files ahead of cache expiry, so user requests never pay
+/// for the download and parse.
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    if let Err(err) = Config::configure(&env) {
+        log_error!("{}", err);
+    }
+    if event.cron() == ALERTS_CRON {
+        if let Err(err) = push::check_alerts(&env).await {
+            log_error!("arrival alerts check failed: {}", err);
+        }
+        if let Err(err) = webhooks::check_webhooks(&env).await {
+            log_error!("webhooks check failed: {}", err);
         }
+        return;
     }
     let service = TransportService::get_service();
-    let stop_map = service.get_stop_map().await?;
-    let arrivals_cache = &Caches::get_cache().stop_arrival;
-    let mut stop_states: Vec<StopArrivalState> = stops_request
+    if let Err(err) = service.warm_caches(&env).await {
+        log_error!("cache warming ({}) failed: {:?}", event.cron(), err);
+    }
+}
+
+/// Trigger of the arrival alerts and webhooks check, the other triggers warm the caches.
+const ALERTS_CRON: &str = "* * * * *";
+
+/// The OpenAPI specification with the paths of the current version, the unversioned ones being
+/// deprecated aliases.
+fn api_doc() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    openapi.paths.paths = std::mem::take(&mut openapi.paths.paths)
         .into_iter()
-        .map(StopId)
-        .map(StopArrivalState::StopId)
-        .map(|state| match state {
-            StopArrivalState::StopId(stop_id) => stop_id.validate(&stop_map),
-            other => other,
-        })
-        .map(|state| match state {
-            StopArrivalState::Valid(valid_stop_id) => {
-                valid_stop_id.fetch_arrivals_from_cache(arrivals_cache)
-            }
-            other => other,
+        .map(|(path, item)| match successor_path(&path) {
+            Some(successor) => (successor, item),
+            None => (path, item),
         })
         .collect();
-    let missing_caches = stop_states
-        .iter()
-        .filter_map(|state| match state {
-            StopArrivalState::Valid(stop) => Some(stop.data.siri_id.to_string()),
-            _ => None,
-        })
-        .collect::<HashSet<String>>()
-        .into_iter()
-        .fold(String::new(), |mut acc, id| {
-            if !acc.is_empty() {
-                acc.push(',');
-            }
-            acc.push_str(&id);
-            acc
-        });
-    if !missing_caches.is_empty() {
-        service.update_stops_arrival_cache(&missing_caches).await?;
-        stop_states = stop_states
+    openapi
+}
+
+/// Serves the OpenAPI specification
+fn openapi_spec(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    Response::from_json(&api_doc())
+}
+
+/// Serves the OpenAPI specification as YAML, for client generators taking only YAML
+fn openapi_spec_yaml(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let openapi = serde_json::to_value(api_doc())?;
+    let mut response = Response::ok(yaml::to_string(&openapi))?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/yaml")?;
+    Ok(response)
+}
+
+/// Swagger UI page, loaded from the unpkg CDN and pointed at `/api/v1/openapi.json`.
+const API_DOCS_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>TLT Stops API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+<script>
+window.ui = SwaggerUIBundle({ url: "/api/v1/openapi.json", dom_id: "#swagger-ui", deepLinking: true });
+</script>
+</body>
+</html>
+"##;
+
+/// Serves Swagger UI for exploring the API from the browser
+fn api_docs(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    let mut response = Response::from_html(API_DOCS_HTML)?;
+    response
+        .headers_mut()
+        .set("Cache-Control", "public, max-age=3600")?;
+    Ok(response)
+}
+
+/// The OpenAPI path templates of the routes with the methods each answers, HEAD and OPTIONS
+/// included. Built once per isolate.
+fn route_templates() -> &'static [(String, Vec<&'static str>)] {
+    static TEMPLATES: std::sync::OnceLock<Vec<(String, Vec<&'static str>)>> =
+        std::sync::OnceLock::new();
+    TEMPLATES.get_or_init(|| {
+        let openapi = ApiDoc::openapi();
+        openapi
+            .paths
+            .paths
             .into_iter()
-            .map(|state| match state {
-                StopArrivalState::Valid(valid_stop_id) => {
-                    valid_stop_id.fetch_arrivals_from_cache(arrivals_cache)
+            .map(|(template, item)| {
+                let mut methods = Vec::new();
+                if item.get.is_some() {
+                    methods.extend(["GET", "HEAD"]);
+                }
+                if item.post.is_some() {
+                    methods.push("POST");
+                }
+                if item.delete.is_some() {
+                    methods.push("DELETE");
                 }
-                other => other,
+                methods.push("OPTIONS");
+                (template, methods)
             })
-            .collect();
+            .collect()
+    })
+}
+
+/// The template and methods of the route answering the path, of the `/api` alias for the
+/// versioned paths.
+pub(crate) fn route_template(path: &str) -> Option<&'static (String, Vec<&'static str>)> {
+    let path = unversioned_path(path);
+    let segments = path.trim_end_matches('/').split('/').collect::<Vec<&str>>();
+    route_templates().iter().find(|(template, _)| {
+        let template = template.split('/').collect::<Vec<&str>>();
+        template.len() == segments.len()
+            && template.iter().zip(&segments).all(|(template, segment)| {
+                template == segment || (template.starts_with('{') && !segment.is_empty())
+            })
+    })
+}
+
+/// Answers the CORS preflight of any path with the methods the OpenAPI spec lists for it, 404
+/// for paths it doesn't know.
+fn preflight(req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+    preflight_reply(&req)?.into_response()
+}
+
+fn preflight_reply(req: &dyn Incoming) -> Result<Reply> {
+    let Some((_, methods)) = route_template(req.url()?.path()) else {
+        return error_reply("Not Found", 404);
+    };
+    let allow = methods.join(", ");
+    let mut headers = vec![
+        ("Allow", allow.clone()),
+        ("Access-Control-Allow-Origin", "*".to_string()),
+        ("Access-Control-Allow-Methods", allow),
+    ];
+    if let Some(requested) = req.header("Access-Control-Request-Headers")? {
+        headers.push(("Access-Control-Allow-Headers", requested));
     }
-    let stop_arrivals = stop_states
-        .into_iter()
-        .map(|state| match state {
-            StopArrivalState::Ready(ready_stop_arrivals) => Ok(Some(ready_stop_arrivals.0)),
-            StopArrivalState::Valid(stop_data) => {
-                let stop_arrival = StopArrivals {
-                    id: stop_data.data.siri_id.to_string(),
-                    name: stop_data.data.name.to_string(),
-                    arrivals: HashMap::new(),
-                };
-                Ok(Some(Rc::new(stop_arrival)))
-            }
-            StopArrivalState::Invalid => Ok(None),
-            StopArrivalState::StopId(_) => Err(ParsingUpstreamError::Error(
-                "unreachable state: StopId after validation".to_string(),
-            )),
-        })
-        .collect::<core::result::Result<Vec<Option<Rc<StopArrivals>>>, ParsingUpstreamError>>()
-        .map(|stops| PostArrivalsResponse { stops });
-    Response::from_json(&stop_arrivals?)
+    headers.push(("Access-Control-Max-Age", "86400".to_string()));
+    Ok(Reply {
+        status: 204,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Whether an `If-None-Match` header lists the tag, compared weakly.
+pub(crate) fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+pub(crate) fn not_modified(etag: &str) -> Reply {
+    Reply {
+        status: 304,
+        headers: vec![("ETag", etag.to_string())],
+        body: Vec::new(),
+    }
+}
+
+/// `Cache-Control` for what remains of the backing cache's TTL, and the data's age in
+/// `X-Data-Age`. Not `Age`, which shared caches would count against the max-age a second time.
+pub(crate) fn set_freshness(
+    response: &mut Response,
+    ttl_secs: u32,
+    age_secs: Option<u32>,
+) -> Result<()> {
+    for (name, value) in freshness_headers(ttl_secs, age_secs) {
+        response.headers_mut().set(name, &value)?;
+    }
+    Ok(())
+}
+
+/// The headers of [`set_freshness`], none without an age.
+pub(crate) fn freshness_headers(
+    ttl_secs: u32,
+    age_secs: Option<u32>,
+) -> Vec<(&'static str, String)> {
+    let Some(age_secs) = age_secs else {
+        return Vec::new();
+    };
+    vec![
+        (
+            "Cache-Control",
+            format!("public, max-age={}", ttl_secs.saturating_sub(age_secs)),
+        ),
+        ("X-Data-Age", age_secs.to_string()),
+    ]
 }
@@ -0,0 +1,274 @@
+//! Structured logging: JSON lines tagged with the `X-Request-Id` and W3C trace id of the request
+//! they were written for, plus one line per request with what it cost. `LOG_LEVEL` drops the
+//! lines below it.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+use serde_json::{Value, json};
+use worker::{Headers, Method, Request, Result};
+
+use crate::config::Config;
+use crate::crypto::{random_hex, random_id};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(level: &str) -> core::result::Result<Self, ()> {
+        match level.to_ascii_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The W3C trace context of a request, continued by its upstream fetches.
+pub struct Trace {
+    pub trace_id: String,
+    flags: String,
+    /// The client's `tracestate`, passed on untouched
+    state: Option<String>,
+}
+
+/// `traceparent` fields are lowercase hex of a fixed length, the ids not all zeros.
+fn trace_field(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+/// The client's `traceparent` when valid, otherwise a new trace. Versions after `00` are read
+/// for the fields `00` defines.
+pub fn trace(req: &Request) -> Result<Trace> {
+    let traceparent = req.headers().get("traceparent")?.unwrap_or_default();
+    let fields = traceparent.split('-').collect::<Vec<&str>>();
+    let valid = match fields.as_slice() {
+        [version, trace_id, parent_id, flags, rest @ ..] => {
+            trace_field(version, 2)
+                && *version != "ff"
+                && (rest.is_empty() || *version != "00")
+                && trace_field(trace_id, 32)
+                && trace_id.bytes().any(|byte| byte != b'0')
+                && trace_field(parent_id, 16)
+                && parent_id.bytes().any(|byte| byte != b'0')
+                && trace_field(flags, 2)
+        }
+        _ => false,
+    };
+    if !valid {
+        return Ok(Trace {
+            trace_id: random_hex::<16>()?,
+            flags: "00".to_string(),
+            state: None,
+        });
+    }
+    Ok(Trace {
+        trace_id: fields[1].to_string(),
+        flags: fields[3].to_string(),
+        state: req.headers().get("tracestate")?,
+    })
+}
+
+/// What a request did while it was handled, for its request line.
+struct RequestContext {
+    id: String,
+    trace: Trace,
+    cache_hits: Cell<u32>,
+    cache_misses: Cell<u32>,
+    upstream_calls: Cell<u32>,
+    upstream_ms: Cell<f64>,
+}
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// Longest `X-Request-Id` taken from a client, longer ones are replaced by a new id.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The client's `X-Request-Id` when it's a plain token, otherwise a new random id.
+pub fn request_id(req: &Request) -> Result<String> {
+    let propagated = req.headers().get("X-Request-Id")?.filter(|id| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+    });
+    match propagated {
+        Some(id) => Ok(id),
+        None => random_id(),
+    }
+}
+
+/// Runs the handling of a request, tagging the lines logged meanwhile with its ids.
+pub async fn with_request_id<F: Future>(id: String, trace: Trace, future: F) -> F::Output {
+    let context = RequestContext {
+        id,
+        trace,
+        cache_hits: Cell::new(0),
+        cache_misses: Cell::new(0),
+        upstream_calls: Cell::new(0),
+        upstream_ms: Cell::new(0.0),
+    };
+    REQUEST.scope(context, future).await
+}
+
+/// The trace id of the request being handled.
+pub fn trace_id() -> Option<String> {
+    REQUEST
+        .try_with(|request| request.trace.trace_id.clone())
+        .ok()
+}
+
+/// Sets the `traceparent` of an upstream fetch made for the request, with a span id of its
+/// own, and the client's `tracestate`.
+pub fn propagate_trace(headers: &Headers) -> Result<()> {
+    let Ok((trace_id, flags, state)) = REQUEST.try_with(|request| {
+        let trace = &request.trace;
+        (
+            trace.trace_id.clone(),
+            trace.flags.clone(),
+            trace.state.clone(),
+        )
+    }) else {
+        return Ok(());
+    };
+    let span_id = random_hex::<8>()?;
+    headers.set("traceparent", &format!("00-{trace_id}-{span_id}-{flags}"))?;
+    if let Some(state) = state {
+        headers.set("tracestate", &state)?;
+    }
+    Ok(())
+}
+
+/// Counts a lookup of an isolate cache for the request's line, stale hits being hits.
+pub fn record_cache_lookup(hit: bool) {
+    REQUEST
+        .try_with(|request| {
+            let counter = match hit {
+                true => &request.cache_hits,
+                false => &request.cache_misses,
+            };
+            counter.set(counter.get().saturating_add(1));
+        })
+        .ok();
+}
+
+/// Counts a call to an upstream server for the request's line.
+pub fn record_upstream_call(duration_ms: f64) {
+    REQUEST
+        .try_with(|request| {
+            request
+                .upstream_calls
+                .set(request.upstream_calls.get().saturating_add(1));
+            request
+                .upstream_ms
+                .set(request.upstream_ms.get() + duration_ms);
+        })
+        .ok();
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= Config::get().log_level
+}
+
+/// Writes the line with its level and, outside scheduled events and work left to
+/// `wait_until`, the request and trace ids.
+fn emit(level: Level, mut line: Value) {
+    line["level"] = level.name().into();
+    if let Ok((id, trace_id)) =
+        REQUEST.try_with(|request| (request.id.clone(), request.trace.trace_id.clone()))
+    {
+        line["requestId"] = id.into();
+        line["traceId"] = trace_id.into();
+    }
+    #[cfg(target_arch = "wasm32")]
+    match level {
+        Level::Error => worker::console_error!("{}", line),
+        Level::Warn => worker::console_warn!("{}", line),
+        Level::Info | Level::Debug => worker::console_log!("{}", line),
+    }
+    // the host has no console, only the tests run there
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", line);
+}
+
+/// What the logging macros write.
+pub fn write(level: Level, message: fmt::Arguments) {
+    if enabled(level) {
+        emit(level, json!({ "message": message.to_string() }));
+    }
+}
+
+/// The line of a handled request, at `error` for the 5xx answers.
+pub fn write_request(method: &Method, path: &str, status: u16, duration_ms: f64) {
+    let level = match status {
+        500.. => Level::Error,
+        _ => Level::Info,
+    };
+    if !enabled(level) {
+        return;
+    }
+    let Ok(line) = REQUEST.try_with(|request| {
+        json!({
+            "message": "request",
+            "method": method.to_string(),
+            "path": path,
+            "status": status,
+            "durationMs": duration_ms,
+            "cacheHits": request.cache_hits.get(),
+            "cacheMisses": request.cache_misses.get(),
+            "upstreamCalls": request.upstream_calls.get(),
+            "upstreamMs": request.upstream_ms.get(),
+        })
+    }) else {
+        return;
+    };
+    emit(level, line);
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {log_debug, log_error, log_warn};
@@ -0,0 +1,205 @@
+//! Request, upstream and cache metrics of this isolate, served by `/api/metrics` in the
+//! Prometheus text exposition format. Each isolate counts its own requests, a scrape sees the
+//! isolate that answered it.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use worker::send::SendWrapper;
+use worker::{Method, Url};
+
+use crate::caches::CacheStats;
+
+/// A cache counter's name and help text with how to read it from the stats.
+type CacheCounter = (&'static str, &'static str, fn(&CacheStats) -> u64);
+
+pub static METRICS: OnceLock<SendWrapper<Metrics>> = OnceLock::new();
+
+/// Upper bounds of the duration histograms' buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}").ok();
+        }
+        writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count).ok();
+        writeln!(out, "{name}_sum{{{labels}}} {}", self.sum).ok();
+        writeln!(out, "{name}_count{{{labels}}} {}", self.count).ok();
+    }
+}
+
+/// A label value with `\`, `"` and newlines escaped.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by method, route template and status
+    requests: RefCell<BTreeMap<(String, &'static str, u16), u64>>,
+    request_durations: RefCell<BTreeMap<&'static str, Histogram>>,
+    /// Upstream requests by host and whether they failed or answered 5xx
+    upstream_requests: RefCell<BTreeMap<(String, bool), u64>>,
+    upstream_durations: RefCell<BTreeMap<String, Histogram>>,
+}
+
+impl Metrics {
+    pub fn get() -> &'static SendWrapper<Metrics> {
+        METRICS.get_or_init(|| SendWrapper::new(Metrics::default()))
+    }
+
+    /// Counts a handled request under its route template, `unmatched` for paths of no route.
+    pub fn record_request(&self, method: &Method, path: &str, status: u16, duration_ms: f64) {
+        let endpoint = crate::route_template(path).map_or("unmatched", |(template, _)| template);
+        if let Ok(mut requests) = self.requests.try_borrow_mut() {
+            *requests
+                .entry((method.to_string(), endpoint, status))
+                .or_default() += 1;
+        }
+        if let Ok(mut durations) = self.request_durations.try_borrow_mut() {
+            durations
+                .entry(endpoint)
+                .or_default()
+                .observe(duration_ms / 1000.0);
+        }
+    }
+
+    pub fn record_upstream(&self, uri: &str, duration_ms: f64, failed: bool) {
+        let host = Url::parse(uri)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        if let Ok(mut requests) = self.upstream_requests.try_borrow_mut() {
+            *requests.entry((host.clone(), failed)).or_default() += 1;
+        }
+        if let Ok(mut durations) = self.upstream_durations.try_borrow_mut() {
+            durations
+                .entry(host)
+                .or_default()
+                .observe(duration_ms / 1000.0);
+        }
+    }
+
+    /// The metrics with the cache counters, in the text exposition format.
+    pub fn render(&self, caches: &BTreeMap<&'static str, CacheStats>) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP tlt_http_requests_total Requests handled by this isolate.\n");
+        out.push_str("# TYPE tlt_http_requests_total counter\n");
+        for ((method, endpoint, status), count) in self.requests.borrow().iter() {
+            writeln!(
+                out,
+                "tlt_http_requests_total{{method=\"{}\",endpoint=\"{}\",status=\"{}\"}} {}",
+                label(method),
+                label(endpoint),
+                status,
+                count
+            )
+            .ok();
+        }
+        out.push_str("# HELP tlt_http_request_duration_seconds Time to answer a request.\n");
+        out.push_str("# TYPE tlt_http_request_duration_seconds histogram\n");
+        for (endpoint, histogram) in self.request_durations.borrow().iter() {
+            let labels = format!("endpoint=\"{}\"", label(endpoint));
+            histogram.write(&mut out, "tlt_http_request_duration_seconds", &labels);
+        }
+        out.push_str("# HELP tlt_upstream_requests_total Requests to the upstream servers.\n");
+        out.push_str("# TYPE tlt_upstream_requests_total counter\n");
+        for ((host, failed), count) in self.upstream_requests.borrow().iter() {
+            writeln!(
+                out,
+                "tlt_upstream_requests_total{{host=\"{}\",failed=\"{}\"}} {}",
+                label(host),
+                failed,
+                count
+            )
+            .ok();
+        }
+        out.push_str(
+            "# HELP tlt_upstream_request_duration_seconds Time to an upstream response's headers.\n",
+        );
+        out.push_str("# TYPE tlt_upstream_request_duration_seconds histogram\n");
+        for (host, histogram) in self.upstream_durations.borrow().iter() {
+            let labels = format!("host=\"{}\"", label(host));
+            histogram.write(&mut out, "tlt_upstream_request_duration_seconds", &labels);
+        }
+        let cache_counters: [CacheCounter; 5] = [
+            ("hits", "Lookups answered from the cache.", |stats| {
+                stats.hits
+            }),
+            ("misses", "Lookups finding nothing stored.", |stats| {
+                stats.misses
+            }),
+            (
+                "expirations",
+                "Lookups finding only an expired record.",
+                |stats| stats.expirations,
+            ),
+            (
+                "stale_hits",
+                "Expired records served while refreshed.",
+                |stats| stats.stale_hits,
+            ),
+            (
+                "evictions",
+                "Records dropped to stay within the entry limit.",
+                |stats| stats.evictions,
+            ),
+        ];
+        for (name, help, value) in cache_counters {
+            writeln!(out, "# HELP tlt_cache_{name}_total {help}").ok();
+            writeln!(out, "# TYPE tlt_cache_{name}_total counter").ok();
+            for (cache, stats) in caches {
+                writeln!(
+                    out,
+                    "tlt_cache_{name}_total{{cache=\"{cache}\"}} {}",
+                    value(stats)
+                )
+                .ok();
+            }
+        }
+        out.push_str("# HELP tlt_cache_entries Records held by the cache.\n");
+        out.push_str("# TYPE tlt_cache_entries gauge\n");
+        for (cache, stats) in caches {
+            writeln!(
+                out,
+                "tlt_cache_entries{{cache=\"{cache}\"}} {}",
+                stats.entries
+            )
+            .ok();
+        }
+        out.push_str("# HELP tlt_cache_hit_ratio Hits over all lookups of the cache.\n");
+        out.push_str("# TYPE tlt_cache_hit_ratio gauge\n");
+        for (cache, stats) in caches {
+            if let Some(hit_ratio) = stats.hit_ratio {
+                writeln!(out, "tlt_cache_hit_ratio{{cache=\"{cache}\"}} {hit_ratio}").ok();
+            }
+        }
+        out
+    }
+}
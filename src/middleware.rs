@@ -0,0 +1,562 @@
+//! The pipeline every request runs through around the router: each middleware gets the request
+//! and the rest of the pipeline, and can answer itself, change the request before passing it on
+//! or change the response coming back. [`PIPELINE`] lists them outermost first, each with why
+//! it sits where it does.
+
+use std::cell::Cell;
+
+use futures::future::LocalBoxFuture;
+use worker::{Context, Env, Method, Request, RequestInit, Response, Result, Url};
+
+use crate::api_keys::{self, Access};
+use crate::caches::Caches;
+use crate::clock;
+use crate::config::Config;
+use crate::extract::Incoming;
+use crate::handlers::status::READY_PATH;
+use crate::logging::{self, log_error};
+use crate::metrics::Metrics;
+use crate::rate_limit;
+use crate::replay;
+use crate::reply::Reply;
+use crate::services::TransportService;
+use crate::str_utils::fnv1a_64;
+use crate::{
+    error_response, etag_matches, freshness_headers, json_error_response, not_modified, router,
+    successor_path, unversioned_path, with_problem_context,
+};
+
+/// What the middlewares share about the request, as it came from the client.
+pub struct Exchange {
+    pub env: Env,
+    pub ctx: Context,
+    pub method: Method,
+    pub url: Url,
+    pub request_id: String,
+    /// Set by [`authenticate`] for requests limited by their API key's quotas instead
+    keyed: Cell<bool>,
+}
+
+pub type Middleware =
+    for<'a> fn(Request, &'a Exchange, Next<'a>) -> LocalBoxFuture<'a, Result<Response>>;
+
+/// The rest of the pipeline after a middleware, ending in the router.
+pub struct Next<'a> {
+    exchange: &'a Exchange,
+    pipeline: &'a [Middleware],
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Request) -> LocalBoxFuture<'a, Result<Response>> {
+        match self.pipeline.split_first() {
+            Some((middleware, pipeline)) => middleware(
+                req,
+                self.exchange,
+                Next {
+                    exchange: self.exchange,
+                    pipeline,
+                },
+            ),
+            None => Box::pin(router().run(req, self.exchange.env.clone())),
+        }
+    }
+}
+
+/// A middleware sees the response after every one below it, so each stage sits above those whose
+/// answers it has to cover.
+pub const PIPELINE: [Middleware; 11] = [
+    // Outermost, so the request line, metrics and X-Request-Id cover every answer, refusals too
+    log_request,
+    // The stages below see a GET, the body they answer is dropped here
+    head,
+    // Compresses whatever is answered below, problems and refusals included
+    compression,
+    // Runs once the response is ready, also after a refusal, so any traffic keeps caches warm
+    refresh_caches,
+    // Above the refusals, so a browser can read a 401 or 429 instead of failing on CORS
+    cors,
+    // Every answer of an unversioned alias points to its successor, problems included
+    deprecation,
+    // Samples the responses as the client gets them, problem bodies included, for replays
+    record_replay,
+    // Turns the failures below into problems, refusals and handler errors alike
+    problems,
+    // Before the limiter, which skips the keyed requests limited by their key's quotas instead
+    authenticate,
+    // Anonymous requests by client address, before any handler work
+    rate_limit,
+    // Innermost, only requests let through reach the 304 shortcut that skips the handler
+    listing_etag,
+];
+
+/// What the middlewares `tlt-serve` shares with the worker change of a response, the worker's
+/// `Response` or the host's `http::Response`.
+pub trait Outgoing: Sized {
+    fn status(&self) -> u16;
+
+    fn has_header(&self, name: &str) -> Result<bool>;
+
+    fn set_header(&mut self, name: &'static str, value: &str) -> Result<()>;
+
+    fn from_reply(reply: Reply) -> Result<Self>;
+}
+
+impl Outgoing for Response {
+    fn status(&self) -> u16 {
+        self.status_code()
+    }
+
+    fn has_header(&self, name: &str) -> Result<bool> {
+        self.headers().has(name)
+    }
+
+    fn set_header(&mut self, name: &'static str, value: &str) -> Result<()> {
+        self.headers_mut().set(name, value)
+    }
+
+    fn from_reply(reply: Reply) -> Result<Self> {
+        reply.into_response()
+    }
+}
+
+/// Runs the request through the [`PIPELINE`].
+pub async fn run(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    Config::configure(&env)?;
+    let exchange = Exchange {
+        env,
+        ctx,
+        method: req.method(),
+        url: req.url()?,
+        request_id: logging::request_id(&req)?,
+        keyed: Cell::new(false),
+    };
+    let next = Next {
+        exchange: &exchange,
+        pipeline: &PIPELINE,
+    };
+    next.run(req).await
+}
+
+/// Tags the lines logged while handling the request with its ids, then writes its request line
+/// and metrics and answers its `X-Request-Id`.
+fn log_request<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let trace = logging::trace(&req)?;
+        let (method, path) = (&exchange.method, exchange.url.path());
+        logging::with_request_id(exchange.request_id.clone(), trace, async {
+            let started_ms = clock::now_ms();
+            let mut response = next.run(req).await;
+            let status = response.as_ref().map_or(500, Response::status_code);
+            let duration_ms = clock::now_ms() - started_ms;
+            logging::write_request(method, path, status, duration_ms);
+            Metrics::get().record_request(method, path, status, duration_ms);
+            if let Ok(response) = &mut response
+                && response.status_code() != 101
+            {
+                response
+                    .headers_mut()
+                    .set("X-Request-Id", &exchange.request_id)?;
+            }
+            response
+        })
+        .await
+    })
+}
+
+/// The routes are registered for GET only, HEAD runs the GET and drops its body.
+fn head<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        if exchange.method != Method::Head {
+            return next.run(req).await;
+        }
+        let mut init = RequestInit::new();
+        init.with_method(Method::Get)
+            .with_headers(req.headers().clone());
+        let response = next
+            .run(Request::new_with_init(exchange.url.as_str(), &init)?)
+            .await?;
+        Ok(Response::empty()?
+            .with_status(response.status_code())
+            .with_headers(response.headers().clone()))
+    })
+}
+
+/// Content codings the runtime compresses bodies with, preferred first.
+const CONTENT_CODINGS: [&str; 2] = ["br", "gzip"];
+
+/// The most preferred of the [`CONTENT_CODINGS`] an `Accept-Encoding` header accepts.
+fn negotiate_content_coding(accept_encoding: &str) -> Option<&'static str> {
+    let codings = accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q=")?.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, quality))
+        })
+        .collect::<Vec<(String, f32)>>();
+    let quality = |coding: &str| {
+        let named = codings.iter().find(|(name, _)| name == coding);
+        named
+            .or_else(|| codings.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+    };
+    CONTENT_CODINGS
+        .into_iter()
+        .filter_map(|coding| Some((coding, quality(coding)?)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(None, |best: Option<(&str, f32)>, coding| match best {
+            Some(best) if best.1 >= coding.1 => Some(best),
+            _ => Some(coding),
+        })
+        .map(|(coding, _)| coding)
+}
+
+/// Has the runtime compress a JSON or text body with the coding, setting `Content-Encoding`.
+/// Event streams are left alone, compressing them would hold back the events.
+fn compress(response: &mut Response, coding: &str) -> Result<()> {
+    if matches!(response.status_code(), 101 | 204 | 304)
+        || response.headers().has("Content-Encoding")?
+    {
+        return Ok(());
+    }
+    let content_type = response.headers().get("Content-Type")?.unwrap_or_default();
+    let compressible = content_type.contains("json")
+        || content_type.starts_with("application/yaml")
+        || (content_type.starts_with("text/") && !content_type.starts_with("text/event-stream"));
+    if !compressible {
+        return Ok(());
+    }
+    let headers = response.headers_mut();
+    headers.set("Content-Encoding", coding)?;
+    headers.append("Vary", "Accept-Encoding")?;
+    Ok(())
+}
+
+fn compression<'a>(
+    req: Request,
+    _exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let accept_encoding = req.headers().get("Accept-Encoding")?;
+        let mut response = next.run(req).await?;
+        if let Some(coding) = accept_encoding
+            .as_deref()
+            .and_then(negotiate_content_coding)
+        {
+            compress(&mut response, coding)?;
+        }
+        Ok(response)
+    })
+}
+
+/// Handlers are answered from whatever was cached, expired or not; refreshes that after the
+/// response instead of blocking on it, and warms cold caches for a readiness check.
+fn refresh_caches<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let response = next.run(req).await;
+        TransportService::revalidate_stale(&exchange.env, &exchange.ctx);
+        if unversioned_path(exchange.url.path()) == READY_PATH {
+            TransportService::warm_up(&exchange.env, &exchange.ctx);
+        }
+        response
+    })
+}
+
+/// API responses are readable from any origin, as the preflight allows.
+fn cors<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let mut response = next.run(req).await?;
+        allow_any_origin(exchange.url.path(), &mut response)?;
+        Ok(response)
+    })
+}
+
+pub(crate) fn allow_any_origin(path: &str, response: &mut impl Outgoing) -> Result<()> {
+    if path.starts_with("/api")
+        && response.status() != 101
+        && !response.has_header("Access-Control-Allow-Origin")?
+    {
+        response.set_header("Access-Control-Allow-Origin", "*")?;
+    }
+    Ok(())
+}
+
+/// Marks the unversioned `/api` aliases deprecated, linking their `/api/v1` successor.
+fn deprecation<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let mut response = next.run(req).await?;
+        link_successor(exchange.url.path(), &mut response)?;
+        Ok(response)
+    })
+}
+
+pub(crate) fn link_successor(path: &str, response: &mut impl Outgoing) -> Result<()> {
+    if let Some(successor) = successor_path(path)
+        && response.status() != 101
+    {
+        response.set_header("Deprecation", "true")?;
+        response.set_header(
+            "Link",
+            &format!("<{}>; rel=\"successor-version\"", successor),
+        )?;
+    }
+    Ok(())
+}
+
+fn record_replay<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let mut response = next.run(req).await?;
+        replay::record_sampled(
+            &exchange.env,
+            &exchange.ctx,
+            exchange.method.clone(),
+            &exchange.url,
+            &mut response,
+        );
+        Ok(response)
+    })
+}
+
+/// Answers every failure as a problem with the request's path and ids.
+fn problems<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let response = match next.run(req).await {
+            // Errors carrying a status (upstream failures and timeouts, bad parameters) answer
+            // with it instead of the runtime's bare 500.
+            Err(worker::Error::Json((message, status))) => json_error_response(message, status),
+            // The router's own 404 and 405 are bare text.
+            Ok(mut response)
+                if response.status_code() >= 400
+                    && response.headers().get("Content-Type")?.is_none() =>
+            {
+                error_response(response.text().await?, response.status_code())
+            }
+            Err(err) => {
+                log_error!(
+                    "{} {} failed: {}",
+                    exchange.method,
+                    exchange.url.path(),
+                    err
+                );
+                error_response("internal error", 500)
+            }
+            response => response,
+        }?;
+        with_problem_context(response, exchange.url.path(), Some(&exchange.request_id)).await
+    })
+}
+
+/// Refuses requests with an invalid key or over their key's quotas.
+fn authenticate<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let remaining = match api_keys::check(&exchange.env, &req).await? {
+            Access::Denied(response) => return Ok(response),
+            Access::Anonymous => return next.run(req).await,
+            Access::Keyed { remaining } => remaining,
+        };
+        exchange.keyed.set(true);
+        let mut response = next.run(req).await?;
+        if let Some(remaining) = remaining {
+            response
+                .headers_mut()
+                .set("X-RateLimit-Remaining", &remaining.to_string())?;
+        }
+        Ok(response)
+    })
+}
+
+/// Limits the anonymous requests by the client address.
+fn rate_limit<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        if !exchange.keyed.get()
+            && exchange.method != Method::Options
+            && rate_limit::over_limit(&exchange.env, &req).await
+        {
+            return rate_limit::too_many_requests();
+        }
+        next.run(req).await
+    })
+}
+
+/// The listings, derived only from routes.txt and stops.txt and so validated by their
+/// [`Caches::data_hash`] and fresh for as long as those are cached.
+fn is_listing_path(path: &str) -> bool {
+    let path = unversioned_path(path);
+    let segments = path.trim_end_matches('/').split('/').collect::<Vec<&str>>();
+    matches!(
+        segments.as_slice(),
+        ["", "api", "types"]
+            | ["", "api", "types", _, "routes"]
+            | ["", "api", "types", _, "routes", _, "directions"]
+            | ["", "api", "types", _, "routes", _, "directions", _, "stops"]
+            | ["", "api", "stops"]
+    )
+}
+
+/// What a listing response varies by besides the data: the path, its version picking the
+/// listing's shape, the query, `Accept` and the content coding [`compression`] picks by
+/// `Accept-Encoding`. `None` outside the listings and for `profile` (stored apart from the data)
+/// and `envelope` (timestamped) responses.
+fn listing_etag_key(req: &dyn Incoming, url: &Url) -> Result<Option<String>> {
+    let unvalidated = url
+        .query_pairs()
+        .any(|(k, v)| k == "profile" || (k == "envelope" && v == "true"));
+    if !is_listing_path(url.path()) || unvalidated {
+        return Ok(None);
+    }
+    Ok(Some(listing_variant(
+        url.path(),
+        url.query().unwrap_or_default(),
+        &req.header("Accept")?.unwrap_or_default(),
+        req.header("Accept-Encoding")?.as_deref(),
+    )))
+}
+
+fn listing_variant(path: &str, query: &str, accept: &str, accept_encoding: Option<&str>) -> String {
+    let coding = accept_encoding
+        .and_then(negotiate_content_coding)
+        .unwrap_or("identity");
+    format!("{}?{}|{}|{}", path, query, accept, coding)
+}
+
+/// Strong validator of a listing response, `None` until the routes and stops are loaded.
+fn listing_etag_of(key: &str) -> Option<String> {
+    let data_hash = Caches::get_cache().data_hash()?;
+    let key = format!("{:016x}|{}", data_hash, key);
+    Some(format!("\"{:016x}\"", fnv1a_64(key.as_bytes())))
+}
+
+/// Tags the listings with their ETag and freshness. A listing unchanged since the client's copy
+/// skips its handler, the data is cached already.
+fn listing_etag<'a>(
+    req: Request,
+    exchange: &'a Exchange,
+    next: Next<'a>,
+) -> LocalBoxFuture<'a, Result<Response>> {
+    Box::pin(async move {
+        let tag = ListingTag::of(&req, exchange.method == Method::Get)?;
+        let response = match tag.unchanged() {
+            Some(reply) => reply.into_response()?,
+            None => next.run(req).await?,
+        };
+        tag.apply(response)
+    })
+}
+
+/// What [`listing_etag`] knows of the request before its handler runs.
+pub(crate) struct ListingTag {
+    listing: bool,
+    /// The [`listing_etag_key`] of a GET
+    etag_key: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl ListingTag {
+    pub(crate) fn of(req: &dyn Incoming, get: bool) -> Result<Self> {
+        let url = req.url()?;
+        let etag_key = if get {
+            listing_etag_key(req, &url)?
+        } else {
+            None
+        };
+        Ok(Self {
+            listing: is_listing_path(url.path()),
+            etag_key,
+            if_none_match: req.header("If-None-Match")?,
+        })
+    }
+
+    fn matches(&self, etag: &str) -> bool {
+        self.if_none_match
+            .as_deref()
+            .is_some_and(|tags| etag_matches(tags, etag))
+    }
+
+    /// The 304 answering the request in place of its handler, when the client's copy is current.
+    pub(crate) fn unchanged(&self) -> Option<Reply> {
+        let etag = self.etag_key.as_deref().and_then(listing_etag_of)?;
+        self.matches(&etag).then(|| not_modified(&etag))
+    }
+
+    pub(crate) fn apply<R: Outgoing>(&self, mut response: R) -> Result<R> {
+        if let Some(key) = &self.etag_key
+            && response.status() == 200
+            && let Some(etag) = listing_etag_of(key)
+        {
+            if self.matches(&etag) {
+                response = R::from_reply(not_modified(&etag))?;
+            } else {
+                response.set_header("ETag", &etag)?;
+            }
+        }
+        if self.listing && matches!(response.status(), 200 | 304) {
+            let ttl_secs = Config::get().data_cache_ttl_secs;
+            let age_secs = Caches::get_cache().data_age_secs();
+            for (name, value) in freshness_headers(ttl_secs, age_secs) {
+                response.set_header(name, &value)?;
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_listings_of_each_version_and_coding_apart() {
+        let variant = |path, accept_encoding| listing_variant(path, "", "", accept_encoding);
+        let v1 = variant("/api/v1/types/bus/routes/1/directions/0/stops", None);
+        let v2 = variant("/api/v2/types/bus/routes/1/directions/0/stops", None);
+        assert_ne!(v1, v2);
+        let gzip = variant("/api/v1/types", Some("gzip"));
+        assert_ne!(gzip, variant("/api/v1/types", Some("br, gzip")));
+        assert_ne!(gzip, variant("/api/v1/types", None));
+        assert_eq!(gzip, variant("/api/v1/types", Some("gzip;q=1, deflate")));
+        assert_eq!(
+            variant("/api/v1/types", Some("deflate")),
+            variant("/api/v1/types", None)
+        );
+    }
+}
@@ -0,0 +1,662 @@
+//! MessagePack encoding through serde, for the `Accept: application/msgpack` responses. Values
+//! are written straight from their `Serialize` impl, shaped as their JSON: structs are maps keyed
+//! by field name, enums are externally tagged, floats that JSON can't hold are nil.
+
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl From<Error> for worker::Error {
+    fn from(err: Error) -> Self {
+        worker::Error::RustError(format!("MessagePack encoding failed: {}", err))
+    }
+}
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    value.serialize(&mut Serializer { buf: &mut buf })?;
+    Ok(buf)
+}
+
+/// Length prefix of a str/array/map: the fix format up to `fix_max`, then the 8 (str only), 16
+/// and 32 bit forms.
+fn write_len(buf: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [Option<u8>; 3]) {
+    match (len, markers) {
+        (len, _) if len <= fix_max => buf.push(fix | len as u8),
+        (len, [Some(marker), _, _]) if len <= u8::MAX as usize => {
+            buf.push(marker);
+            buf.push(len as u8);
+        }
+        (len, [_, Some(marker), _]) if len <= u16::MAX as usize => {
+            buf.push(marker);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        (len, [_, _, Some(marker)]) => {
+            buf.push(marker);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => unreachable!("every length has a marker"),
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, string: &str) {
+    write_len(
+        buf,
+        string.len(),
+        0xa0,
+        31,
+        [Some(0xd9), Some(0xda), Some(0xdb)],
+    );
+    buf.extend_from_slice(string.as_bytes());
+}
+
+fn write_array_len(buf: &mut Vec<u8>, len: usize) {
+    write_len(buf, len, 0x90, 15, [None, Some(0xdc), Some(0xdd)]);
+}
+
+fn write_map_len(buf: &mut Vec<u8>, len: usize) {
+    write_len(buf, len, 0x80, 15, [None, Some(0xde), Some(0xdf)]);
+}
+
+fn write_uint(buf: &mut Vec<u8>, uint: u64) {
+    match uint {
+        0..=0x7f => buf.push(uint as u8),
+        0x80..=0xff => buf.extend_from_slice(&[0xcc, uint as u8]),
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(uint as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(uint as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xcf);
+            buf.extend_from_slice(&uint.to_be_bytes());
+        }
+    }
+}
+
+/// Non-negative integers go through `write_uint`, as JSON numbers don't keep the sign type.
+fn write_int(buf: &mut Vec<u8>, int: i64) {
+    match int {
+        0.. => write_uint(buf, int as u64),
+        -32..=-1 => buf.push(int as i8 as u8),
+        -128..=-33 => buf.extend_from_slice(&[0xd0, int as i8 as u8]),
+        -32_768..=-129 => {
+            buf.push(0xd1);
+            buf.extend_from_slice(&(int as i16).to_be_bytes());
+        }
+        -2_147_483_648..=-32_769 => {
+            buf.push(0xd2);
+            buf.extend_from_slice(&(int as i32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xd3);
+            buf.extend_from_slice(&int.to_be_bytes());
+        }
+    }
+}
+
+struct Serializer<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+/// An array or map being written. With the length known up front the items go straight to the
+/// output, otherwise (`#[serde(flatten)]` maps) they're buffered until the count is known.
+struct Compound<'a> {
+    buf: &'a mut Vec<u8>,
+    pending: Option<(Vec<u8>, usize)>,
+    is_map: bool,
+}
+
+impl<'a> Compound<'a> {
+    fn new(buf: &'a mut Vec<u8>, len: Option<usize>, is_map: bool) -> Self {
+        let pending = match len {
+            Some(len) if is_map => {
+                write_map_len(buf, len);
+                None
+            }
+            Some(len) => {
+                write_array_len(buf, len);
+                None
+            }
+            None => Some((Vec::new(), 0)),
+        };
+        Self {
+            buf,
+            pending,
+            is_map,
+        }
+    }
+
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        let buf = match &mut self.pending {
+            Some((pending, count)) => {
+                *count += 1;
+                pending
+            }
+            None => &mut *self.buf,
+        };
+        value.serialize(&mut Serializer { buf })
+    }
+
+    /// A map value, counted with its key.
+    fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        let buf = match &mut self.pending {
+            Some((pending, _)) => pending,
+            None => &mut *self.buf,
+        };
+        value.serialize(&mut Serializer { buf })
+    }
+
+    fn end(self) -> Result {
+        if let Some((pending, count)) = self.pending {
+            match self.is_map {
+                true => write_map_len(self.buf, count),
+                false => write_array_len(self.buf, count),
+            }
+            self.buf.extend_from_slice(&pending);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, value: bool) -> Result {
+        self.buf.push(if value { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, value: i8) -> Result {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i16(self, value: i16) -> Result {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i32(self, value: i32) -> Result {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i64(self, value: i64) -> Result {
+        write_int(self.buf, value);
+        Ok(())
+    }
+
+    fn serialize_i128(self, value: i128) -> Result {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => self.serialize_u128(
+                u128::try_from(value).map_err(|_| Error("integer out of range".to_string()))?,
+            ),
+        }
+    }
+
+    fn serialize_u8(self, value: u8) -> Result {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u16(self, value: u16) -> Result {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u32(self, value: u32) -> Result {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u64(self, value: u64) -> Result {
+        write_uint(self.buf, value);
+        Ok(())
+    }
+
+    fn serialize_u128(self, value: u128) -> Result {
+        let value = u64::try_from(value).map_err(|_| Error("integer out of range".to_string()))?;
+        self.serialize_u64(value)
+    }
+
+    fn serialize_f32(self, value: f32) -> Result {
+        self.serialize_f64(value.into())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result {
+        if !value.is_finite() {
+            return self.serialize_unit();
+        }
+        self.buf.push(0xcb);
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, value: char) -> Result {
+        write_str(self.buf, value.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, value: &str) -> Result {
+        write_str(self.buf, value);
+        Ok(())
+    }
+
+    /// An array of byte values, as in the JSON.
+    fn serialize_bytes(self, value: &[u8]) -> Result {
+        write_array_len(self.buf, value.len());
+        value
+            .iter()
+            .for_each(|&byte| write_uint(self.buf, byte.into()));
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result {
+        self.buf.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result {
+        write_map_len(self.buf, 1);
+        write_str(self.buf, variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(Compound::new(self.buf, len, false))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>> {
+        write_map_len(self.buf, 1);
+        write_str(self.buf, variant);
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(Compound::new(self.buf, len, true))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>> {
+        write_map_len(self.buf, 1);
+        write_str(self.buf, variant);
+        self.serialize_map(Some(len))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.item(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.item(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.item(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.item(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result {
+        self.item(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result {
+        self.value(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result {
+        self.item(key)?;
+        self.value(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result {
+        self.item(key)?;
+        self.value(value)
+    }
+
+    fn end(self) -> Result {
+        Compound::end(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+
+    fn encoded<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+        to_vec(value).unwrap()
+    }
+
+    /// The first bytes, the type marker and length or value that follow it.
+    fn head<T: Serialize + ?Sized>(value: &T, len: usize) -> Vec<u8> {
+        encoded(value)[..len].to_vec()
+    }
+
+    /// Decoder of the spec's formats, to check the encoded values against their JSON.
+    fn decode(buf: &[u8], pos: &mut usize) -> Value {
+        let take = |pos: &mut usize, len: usize| {
+            *pos += len;
+            &buf[*pos - len..*pos]
+        };
+        let be = |bytes: &[u8]| bytes.iter().fold(0u64, |n, &b| n << 8 | u64::from(b));
+        let marker = take(pos, 1)[0];
+        let (kind, len) = match marker {
+            0x00..=0x7f => return json!(marker),
+            0x80..=0x8f => ("map", (marker & 0x0f) as usize),
+            0x90..=0x9f => ("array", (marker & 0x0f) as usize),
+            0xa0..=0xbf => ("str", (marker & 0x1f) as usize),
+            0xc0 => return Value::Null,
+            0xc2 => return json!(false),
+            0xc3 => return json!(true),
+            0xcb => return json!(f64::from_bits(be(take(pos, 8)))),
+            0xcc..=0xcf => return json!(be(take(pos, 1 << (marker - 0xcc)))),
+            0xd0 => return json!(take(pos, 1)[0] as i8),
+            0xd1 => return json!(i16::from_be_bytes(take(pos, 2).try_into().unwrap())),
+            0xd2 => return json!(i32::from_be_bytes(take(pos, 4).try_into().unwrap())),
+            0xd3 => return json!(i64::from_be_bytes(take(pos, 8).try_into().unwrap())),
+            0xd9..=0xdb => ("str", be(take(pos, 1 << (marker - 0xd9))) as usize),
+            0xdc | 0xdd => ("array", be(take(pos, 2 << (marker - 0xdc))) as usize),
+            0xde | 0xdf => ("map", be(take(pos, 2 << (marker - 0xde))) as usize),
+            0xe0..=0xff => return json!(marker as i8),
+            _ => panic!("unexpected marker {:#04x}", marker),
+        };
+        match kind {
+            "str" => json!(std::str::from_utf8(take(pos, len)).unwrap()),
+            "array" => Value::Array((0..len).map(|_| decode(buf, pos)).collect()),
+            _ => Value::Object(
+                (0..len)
+                    .map(|_| match decode(buf, pos) {
+                        Value::String(key) => (key, decode(buf, pos)),
+                        key => panic!("non-string key {}", key),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn decoded<T: Serialize + ?Sized>(value: &T) -> Value {
+        let buf = encoded(value);
+        let mut pos = 0;
+        let value = decode(&buf, &mut pos);
+        assert_eq!(pos, buf.len(), "trailing bytes");
+        value
+    }
+
+    #[test]
+    fn encodes_unsigned_integers_at_their_boundaries() {
+        assert_eq!(encoded(&0u8), [0x00]);
+        assert_eq!(encoded(&127u8), [0x7f]);
+        assert_eq!(encoded(&128u8), [0xcc, 0x80]);
+        assert_eq!(encoded(&255u16), [0xcc, 0xff]);
+        assert_eq!(encoded(&256u16), [0xcd, 0x01, 0x00]);
+        assert_eq!(encoded(&65_535u32), [0xcd, 0xff, 0xff]);
+        assert_eq!(encoded(&65_536u32), [0xce, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(encoded(&u32::MAX), [0xce, 0xff, 0xff, 0xff, 0xff]);
+        let above_u32 = [0xcf, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert_eq!(encoded(&(u32::MAX as u64 + 1)), above_u32);
+        assert_eq!(encoded(&u64::MAX), [&[0xcf][..], &[0xff; 8]].concat());
+    }
+
+    #[test]
+    fn encodes_signed_integers_at_their_boundaries() {
+        assert_eq!(encoded(&5i32), [0x05]);
+        assert_eq!(encoded(&200i32), [0xcc, 200]);
+        assert_eq!(encoded(&-1i8), [0xff]);
+        assert_eq!(encoded(&-32i8), [0xe0]);
+        assert_eq!(encoded(&-33i8), [0xd0, 0xdf]);
+        assert_eq!(encoded(&-128i16), [0xd0, 0x80]);
+        assert_eq!(encoded(&-129i16), [0xd1, 0xff, 0x7f]);
+        assert_eq!(encoded(&-32_768i32), [0xd1, 0x80, 0x00]);
+        assert_eq!(encoded(&-32_769i32), [0xd2, 0xff, 0xff, 0x7f, 0xff]);
+        assert_eq!(encoded(&i32::MIN), [0xd2, 0x80, 0x00, 0x00, 0x00]);
+        let below_i32 = [0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff];
+        assert_eq!(encoded(&(i32::MIN as i64 - 1)), below_i32);
+        assert_eq!(encoded(&i64::MIN), [0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_string_lengths_at_their_boundaries() {
+        let string = |len| "a".repeat(len);
+        assert_eq!(encoded(""), [0xa0]);
+        assert_eq!(head(&string(31), 1), [0xbf]);
+        assert_eq!(head(&string(32), 2), [0xd9, 32]);
+        assert_eq!(head(&string(255), 2), [0xd9, 0xff]);
+        assert_eq!(head(&string(256), 3), [0xda, 0x01, 0x00]);
+        assert_eq!(head(&string(65_535), 3), [0xda, 0xff, 0xff]);
+        assert_eq!(head(&string(65_536), 5), [0xdb, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(encoded(&string(32)).len(), 2 + 32);
+        // lengths count bytes, not characters
+        assert_eq!(encoded("ä"), [0xa2, 0xc3, 0xa4]);
+    }
+
+    #[test]
+    fn encodes_array_and_map_lengths_at_their_boundaries() {
+        assert_eq!(encoded(&[0u8; 0]), [0x90]);
+        assert_eq!(head(&vec![0u8; 15], 1), [0x9f]);
+        assert_eq!(head(&vec![0u8; 16], 3), [0xdc, 0x00, 0x10]);
+        assert_eq!(head(&vec![0u8; 65_535], 3), [0xdc, 0xff, 0xff]);
+        assert_eq!(head(&vec![0u8; 65_536], 5), [0xdd, 0x00, 0x01, 0x00, 0x00]);
+
+        let map = |len: u32| (0..len).map(|n| (n, n)).collect::<BTreeMap<u32, u32>>();
+        assert_eq!(head(&map(15), 1), [0x8f]);
+        assert_eq!(head(&map(16), 3), [0xde, 0x00, 0x10]);
+        assert_eq!(head(&map(65_535), 3), [0xde, 0xff, 0xff]);
+        assert_eq!(head(&map(65_536), 5), [0xdf, 0x00, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn encodes_scalars_as_their_json() {
+        assert_eq!(encoded(&()), [0xc0]);
+        assert_eq!(encoded(&None::<u8>), [0xc0]);
+        assert_eq!(encoded(&Some(true)), [0xc3]);
+        assert_eq!(encoded(&false), [0xc2]);
+        assert_eq!(encoded(&1.5f64), [0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encoded(&1.5f32), [0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encoded(&f64::NAN), [0xc0]);
+        assert_eq!(encoded(&'ž'), [0xa2, 0xc5, 0xbe]);
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    enum Kind {
+        Unit,
+        Newtype(u8),
+        Tuple(u8, i8),
+        Struct { stop_id: String },
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Meta {
+        cache_age_secs: Option<u32>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Document {
+        id: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        siri_id: Option<&'static str>,
+        lat: f64,
+        kinds: Vec<Kind>,
+        arrivals: BTreeMap<String, Vec<i64>>,
+        #[serde(flatten)]
+        meta: Meta,
+    }
+
+    #[test]
+    fn decodes_to_the_json_of_the_value() {
+        let document = Document {
+            id: "1001",
+            siri_id: None,
+            lat: 59.43686,
+            kinds: vec![
+                Kind::Unit,
+                Kind::Newtype(7),
+                Kind::Tuple(1, -40),
+                Kind::Struct {
+                    stop_id: "x".repeat(40),
+                },
+            ],
+            arrivals: BTreeMap::from([("tram".to_string(), vec![-1, 300, -70_000])]),
+            meta: Meta {
+                cache_age_secs: Some(65_536),
+            },
+        };
+        assert_eq!(decoded(&document), serde_json::to_value(&document).unwrap());
+        // the flattened struct makes the length unknown up front, counted once written
+        assert_eq!(encoded(&document)[0], 0x85);
+
+        let value = json!({"a": [1, -1, 1.25, null, "ž"], "b": {"c": true}});
+        assert_eq!(decoded(&value), value);
+    }
+}
@@ -0,0 +1,706 @@
+//! The read-only API on the host, `tlt-serve` of the `native` feature, for running and self-hosting
+//! it without the Workers runtime. An axum [`Router`] serves the worker's own handlers of the route
+//! tree, the shapes and the arrivals, with [`Host`] as their bindings: the worker's
+//! [`TransportService`] and caches, [`CurlClient`] sending the upstream requests, [`SystemClock`]
+//! keeping the time and none of the Cloudflare bindings. The endpoints needing those answer 501.
+//! [`answer`] runs around the router the steps of the worker's middleware the host has what they
+//! need for. axum's server is hyper's, which isn't among the dependencies, so [`serve`] reads the
+//! HTTP/1.1 requests itself.
+
+use std::future::poll_fn;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::extract::{MatchedPath, State};
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::{self, HeaderName, HeaderValue, StatusCode};
+use axum::routing::get;
+use futures::StreamExt;
+use futures::future::{BoxFuture, LocalBoxFuture};
+use tower_service::Service;
+use worker::send::{SendFuture, SendWrapper};
+use worker::{Method, ObjectNamespace, Url};
+
+use crate::bindings::Bindings;
+use crate::clock::{self, Clock};
+use crate::config::{Config, ProcessVars};
+use crate::extract::{Incoming, PathParams, RequestError};
+use crate::handlers::{arrivals, routes};
+use crate::logging::{log_error, log_warn};
+use crate::middleware::{ListingTag, Outgoing, allow_any_origin, link_successor};
+use crate::profiles::ResponseProfile;
+use crate::reply::Reply;
+use crate::services::{ParsingUpstreamError, StopsRouteStore, TransportService};
+use crate::snapshots::SnapshotStore;
+use crate::upstream::{UpstreamClient, UpstreamRequest, UpstreamResponse};
+use crate::{
+    ApiVersion, PROBLEM_CONTENT_TYPE, api_doc, error_reply, json_error_reply, preflight_reply,
+    problem_in_context, route_template,
+};
+
+/// Longest request line and header line read.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+const CURL_MISSING: &str = "curl is required to fetch HTTP upstreams but was not found on PATH";
+
+type Headers = Vec<(String, String)>;
+
+/// The system time, waiting on tokio's timers.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64() * 1000.0)
+    }
+
+    fn delay(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Sends the upstream requests with the system's `curl`, `file://` URLs read directly.
+pub struct CurlClient;
+
+impl CurlClient {
+    fn fetch(uri: &str, req: &UpstreamRequest) -> worker::Result<UpstreamResponse> {
+        if let Some(path) = uri.strip_prefix("file://") {
+            // The arrivals URL carries the stop ids as a query, meaningless for a file
+            let path = path.split_once('?').map_or(path, |(path, _)| path);
+            let body = std::fs::read(path)
+                .map_err(|err| worker::Error::RustError(format!("{}: {}", uri, err)))?;
+            return Ok(Self::response(200, Vec::new(), body));
+        }
+        let timeout_secs = Config::get().upstream_timeout_ms as f64 / 1000.0;
+        let mut curl = Command::new("curl");
+        curl.args(["--silent", "--show-error", "--location", "--compressed"])
+            .args(["--include", "--max-time", &timeout_secs.to_string()]);
+        match req.method {
+            Method::Get => {}
+            Method::Head => {
+                curl.arg("--head");
+            }
+            ref method => {
+                curl.args(["--request", method.as_ref()]);
+            }
+        }
+        for (name, value) in &req.headers {
+            curl.arg("--header").arg(format!("{}: {}", name, value));
+        }
+        let output = curl.arg(uri).output().map_err(|err| match err.kind() {
+            ErrorKind::NotFound => worker::Error::RustError(CURL_MISSING.to_string()),
+            _ => worker::Error::RustError(format!("running curl: {}", err)),
+        })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(worker::Error::RustError(format!(
+                "{}: {}",
+                uri,
+                stderr.trim()
+            )));
+        }
+        let (status, headers, body) = parse_included(&output.stdout)
+            .ok_or_else(|| worker::Error::RustError(format!("{}: unreadable curl output", uri)))?;
+        Ok(Self::response(status, headers, body.to_vec()))
+    }
+
+    fn response(status: u16, headers: Headers, body: Vec<u8>) -> UpstreamResponse {
+        let body = futures::stream::once(futures::future::ready(Ok(body)));
+        UpstreamResponse::new(status, headers, body.boxed_local())
+    }
+}
+
+impl UpstreamClient for CurlClient {
+    fn send<'a>(
+        &'a self,
+        uri: &'a str,
+        req: &'a UpstreamRequest,
+    ) -> LocalBoxFuture<'a, worker::Result<UpstreamResponse>> {
+        Box::pin(futures::future::ready(Self::fetch(uri, req)))
+    }
+}
+
+/// Status, headers and body of curl's `--include` output, those of the last response when it
+/// followed redirects or got an interim response first.
+fn parse_included(mut output: &[u8]) -> Option<(u16, Headers, &[u8])> {
+    loop {
+        let end = memchr::memmem::find(output, b"\r\n\r\n")?;
+        let head = str::from_utf8(&output[..end]).ok()?;
+        let body = &output[end + 4..];
+        let mut lines = head.split("\r\n");
+        let status = lines.next()?.split(' ').nth(1)?.parse::<u16>().ok()?;
+        if matches!(status / 100, 1 | 3) && body.starts_with(b"HTTP/") {
+            output = body;
+            continue;
+        }
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        return Some((status, headers, body));
+    }
+}
+
+/// The bindings of the handlers on the host: the service without any of the Cloudflare ones, so
+/// no snapshots, D1 store, arrivals Durable Object or response profiles.
+struct Host {
+    service: TransportService,
+}
+
+impl SnapshotStore for Host {
+    fn latest<'a>(&'a self, _file: &'a str) -> LocalBoxFuture<'a, Option<Vec<u8>>> {
+        Box::pin(futures::future::ready(None))
+    }
+
+    fn store_if_changed<'a>(&'a self, _file: &'a str, _buf: &'a [u8]) -> LocalBoxFuture<'a, ()> {
+        Box::pin(futures::future::ready(()))
+    }
+}
+
+impl Bindings for Host {
+    fn service(&self) -> &TransportService {
+        &self.service
+    }
+
+    fn synced_store(&self) -> Option<StopsRouteStore> {
+        None
+    }
+
+    fn arrivals_object(&self) -> Option<ObjectNamespace> {
+        None
+    }
+
+    fn profile<'a>(
+        &'a self,
+        _name: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Rc<Option<ResponseProfile>>, ParsingUpstreamError>> {
+        Box::pin(futures::future::ready(Ok(Rc::new(None))))
+    }
+}
+
+/// A request as the handlers read it, with the raw path parameters of the route it matched as
+/// the worker's router gives them.
+struct HostRequest {
+    url: Url,
+    headers: http::HeaderMap,
+    params: Vec<(String, String)>,
+}
+
+impl HostRequest {
+    fn new(parts: &Parts) -> worker::Result<Self> {
+        let url = Url::parse(&format!("http://localhost{}", parts.uri))
+            .map_err(|_| RequestError::InvalidParameter("malformed request target".to_string()))?;
+        let params = parts
+            .extensions
+            .get::<MatchedPath>()
+            .map_or_else(Vec::new, |matched| {
+                let segments = matched.as_str().split('/').zip(url.path().split('/'));
+                segments
+                    .filter_map(|(template, segment)| {
+                        let name = template.strip_prefix('{')?.strip_suffix('}')?;
+                        Some((name.to_string(), segment.to_string()))
+                    })
+                    .collect()
+            });
+        Ok(Self {
+            url,
+            headers: parts.headers.clone(),
+            params,
+        })
+    }
+}
+
+impl Incoming for HostRequest {
+    fn url(&self) -> worker::Result<Url> {
+        Ok(self.url.clone())
+    }
+
+    fn header(&self, name: &str) -> worker::Result<Option<String>> {
+        let value = self.headers.get(name).and_then(|value| value.to_str().ok());
+        Ok(value.map(str::to_string))
+    }
+}
+
+impl PathParams for HostRequest {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Outgoing for http::Response<Body> {
+    fn status(&self) -> u16 {
+        http::Response::status(self).as_u16()
+    }
+
+    fn has_header(&self, name: &str) -> worker::Result<bool> {
+        Ok(self.headers().contains_key(name))
+    }
+
+    fn set_header(&mut self, name: &'static str, value: &str) -> worker::Result<()> {
+        let invalid = |err: &dyn std::fmt::Display| worker::Error::RustError(err.to_string());
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(&err))?;
+        let value = HeaderValue::from_str(value).map_err(|err| invalid(&err))?;
+        self.headers_mut().insert(name, value);
+        Ok(())
+    }
+
+    fn from_reply(reply: Reply) -> worker::Result<Self> {
+        Ok(into_http(reply))
+    }
+}
+
+fn into_http(reply: Reply) -> http::Response<Body> {
+    let mut response = http::Response::new(Body::from(reply.body));
+    *response.status_mut() =
+        StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    for (name, value) in reply.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// The reply of a handler, its errors answered as problems the way the worker's `problems`
+/// middleware does.
+fn reply_of(result: worker::Result<Reply>, path: &str) -> Reply {
+    let reply = match result {
+        Ok(reply) => return reply,
+        Err(worker::Error::Json((message, status))) => json_error_reply(message, status),
+        Err(err) => {
+            log_error!("{} failed: {}", path, err);
+            error_reply("internal error", 500)
+        }
+    };
+    reply.unwrap_or_else(|_| Reply::new(PROBLEM_CONTENT_TYPE, Vec::new()).with_status(500))
+}
+
+type Shared = Arc<SendWrapper<Host>>;
+
+/// The axum handler of one of the worker's. The handlers' futures aren't `Send`, which axum asks
+/// for, and are run on the one thread of [`serve`]'s runtime.
+fn shared<H>(
+    handler: H,
+) -> impl Fn(State<Shared>, http::Request<Body>) -> BoxFuture<'static, http::Response<Body>>
++ Clone
++ Send
++ Sync
++ 'static
+where
+    H: AsyncFn(&HostRequest, &Host) -> worker::Result<Reply> + Clone + Send + Sync + 'static,
+{
+    move |State(host): State<Shared>, request: http::Request<Body>| {
+        let handler = handler.clone();
+        Box::pin(SendFuture::new(async move {
+            let (parts, _) = request.into_parts();
+            let result = match HostRequest::new(&parts) {
+                Ok(req) => handler(&req, &**host).await,
+                Err(err) => Err(err),
+            };
+            into_http(reply_of(result, parts.uri.path()))
+        })) as BoxFuture<'static, _>
+    }
+}
+
+/// The routes of the worker the host serves, under the same prefixes: the route tree, the
+/// shapes, the arrivals and the OpenAPI document. Any other path of the API answers 501.
+fn router(host: Host) -> Router {
+    let prefixes = ApiVersion::ALL.map(ApiVersion::prefix);
+    let router = prefixes
+        .into_iter()
+        .chain(["/api"])
+        .fold(Router::new(), |router, prefix| {
+            let path = |path: &str| path.replacen("/api", prefix, 1);
+            router
+                .route(
+                    &path("/api/openapi.json"),
+                    get(shared(async |_, _| openapi_spec())),
+                )
+                .route(
+                    &path("/api/types"),
+                    get(shared(async |req, host| routes::types(req, host).await)),
+                )
+                .route(
+                    &path("/api/types/{type}/routes"),
+                    get(shared(async |req, host| {
+                        routes::routes_by_type(req, req, host).await
+                    })),
+                )
+                .route(
+                    &path("/api/types/{type}/routes/{number}/directions"),
+                    get(shared(async |req, host| {
+                        routes::directions_by_route_type_number(req, req, host).await
+                    })),
+                )
+                .route(
+                    &path("/api/types/{type}/routes/{number}/directions/{direction}/stops"),
+                    get(shared(async |req, host| {
+                        routes::stops_by_route_type_number_direction(req, req, host).await
+                    })),
+                )
+                .route(
+                    &path("/api/types/{type}/routes/{number}/directions/{direction}/shape"),
+                    get(shared(async |req, host| {
+                        routes::route_shape(req, req, host).await
+                    })),
+                )
+                .route(
+                    &path("/api/arrivals"),
+                    get(shared(async |req, host| {
+                        arrivals::stop_arrivals(req, host).await
+                    })),
+                )
+                .route(
+                    &path("/api/stops/{id}/arrivals"),
+                    get(shared(async |req, host| {
+                        arrivals::single_stop_arrivals(req, req, host).await
+                    })),
+                )
+        });
+    router
+        .fallback(shared(async |req, _| unserved(req)))
+        .with_state(Arc::new(SendWrapper::new(host)))
+}
+
+fn openapi_spec() -> worker::Result<Reply> {
+    Ok(Reply::new(
+        "application/json",
+        serde_json::to_vec(&api_doc())?,
+    ))
+}
+
+/// 501 for the paths of the API the worker answers with its Cloudflare bindings, 404 for those
+/// neither knows.
+fn unserved(req: &HostRequest) -> worker::Result<Reply> {
+    match route_template(req.url.path()) {
+        Some(_) => error_reply(
+            "answered only by the worker, with its Cloudflare bindings",
+            501,
+        ),
+        None => error_reply("Not Found", 404),
+    }
+}
+
+/// Answers the request through the router and the steps of the worker's middleware the host can
+/// run, in the worker's order: the problems of the bare failures and every problem's `instance`,
+/// CORS and the deprecation links around the preflight and the listings' ETags and freshness.
+async fn answer(router: &mut Router, request: http::Request<Body>) -> http::Response<Body> {
+    let path = request.uri().path().to_string();
+    let response = match route(router, request).await {
+        Ok(response) => response,
+        Err(err) => into_http(reply_of(Err(err), &path)),
+    };
+    let mut response = problems(response, &path).await;
+    if let Err(err) =
+        allow_any_origin(&path, &mut response).and_then(|()| link_successor(&path, &mut response))
+    {
+        log_warn!("{} headers failed: {}", path, err);
+    }
+    response
+}
+
+async fn route(
+    router: &mut Router,
+    request: http::Request<Body>,
+) -> worker::Result<http::Response<Body>> {
+    let (parts, body) = request.into_parts();
+    let req = HostRequest::new(&parts)?;
+    if parts.method == http::Method::OPTIONS {
+        return Ok(into_http(preflight_reply(&req)?));
+    }
+    let tag = ListingTag::of(&req, parts.method == http::Method::GET)?;
+    let response = match tag.unchanged() {
+        Some(reply) => into_http(reply),
+        None => {
+            let request = http::Request::from_parts(parts, body);
+            let Ok(()) = poll_fn(|cx| Service::<http::Request<Body>>::poll_ready(router, cx)).await;
+            let Ok(response) = router.call(request).await;
+            response
+        }
+    };
+    tag.apply(response)
+}
+
+/// The bare failures, the router's 405 among them, answered as problems, and every problem given
+/// the request's path as its `instance`.
+async fn problems(response: http::Response<Body>, path: &str) -> http::Response<Body> {
+    let content_type = response.headers().get(CONTENT_TYPE);
+    let problem = content_type
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(PROBLEM_CONTENT_TYPE));
+    let bare = response.status().as_u16() >= 400 && content_type.is_none();
+    if !problem && !bare {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let body = match bare {
+        false => body.to_vec(),
+        true => {
+            let detail = match String::from_utf8_lossy(&body).into_owned() {
+                detail if detail.is_empty() => parts
+                    .status
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .to_string(),
+                detail => detail,
+            };
+            reply_of(error_reply(detail, parts.status.as_u16()), path).body
+        }
+    };
+    let body = match serde_json::from_slice(&body) {
+        Ok(problem) => serde_json::to_vec(&problem_in_context(problem, path, None)).unwrap_or(body),
+        Err(_) => body,
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+    http::Response::from_parts(parts, Body::from(body))
+}
+
+/// Answers the HTTP/1.1 requests of `listener` one at a time, each connection closed after its
+/// response, until the process is stopped.
+pub fn serve(listener: TcpListener) -> io::Result<()> {
+    Config::configure(&ProcessVars).map_err(|err| io::Error::other(err.to_string()))?;
+    clock::install(SystemClock);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let mut router = router(Host {
+        service: TransportService::with_client(CurlClient),
+    });
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| runtime.block_on(respond(&mut router, stream)));
+        if let Err(err) = result {
+            log_warn!("connection failed: {}", err);
+        }
+    }
+    Ok(())
+}
+
+async fn respond(router: &mut Router, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let (response, head) = match read_request(&stream) {
+        Ok((request, head)) => (answer(router, request).await, head),
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            let reply = reply_of(error_reply(err.to_string(), 400), "");
+            (into_http(reply), false)
+        }
+        Err(err) => return Err(err),
+    };
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await.map_err(io::Error::other)?;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n",
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or_default()
+    )?;
+    for (name, value) in parts
+        .headers
+        .iter()
+        .filter(|(name, _)| **name != CONTENT_LENGTH)
+    {
+        write!(stream, "{}: ", name)?;
+        stream.write_all(value.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    // A HEAD is answered by its GET, without the body
+    if !head {
+        stream.write_all(&body)?;
+    }
+    stream.flush()
+}
+
+/// The request line and headers of the connection, a HEAD read as a GET and told apart. The
+/// bodies are left unread, the host serving GETs only.
+fn read_request(stream: &TcpStream) -> io::Result<(http::Request<Body>, bool)> {
+    let malformed =
+        |what: &str| io::Error::new(ErrorKind::InvalidData, format!("malformed {}", what));
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut reader)
+        .take(MAX_LINE_LEN)
+        .read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(malformed("request line"));
+    };
+    let head = method == "HEAD";
+    let method = if head { "GET" } else { method };
+    let mut request = http::Request::builder().method(method).uri(target);
+    loop {
+        let mut header = String::new();
+        if (&mut reader).take(MAX_LINE_LEN).read_line(&mut header)? == 0
+            || header.trim_end().is_empty()
+        {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| malformed("header"))?;
+        request = request.header(name.trim(), value.trim());
+    }
+    let request = request
+        .body(Body::empty())
+        .map_err(|_| malformed("request"))?;
+    Ok((request, head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::routes::UNRESOLVED_STOP_NAME;
+    use crate::upstream::FixtureClient;
+    use futures::executor::block_on;
+    use serde_json::{Value, json};
+
+    const ROUTES: &str = "RouteNum;Transport;RouteName;RouteStops\n\
+        1;tram;Kopli - Linnahall;1001,1002\n\
+        ;;Linnahall - Kopli;1002,1003\n";
+    const STOPS: &str = "ID;SiriID;Name\n1001;101;Kopli\n1002;102;Linnahall\n";
+    const SIRI: &str = "Transport,RouteNum,ExpectedTimeInSeconds,ScheduleTimeInSeconds\n\
+        stop,101\n\
+        tram,1,36000,35940,Linnahall,,Z\n";
+
+    fn service() -> TransportService {
+        clock::install(ManualClock::new(1_760_000_000_000.0));
+        let config = Config::get();
+        let client = FixtureClient::new()
+            .with_body(config.routes_url.as_str(), ROUTES)
+            .with_body(config.stops_url.as_str(), STOPS)
+            .with_body(format!("{}?stopid=101", config.arrivals_url), SIRI);
+        TransportService::with_client(client)
+    }
+
+    fn request(host: &mut Router, target: &str, headers: &[(&str, &str)]) -> http::Response<Body> {
+        let mut request = http::Request::builder().uri(target);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        block_on(answer(host, request.body(Body::empty()).unwrap()))
+    }
+
+    fn get(host: &mut Router, target: &str) -> (u16, Value) {
+        let response = request(host, target, &[]);
+        let status = response.status().as_u16();
+        let body = block_on(to_bytes(response.into_body(), usize::MAX)).unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn host() -> Router {
+        router(Host { service: service() })
+    }
+
+    #[test]
+    fn serves_the_route_listings() {
+        let host = &mut host();
+        assert_eq!(get(host, "/api/types"), (200, json!(["tram"])));
+        assert_eq!(get(host, "/api/v1/types/TRAM/routes"), (200, json!(["1"])));
+        assert_eq!(
+            get(host, "/api/types/tram/routes/1/directions"),
+            (200, json!(["Kopli - Linnahall", "Linnahall - Kopli"]))
+        );
+        assert_eq!(
+            get(
+                host,
+                "/api/types/tram/routes/1/directions/linnahall%20-%20kopli/stops"
+            ),
+            (
+                200,
+                json!([["1002", "Linnahall"], ["1003", UNRESOLVED_STOP_NAME]])
+            )
+        );
+        assert_eq!(
+            get(host, "/api/types/tram/routes/1/directions/0/stops").1[0],
+            json!(["1001", "Kopli"])
+        );
+        let (status, stops) = get(host, "/api/v2/types/tram/routes/1/directions/0/stops");
+        assert_eq!((status, &stops["stops"][0]["siriId"]), (200, &json!("101")));
+    }
+
+    #[test]
+    fn answers_problems_for_unknown_paths() {
+        let host = &mut host();
+        let (status, problem) = get(host, "/api/types/boat/routes");
+        assert_eq!((status, &problem["code"]), (404, &json!("UNKNOWN_TYPE")));
+        assert_eq!(problem["instance"], "/api/types/boat/routes");
+        let (status, problem) = get(host, "/api/types/tram/routes/9/directions");
+        assert_eq!((status, &problem["code"]), (404, &json!("UNKNOWN_ROUTE")));
+        let (status, problem) = get(host, "/api/types/tram/routes/1/directions/x/stops");
+        assert_eq!(
+            (status, &problem["code"]),
+            (404, &json!("UNKNOWN_DIRECTION"))
+        );
+        assert_eq!(
+            problem["directions"],
+            json!(["Kopli - Linnahall", "Linnahall - Kopli"])
+        );
+        let (status, problem) = get(host, "/api/vehicles");
+        assert_eq!((status, &problem["code"]), (501, &json!("NOT_IMPLEMENTED")));
+        assert_eq!(get(host, "/nowhere").0, 404);
+        assert_eq!(get(host, "/api/arrivals").0, 400);
+    }
+
+    #[test]
+    fn serves_arrivals_per_stop() {
+        let host = &mut host();
+        let (status, body) = get(host, "/api/arrivals?stops=1001,404");
+        assert_eq!(status, 200);
+        assert_eq!(body["stops"][0]["status"], "ok");
+        assert_eq!(body["stops"][0]["data"]["name"], "Kopli");
+        assert!(body["stops"][0]["data"]["arrivals"]["tram"]["1"].is_array());
+        assert_eq!(
+            body["stops"][1],
+            json!({ "id": "404", "status": "unknown_stop" })
+        );
+    }
+
+    #[test]
+    fn runs_the_middleware_around_the_handlers() {
+        let host = &mut host();
+        let stops = "/api/types/tram/routes/1/directions/0/stops";
+        let response = request(host, stops, &[]);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(headers.contains_key("deprecation"));
+        let etag = headers["etag"].to_str().unwrap().to_string();
+        let response = request(host, stops, &[("If-None-Match", &etag)]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = request(host, "/api/v1/types", &[]);
+        assert!(!response.headers().contains_key("deprecation"));
+    }
+
+    #[test]
+    fn reads_the_last_response_of_curl_output() {
+        let output = b"HTTP/1.1 301 Moved Permanently\r\nLocation: /b\r\n\r\n\
+            HTTP/2 200\r\netag: \"v1\"\r\nlast-modified: Tue, 14 Oct 2025 08:00:00 GMT\r\n\r\nbody";
+        let (status, headers, body) = parse_included(output).unwrap();
+        assert_eq!((status, body), (200, &b"body"[..]));
+        assert_eq!(headers[0], ("etag".to_string(), "\"v1\"".to_string()));
+        assert_eq!(headers[1].1, "Tue, 14 Oct 2025 08:00:00 GMT");
+
+        let (status, _, body) = parse_included(b"HTTP/1.1 304 Not Modified\r\n\r\n").unwrap();
+        assert_eq!((status, body), (304, &b""[..]));
+        assert!(parse_included(b"not http").is_none());
+    }
+}
@@ -0,0 +1,384 @@
+use std::rc::Rc;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+use worker::{Env, Request, Response};
+
+use crate::bindings::Bindings;
+use crate::caches::Caches;
+use crate::extract::{Incoming, RequestError};
+use crate::msgpack;
+use crate::reply::Reply;
+use crate::services::ParsingUpstreamError;
+use crate::{Encoding, negotiate};
+
+/// KV namespace holding operator-defined response profiles as `profile:<name>` JSON, optional.
+pub const RESPONSE_PROFILES_BINDING: &str = "RESPONSE_PROFILES";
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProfileShape {
+    #[default]
+    Default,
+    /// Replaces a single-key root object by its value, e.g. `{"stops": [..]}` becomes `[..]`
+    Unwrapped,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProfileTimeFormat {
+    #[default]
+    Iso,
+    EpochMillis,
+    EpochSeconds,
+}
+
+/// Named response shaping rules, selected by clients with `?profile=<name>`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResponseProfile {
+    /// Keys kept in objects having at least one of them, objects having none are data maps
+    /// (keyed by type or route number) and kept whole
+    pub fields: Vec<String>,
+    /// Maximum number of items kept in every array
+    pub limit: Option<usize>,
+    pub time_format: ProfileTimeFormat,
+    pub shape: ProfileShape,
+}
+
+impl ResponseProfile {
+    /// Loads `name` from KV, caching hits and misses for a few minutes per isolate.
+    pub async fn load(
+        env: &Env,
+        name: &str,
+    ) -> Result<Rc<Option<ResponseProfile>>, ParsingUpstreamError> {
+        let cache = &Caches::get_cache().response_profiles;
+        let key = name.to_string();
+        if let Some(profile) = cache.get(&key) {
+            return Ok(profile);
+        }
+        let profile = match env.kv(RESPONSE_PROFILES_BINDING) {
+            Ok(kv) => kv
+                .get(&format!("profile:{}", name))
+                .json::<ResponseProfile>()
+                .await
+                .map_err(worker::Error::from)?,
+            Err(_) => None,
+        };
+        let profile = Rc::new(profile);
+        cache.set(key, Rc::clone(&profile)).ok();
+        Ok(profile)
+    }
+
+    pub fn apply(&self, value: Value) -> Value {
+        let value = self.shape_value(value);
+        match (self.shape, value) {
+            (ProfileShape::Unwrapped, Value::Object(map)) if map.len() == 1 => map
+                .into_iter()
+                .next()
+                .map(|(_, v)| v)
+                .unwrap_or(Value::Null),
+            (_, value) => value,
+        }
+    }
+
+    fn shape_value(&self, value: Value) -> Value {
+        match value {
+            Value::Array(items) => Value::Array(
+                items
+                    .into_iter()
+                    .take(self.limit.unwrap_or(usize::MAX))
+                    .map(|item| self.shape_value(item))
+                    .collect(),
+            ),
+            Value::Object(map) => {
+                let keep_all =
+                    self.fields.is_empty() || !map.keys().any(|key| self.fields.contains(key));
+                Value::Object(
+                    map.into_iter()
+                        .filter(|(key, _)| keep_all || self.fields.contains(key))
+                        .map(|(key, value)| {
+                            let value = if key == "time" {
+                                self.format_time(value)
+                            } else {
+                                self.shape_value(value)
+                            };
+                            (key, value)
+                        })
+                        .collect(),
+                )
+            }
+            other => other,
+        }
+    }
+
+    fn format_time(&self, value: Value) -> Value {
+        let Value::String(time) = &value else {
+            return value;
+        };
+        let Ok(parsed) = DateTime::parse_from_rfc3339(time) else {
+            return value;
+        };
+        match self.time_format {
+            ProfileTimeFormat::Iso => value,
+            ProfileTimeFormat::EpochMillis => Value::from(parsed.timestamp_millis()),
+            ProfileTimeFormat::EpochSeconds => Value::from(parsed.timestamp()),
+        }
+    }
+}
+
+/// Sparse fieldsets: `?fields=` (or `fields[stops]=`) keeps the listed members of the stop
+/// objects, `id` always, and `fields[arrivals]=` those of the arrivals. JSON:API resources have
+/// their attributes filtered by the fieldset of their type.
+#[derive(Default)]
+pub struct Fieldsets {
+    stops: Option<Vec<String>>,
+    arrivals: Option<Vec<String>>,
+}
+
+impl Fieldsets {
+    /// The fieldsets of the query, `None` without any.
+    pub fn from_request(req: &dyn Incoming) -> worker::Result<Option<Fieldsets>> {
+        let mut fieldsets = Fieldsets::default();
+        for (key, value) in req.url()?.query_pairs() {
+            let fieldset = match key.as_ref() {
+                "fields" | "fields[stops]" => &mut fieldsets.stops,
+                "fields[arrivals]" => &mut fieldsets.arrivals,
+                key if key.starts_with("fields[") => {
+                    return Err(RequestError::InvalidParameter(format!(
+                        "unknown fieldset {} (fields[stops], fields[arrivals])",
+                        key
+                    ))
+                    .into());
+                }
+                _ => continue,
+            };
+            let fields = value
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .map(str::to_string);
+            fieldset.get_or_insert_with(Vec::new).extend(fields);
+        }
+        Ok((fieldsets.stops.is_some() || fieldsets.arrivals.is_some()).then_some(fieldsets))
+    }
+
+    pub fn apply(&self, value: Value) -> Value {
+        self.filter(value, false)
+    }
+
+    fn filter(&self, value: Value, in_arrivals: bool) -> Value {
+        let mut map = match value {
+            Value::Array(items) => {
+                return Value::Array(
+                    items
+                        .into_iter()
+                        .map(|item| self.filter(item, in_arrivals))
+                        .collect(),
+                );
+            }
+            Value::Object(map) => map,
+            other => return other,
+        };
+        let resource_fieldset = match map.get("type").and_then(Value::as_str) {
+            Some("stops") => Some(&self.stops),
+            Some("arrivals") => Some(&self.arrivals),
+            _ => None,
+        };
+        if let (Some(fieldset), Some(Value::Object(attributes))) =
+            (resource_fieldset, map.get_mut("attributes"))
+        {
+            if let Some(fields) = fieldset {
+                attributes.retain(|key, _| fields.contains(key));
+            }
+            return Value::Object(map);
+        }
+        let fieldset = if in_arrivals && map.contains_key("time") {
+            &self.arrivals
+        } else if map.contains_key("id") && map.contains_key("name") {
+            &self.stops
+        } else {
+            &None
+        };
+        if let Some(fields) = fieldset {
+            map.retain(|key, _| key == "id" || fields.contains(key));
+        }
+        Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let in_arrivals = in_arrivals || key == "arrivals";
+                    (key, self.filter(value, in_arrivals))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// `meta` of a `?envelope=true` response, for showing how current the data is.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvelopeMeta {
+    #[schema(example = "2025-10-14T08:15:09Z")]
+    pub generated_at: String,
+    /// Seconds the data spent in the isolate cache, absent when it couldn't be told
+    #[schema(example = 4)]
+    pub cache_age_seconds: Option<u32>,
+    /// When the data was fetched from upstream
+    #[schema(example = "2025-10-14T08:15:05Z")]
+    pub source_timestamp: Option<String>,
+}
+
+impl EnvelopeMeta {
+    fn new(cache_age_secs: Option<u32>) -> Self {
+        let now = Utc::now();
+        let source_time = cache_age_secs.map(|age| now - Duration::seconds(i64::from(age)));
+        Self {
+            generated_at: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            cache_age_seconds: cache_age_secs,
+            source_timestamp: source_time
+                .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        }
+    }
+}
+
+/// `{"data", "meta"}`, or the meta added to the document's own root for JSON:API and GeoJSON,
+/// next to the members of a meta the document has already.
+fn enveloped(value: Value, encoding: Encoding, cache_age_secs: Option<u32>) -> Value {
+    let meta = serde_json::to_value(EnvelopeMeta::new(cache_age_secs)).unwrap_or_default();
+    match (encoding, value) {
+        (Encoding::JsonApi | Encoding::GeoJson, Value::Object(mut document)) => {
+            match (document.get_mut("meta"), meta) {
+                (Some(Value::Object(existing)), Value::Object(meta)) => existing.extend(meta),
+                (_, meta) => {
+                    document.insert("meta".to_string(), meta);
+                }
+            }
+            Value::Object(document)
+        }
+        (_, data) => serde_json::json!({ "data": data, "meta": meta }),
+    }
+}
+
+fn encoded<T: Serialize>(value: &T, encoding: Encoding) -> worker::Result<Reply> {
+    let body = match encoding {
+        Encoding::Json | Encoding::GeoJson | Encoding::JsonApi => serde_json::to_vec(value)?,
+        Encoding::MessagePack => msgpack::to_vec(value)?,
+        Encoding::Csv => {
+            return Err(worker::Error::RustError(
+                "CSV rows are built by the handlers".to_string(),
+            ));
+        }
+    };
+    Ok(Reply::new(encoding.media_types()[0], body))
+}
+
+/// Serializes `value` as JSON, or MessagePack when `Accept` prefers it, shaped by the profile
+/// named in `?profile=` and the [`Fieldsets`] when given, wrapped with `?envelope=true`.
+pub async fn respond<T: Serialize>(
+    req: &Request,
+    env: &Env,
+    value: &T,
+) -> worker::Result<Response> {
+    reply(req, env, value).await?.into_response()
+}
+
+/// [`respond`] in an encoding the handler negotiated, any but CSV.
+pub async fn respond_as<T: Serialize>(
+    req: &Request,
+    env: &Env,
+    value: &T,
+    encoding: Encoding,
+) -> worker::Result<Response> {
+    reply_as(req, env, value, encoding).await?.into_response()
+}
+
+/// [`respond_as`] for data other than routes.txt and stops.txt, `cache_age_secs` being its age
+/// in the `?envelope=true` metadata.
+pub async fn respond_with_age<T: Serialize>(
+    req: &Request,
+    env: &Env,
+    value: &T,
+    encoding: Encoding,
+    cache_age_secs: Option<u32>,
+) -> worker::Result<Response> {
+    reply_with_age(req, env, value, encoding, cache_age_secs)
+        .await?
+        .into_response()
+}
+
+/// [`respond`] as a [`Reply`], for the handlers `tlt-serve` shares.
+pub async fn reply<T: Serialize>(
+    req: &dyn Incoming,
+    bindings: &dyn Bindings,
+    value: &T,
+) -> worker::Result<Reply> {
+    let encoding = negotiate(req, None, &[Encoding::Json, Encoding::MessagePack])?;
+    reply_as(req, bindings, value, encoding).await
+}
+
+pub async fn reply_as<T: Serialize>(
+    req: &dyn Incoming,
+    bindings: &dyn Bindings,
+    value: &T,
+    encoding: Encoding,
+) -> worker::Result<Reply> {
+    let cache_age_secs = Caches::get_cache().data_age_secs();
+    reply_with_age(req, bindings, value, encoding, cache_age_secs).await
+}
+
+pub async fn reply_with_age<T: Serialize>(
+    req: &dyn Incoming,
+    bindings: &dyn Bindings,
+    value: &T,
+    encoding: Encoding,
+    cache_age_secs: Option<u32>,
+) -> worker::Result<Reply> {
+    let url = req.url()?;
+    let profile_name = url
+        .query_pairs()
+        .find_map(|(k, v)| (k == "profile" && !v.is_empty()).then(|| v.into_owned()));
+    let envelope = url
+        .query_pairs()
+        .any(|(k, v)| k == "envelope" && v == "true");
+    let fieldsets = Fieldsets::from_request(req)?;
+    if profile_name.is_none() && fieldsets.is_none() && !envelope {
+        return encoded(value, encoding);
+    }
+    let mut value = serde_json::to_value(value)?;
+    if let Some(profile_name) = profile_name {
+        let profile = bindings.profile(&profile_name).await?;
+        let Some(profile) = profile.as_ref() else {
+            return crate::error_reply(format!("unknown profile {}", profile_name), 400);
+        };
+        value = profile.apply(value);
+    }
+    if let Some(fieldsets) = fieldsets {
+        value = fieldsets.apply(value);
+    }
+    if envelope {
+        value = enveloped(value, encoding, cache_age_secs);
+    }
+    encoded(&value, encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn envelopes_documents_next_to_their_own_meta() {
+        let document = json!({ "data": [], "meta": { "missingNames": ["1003"] } });
+        let document = enveloped(document, Encoding::JsonApi, Some(4));
+        assert_eq!(document["meta"]["missingNames"], json!(["1003"]));
+        assert_eq!(document["meta"]["cacheAgeSeconds"], 4);
+        assert_eq!(document["data"], json!([]));
+
+        let listing = json!({ "stops": [], "missingNames": [] });
+        let enveloped = enveloped(listing.clone(), Encoding::Json, None);
+        assert_eq!(enveloped["data"], listing);
+        assert!(enveloped["meta"]["generatedAt"].is_string());
+    }
+}
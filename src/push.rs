@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+use worker::{Env, Fetch, Headers, KvStore, Method, Request, RequestInit, Result, Url};
+
+use crate::caches::now_secs;
+use crate::crypto::{base64url_encode, random_id, sign_es256};
+use crate::handlers::arrivals::{ArrivalsLookup, lookup_stop_arrivals};
+use crate::logging::{log_error, log_warn};
+use crate::models::{StopArrivalsStatus, TransportType};
+use crate::services::kv_list_json;
+
+/// KV namespace of the pending arrival alerts as `alert:<id>` JSON, optional.
+pub const PUSH_ALERTS_BINDING: &str = "PUSH_ALERTS";
+/// VAPID key pair secrets: the base64url P-256 private scalar and uncompressed public point.
+const VAPID_PRIVATE_KEY_SECRET: &str = "VAPID_PRIVATE_KEY";
+const VAPID_PUBLIC_KEY_SECRET: &str = "VAPID_PUBLIC_KEY";
+/// `mailto:` or `https:` contact of the operator sent to the push services, optional but some
+/// reject pushes without it.
+const VAPID_SUBJECT_VAR: &str = "VAPID_SUBJECT";
+const ALERT_PREFIX: &str = "alert:";
+/// Alerts that never fired are dropped after this long.
+const ALERT_TTL_SECS: u64 = 60 * 60 * 12;
+/// Validity of the VAPID JWT, push services reject more than 24 hours.
+const VAPID_JWT_TTL_SECS: u32 = 60 * 60 * 12;
+
+/// The browser's `PushSubscription.toJSON()`.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PushSubscription {
+    #[schema(example = "https://fcm.googleapis.com/fcm/send/abc123")]
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PushSubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// "Notify me when bus 8 is at most 5 minutes from stop 1001."
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArrivalAlertRequest {
+    pub subscription: PushSubscription,
+    /// Stop ID or SIRI ID
+    #[schema(example = "1001")]
+    pub stop_id: String,
+    pub r#type: TransportType,
+    #[schema(example = "8")]
+    pub number: String,
+    /// Minutes before the arrival to notify at (1-60)
+    #[schema(example = 5)]
+    pub threshold_minutes: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArrivalAlertCreated {
+    /// Needed to cancel the alert, it fires once and is then removed
+    #[schema(example = "q1w2e3r4t5y6u7i8o9p0aA")]
+    pub id: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VapidPublicKey {
+    /// `applicationServerKey` for `PushManager.subscribe()`
+    pub public_key: String,
+}
+
+pub struct Vapid {
+    private_key: String,
+    pub public_key: String,
+    subject: Option<String>,
+}
+
+impl Vapid {
+    /// The configured key pair, `None` unless both secrets are set.
+    pub fn from_env(env: &Env) -> Option<Self> {
+        Some(Self {
+            private_key: env.secret(VAPID_PRIVATE_KEY_SECRET).ok()?.to_string(),
+            public_key: env.secret(VAPID_PUBLIC_KEY_SECRET).ok()?.to_string(),
+            subject: env.var(VAPID_SUBJECT_VAR).ok().map(|var| var.to_string()),
+        })
+    }
+
+    /// `Authorization` value for the push service of `endpoint`.
+    async fn authorization(&self, endpoint: &Url) -> Result<String> {
+        let header = base64url_encode(br#"{"typ":"JWT","alg":"ES256"}"#);
+        let mut claims = json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": now_secs() + VAPID_JWT_TTL_SECS,
+        });
+        if let Some(subject) = &self.subject {
+            claims["sub"] = json!(subject);
+        }
+        let signing_input = format!(
+            "{}.{}",
+            header,
+            base64url_encode(claims.to_string().as_bytes())
+        );
+        let signature = sign_es256(
+            &self.private_key,
+            &self.public_key,
+            signing_input.as_bytes(),
+        )
+        .await?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            base64url_encode(&signature),
+            self.public_key
+        ))
+    }
+
+    /// Sends a push without payload, the service worker fetches the arrivals itself. Answers
+    /// the push service's status, 404 or 410 meaning the subscription is gone.
+    async fn send(&self, subscription: &PushSubscription) -> Result<u16> {
+        let endpoint = Url::parse(&subscription.endpoint)?;
+        let headers = Headers::new();
+        headers.set("Authorization", &self.authorization(&endpoint).await?)?;
+        headers.set("TTL", "60")?;
+        headers.set("Urgency", "high")?;
+        headers.set("Content-Length", "0")?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post).with_headers(headers);
+        let request = Request::new_with_init(endpoint.as_str(), &init)?;
+        Ok(Fetch::Request(request).send().await?.status_code())
+    }
+}
+
+pub fn alerts_store(env: &Env) -> Option<KvStore> {
+    env.kv(PUSH_ALERTS_BINDING).ok()
+}
+
+pub async fn create_alert(kv: &KvStore, request: ArrivalAlertRequest) -> Result<String> {
+    let id = random_id()?;
+    let alert = serde_json::to_string(&request)?;
+    kv.put(&format!("{}{}", ALERT_PREFIX, id), alert.as_str())?
+        .expiration_ttl(ALERT_TTL_SECS)
+        .execute()
+        .await?;
+    Ok(id)
+}
+
+pub async fn delete_alert(kv: &KvStore, id: &str) -> Result<()> {
+    Ok(kv.delete(&format!("{}{}", ALERT_PREFIX, id)).await?)
+}
+
+/// Sends the alerts whose route is now within its threshold of the stop, run every minute by
+/// the scheduled handler.
+pub async fn check_alerts(env: &Env) -> Result<()> {
+    let (Some(kv), Some(vapid)) = (alerts_store(env), Vapid::from_env(env)) else {
+        return Ok(());
+    };
+    let alerts = kv_list_json::<ArrivalAlertRequest>(&kv, ALERT_PREFIX).await?;
+    if alerts.is_empty() {
+        return Ok(());
+    }
+    let mut stop_ids = alerts
+        .iter()
+        .map(|(_, alert)| alert.stop_id.clone())
+        .collect::<Vec<String>>();
+    stop_ids.sort_unstable();
+    stop_ids.dedup();
+    // A paused upstream is retried with the next run.
+    let ArrivalsLookup::Found { stops, .. } = lookup_stop_arrivals(env, stop_ids).await? else {
+        return Ok(());
+    };
+    let stops = stops
+        .into_iter()
+        .map(|stop| (stop.id.clone(), stop))
+        .collect::<HashMap<_, _>>();
+    let now = chrono::Utc::now();
+    for (key, alert) in alerts {
+        let Some(stop) = stops.get(&alert.stop_id) else {
+            continue;
+        };
+        if stop.status == StopArrivalsStatus::UnknownStop {
+            kv.delete(&key).await?;
+            continue;
+        }
+        let due = stop.data.as_ref().is_some_and(|data| {
+            data.next_arrival_within(
+                alert.r#type.as_str(),
+                &alert.number,
+                alert.threshold_minutes,
+                now,
+            )
+            .is_some()
+        });
+        if !due {
+            continue;
+        }
+        match vapid.send(&alert.subscription).await {
+            Ok(status) if (200..300).contains(&status) || status == 404 || status == 410 => {
+                kv.delete(&key).await?;
+            }
+            Ok(status) => log_warn!("push service answered {} for {}", status, key),
+            Err(err) => log_error!("push for {} failed: {}", key, err),
+        }
+    }
+    Ok(())
+}
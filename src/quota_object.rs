@@ -0,0 +1,116 @@
+use std::cell::Cell;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::caches::now_secs;
+
+const DAY_SECS: u32 = 60 * 60 * 24;
+/// Storage key of the day's count, kept across evictions unlike the minute's.
+const DAILY_KEY: &str = "daily";
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Window {
+    start: u32,
+    count: u32,
+}
+
+impl Window {
+    /// The window of `len_secs` holding `now`, this one's count kept if it's the same.
+    fn current(self, now: u32, len_secs: u32) -> Self {
+        let start = now - now % len_secs;
+        if self.start == start {
+            self
+        } else {
+            Window { start, count: 0 }
+        }
+    }
+}
+
+/// Answer of the [`ApiKeyQuotaObject`] to one request of its key.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaOutcome {
+    /// Requests left within the tighter of the key's limits
+    pub remaining: u32,
+    /// Seconds until the exceeded limit resets, `None` when the request was counted
+    pub retry_after_secs: Option<u32>,
+    /// Whether the exceeded limit is the daily quota rather than the per-minute one
+    pub daily: bool,
+}
+
+/// Durable Object counting the requests of one API key, named by the key, so every isolate
+/// counts against the same quota.
+///
+/// - `POST /consume?dailyQuota=…&perMinute=…` counts a request unless it's over either limit,
+///   answering a [`QuotaOutcome`]. Days are UTC days.
+#[durable_object]
+pub struct ApiKeyQuotaObject {
+    state: State,
+    minute: Cell<Window>,
+}
+
+impl DurableObject for ApiKeyQuotaObject {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            state,
+            minute: Cell::new(Window::default()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        if req.method() != Method::Post || url.path() != "/consume" {
+            return crate::error_response("not found", 404);
+        }
+        let limit = |name: &str| {
+            url.query_pairs()
+                .find_map(|(k, v)| (k == name).then(|| v.parse::<u32>().ok()).flatten())
+        };
+        let (daily_quota, per_minute) = (limit("dailyQuota"), limit("perMinute"));
+        let now = now_secs();
+        let storage = self.state.storage();
+        let day = storage
+            .get::<Window>(DAILY_KEY)
+            .await
+            .unwrap_or_default()
+            .current(now, DAY_SECS);
+        let minute = self.minute.get().current(now, 60);
+        let daily_left = daily_quota.map(|quota| quota.saturating_sub(day.count));
+        let minute_left = per_minute.map(|limit| limit.saturating_sub(minute.count));
+        let exceeded = if daily_left == Some(0) {
+            Some((day.start + DAY_SECS - now, true))
+        } else if minute_left == Some(0) {
+            Some((minute.start + 60 - now, false))
+        } else {
+            None
+        };
+        let remaining = [daily_left, minute_left].into_iter().flatten().min();
+        let outcome = match exceeded {
+            Some((retry_after_secs, daily)) => QuotaOutcome {
+                remaining: 0,
+                retry_after_secs: Some(retry_after_secs),
+                daily,
+            },
+            None => {
+                self.minute.set(Window {
+                    count: minute.count + 1,
+                    ..minute
+                });
+                if daily_quota.is_some() {
+                    let day = Window {
+                        count: day.count + 1,
+                        ..day
+                    };
+                    storage.put(DAILY_KEY, day).await?;
+                }
+                QuotaOutcome {
+                    remaining: remaining.unwrap_or_default().saturating_sub(1),
+                    retry_after_secs: None,
+                    daily: false,
+                }
+            }
+        };
+        Response::from_json(&outcome)
+    }
+}
@@ -0,0 +1,41 @@
+//! Per-client rate limiting of the API by the Workers rate-limiting binding, keyed on the
+//! `CF-Connecting-IP` address, so one misbehaving client can't exhaust the SIRI upstream for
+//! everyone.
+
+use worker::{Env, Request, Response, Result};
+
+use crate::config::Config;
+use crate::logging::log_error;
+use crate::models::ErrorCode;
+
+/// Rate-limiting binding whose `simple` limit is what one client gets, optional.
+pub const RATE_LIMITER_BINDING: &str = "RATE_LIMITER";
+
+/// Whether the client is over its limit. Without the binding or a client address nothing is
+/// limited, and neither when the limiter fails.
+pub async fn over_limit(env: &Env, req: &Request) -> bool {
+    let Ok(limiter) = env.rate_limiter(RATE_LIMITER_BINDING) else {
+        return false;
+    };
+    let Ok(Some(address)) = req.headers().get("CF-Connecting-IP") else {
+        return false;
+    };
+    match limiter.limit(format!("ip:{}", address)).await {
+        Ok(outcome) => !outcome.success,
+        Err(err) => {
+            log_error!("rate limiter failed: {}", err);
+            false
+        }
+    }
+}
+
+/// The 429 of a limited client, retried after the limiter's period.
+pub fn too_many_requests() -> Result<Response> {
+    let mut response =
+        crate::coded_error_response(ErrorCode::RateLimited, "too many requests", 429)?;
+    response.headers_mut().set(
+        "Retry-After",
+        &Config::get().rate_limit_period_secs.to_string(),
+    )?;
+    Ok(response)
+}
@@ -0,0 +1,460 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use worker::{Bucket, Context, Env, Method, Request, Response, Router, Url};
+
+use crate::caches::{Caches, now_secs};
+use crate::handlers::arrivals::{ARRIVALS_SOCKET_PATH, ARRIVALS_STREAM_PATH};
+use crate::logging::log_error;
+use crate::models::StopArrivals;
+use crate::snapshots::{ROUTES_FILE, STOPS_FILE};
+use crate::str_utils::fnv1a_64;
+use crate::{unversioned_path, with_problem_context};
+
+/// R2 bucket receiving sampled request recordings and the upstream payloads they used, optional.
+pub const REPLAY_BINDING: &str = "REPLAY";
+/// Fraction of GET requests recorded while the bucket is bound, defaults to 1%.
+const SAMPLE_RATE_VAR: &str = "REPLAY_SAMPLE_RATE";
+const DEFAULT_SAMPLE_RATE: f64 = 0.01;
+/// Only a build with this var set to "true" exposes the replay endpoint.
+const REPLAY_ENABLED_VAR: &str = "REPLAY_ENABLED";
+
+const RECORDINGS_PREFIX: &str = "recordings/";
+const PAYLOADS_PREFIX: &str = "payloads/";
+
+/// One recorded request, with the routes.txt/stops.txt versions (by content hash) and the
+/// arrivals it was answered from.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recording {
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+    pub recorded_at: u32,
+    pub routes: Option<String>,
+    pub stops: Option<String>,
+    #[serde(default)]
+    pub arrivals: Vec<Rc<StopArrivals>>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayMismatch {
+    #[schema(example = "recordings/1761000000-3f2a9c1b.json")]
+    pub key: String,
+    #[schema(example = "/api/types/bus/routes")]
+    pub url: String,
+    pub expected_status: u16,
+    pub actual_status: u16,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub matched: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+    /// Recordings whose upstream payloads are no longer in the bucket
+    pub skipped: Vec<String>,
+}
+
+fn bucket(env: &Env) -> Option<Bucket> {
+    env.bucket(REPLAY_BINDING).ok()
+}
+
+fn payload_key(file: &str, hash: &str) -> String {
+    format!("{}{}-{}.txt", PAYLOADS_PREFIX, file, hash)
+}
+
+fn is_replayable(path: &str) -> bool {
+    path.starts_with("/api/")
+        && !path.starts_with("/api/admin/")
+        && path != ARRIVALS_STREAM_PATH
+        && path != ARRIVALS_SOCKET_PATH
+        // Replays would refetch the SIRI body from the live upstream.
+        && !path.starts_with("/api/raw/")
+        // Protobuf bodies don't survive being recorded as text.
+        && !path.starts_with("/api/gtfs-rt/")
+        // gps.txt isn't recorded, replaying would compare against the live feed.
+        && !path.starts_with("/api/vehicles")
+        && !path.ends_with("/vehicles")
+}
+
+pub fn replay_enabled(env: &Env) -> bool {
+    env.var(REPLAY_ENABLED_VAR)
+        .is_ok_and(|var| var.to_string() == "true")
+}
+
+/// Records a sample of the GET requests, writing the response and the payloads it was built
+/// from after the response has been sent.
+pub fn record_sampled(
+    env: &Env,
+    ctx: &Context,
+    method: Method,
+    url: &Url,
+    response: &mut Response,
+) {
+    let Some(bucket) = bucket(env) else {
+        return;
+    };
+    // A 304 answered the client's `If-None-Match`, which replays don't send.
+    if method != Method::Get
+        || response.status_code() == 304
+        || !is_replayable(&unversioned_path(url.path()))
+    {
+        return;
+    }
+    // Recordings keep the body as text, and replays don't send the Accept header that may have
+    // picked a JSON:API document.
+    let content_type = response.headers().get("Content-Type").ok().flatten();
+    if content_type.is_some_and(|content_type| {
+        content_type.starts_with("application/msgpack")
+            || content_type.starts_with("application/vnd.api+json")
+    }) {
+        return;
+    }
+    let sample_rate = env
+        .var(SAMPLE_RATE_VAR)
+        .ok()
+        .and_then(|var| var.to_string().parse::<f64>().ok())
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
+    if js_sys::Math::random() >= sample_rate {
+        return;
+    }
+    let Ok(mut response) = response.cloned() else {
+        return;
+    };
+
+    let url = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    // Capture the payloads now, a background refresh may replace them before the write runs.
+    let cache = Caches::get_cache();
+    let routes = cache.routes_raw.get_stale();
+    let stops = cache.stops_raw.get_stale();
+    ctx.wait_until(async move {
+        let recorded = async {
+            let body = response.text().await?;
+            let recording = Recording {
+                arrivals: recorded_arrivals(&url, &body),
+                routes: store_payload(&bucket, ROUTES_FILE, routes).await?,
+                stops: store_payload(&bucket, STOPS_FILE, stops).await?,
+                url,
+                status: response.status_code(),
+                body,
+                recorded_at: now_secs(),
+            };
+            let key = format!(
+                "{}{}-{:08x}.json",
+                RECORDINGS_PREFIX,
+                recording.recorded_at,
+                (js_sys::Math::random() * u32::MAX as f64) as u32
+            );
+            bucket
+                .put(key, serde_json::to_vec(&recording)?)
+                .execute()
+                .await?;
+            Ok::<_, worker::Error>(())
+        }
+        .await;
+        if let Err(err) = recorded {
+            log_error!("failed to record request: {}", err);
+        }
+    });
+}
+
+/// Arrivals served by an arrivals response, taken from the cache it was answered from.
+fn recorded_arrivals(url: &str, body: &str) -> Vec<Rc<StopArrivals>> {
+    #[derive(Deserialize)]
+    struct ArrivalsStop {
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct ArrivalsResult {
+        data: Option<ArrivalsStop>,
+    }
+    #[derive(Deserialize)]
+    struct ArrivalsBody {
+        stops: Vec<ArrivalsResult>,
+    }
+    if !url.starts_with("/api/arrivals") {
+        return Vec::new();
+    }
+    let arrivals_cache = &Caches::get_cache().stop_arrival;
+    serde_json::from_str::<ArrivalsBody>(body)
+        .map(|body| {
+            body.stops
+                .into_iter()
+                .filter_map(|result| result.data)
+                .filter_map(|stop| arrivals_cache.get(&stop.id))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Stores a payload under its content hash unless already there, returning the hash.
+async fn store_payload(
+    bucket: &Bucket,
+    file: &str,
+    buf: Option<Rc<Vec<u8>>>,
+) -> worker::Result<Option<String>> {
+    let Some(buf) = buf else {
+        return Ok(None);
+    };
+    let hash = format!("{:016x}", fnv1a_64(&buf));
+    let key = payload_key(file, &hash);
+    if bucket.head(&key).await?.is_none() {
+        bucket.put(key, buf.to_vec()).execute().await?;
+    }
+    Ok(Some(hash))
+}
+
+async fn load_payload(bucket: &Bucket, file: &str, hash: &str) -> worker::Result<Option<Vec<u8>>> {
+    match bucket.get(payload_key(file, hash)).execute().await? {
+        Some(object) => match object.body() {
+            Some(body) => Ok(Some(body.bytes().await?)),
+            None => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Responses are compared as JSON when both sides parse, so key order doesn't count as a diff.
+/// The `requestId` of a recorded problem is the recorded request's and isn't compared.
+fn same_body(expected: &str, actual: &str) -> bool {
+    match (
+        serde_json::from_str::<serde_json::Value>(expected),
+        serde_json::from_str::<serde_json::Value>(actual),
+    ) {
+        (Ok(mut expected), Ok(actual)) => {
+            if let Some(problem) = expected.as_object_mut() {
+                problem.remove("requestId");
+                problem.remove("traceId");
+            }
+            expected == actual
+        }
+        _ => expected == actual,
+    }
+}
+
+/// Drops the payloads replays seed and everything derived from them, recorded arrivals included.
+fn reset_caches(cache: &Caches) {
+    for name in ["routes_raw", "stops_raw", "stop_arrival"] {
+        cache.purge(name);
+    }
+}
+
+/// Leaves the caches holding only what the recording was answered from, nothing of the live
+/// data or of the previous recording.
+fn seed_caches(
+    cache: &Caches,
+    routes: Option<Vec<u8>>,
+    stops: Option<Vec<u8>>,
+    arrivals: Vec<Rc<StopArrivals>>,
+) {
+    reset_caches(cache);
+    if let Some(routes) = routes {
+        cache.routes_raw.set(Rc::new(routes)).ok();
+    }
+    if let Some(stops) = stops {
+        cache.stops_raw.set(Rc::new(stops)).ok();
+    }
+    for stop_arrivals in arrivals {
+        cache
+            .stop_arrival
+            .set(stop_arrivals.id.clone(), stop_arrivals)
+            .ok();
+    }
+}
+
+/// Replays the oldest `limit` recordings through `router` with the caches seeded from the
+/// recorded payloads, then drops those caches so live traffic reloads from upstream.
+///
+/// Routing through the D1 store still reads the live tables, replay is meant for builds without
+/// `STOPS_DB` bound.
+pub async fn replay(
+    env: &Env,
+    limit: usize,
+    router: fn() -> Router<'static, ()>,
+) -> worker::Result<ReplayReport> {
+    let Some(bucket) = bucket(env) else {
+        return Err(worker::Error::RustError(format!(
+            "{} bucket is not bound",
+            REPLAY_BINDING
+        )));
+    };
+    let objects = bucket
+        .list()
+        .prefix(RECORDINGS_PREFIX)
+        .limit(limit as u32)
+        .execute()
+        .await?
+        .objects();
+
+    let cache = Caches::get_cache();
+    let mut report = ReplayReport {
+        replayed: 0,
+        matched: 0,
+        mismatches: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for object in objects {
+        let key = object.key();
+        let Some(recording) = bucket.get(&key).execute().await? else {
+            continue;
+        };
+        let Some(body) = recording.body() else {
+            continue;
+        };
+        let recording = serde_json::from_slice::<Recording>(&body.bytes().await?)?;
+
+        let routes = match &recording.routes {
+            Some(hash) => load_payload(&bucket, ROUTES_FILE, hash).await?,
+            None => None,
+        };
+        let stops = match &recording.stops {
+            Some(hash) => load_payload(&bucket, STOPS_FILE, hash).await?,
+            None => None,
+        };
+        if routes.is_none() != recording.routes.is_none()
+            || stops.is_none() != recording.stops.is_none()
+        {
+            report.skipped.push(key);
+            continue;
+        }
+
+        seed_caches(cache, routes, stops, recording.arrivals);
+
+        let req = Request::new(&format!("https://replay{}", recording.url), Method::Get)?;
+        let res = router().run(req, env.clone()).await?;
+        // Recorded after `fetch` set the problem instance.
+        let path = recording.url.split('?').next().unwrap_or_default();
+        let mut res = with_problem_context(res, path, None).await?;
+        let actual = res.text().await?;
+        report.replayed += 1;
+        if res.status_code() == recording.status && same_body(&recording.body, &actual) {
+            report.matched += 1;
+        } else {
+            report.mismatches.push(ReplayMismatch {
+                key,
+                url: recording.url,
+                expected_status: recording.status,
+                actual_status: res.status_code(),
+                expected: recording.body,
+                actual,
+            });
+        }
+    }
+
+    reset_caches(cache);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, ManualClock};
+    use crate::models::ErrorCode;
+    use crate::services::TransportService;
+    use crate::snapshots::MemorySnapshots;
+    use crate::upstream::FixtureClient;
+    use futures::executor::block_on;
+    use std::collections::HashMap;
+
+    const ROUTES: &str = "RouteNum;Transport;RouteName;RouteStops\n1;tram;Kopli;1001\n";
+    const STOPS_A: &str = "ID;SiriID;Name\n1001;101;Kopli\n";
+    const STOPS_B: &str = "ID;SiriID;Name\n1001;101;Vana-Kopli\n";
+
+    fn stop_arrivals(id: &str) -> Rc<StopArrivals> {
+        Rc::new(StopArrivals {
+            id: id.to_string(),
+            name: "Kopli".to_string(),
+            lat: None,
+            lon: None,
+            arrivals: HashMap::new(),
+        })
+    }
+
+    /// Replays answer only from what was seeded, any upstream call shows in `requested`.
+    fn service() -> (Rc<FixtureClient>, TransportService) {
+        clock::install(ManualClock::new(1_760_000_000_000.0));
+        let client = Rc::new(FixtureClient::new());
+        (Rc::clone(&client), TransportService::with_client(client))
+    }
+
+    #[test]
+    fn seeding_a_recording_drops_what_the_previous_one_left() {
+        let (client, service) = service();
+        let snapshots = MemorySnapshots::default();
+        let cache = Caches::get_cache();
+
+        let routes = Some(ROUTES.as_bytes().to_vec());
+        let stops = Some(STOPS_A.as_bytes().to_vec());
+        seed_caches(cache, routes, stops, vec![stop_arrivals("101")]);
+        let names = block_on(service.get_stop_name_index(&snapshots)).unwrap();
+        assert_eq!(*names.entries[0].1.name, "Kopli");
+        block_on(service.get_stop_grid(&snapshots)).unwrap();
+        block_on(service.get_sorted_types(&snapshots)).unwrap();
+        let code = Rc::new(ErrorCode::UnknownRoute);
+        cache.unknown_routes.set("bus/9".to_string(), code).ok();
+        cache.store_synced.set(Rc::new(())).ok();
+
+        let stops = Some(STOPS_B.as_bytes().to_vec());
+        seed_caches(cache, None, stops, vec![stop_arrivals("102")]);
+        assert!(cache.routes_raw.peek().is_none() && cache.types.peek().is_none());
+        assert!(cache.stop_map.peek().is_none() && cache.stop_names.peek().is_none());
+        assert!(cache.stop_grid.peek().is_none() && cache.store_synced.peek().is_none());
+        assert!(
+            cache
+                .unknown_routes
+                .get_stale(&"bus/9".to_string())
+                .is_none()
+        );
+        assert!(cache.stop_arrival.get_stale(&"101".to_string()).is_none());
+        assert!(cache.stop_arrival.get(&"102".to_string()).is_some());
+
+        let names = block_on(service.get_stop_name_index(&snapshots)).unwrap();
+        assert_eq!(*names.entries[0].1.name, "Vana-Kopli");
+        assert!(client.requested().is_empty());
+    }
+
+    #[test]
+    fn reset_leaves_nothing_for_live_traffic() {
+        service();
+        let cache = Caches::get_cache();
+        let routes = Some(ROUTES.as_bytes().to_vec());
+        let stops = Some(STOPS_A.as_bytes().to_vec());
+        seed_caches(cache, routes, stops, vec![stop_arrivals("101")]);
+        reset_caches(cache);
+        assert!(cache.routes_raw.peek().is_none() && cache.stops_raw.peek().is_none());
+        assert!(cache.stop_arrival.get_stale(&"101".to_string()).is_none());
+    }
+
+    #[test]
+    fn compares_bodies_as_json_without_request_ids() {
+        assert!(same_body(
+            r#"{"a":1,"b":[1,2],"requestId":"x","traceId":"y"}"#,
+            r#"{"b":[1,2],"a":1}"#
+        ));
+        assert!(!same_body(r#"{"b":[2,1]}"#, r#"{"b":[1,2]}"#));
+        assert!(same_body("<html>", "<html>"));
+        assert!(!same_body("<html>", r#"{"a":1}"#));
+    }
+
+    #[test]
+    fn records_only_replayable_paths() {
+        assert!(is_replayable("/api/types/bus/routes"));
+        assert!(is_replayable("/api/arrivals"));
+        for path in [
+            "/api/admin/replay",
+            "/api/raw/siri",
+            "/api/vehicles",
+            "/health",
+        ] {
+            assert!(!is_replayable(path), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! [`Reply`], a response built without the runtime's objects. The handlers `tlt-serve` shares
+//! with the worker answer it, turned into a `worker::Response` by the worker and into an
+//! `http::Response` on the host.
+
+use worker::{Response, Result};
+
+use crate::freshness_headers;
+
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    /// A 200 of the body with its `Content-Type`.
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Sets the header, replacing any value it had.
+    pub fn set_header(&mut self, name: &'static str, value: impl Into<String>) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self.headers.push((name, value.into()));
+    }
+
+    /// [`set_freshness`](crate::set_freshness) of the reply.
+    pub fn set_freshness(&mut self, ttl_secs: u32, age_secs: Option<u32>) {
+        for (name, value) in freshness_headers(ttl_secs, age_secs) {
+            self.set_header(name, value);
+        }
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        let response = if self.body.is_empty() {
+            Response::empty()?
+        } else {
+            Response::from_bytes(self.body)?
+        };
+        let mut response = response.with_status(self.status);
+        let headers = response.headers_mut();
+        for (name, value) in &self.headers {
+            headers.set(name, value)?;
+        }
+        Ok(response)
+    }
+}
@@ -1,22 +1,58 @@
-use crate::Caches;
+use crate::bindings::Bindings;
+use crate::caches::{Caches, now_secs};
+use crate::clock;
+use crate::config::Config;
+use crate::geo::StopGrid;
+use crate::logging::{log_error, log_warn};
 use crate::models::*;
+use crate::snapshots::{ROUTES_FILE, STOPS_FILE, SnapshotStore};
 use crate::str_utils::*;
+use crate::upstream::{BodyStream, FetchClient, UpstreamClient, UpstreamRequest, UpstreamResponse};
 
 use futures::TryStreamExt;
+use futures::future::Either;
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 use std::sync::OnceLock;
-use worker::ByteStream;
+use std::time::Duration;
 use worker::send::SendWrapper;
+use worker::wasm_bindgen::JsValue;
+use worker::{Context, D1Database, D1PreparedStatement, Env, KvStore, ObjectNamespace};
 
 pub static SERVICE: OnceLock<SendWrapper<TransportService>> = OnceLock::new();
 
+/// Durable Object binding owning the arrivals cache shared by all isolates, optional.
+pub const ARRIVALS_CACHE_BINDING: &str = "ARRIVALS_CACHE";
+const ARRIVALS_CACHE_OBJECT_NAME: &str = "arrivals";
+
+/// KV namespace holding the operator-edited stop alias table, optional.
+pub const STOP_ALIASES_BINDING: &str = "STOP_ALIASES";
+/// Single JSON document `{"Old name": ["stop id", …], …}`.
+const STOP_ALIASES_KEY: &str = "stop-aliases";
+
+/// D1 database mirroring routes.txt and stops.txt, optional.
+pub const STOPS_DB_BINDING: &str = "STOPS_DB";
+const STORE_SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS stops (id TEXT PRIMARY KEY, siri_id TEXT NOT NULL, name TEXT NOT NULL, lat REAL, lon REAL, search_name TEXT NOT NULL, zone TEXT, search_zone TEXT)
+CREATE INDEX IF NOT EXISTS stops_name ON stops (search_name)
+CREATE INDEX IF NOT EXISTS stops_zone ON stops (search_zone)
+CREATE TABLE IF NOT EXISTS route_stops (type TEXT NOT NULL, number TEXT NOT NULL, direction TEXT NOT NULL, seq INTEGER NOT NULL, stop_id TEXT NOT NULL, PRIMARY KEY (type, number, direction, seq))
+CREATE INDEX IF NOT EXISTS route_stops_stop ON route_stops (stop_id)
+CREATE TABLE IF NOT EXISTS store_meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL)";
+// D1 caps bound parameters per statement at 100.
+const STORE_MAX_PARAMS: usize = 100;
+const STORE_BATCH_SIZE: usize = 50;
+
 #[derive(Debug)]
 pub enum ParsingUpstreamError {
     Http(worker::Error),
     Utf8,
+    /// Upstream didn't finish answering within the configured timeout
+    Timeout,
+    /// Circuit breaker open after repeated failures, seconds until upstream is tried again
+    Unavailable(u32),
     Error(String),
 }
 
@@ -32,13 +68,340 @@ impl From<FromUtf8Error> for ParsingUpstreamError {
     }
 }
 
+impl From<ParseError> for ParsingUpstreamError {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::Utf8 => ParsingUpstreamError::Utf8,
+            // A data file without its header columns is a broken upstream, not a broken worker
+            ParseError::MissingColumn(msg) => {
+                ParsingUpstreamError::Http(worker::Error::RustError(msg))
+            }
+            ParseError::Malformed(msg) => ParsingUpstreamError::Error(msg),
+        }
+    }
+}
+
 impl From<Utf8Error> for ParsingUpstreamError {
     fn from(_err: Utf8Error) -> Self {
         ParsingUpstreamError::Utf8
     }
 }
 
-pub struct TransportService {}
+/// `term` matched literally inside a `LIKE ? ESCAPE '\'` pattern, its `%` and `_` no longer
+/// wildcards.
+fn like_escaped(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// D1-backed view of the routes and stops data, so lookups and filtering run as SQL instead of
+/// against the whole dataset held in isolate memory.
+pub struct StopsRouteStore {
+    db: D1Database,
+}
+
+impl StopsRouteStore {
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.d1(STOPS_DB_BINDING).ok().map(|db| Self { db })
+    }
+
+    async fn loaded_at(&self) -> worker::Result<Option<u32>> {
+        self.db
+            .prepare("SELECT value FROM store_meta WHERE key = 'loaded_at'")
+            .first::<u32>(Some("value"))
+            .await
+    }
+
+    fn insert_statements(
+        &self,
+        table: &str,
+        columns: &[&str],
+        rows: Vec<Vec<JsValue>>,
+    ) -> worker::Result<Vec<D1PreparedStatement>> {
+        let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+        let rows_per_statement = STORE_MAX_PARAMS / columns.len();
+        rows.chunks(rows_per_statement)
+            .map(|chunk| {
+                let sql = format!(
+                    "INSERT OR REPLACE INTO {} ({}) VALUES {}",
+                    table,
+                    columns.join(", "),
+                    vec![placeholders.as_str(); chunk.len()].join(", ")
+                );
+                self.db.prepare(sql).bind(&chunk.concat())
+            })
+            .collect()
+    }
+
+    /// Replaces the stored dataset with the given parsed upstream data.
+    pub async fn populate(
+        &self,
+        route_map: &HashMap<String, HashMap<String, RouteGroup>>,
+        stop_map: &HashMap<String, Rc<StopData>>,
+    ) -> worker::Result<()> {
+        // Stores created before the coordinate, search and zone columns existed are rebuilt from
+        // scratch.
+        if self
+            .db
+            .prepare("SELECT lat, lon, search_name, search_zone FROM stops LIMIT 0")
+            .all()
+            .await
+            .is_err()
+        {
+            self.db.exec("DROP TABLE IF EXISTS stops").await?;
+        }
+        self.db.exec(STORE_SCHEMA).await?;
+
+        let stop_rows = stop_map
+            .iter()
+            .filter(|(key, stop)| **key == stop.id)
+            .map(|(_, stop)| {
+                vec![
+                    JsValue::from_str(&stop.id),
+                    JsValue::from_str(&stop.siri_id),
+                    JsValue::from_str(&stop.name),
+                    stop.lat.map_or(JsValue::NULL, JsValue::from_f64),
+                    stop.lon.map_or(JsValue::NULL, JsValue::from_f64),
+                    JsValue::from_str(&normalize_name(&stop.name)),
+                    stop.zone
+                        .as_deref()
+                        .map_or(JsValue::NULL, JsValue::from_str),
+                    stop.zone.as_deref().map_or(JsValue::NULL, |zone| {
+                        JsValue::from_str(&normalize_name(zone))
+                    }),
+                ]
+            })
+            .collect::<Vec<Vec<JsValue>>>();
+        let route_stop_rows = route_map
+            .values()
+            .flat_map(HashMap::values)
+            .flat_map(|group| {
+                group.directions.iter().flat_map(move |(direction, stops)| {
+                    stops.iter().enumerate().map(move |(seq, stop_id)| {
+                        vec![
+                            JsValue::from_str(group.r#type.as_str()),
+                            JsValue::from_str(&group.number),
+                            JsValue::from_str(direction),
+                            JsValue::from_f64(seq as f64),
+                            JsValue::from_str(stop_id),
+                        ]
+                    })
+                })
+            })
+            .collect::<Vec<Vec<JsValue>>>();
+
+        let mut statements = vec![
+            self.db.prepare("DELETE FROM stops"),
+            self.db.prepare("DELETE FROM route_stops"),
+        ];
+        statements.extend(self.insert_statements(
+            "stops",
+            &[
+                "id",
+                "siri_id",
+                "name",
+                "lat",
+                "lon",
+                "search_name",
+                "zone",
+                "search_zone",
+            ],
+            stop_rows,
+        )?);
+        statements.extend(self.insert_statements(
+            "route_stops",
+            &["type", "number", "direction", "seq", "stop_id"],
+            route_stop_rows,
+        )?);
+        statements.push(
+            self.db
+                .prepare("INSERT OR REPLACE INTO store_meta (key, value) VALUES ('loaded_at', ?)")
+                .bind(&[JsValue::from_f64(now_secs() as f64)])?,
+        );
+
+        let mut statements = statements.into_iter().peekable();
+        while statements.peek().is_some() {
+            let batch = statements.by_ref().take(STORE_BATCH_SIZE).collect();
+            self.db.batch(batch).await?;
+        }
+        Ok(())
+    }
+
+    async fn strings(&self, sql: &str, params: &[JsValue]) -> worker::Result<Vec<String>> {
+        let rows = self.db.prepare(sql).bind(params)?.raw::<String>().await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .collect())
+    }
+
+    pub async fn has_type(&self, route_type: &str) -> worker::Result<bool> {
+        let found = self
+            .strings(
+                "SELECT type FROM route_stops WHERE type = ? LIMIT 1",
+                &[JsValue::from_str(route_type)],
+            )
+            .await?;
+        Ok(!found.is_empty())
+    }
+
+    pub async fn route_numbers(&self, route_type: &str) -> worker::Result<Vec<String>> {
+        self.strings(
+            "SELECT DISTINCT number FROM route_stops WHERE type = ? ORDER BY number",
+            &[JsValue::from_str(route_type)],
+        )
+        .await
+    }
+
+    pub async fn directions(
+        &self,
+        route_type: &str,
+        route_number: &str,
+    ) -> worker::Result<Vec<String>> {
+        self.strings(
+            "SELECT DISTINCT direction FROM route_stops WHERE type = ? AND number = ? ORDER BY direction",
+            &[JsValue::from_str(route_type), JsValue::from_str(route_number)],
+        )
+        .await
+    }
+
+    /// The stops along one route direction.
+    pub async fn stops(
+        &self,
+        route_type: &str,
+        route_number: &str,
+        direction: &str,
+    ) -> worker::Result<RouteStops> {
+        let rows = self
+            .db
+            .prepare(
+                "SELECT r.stop_id AS stopId, s.siri_id AS siriId, s.name, s.lat, s.lon, s.zone \
+                 FROM route_stops r LEFT JOIN stops s ON s.id = r.stop_id \
+                 WHERE r.type = ? AND r.number = ? AND r.direction = ? ORDER BY r.seq",
+            )
+            .bind(&[
+                JsValue::from_str(route_type),
+                JsValue::from_str(route_number),
+                JsValue::from_str(direction),
+            ])?
+            .all()
+            .await?
+            .results::<RouteStopRow>()?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let stop = row.siri_id.zip(row.name).map(|(siri_id, name)| {
+                    Rc::new(StopData {
+                        id: row.stop_id.clone(),
+                        siri_id,
+                        name: Rc::new(name),
+                        lat: row.lat,
+                        lon: row.lon,
+                        zone: row.zone,
+                    })
+                });
+                (row.stop_id, stop)
+            })
+            .collect())
+    }
+
+    /// Stops whose normalized name contains `name`, which is expected normalized, optionally only
+    /// those served by a transport type.
+    pub async fn search_stops(
+        &self,
+        name: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> worker::Result<Vec<StopSearchResult>> {
+        let escaped = like_escaped(name);
+        let pattern = JsValue::from_str(&format!("%{}%", escaped));
+        let exact = JsValue::from_str(name);
+        let prefix = JsValue::from_str(&format!("{}%", escaped));
+        let limit = JsValue::from_f64(limit as f64);
+        let statement = match route_type {
+            Some(route_type) => self
+                .db
+                .prepare(
+                    "SELECT s.id, s.siri_id AS siriId, s.name, s.lat, s.lon FROM stops s \
+                     WHERE s.search_name LIKE ? ESCAPE '\\' AND EXISTS \
+                     (SELECT 1 FROM route_stops r WHERE r.stop_id = s.id AND r.type = ?) \
+                     ORDER BY CASE WHEN s.search_name = ? THEN 0 \
+                     WHEN s.search_name LIKE ? ESCAPE '\\' THEN 1 ELSE 2 END, \
+                     s.name, s.id LIMIT ?",
+                )
+                .bind(&[pattern, JsValue::from_str(route_type), exact, prefix, limit])?,
+            None => self
+                .db
+                .prepare(
+                    "SELECT s.id, s.siri_id AS siriId, s.name, s.lat, s.lon FROM stops s \
+                     WHERE s.search_name LIKE ? ESCAPE '\\' \
+                     ORDER BY CASE WHEN s.search_name = ? THEN 0 \
+                     WHEN s.search_name LIKE ? ESCAPE '\\' THEN 1 ELSE 2 END, \
+                     s.name, s.id LIMIT ?",
+                )
+                .bind(&[pattern, exact, prefix, limit])?,
+        };
+        statement.all().await?.results()
+    }
+
+    /// Stops of the zone `zone`, which is expected normalized, by name, optionally only those
+    /// served by a transport type.
+    pub async fn stops_in_zone(
+        &self,
+        zone: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> worker::Result<Vec<StopSearchResult>> {
+        let zone = JsValue::from_str(zone);
+        let limit = JsValue::from_f64(limit as f64);
+        let statement = match route_type {
+            Some(route_type) => self
+                .db
+                .prepare(
+                    "SELECT s.id, s.siri_id AS siriId, s.name, s.lat, s.lon FROM stops s \
+                     WHERE s.search_zone = ? AND EXISTS \
+                     (SELECT 1 FROM route_stops r WHERE r.stop_id = s.id AND r.type = ?) \
+                     ORDER BY s.name, s.id LIMIT ?",
+                )
+                .bind(&[zone, JsValue::from_str(route_type), limit])?,
+            None => self
+                .db
+                .prepare(
+                    "SELECT s.id, s.siri_id AS siriId, s.name, s.lat, s.lon FROM stops s \
+                     WHERE s.search_zone = ? ORDER BY s.name, s.id LIMIT ?",
+                )
+                .bind(&[zone, limit])?,
+        };
+        statement.all().await?.results()
+    }
+}
+
+/// The ids of a route direction's stops in order with their data, `None` for stops missing from
+/// stops.txt.
+pub type RouteStops = Vec<(String, Option<Rc<StopData>>)>;
+
+/// A `route_stops` row joined with its stop, whose columns are null when stops.txt lacks it.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RouteStopRow {
+    stop_id: String,
+    siri_id: Option<String>,
+    name: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    zone: Option<String>,
+}
+
+pub struct TransportService {
+    config: &'static Config,
+    client: Box<dyn UpstreamClient>,
+}
 
 impl TransportService {
     pub fn get_service() -> &'static SendWrapper<TransportService> {
@@ -46,121 +409,644 @@ impl TransportService {
     }
 
     pub fn new() -> Self {
-        Self {}
+        Self::with_client(FetchClient)
     }
 
-    async fn get_routes_stream() -> worker::Result<ByteStream> {
-        let uri = "https://transport.tallinn.ee/data/routes.txt";
-        let req_init = worker::RequestInit {
-            method: worker::Method::Get,
-            cf: worker::CfProperties {
-                cache_ttl: Some(3600),
-                ..Default::default()
-            },
-            ..Default::default()
+    /// A service sending its upstream requests through `client`, fixtures included.
+    pub fn with_client(client: impl UpstreamClient + 'static) -> Self {
+        Self {
+            config: Config::get(),
+            client: Box::new(client),
+        }
+    }
+
+    /// Fails with `ParsingUpstreamError::Timeout` once `upstream` runs past the configured upstream
+    /// timeout. Dropping it there abandons the pending fetch or body read.
+    async fn with_timeout<T>(
+        &self,
+        upstream: impl Future<Output = Result<T, ParsingUpstreamError>>,
+    ) -> Result<T, ParsingUpstreamError> {
+        let timeout = clock::delay(Duration::from_millis(self.config.upstream_timeout_ms));
+        futures::pin_mut!(upstream, timeout);
+        match futures::future::select(upstream, timeout).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(ParsingUpstreamError::Timeout),
+        }
+    }
+
+    /// Probes routes.txt and stops.txt with HEAD and SIRI with a one-byte range, each straight
+    /// to the primary upstream and past the edge cache.
+    pub async fn probe_upstreams(&self) -> Vec<DependencyHealth> {
+        let probes = [
+            (
+                "routes",
+                self.config.routes_url.as_str(),
+                worker::Method::Head,
+            ),
+            (
+                "stops",
+                self.config.stops_url.as_str(),
+                worker::Method::Head,
+            ),
+            (
+                "siri",
+                self.config.arrivals_url.as_str(),
+                worker::Method::Get,
+            ),
+        ];
+        futures::future::join_all(probes.map(|(name, uri, method)| self.probe(name, uri, method)))
+            .await
+    }
+
+    async fn probe(
+        &self,
+        name: &'static str,
+        uri: &str,
+        method: worker::Method,
+    ) -> DependencyHealth {
+        let started_ms = clock::now_ms();
+        let res = self
+            .with_timeout(async {
+                let mut req = UpstreamRequest::new(method.clone()).with_cache_ttl(0);
+                if method == worker::Method::Get {
+                    req = req.with_header("Range", "bytes=0-0");
+                }
+                Ok(self.client.send(uri, &req).await?.status)
+            })
+            .await;
+        let latency_ms = (clock::now_ms() - started_ms) as u32;
+        let (status, error) = match res {
+            Ok(status) => (Some(status), None),
+            Err(ParsingUpstreamError::Timeout) => (None, Some("timed out".to_string())),
+            Err(ParsingUpstreamError::Http(err)) => (None, Some(err.to_string())),
+            Err(err) => (None, Some(format!("{err:?}"))),
         };
-        let req = worker::Request::new_with_init(uri, &req_init)?;
-        let mut res = worker::Fetch::Request(req).send().await?;
-        res.stream()
+        DependencyHealth {
+            name,
+            healthy: status.is_some_and(|status| status < 500),
+            status,
+            latency_ms,
+            error,
+        }
     }
 
-    async fn get_stops_stream() -> worker::Result<ByteStream> {
-        let uri = "https://transport.tallinn.ee/data/stops.txt";
-        let req_init = worker::RequestInit {
-            method: worker::Method::Get,
-            cf: worker::CfProperties {
-                cache_ttl: Some(3600),
-                ..Default::default()
-            },
-            ..Default::default()
+    /// Sends the request to `uri`, then to the same path on the mirror when one is configured and
+    /// the primary failed or answered 5xx.
+    async fn send_with_mirror(
+        &self,
+        uri: &str,
+        req: &UpstreamRequest,
+    ) -> worker::Result<UpstreamResponse> {
+        let primary = self.client.send(uri, req).await;
+        let Some(mirror_uri) = self.config.mirror_url(uri) else {
+            return primary;
+        };
+        match &primary {
+            Ok(res) if res.status < 500 => return primary,
+            Ok(res) => log_warn!("{} answered {}, trying mirror", uri, res.status),
+            Err(err) => log_warn!("{} failed ({}), trying mirror", uri, err),
+        }
+        self.client.send(&mirror_uri, req).await
+    }
+
+    /// GET of an upstream data file, conditional when the validators of the cached copy are
+    /// given, in which case an unchanged file answers 304 without a body.
+    async fn get_data_file(
+        &self,
+        uri: &str,
+        validators: Option<&UpstreamValidators>,
+    ) -> worker::Result<UpstreamResponse> {
+        let mut req = UpstreamRequest::get().with_cache_ttl(self.config.data_cf_cache_ttl_secs);
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                req = req.with_header("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                req = req.with_header("If-Modified-Since", last_modified);
+            }
+        }
+        self.send_with_mirror(uri, &req).await
+    }
+
+    fn validators_of(res: &UpstreamResponse) -> UpstreamValidators {
+        UpstreamValidators {
+            etag: res.header("ETag"),
+            last_modified: res.header("Last-Modified"),
+        }
+    }
+
+    async fn get_routes_stream(&self) -> worker::Result<(BodyStream, UpstreamValidators)> {
+        let res = self.get_data_file(&self.config.routes_url, None).await?;
+        let validators = Self::validators_of(&res);
+        Ok((res.stream(), validators))
+    }
+
+    async fn get_stops_stream(&self) -> worker::Result<(BodyStream, UpstreamValidators)> {
+        let res = self.get_data_file(&self.config.stops_url, None).await?;
+        let validators = Self::validators_of(&res);
+        Ok((res.stream(), validators))
+    }
+
+    /// Caches a freshly loaded upstream file with the validators it was served with, `None` when
+    /// it came from a snapshot, and snapshots it to R2 if it changed.
+    async fn store_data_file(
+        snapshots: &dyn SnapshotStore,
+        file: &str,
+        buf: Vec<u8>,
+        validators: Option<UpstreamValidators>,
+    ) {
+        let cache = Caches::get_cache();
+        let (raw, raw_validators) = match file {
+            ROUTES_FILE => (&cache.routes_raw, &cache.routes_validators),
+            _ => (&cache.stops_raw, &cache.stops_validators),
         };
-        let req = worker::Request::new_with_init(uri, &req_init)?;
-        let mut res = worker::Fetch::Request(req).send().await?;
-        res.stream()
+        snapshots.store_if_changed(file, &buf).await;
+        raw.set(Rc::new(buf)).ok();
+        match validators {
+            Some(validators) => {
+                raw_validators.set(Rc::new(validators)).ok();
+            }
+            None => raw_validators.clear(),
+        }
     }
 
     async fn get_stops_arrivals(&self, stop_siri_ids: &str) -> worker::Result<String> {
+        let uri = format!("{}?stopid={}", self.config.arrivals_url, stop_siri_ids);
+        let req = UpstreamRequest::get().with_cache_ttl(self.config.arrivals_cf_cache_ttl_secs);
+        let res = self.send_with_mirror(&uri, &req).await?;
+        if res.status >= 500 {
+            return Err(worker::Error::RustError(format!(
+                "SIRI upstream answered {}",
+                res.status
+            )));
+        }
+        res.text().await
+    }
+
+    /// Live positions of every vehicle, re-fetched once the short vehicles cache expires.
+    pub async fn get_vehicle_positions(
+        &self,
+    ) -> Result<Rc<Vec<VehiclePosition>>, ParsingUpstreamError> {
+        let cache = &Caches::get_cache().vehicles;
+        if let Some(vehicles) = cache.get() {
+            return Ok(vehicles);
+        }
+        let gps_raw = self
+            .with_timeout(async {
+                let res = self
+                    .send_with_mirror(&self.config.gps_url, &UpstreamRequest::get())
+                    .await?;
+                if res.status >= 400 {
+                    return Err(ParsingUpstreamError::Http(worker::Error::RustError(
+                        format!("GPS upstream answered {}", res.status),
+                    )));
+                }
+                Ok(res.bytes().await?)
+            })
+            .await?;
+        let vehicles = Rc::new(extract_vehicle_positions(&gps_raw));
+        cache.set(Rc::clone(&vehicles)).ok();
+        Ok(vehicles)
+    }
+
+    /// The upstream SIRI body for the comma-separated SIRI ids as it was served, behind the same
+    /// breaker and timeout as the parsed arrivals.
+    pub async fn get_raw_stops_arrivals(
+        &self,
+        stop_siri_ids: &str,
+    ) -> Result<Rc<String>, ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        let key = stop_siri_ids.to_string();
+        if let Some(raw) = cache.raw_siri.get(&key) {
+            return Ok(raw);
+        }
+        if let Some(retry_after) = cache.arrivals_breaker.retry_after() {
+            return Err(ParsingUpstreamError::Unavailable(retry_after));
+        }
+        let raw = match self
+            .with_timeout(async { Ok(self.get_stops_arrivals(stop_siri_ids).await?) })
+            .await
+        {
+            Ok(raw) => {
+                cache.arrivals_breaker.record_success();
+                Rc::new(raw)
+            }
+            Err(err) => {
+                cache.arrivals_breaker.record_failure();
+                return Err(err);
+            }
+        };
+        cache.raw_siri.set(key, Rc::clone(&raw)).ok();
+        Ok(raw)
+    }
+
+    /// Fetches and parses arrivals for the comma-separated SIRI ids straight from upstream.
+    pub async fn fetch_stops_arrivals(
+        &self,
+        snapshots: &dyn SnapshotStore,
+        stop_siri_ids: &str,
+    ) -> core::result::Result<Vec<StopArrivals>, ParsingUpstreamError> {
+        let breaker = &Caches::get_cache().arrivals_breaker;
+        if let Some(retry_after) = breaker.retry_after() {
+            return Err(ParsingUpstreamError::Unavailable(retry_after));
+        }
+        let arrivals_raw = match self
+            .with_timeout(async { Ok(self.get_stops_arrivals(stop_siri_ids).await?) })
+            .await
+        {
+            Ok(arrivals_raw) => {
+                breaker.record_success();
+                arrivals_raw
+            }
+            Err(err) => {
+                breaker.record_failure();
+                return Err(err);
+            }
+        };
+        let arrivals_bytes = arrivals_raw.as_bytes();
+        let stop_map = self.get_stop_map(snapshots).await?;
+        split_arrival_by_stops(arrivals_bytes)
+            .flat_map(|stop_arrival_raw| {
+                self::extract_arrival_stop_data_from_line(
+                    stop_arrival_raw,
+                    &stop_map,
+                    self.config.region_timezone,
+                )
+            })
+            .map(|stop_arrivals| Ok(stop_arrivals?))
+            .collect()
+    }
+
+    /// Asks the shared arrivals Durable Object for the stops, letting it hit upstream only for
+    /// the ones no isolate has refreshed recently.
+    async fn refresh_stops_arrivals_via_object(
+        namespace: &ObjectNamespace,
+        stop_siri_ids: &str,
+    ) -> core::result::Result<Vec<StopArrivals>, ParsingUpstreamError> {
+        let stub = namespace.get_by_name(ARRIVALS_CACHE_OBJECT_NAME)?;
         let uri = format!(
-            "https://transport.tallinn.ee/siri-stop-departures.php?stopid={}",
-            stop_siri_ids
+            "https://arrivals-cache/refresh?stops={}",
+            urlencoding::encode(stop_siri_ids)
         );
         let req_init = worker::RequestInit {
-            method: worker::Method::Get,
-            cf: worker::CfProperties {
-                cache_ttl: Some(120),
-                ..Default::default()
-            },
+            method: worker::Method::Post,
             ..Default::default()
         };
         let req = worker::Request::new_with_init(&uri, &req_init)?;
-        let mut res = worker::Fetch::Request(req).send().await?;
-        res.text().await
+        let mut res = stub.fetch_with_request(req).await?;
+        match res.status_code() {
+            200 => {}
+            503 => {
+                let retry_after = res
+                    .headers()
+                    .get("Retry-After")?
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(1);
+                return Err(ParsingUpstreamError::Unavailable(retry_after));
+            }
+            504 => return Err(ParsingUpstreamError::Timeout),
+            _ => return Err(ParsingUpstreamError::Error(res.text().await?)),
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Drops the arrivals the shared Durable Object holds, when it's bound.
+    pub async fn purge_arrivals_object(env: &Env) -> worker::Result<()> {
+        let Ok(namespace) = env.durable_object(ARRIVALS_CACHE_BINDING) else {
+            return Ok(());
+        };
+        let stub = namespace.get_by_name(ARRIVALS_CACHE_OBJECT_NAME)?;
+        let req_init = worker::RequestInit {
+            method: worker::Method::Post,
+            ..Default::default()
+        };
+        let req = worker::Request::new_with_init("https://arrivals-cache/purge", &req_init)?;
+        stub.fetch_with_request(req).await?;
+        Ok(())
     }
 
+    /// Refreshes the cached arrivals of the comma-separated SIRI ids, answering the ids whose
+    /// batch failed. Fails as a whole only while the upstream is paused.
     pub async fn update_stops_arrival_cache(
         &self,
+        bindings: &dyn Bindings,
         stop_siri_ids: &str,
-    ) -> core::result::Result<(), ParsingUpstreamError> {
+    ) -> core::result::Result<HashSet<String>, ParsingUpstreamError> {
+        let mut failed_ids = HashSet::new();
         if stop_siri_ids.is_empty() {
-            return Ok(());
+            return Ok(failed_ids);
         }
-        let arrivals_raw = self.get_stops_arrivals(stop_siri_ids).await?;
-        let arrivals_bytes = arrivals_raw.as_bytes();
-        let stop_map = self.get_stop_map().await?;
-        let cache = Caches::get_cache();
-        let stop_arrival_cache = &cache.stop_arrival;
-        let stop_arrivals = split_arrival_by_stops(arrivals_bytes).flat_map(|stop_arrival_raw| {
-            self::extract_arrival_stop_data_from_line(stop_arrival_raw, &stop_map)
-        });
-        for stop_arrival in stop_arrivals {
-            let stop_arrival = stop_arrival?;
-            let stop_arrival = Rc::new(stop_arrival);
-            stop_arrival_cache.set(stop_arrival.id.clone(), stop_arrival).ok();
+        let ids = stop_siri_ids.split(',').collect::<Vec<&str>>();
+        let namespace = bindings.arrivals_object();
+        let batches = ids
+            .chunks(self.config.arrivals_batch_size)
+            .map(|batch| batch.join(","))
+            .collect::<Vec<String>>();
+        let results = futures::future::join_all(batches.iter().map(|batch| async {
+            match &namespace {
+                Some(namespace) => Self::refresh_stops_arrivals_via_object(namespace, batch).await,
+                None => self.fetch_stops_arrivals(bindings, batch).await,
+            }
+        }))
+        .await;
+        // keep what the succeeding batches fetched even when another one failed
+        let stop_arrival_cache = &Caches::get_cache().stop_arrival;
+        let mut retry_after = None;
+        for (batch, result) in batches.iter().zip(results) {
+            match result {
+                Ok(stop_arrivals) => {
+                    for stop_arrival in stop_arrivals {
+                        let stop_arrival = Rc::new(stop_arrival);
+                        stop_arrival_cache
+                            .set(stop_arrival.id.clone(), stop_arrival)
+                            .ok();
+                    }
+                }
+                Err(ParsingUpstreamError::Unavailable(seconds)) => {
+                    retry_after.get_or_insert(seconds);
+                }
+                Err(err) => {
+                    log_error!("arrivals of {} failed: {:?}", batch, err);
+                    failed_ids.extend(batch.split(',').map(str::to_string));
+                }
+            }
+        }
+        match retry_after {
+            Some(retry_after) => Err(ParsingUpstreamError::Unavailable(retry_after)),
+            None => Ok(failed_ids),
         }
-        Ok(())
     }
 
-    pub async fn get_types(&self) -> Result<HashSet<String>, ParsingUpstreamError> {
+    pub async fn get_types(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<HashSet<String>, ParsingUpstreamError> {
         let cache = Caches::get_cache();
-        let from_cache = cache.routes_raw.get();
+        let from_cache = cache.routes_raw.get_stale();
 
         let (buf, type_set) = match from_cache {
             Some(cache) => {
                 let (type_set, _, _) =
-                    extract_type_from_buffer(&cache[..], HashSet::with_capacity(5), 0usize, false)
+                    extract_type_from_buffer(&cache[..], HashSet::with_capacity(5), 0usize, None)
                         .await?;
                 (None, type_set)
             }
             None => {
-                let reader = Self::get_routes_stream().await?;
-                let (mut buf, type_set, _, _) = reader
+                let streamed = self
+                    .with_timeout(async {
+                        let (reader, validators) = self.get_routes_stream().await?;
+                        let (mut buf, type_set, _, _) = reader
+                            .map_err(ParsingUpstreamError::from)
+                            .try_fold(
+                                (
+                                    Vec::with_capacity(128 * 1024),
+                                    HashSet::with_capacity(5),
+                                    0usize,
+                                    None,
+                                ),
+                                extract_type_from_buffer_fold,
+                            )
+                            .await?;
+                        buf.shrink_to_fit();
+                        Ok::<_, ParsingUpstreamError>((buf, type_set, validators))
+                    })
+                    .await;
+                match streamed {
+                    Ok((buf, type_set, validators)) => (Some((buf, Some(validators))), type_set),
+                    Err(err) => {
+                        let Some(buf) = snapshots.latest(ROUTES_FILE).await else {
+                            return Err(err);
+                        };
+                        let (type_set, _, _) =
+                            extract_type_from_buffer(&buf, HashSet::with_capacity(5), 0usize, None)
+                                .await?;
+                        (Some((buf, None)), type_set)
+                    }
+                }
+            }
+        };
+
+        if let Some((buf, validators)) = buf {
+            Self::store_data_file(snapshots, ROUTES_FILE, buf, validators).await;
+        }
+
+        Ok(type_set)
+    }
+
+    /// Transport types sorted by name, cached separately from the raw routes.
+    pub async fn get_sorted_types(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<Rc<Vec<String>>, ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        if let Some(types) = cache.types.get() {
+            return Ok(types);
+        }
+        let mut types = self
+            .get_types(snapshots)
+            .await?
+            .into_iter()
+            .collect::<Vec<String>>();
+        types.sort_unstable();
+        let types = Rc::new(types);
+        cache.types.set(Rc::clone(&types)).ok();
+        Ok(types)
+    }
+
+    /// Refreshes routes.txt and stops.txt, skipping the download of unchanged files, and rebuilds
+    /// whatever was derived from the changed ones, including the D1 store when bound.
+    pub async fn warm_caches(&self, env: &Env) -> Result<(), ParsingUpstreamError> {
+        self.revalidate_routes(env).await?;
+        self.revalidate_stops(env).await?;
+
+        self.get_sorted_types(env).await?;
+        self.get_stop_map(env).await?;
+        self.get_stop_grid(env).await?;
+        self.get_stop_name_index(env).await?;
+        self.get_stop_routes_index(env).await?;
+        self.sync_store(env).await?;
+        let non_utf8 = non_utf8_field_count();
+        if non_utf8 > 0 {
+            log_warn!(
+                "{} upstream fields were not UTF-8, decoded as Windows-1257",
+                non_utf8
+            );
+        }
+        Ok(())
+    }
+
+    /// Starts warming the caches in the background unless they're ready or already warming.
+    pub fn warm_up(env: &Env, ctx: &Context) {
+        let cache = Caches::get_cache();
+        if cache.is_ready() || !cache.begin_warm_up() {
+            return;
+        }
+        let env = env.clone();
+        ctx.wait_until(async move {
+            if let Err(err) = Self::get_service().warm_caches(&env).await {
+                log_error!("warm-up failed: {:?}", err);
+            }
+            Caches::get_cache().end_warm_up();
+        });
+    }
+
+    /// Starts a background download of each upstream file whose cached copy has expired, so
+    /// requests keep being answered from the stale copy instead of waiting on the download, and
+    /// a background sync of the D1 store when it isn't known to be in sync.
+    pub fn revalidate_stale(env: &Env, ctx: &Context) {
+        let cache = Caches::get_cache();
+        if StopsRouteStore::from_env(env).is_some()
+            && cache.store_synced.get().is_none()
+            && cache.begin_store_sync()
+        {
+            let env = env.clone();
+            ctx.wait_until(async move {
+                if let Err(err) = Self::get_service().sync_store(&env).await {
+                    log_error!("D1 store sync failed: {:?}", err);
+                }
+                Caches::get_cache().end_store_sync();
+            });
+        }
+        if cache.routes_raw.begin_revalidation() {
+            let env = env.clone();
+            ctx.wait_until(async move {
+                if let Err(err) = Self::get_service().revalidate_routes(&env).await {
+                    log_error!("routes revalidation failed: {:?}", err);
+                }
+            });
+        }
+        if cache.stops_raw.begin_revalidation() {
+            let env = env.clone();
+            ctx.wait_until(async move {
+                if let Err(err) = Self::get_service().revalidate_stops(&env).await {
+                    log_error!("stops revalidation failed: {:?}", err);
+                }
+            });
+        }
+    }
+
+    /// Re-downloads routes.txt unless upstream confirms the cached copy is still current, then
+    /// drops what was derived from the old copy.
+    async fn revalidate_routes(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<(), ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        let cached = cache.routes_raw.get_stale();
+        let validators = cached.as_ref().and(cache.routes_validators.get_stale());
+        let streamed = self
+            .with_timeout(async {
+                let res = self
+                    .get_data_file(&self.config.routes_url, validators.as_deref())
+                    .await?;
+                if res.status == 304 {
+                    return Ok(None);
+                }
+                let validators = Self::validators_of(&res);
+                let (mut buf, _, _, _, _) = res
+                    .stream()
+                    .map_err(ParsingUpstreamError::from)
                     .try_fold(
                         (
                             Vec::with_capacity(128 * 1024),
-                            HashSet::with_capacity(5),
+                            HashMap::<String, HashMap<String, RouteGroup>>::new(),
+                            LastRouteData::default(),
                             0usize,
-                            false,
+                            None,
                         ),
-                        extract_type_from_buffer_fold,
+                        extract_route_data_from_buffer_fold,
                     )
                     .await?;
                 buf.shrink_to_fit();
-                (Some(buf), type_set)
-            }
-        };
+                Ok::<_, ParsingUpstreamError>(Some((buf, validators)))
+            })
+            .await;
 
-        if let Some(buf) = buf {
-            cache.routes_raw.set(Rc::new(buf)).ok();
+        match streamed {
+            Ok(Some((buf, validators))) => {
+                Self::store_data_file(snapshots, ROUTES_FILE, buf, Some(validators)).await;
+                cache.types.clear();
+                cache.stop_routes.clear();
+                cache.store_synced.clear();
+            }
+            // Unchanged upstream, a 304 is only possible with a cached copy to renew.
+            Ok(None) => {
+                if let Some(cached) = cached {
+                    cache.routes_raw.set(cached).ok();
+                }
+            }
+            Err(err) => {
+                cache.routes_raw.end_revalidation();
+                return Err(err);
+            }
         }
+        Ok(())
+    }
 
-        Ok(type_set)
+    /// Same as `revalidate_routes` for stops.txt and the stop map parsed from it.
+    async fn revalidate_stops(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<(), ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        let cached = cache.stops_raw.get_stale();
+        let validators = cached.as_ref().and(cache.stops_validators.get_stale());
+        let streamed = self
+            .with_timeout(async {
+                let res = self
+                    .get_data_file(&self.config.stops_url, validators.as_deref())
+                    .await?;
+                if res.status == 304 {
+                    return Ok(None);
+                }
+                let validators = Self::validators_of(&res);
+                let (mut buf, stop_map, _, _, _) = res
+                    .stream()
+                    .map_err(ParsingUpstreamError::from)
+                    .try_fold(
+                        (
+                            Vec::with_capacity(90 * 1024),
+                            HashMap::<String, Rc<StopData>>::new(),
+                            None,
+                            0usize,
+                            None,
+                        ),
+                        extract_stop_data_from_buffer_fold,
+                    )
+                    .await?;
+                buf.shrink_to_fit();
+                Ok::<_, ParsingUpstreamError>(Some((buf, stop_map, validators)))
+            })
+            .await;
+
+        match streamed {
+            Ok(Some((buf, stop_map, validators))) => {
+                Self::store_data_file(snapshots, STOPS_FILE, buf, Some(validators)).await;
+                cache.stop_map.set(Rc::new(stop_map)).ok();
+                cache.store_synced.clear();
+            }
+            Ok(None) => {
+                if let Some(cached) = cached {
+                    cache.stops_raw.set(cached).ok();
+                }
+                if let Some(stop_map) = cache.stop_map.get_stale() {
+                    cache.stop_map.set(stop_map).ok();
+                }
+            }
+            Err(err) => {
+                cache.stops_raw.end_revalidation();
+                return Err(err);
+            }
+        }
+        Ok(())
     }
 
     pub async fn get_route_map(
         &self,
+        snapshots: &dyn SnapshotStore,
     ) -> Result<HashMap<String, HashMap<String, RouteGroup>>, ParsingUpstreamError> {
         let cache = Caches::get_cache();
-        let from_cache = cache.routes_raw.get();
+        let from_cache = cache.routes_raw.get_stale();
 
         let (buf, route_map) = match from_cache {
             Some(cache) => {
@@ -169,32 +1055,54 @@ impl TransportService {
                     HashMap::new(),
                     LastRouteData::default(),
                     0usize,
-                    false,
+                    None,
                 )
                 .await?;
                 (None, route_map)
             }
             None => {
-                let reader = Self::get_routes_stream().await?;
-                let (mut buf, route_map, _, _, _) = reader
-                    .try_fold(
-                        (
-                            Vec::with_capacity(128 * 1024),
-                            HashMap::<String, HashMap<String, RouteGroup>>::new(),
+                let streamed = self
+                    .with_timeout(async {
+                        let (reader, validators) = self.get_routes_stream().await?;
+                        let (mut buf, route_map, _, _, _) = reader
+                            .map_err(ParsingUpstreamError::from)
+                            .try_fold(
+                                (
+                                    Vec::with_capacity(128 * 1024),
+                                    HashMap::<String, HashMap<String, RouteGroup>>::new(),
+                                    LastRouteData::default(),
+                                    0usize,
+                                    None,
+                                ),
+                                extract_route_data_from_buffer_fold,
+                            )
+                            .await?;
+                        buf.shrink_to_fit();
+                        Ok::<_, ParsingUpstreamError>((buf, route_map, validators))
+                    })
+                    .await;
+                match streamed {
+                    Ok((buf, route_map, validators)) => (Some((buf, Some(validators))), route_map),
+                    Err(err) => {
+                        let Some(buf) = snapshots.latest(ROUTES_FILE).await else {
+                            return Err(err);
+                        };
+                        let (route_map, _, _, _) = extract_route_data_from_buffer(
+                            &buf,
+                            HashMap::new(),
                             LastRouteData::default(),
                             0usize,
-                            false,
-                        ),
-                        extract_route_data_from_buffer_fold,
-                    )
-                    .await?;
-                buf.shrink_to_fit();
-                (Some(buf), route_map)
+                            None,
+                        )
+                        .await?;
+                        (Some((buf, None)), route_map)
+                    }
+                }
             }
         };
 
-        if let Some(buf) = buf {
-            cache.routes_raw.set(Rc::new(buf)).ok();
+        if let Some((buf, validators)) = buf {
+            Self::store_data_file(snapshots, ROUTES_FILE, buf, validators).await;
         }
 
         Ok(route_map)
@@ -202,44 +1110,62 @@ impl TransportService {
 
     pub async fn get_stop_map(
         &self,
+        snapshots: &dyn SnapshotStore,
     ) -> Result<Rc<HashMap<String, Rc<StopData>>>, ParsingUpstreamError> {
         let cache = Caches::get_cache();
 
-        let from_cache = cache.stop_map.get();
-        if let Some(stop_map) = from_cache {
+        // Reparsed from stops_raw once expired, so it can't outlive the copy it came from.
+        if let Some(stop_map) = cache.stop_map.get() {
             return Ok(stop_map);
         }
 
-        let from_cache = cache.stops_raw.get();
+        let from_cache = cache.stops_raw.get_stale();
 
         let (buf, stop_map) = match from_cache {
             Some(cache) => {
                 let (stop_map, _, _, _) =
-                    extract_stop_data_from_buffer(&cache[..], HashMap::new(), None, 0usize, false)
+                    extract_stop_data_from_buffer(&cache[..], HashMap::new(), None, 0usize, None)
                         .await?;
                 (None, stop_map)
             }
             None => {
-                let reader = Self::get_stops_stream().await?;
-                let (mut buf, stop_map, _, _, _) = reader
-                    .try_fold(
-                        (
-                            Vec::with_capacity(90 * 1024),
-                            HashMap::<String, Rc<StopData>>::new(),
-                            None,
-                            0usize,
-                            false,
-                        ),
-                        extract_stop_data_from_buffer_fold,
-                    )
-                    .await?;
-                buf.shrink_to_fit();
-                (Some(buf), stop_map)
+                let streamed = self
+                    .with_timeout(async {
+                        let (reader, validators) = self.get_stops_stream().await?;
+                        let (mut buf, stop_map, _, _, _) = reader
+                            .map_err(ParsingUpstreamError::from)
+                            .try_fold(
+                                (
+                                    Vec::with_capacity(90 * 1024),
+                                    HashMap::<String, Rc<StopData>>::new(),
+                                    None,
+                                    0usize,
+                                    None,
+                                ),
+                                extract_stop_data_from_buffer_fold,
+                            )
+                            .await?;
+                        buf.shrink_to_fit();
+                        Ok::<_, ParsingUpstreamError>((buf, stop_map, validators))
+                    })
+                    .await;
+                match streamed {
+                    Ok((buf, stop_map, validators)) => (Some((buf, Some(validators))), stop_map),
+                    Err(err) => {
+                        let Some(buf) = snapshots.latest(STOPS_FILE).await else {
+                            return Err(err);
+                        };
+                        let (stop_map, _, _, _) =
+                            extract_stop_data_from_buffer(&buf, HashMap::new(), None, 0usize, None)
+                                .await?;
+                        (Some((buf, None)), stop_map)
+                    }
+                }
             }
         };
 
-        if let Some(buf) = buf {
-            cache.stops_raw.set(Rc::new(buf)).ok();
+        if let Some((buf, validators)) = buf {
+            Self::store_data_file(snapshots, STOPS_FILE, buf, validators).await;
         }
 
         let stop_map = Rc::new(stop_map);
@@ -248,10 +1174,543 @@ impl TransportService {
         Ok(stop_map)
     }
 
-    #[inline(always)]
-    pub async fn get_stop_name_by_id_async(&self, stop_id: &str) -> Option<Rc<String>> {
-        let stop_map = self.get_stop_map().await.ok()?;
-        TransportService::get_stop_name_by_id(stop_id, &stop_map)
+    /// Reverse index of stop id to the distinct routes serving it, across all directions.
+    pub async fn get_stop_routes_index(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<Rc<HashMap<String, Vec<RouteRef>>>, ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        if let Some(index) = cache.stop_routes.get() {
+            return Ok(index);
+        }
+
+        let route_map = self.get_route_map(snapshots).await?;
+        let mut index = HashMap::<String, HashSet<RouteRef>>::new();
+        for group in route_map.values().flat_map(HashMap::values) {
+            let route_ref = RouteRef {
+                r#type: group.r#type.clone(),
+                number: group.number.clone(),
+            };
+            for stop_id in group.directions.values().flatten() {
+                index
+                    .entry(stop_id.clone())
+                    .or_default()
+                    .insert(route_ref.clone());
+            }
+        }
+        let index = index
+            .into_iter()
+            .map(|(stop_id, routes)| {
+                let mut routes = routes.into_iter().collect::<Vec<RouteRef>>();
+                routes.sort_unstable();
+                (stop_id, routes)
+            })
+            .collect::<HashMap<String, Vec<RouteRef>>>();
+
+        let index = Rc::new(index);
+        cache.stop_routes.set(Rc::clone(&index)).ok();
+        Ok(index)
+    }
+
+    /// The D1 store when bound and known to be in sync with upstream. `None` until
+    /// [`sync_store`](Self::sync_store) has checked or repopulated it in the background, so the
+    /// request is answered from the in-memory maps meanwhile instead of waiting on D1.
+    pub fn get_synced_store(&self, env: &Env) -> Option<StopsRouteStore> {
+        let cache = Caches::get_cache();
+        cache.store_synced.get()?;
+        StopsRouteStore::from_env(env)
+    }
+
+    /// Repopulates the D1 store from upstream once its snapshot is older than the routes/stops
+    /// cache TTL. Runs from the warm-up, the cron and after responses, never inside a request.
+    pub async fn sync_store(&self, env: &Env) -> Result<(), ParsingUpstreamError> {
+        let Some(store) = StopsRouteStore::from_env(env) else {
+            return Ok(());
+        };
+        let cache = Caches::get_cache();
+        if cache.store_synced.get().is_some() {
+            return Ok(());
+        }
+        let fresh = store
+            .loaded_at()
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|loaded_at| {
+                now_secs().saturating_sub(loaded_at) < self.config.data_cache_ttl_secs
+            });
+        if !fresh {
+            let route_map = self.get_route_map(env).await?;
+            let stop_map = self.get_stop_map(env).await?;
+            store.populate(&route_map, &stop_map).await?;
+        }
+        cache.store_synced.set(Rc::new(())).ok();
+        Ok(())
+    }
+
+    /// Case-insensitive substring search over stop names, optionally restricted to stops served
+    /// by a transport type. Runs against D1 when bound, otherwise against the in-memory maps, then
+    /// tops up the results with stops found by an alias.
+    pub async fn search_stops(
+        &self,
+        env: &Env,
+        name: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let needle = normalize_name(name);
+        let mut results = match self.get_synced_store(env) {
+            Some(store) => store.search_stops(&needle, route_type, limit).await?,
+            None => {
+                self.search_stops_in_memory(env, &needle, route_type, limit)
+                    .await?
+            }
+        };
+        results.sort_by_key(|stop| name_match_rank(&normalize_name(&stop.name), &needle));
+        if results.len() < limit && route_type.is_none() {
+            let found = results.iter().map(|stop| stop.id.clone()).collect();
+            results.extend(
+                self.stops_by_alias(env, &found, |alias| alias.contains(&needle))
+                    .await?,
+            );
+            results.truncate(limit);
+        }
+        if results.len() < limit {
+            let found = results.iter().map(|stop| stop.id.clone()).collect();
+            results.extend(
+                self.fuzzy_search_stops(env, &needle, route_type, &found, limit - results.len())
+                    .await?,
+            );
+        }
+        Ok(results)
+    }
+
+    /// Stops not in `found` whose name is within a few typos of `needle`, closest first.
+    async fn fuzzy_search_stops(
+        &self,
+        env: &Env,
+        needle: &str,
+        route_type: Option<&str>,
+        found: &HashSet<String>,
+        limit: usize,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let needle = needle.chars().collect::<Vec<char>>();
+        let max_edits = max_typos(needle.len());
+        if max_edits == 0 {
+            return Ok(Vec::new());
+        }
+        let stop_names = self.get_stop_name_index(env).await?;
+        let stop_routes = match route_type {
+            Some(_) => Some(self.get_stop_routes_index(env).await?),
+            None => None,
+        };
+        let mut matches = stop_names
+            .entries
+            .iter()
+            .filter(|(_, stop)| !found.contains(&stop.id))
+            .filter(|(_, stop)| match (route_type, &stop_routes) {
+                (Some(route_type), Some(stop_routes)) => stop_routes
+                    .get(&stop.id)
+                    .is_some_and(|routes| routes.iter().any(|r| r.r#type.as_str() == route_type)),
+                _ => true,
+            })
+            .filter_map(|(name, stop)| {
+                let distance = fuzzy_substring_distance(&needle, name, max_edits)?;
+                Some((distance, stop))
+            })
+            .collect::<Vec<(usize, &Rc<StopData>)>>();
+        matches.sort_unstable_by(|(a_distance, a), (b_distance, b)| {
+            a_distance
+                .cmp(b_distance)
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        Ok(matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, stop)| StopSearchResult {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                matched_alias: None,
+                lat: stop.lat,
+                lon: stop.lon,
+            })
+            .collect())
+    }
+
+    async fn search_stops_in_memory(
+        &self,
+        env: &Env,
+        needle: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let stop_names = self.get_stop_name_index(env).await?;
+        let stop_routes = match route_type {
+            Some(_) => Some(self.get_stop_routes_index(env).await?),
+            None => None,
+        };
+        let mut results = stop_names
+            .entries
+            .iter()
+            .filter(|(name, _)| name.contains(needle))
+            .map(|(_, stop)| stop)
+            .filter(|stop| match (route_type, &stop_routes) {
+                (Some(route_type), Some(stop_routes)) => stop_routes
+                    .get(&stop.id)
+                    .is_some_and(|routes| routes.iter().any(|r| r.r#type.as_str() == route_type)),
+                _ => true,
+            })
+            .map(|stop| StopSearchResult {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                matched_alias: None,
+                lat: stop.lat,
+                lon: stop.lon,
+            })
+            .collect::<Vec<StopSearchResult>>();
+        results.sort_unstable_by(|a, b| {
+            name_match_rank(&normalize_name(&a.name), needle)
+                .cmp(&name_match_rank(&normalize_name(&b.name), needle))
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        results.truncate(limit);
+        Ok(results)
+    }
+
+    /// Stops of a zone, the stops.txt Area compared ignoring case and diacritics, by name and
+    /// optionally only those served by a transport type. Runs against D1 when bound and in sync,
+    /// otherwise against the in-memory stop map.
+    pub async fn stops_in_zone(
+        &self,
+        env: &Env,
+        zone: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let zone = normalize_name(zone);
+        match self.get_synced_store(env) {
+            Some(store) => Ok(store.stops_in_zone(&zone, route_type, limit).await?),
+            None => {
+                self.zone_stops_in_memory(env, &zone, route_type, limit)
+                    .await
+            }
+        }
+    }
+
+    async fn zone_stops_in_memory(
+        &self,
+        snapshots: &dyn SnapshotStore,
+        zone: &str,
+        route_type: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let stop_map = self.get_stop_map(snapshots).await?;
+        let stop_routes = match route_type {
+            Some(_) => Some(self.get_stop_routes_index(snapshots).await?),
+            None => None,
+        };
+        let mut stops = stop_map
+            .iter()
+            .filter(|(key, stop)| **key == stop.id)
+            .map(|(_, stop)| stop)
+            .filter(|stop| {
+                stop.zone
+                    .as_deref()
+                    .is_some_and(|stop_zone| normalize_name(stop_zone) == zone)
+            })
+            .filter(|stop| match (route_type, &stop_routes) {
+                (Some(route_type), Some(stop_routes)) => stop_routes
+                    .get(&stop.id)
+                    .is_some_and(|routes| routes.iter().any(|r| r.r#type.as_str() == route_type)),
+                _ => true,
+            })
+            .collect::<Vec<&Rc<StopData>>>();
+        stops.sort_unstable_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(stops
+            .into_iter()
+            .take(limit)
+            .map(|stop| StopSearchResult {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                matched_alias: None,
+                lat: stop.lat,
+                lon: stop.lon,
+            })
+            .collect())
+    }
+
+    /// Alias table keyed by normalized alias name, empty when the KV namespace is not bound.
+    pub async fn get_stop_aliases(
+        &self,
+        env: &Env,
+    ) -> Result<Rc<HashMap<String, StopAlias>>, ParsingUpstreamError> {
+        let cache = Caches::get_cache();
+        if let Some(aliases) = cache.stop_aliases.get() {
+            return Ok(aliases);
+        }
+
+        let raw = match env.kv(STOP_ALIASES_BINDING) {
+            Ok(kv) => kv
+                .get(STOP_ALIASES_KEY)
+                .json::<HashMap<String, Vec<String>>>()
+                .await
+                .map_err(worker::Error::from)?
+                .unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        let aliases = raw
+            .into_iter()
+            .map(|(name, stop_ids)| {
+                (
+                    normalize_name(&name),
+                    StopAlias {
+                        name: name.trim().to_string(),
+                        stop_ids,
+                    },
+                )
+            })
+            .collect::<HashMap<String, StopAlias>>();
+
+        let aliases = Rc::new(aliases);
+        cache.stop_aliases.set(Rc::clone(&aliases)).ok();
+        Ok(aliases)
+    }
+
+    /// Stops known under an alias matching `matches`, skipping ids already in `found`.
+    async fn stops_by_alias(
+        &self,
+        env: &Env,
+        found: &HashSet<String>,
+        matches: impl Fn(&str) -> bool,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let aliases = self.get_stop_aliases(env).await?;
+        let mut matched = aliases
+            .iter()
+            .filter(|(key, _)| matches(key))
+            .map(|(_, alias)| alias)
+            .peekable();
+        if matched.peek().is_none() {
+            return Ok(Vec::new());
+        }
+
+        let stop_map = self.get_stop_map(env).await?;
+        let mut seen = found.clone();
+        let mut results = Vec::new();
+        for alias in matched {
+            for stop in alias.stop_ids.iter().filter_map(|id| stop_map.get(id)) {
+                if seen.insert(stop.id.clone()) {
+                    results.push(StopSearchResult {
+                        id: stop.id.clone(),
+                        siri_id: stop.siri_id.clone(),
+                        name: stop.name.to_string(),
+                        matched_alias: Some(alias.name.clone()),
+                        lat: stop.lat,
+                        lon: stop.lon,
+                    });
+                }
+            }
+        }
+        results.sort_unstable_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(results)
+    }
+
+    /// One page of all stops ordered by name, optionally only those whose name contains `name`.
+    pub async fn list_stops(
+        &self,
+        env: &Env,
+        name: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<StopPage, ParsingUpstreamError> {
+        let stop_names = self.get_stop_name_index(env).await?;
+        let needle = name.map(normalize_name);
+        let mut stops = stop_names
+            .entries
+            .iter()
+            .filter(|(name, _)| match &needle {
+                Some(needle) => name.contains(needle.as_str()),
+                None => true,
+            })
+            .map(|(_, stop)| stop)
+            .collect::<Vec<&Rc<StopData>>>();
+        stops.sort_unstable_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(StopPage {
+            total: stops.len(),
+            offset,
+            limit,
+            stops: stops
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|stop| StopSearchResult {
+                    id: stop.id.clone(),
+                    siri_id: stop.siri_id.clone(),
+                    name: stop.name.to_string(),
+                    matched_alias: None,
+                    lat: stop.lat,
+                    lon: stop.lon,
+                })
+                .collect(),
+        })
+    }
+
+    /// Stops grouped by name, ignoring case and diacritics, in name order.
+    pub async fn get_stop_groups(&self, env: &Env) -> Result<Vec<StopGroup>, ParsingUpstreamError> {
+        let stop_names = self.get_stop_name_index(env).await?;
+        Ok(stop_names
+            .groups()
+            .filter_map(|group| StopGroup::from_stops(group.iter().map(|(_, stop)| stop)))
+            .collect())
+    }
+
+    /// The stops named `name`, ignoring case and diacritics, `None` when there are none.
+    pub async fn get_stop_group(
+        &self,
+        env: &Env,
+        name: &str,
+    ) -> Result<Option<StopGroup>, ParsingUpstreamError> {
+        let stop_names = self.get_stop_name_index(env).await?;
+        let named = stop_names.named(&normalize_name(name));
+        Ok(StopGroup::from_stops(named.iter().map(|(_, stop)| stop)))
+    }
+
+    /// Distinct stop names with a word starting with `prefix`, names starting with it first.
+    pub async fn autocomplete_stops(
+        &self,
+        env: &Env,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<StopSuggestion>, ParsingUpstreamError> {
+        let prefix = normalize_name(prefix);
+        let stop_names = self.get_stop_name_index(env).await?;
+        let mut matches = stop_names
+            .with_word_prefix(&prefix)
+            .map(|(name, stop)| (!name.starts_with(&prefix), stop))
+            .collect::<Vec<(bool, &Rc<StopData>)>>();
+        matches.sort_unstable_by(|(a_rank, a), (b_rank, b)| {
+            a_rank
+                .cmp(b_rank)
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        // sorted by name, so the stops sharing one are next to each other
+        let mut suggestions: Vec<StopSuggestion> = Vec::with_capacity(limit);
+        for (_, stop) in matches {
+            if let Some(last) = suggestions.last_mut()
+                && last.name == *stop.name
+            {
+                last.stop_ids.push(stop.id.clone());
+                continue;
+            }
+            if suggestions.len() == limit {
+                break;
+            }
+            suggestions.push(StopSuggestion {
+                name: stop.name.to_string(),
+                stop_ids: vec![stop.id.clone()],
+            });
+        }
+        Ok(suggestions)
+    }
+
+    /// The normalized name index of the current stop map, rebuilt whenever the stop map was
+    /// replaced.
+    pub async fn get_stop_name_index(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<Rc<StopNameIndex>, ParsingUpstreamError> {
+        let stop_map = self.get_stop_map(snapshots).await?;
+        let cache = Caches::get_cache();
+        if let Some(stop_names) = cache.stop_names.get_stale()
+            && Rc::ptr_eq(&stop_names.stop_map, &stop_map)
+        {
+            return Ok(stop_names);
+        }
+        let stop_names = Rc::new(StopNameIndex::build(stop_map));
+        cache.stop_names.set(Rc::clone(&stop_names)).ok();
+        Ok(stop_names)
+    }
+
+    /// The spatial grid over the current stop map, rebuilt whenever the stop map was replaced.
+    pub async fn get_stop_grid(
+        &self,
+        snapshots: &dyn SnapshotStore,
+    ) -> Result<Rc<StopGrid>, ParsingUpstreamError> {
+        let stop_map = self.get_stop_map(snapshots).await?;
+        let cache = Caches::get_cache();
+        if let Some(stop_grid) = cache.stop_grid.get_stale()
+            && Rc::ptr_eq(&stop_grid.stop_map, &stop_map)
+        {
+            return Ok(stop_grid);
+        }
+        let stop_grid = Rc::new(StopGrid::build(stop_map));
+        cache.stop_grid.set(Rc::clone(&stop_grid)).ok();
+        Ok(stop_grid)
+    }
+
+    /// Stops within `radius_m` meters of the point, closest first.
+    pub async fn nearby_stops(
+        &self,
+        env: &Env,
+        lat: f64,
+        lon: f64,
+        radius_m: f64,
+        limit: usize,
+    ) -> Result<Vec<NearbyStop>, ParsingUpstreamError> {
+        let stop_grid = self.get_stop_grid(env).await?;
+        let mut results = stop_grid
+            .within_radius(lat, lon, radius_m)
+            .map(|(stop, distance_m)| NearbyStop {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                lat: stop.lat.unwrap_or_default(),
+                lon: stop.lon.unwrap_or_default(),
+                distance_m: distance_m.round(),
+            })
+            .collect::<Vec<NearbyStop>>();
+        results.sort_unstable_by(|a, b| {
+            a.distance_m
+                .total_cmp(&b.distance_m)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        results.truncate(limit);
+        Ok(results)
+    }
+
+    /// Stops whose current name or one of its aliases equals `name`, ignoring case.
+    pub async fn resolve_stop_name(
+        &self,
+        env: &Env,
+        name: &str,
+    ) -> Result<Vec<StopSearchResult>, ParsingUpstreamError> {
+        let needle = normalize_name(name);
+        let stop_names = self.get_stop_name_index(env).await?;
+        let mut results = stop_names
+            .entries
+            .iter()
+            .filter(|(name, _)| *name == needle)
+            .map(|(_, stop)| StopSearchResult {
+                id: stop.id.clone(),
+                siri_id: stop.siri_id.clone(),
+                name: stop.name.to_string(),
+                matched_alias: None,
+                lat: stop.lat,
+                lon: stop.lon,
+            })
+            .collect::<Vec<StopSearchResult>>();
+        results.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        let found = results.iter().map(|stop| stop.id.clone()).collect();
+        results.extend(
+            self.stops_by_alias(env, &found, |alias| alias == needle)
+                .await?,
+        );
+        Ok(results)
     }
 
     #[inline(always)]
@@ -264,3 +1723,252 @@ impl TransportService {
             .map(|stop_data| Rc::clone(&stop_data.name))
     }
 }
+
+/// Every `prefix` key of a KV namespace with its JSON value, skipping keys gone since listed.
+pub async fn kv_list_json<T: serde::de::DeserializeOwned>(
+    kv: &KvStore,
+    prefix: &str,
+) -> worker::Result<Vec<(String, T)>> {
+    let mut values = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(value) = kv.get(&key.name).json::<T>().await? {
+                values.push((key.name, value));
+            }
+        }
+        if page.list_complete {
+            return Ok(values);
+        }
+        cursor = page.cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::snapshots::MemorySnapshots;
+    use crate::upstream::FixtureClient;
+    use futures::executor::block_on;
+    use futures::future::LocalBoxFuture;
+
+    const ROUTES: &str = "RouteNum;Transport;RouteName;RouteStops\n\
+        1;tram;Kopli - Linnahall;1001,1002\n\
+        ;;Linnahall - Kopli;1002,1001\n\
+        ,,21600,21900\n\
+        5;bus;Männiku - Balti jaam;1002\n";
+    const STOPS: &str = "ID;SiriID;Lat;Lng;Name;Area\n\
+        1001;101;5943686;2475538;Kopli;Põhja-Tallinn\n\
+        1002;102;5943000;2475000;Linnahall;Kesklinn\n\
+        1003;103;5943100;2475100;Viru;Kesklinn\n";
+    const SIRI: &str = "Transport,RouteNum,ExpectedTimeInSeconds,ScheduleTimeInSeconds\n\
+        stop,101\n\
+        tram,1,36000,35940,Linnahall,,Z\n\
+        tram,1,36600,36600,Linnahall,,\n";
+
+    /// Never answers, for the timeouts.
+    struct StalledClient;
+
+    impl UpstreamClient for StalledClient {
+        fn send<'a>(
+            &'a self,
+            _uri: &'a str,
+            _req: &'a UpstreamRequest,
+        ) -> LocalBoxFuture<'a, worker::Result<UpstreamResponse>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    fn manual_clock() -> ManualClock {
+        let clock = ManualClock::new(1_760_000_000_000.0);
+        clock::install(clock.clone());
+        clock
+    }
+
+    fn arrivals_uri(siri_ids: &str) -> String {
+        format!("{}?stopid={}", Config::get().arrivals_url, siri_ids)
+    }
+
+    fn fixtures() -> Rc<FixtureClient> {
+        let config = Config::get();
+        let client = FixtureClient::new()
+            .with_body(config.routes_url.as_str(), ROUTES)
+            .with_body(config.stops_url.as_str(), STOPS)
+            .with_body(arrivals_uri("101"), SIRI)
+            .with_chunk_size(7);
+        Rc::new(client)
+    }
+
+    #[test]
+    fn parses_routes_across_chunks_and_caches_the_file() {
+        manual_clock();
+        let client = fixtures();
+        let service = TransportService::with_client(Rc::clone(&client));
+        let snapshots = MemorySnapshots::default();
+
+        let route_map = block_on(service.get_route_map(&snapshots)).unwrap();
+        let tram = &route_map["tram"]["1"];
+        assert_eq!(tram.directions.len(), 2);
+        assert_eq!(tram.directions["Linnahall - Kopli"], ["1002", "1001"]);
+        assert_eq!(
+            route_map["bus"]["5"].directions["Männiku - Balti jaam"],
+            ["1002"]
+        );
+
+        let types = block_on(service.get_sorted_types(&snapshots)).unwrap();
+        assert_eq!(*types, ["bus", "tram"]);
+        assert_eq!(client.requested(), [Config::get().routes_url.as_str()]);
+        assert_eq!(snapshots.files.borrow()[ROUTES_FILE], ROUTES.as_bytes());
+    }
+
+    #[test]
+    fn lists_the_stops_of_a_zone_from_memory() {
+        manual_clock();
+        let service = TransportService::with_client(fixtures());
+        let snapshots = MemorySnapshots::default();
+        let zone = |zone: &str, route_type, limit| {
+            let stops = block_on(service.zone_stops_in_memory(
+                &snapshots,
+                &normalize_name(zone),
+                route_type,
+                limit,
+            ));
+            let stops = stops.unwrap().into_iter().map(|stop| stop.id);
+            stops.collect::<Vec<String>>()
+        };
+
+        assert_eq!(zone("Kesklinn", None, 20), ["1002", "1003"]);
+        assert_eq!(zone("KESKLINN", None, 1), ["1002"]);
+        assert_eq!(zone("pohja-tallinn", None, 20), ["1001"]);
+        assert_eq!(zone("Kesklinn", Some("bus"), 20), ["1002"]);
+        assert!(zone("Kesklinn", Some("trolleybus"), 20).is_empty());
+        assert!(zone("Kristiine", None, 20).is_empty());
+    }
+
+    #[test]
+    fn keys_stops_by_id_and_siri_id() {
+        manual_clock();
+        let client = fixtures();
+        let service = TransportService::with_client(Rc::clone(&client));
+        let snapshots = MemorySnapshots::default();
+
+        let stop_map = block_on(service.get_stop_map(&snapshots)).unwrap();
+        assert_eq!(stop_map.len(), 6);
+        assert!(Rc::ptr_eq(&stop_map["1001"], &stop_map["101"]));
+        assert_eq!(*stop_map["102"].name, "Linnahall");
+        assert_eq!(stop_map["1001"].lat, Some(59.43686));
+
+        block_on(service.get_stop_map(&snapshots)).unwrap();
+        assert_eq!(client.requested().len(), 1);
+    }
+
+    #[test]
+    fn parses_siri_arrivals_of_known_stops() {
+        manual_clock();
+        let service = TransportService::with_client(fixtures());
+        let snapshots = MemorySnapshots::default();
+
+        let arrivals = block_on(service.fetch_stops_arrivals(&snapshots, "101")).unwrap();
+        assert_eq!(arrivals.len(), 1);
+        assert_eq!(
+            (arrivals[0].id.as_str(), arrivals[0].name.as_str()),
+            ("101", "Kopli")
+        );
+        let tram_1 = &arrivals[0].arrivals["tram"]["1"];
+        assert_eq!(tram_1.len(), 2);
+        assert!(tram_1[0].is_low_entry && tram_1[0].is_realtime);
+        assert!(!tram_1[1].is_low_entry && !tram_1[1].is_realtime);
+        assert_eq!(tram_1[0].destination.as_deref(), Some("Linnahall"));
+    }
+
+    #[test]
+    fn caches_raw_siri_until_it_expires() {
+        let clock = manual_clock();
+        let client = fixtures();
+        let service = TransportService::with_client(Rc::clone(&client));
+
+        let raw = block_on(service.get_raw_stops_arrivals("101")).unwrap();
+        assert_eq!(*raw, SIRI);
+        block_on(service.get_raw_stops_arrivals("101")).unwrap();
+        assert_eq!(client.requested().len(), 1);
+
+        clock.advance(Duration::from_secs(
+            Config::get().arrivals_cache_ttl_secs as u64 + 1,
+        ));
+        block_on(service.get_raw_stops_arrivals("101")).unwrap();
+        assert_eq!(client.requested().len(), 2);
+    }
+
+    #[test]
+    fn times_out_a_stalled_upstream() {
+        manual_clock();
+        let started_ms = clock::now_ms();
+        let service = TransportService::with_client(StalledClient);
+
+        let res = block_on(service.get_raw_stops_arrivals("101"));
+        assert!(matches!(res, Err(ParsingUpstreamError::Timeout)));
+        let waited_ms = clock::now_ms() - started_ms;
+        assert_eq!(waited_ms, Config::get().upstream_timeout_ms as f64);
+
+        let res = block_on(service.get_vehicle_positions());
+        assert!(matches!(res, Err(ParsingUpstreamError::Timeout)));
+    }
+
+    #[test]
+    fn falls_back_to_the_snapshot_when_upstream_times_out() {
+        manual_clock();
+        let service = TransportService::with_client(StalledClient);
+        let snapshots = MemorySnapshots::default();
+        let res = block_on(service.get_route_map(&snapshots));
+        assert!(matches!(res, Err(ParsingUpstreamError::Timeout)));
+
+        block_on(snapshots.store_if_changed(ROUTES_FILE, ROUTES.as_bytes()));
+        let route_map = block_on(service.get_route_map(&snapshots)).unwrap();
+        assert_eq!(route_map["tram"]["1"].directions.len(), 2);
+        let cached = Caches::get_cache().routes_raw.get();
+        assert_eq!(
+            cached.as_deref().map(Vec::as_slice),
+            Some(ROUTES.as_bytes())
+        );
+    }
+
+    #[test]
+    fn breaker_stops_calling_a_failing_upstream_until_the_cooldown_ends() {
+        let clock = manual_clock();
+        let config = Config::get();
+        let client = Rc::new(FixtureClient::new().with_response(arrivals_uri("101"), 503, &[], ""));
+        let service = TransportService::with_client(Rc::clone(&client));
+
+        for _ in 0..config.arrivals_breaker_threshold {
+            let res = block_on(service.get_raw_stops_arrivals("101"));
+            assert!(matches!(res, Err(ParsingUpstreamError::Http(_))));
+        }
+        let calls = client.requested().len();
+        let res = block_on(service.get_raw_stops_arrivals("101"));
+        let cooldown_secs = config.arrivals_breaker_cooldown_secs;
+        assert!(
+            matches!(res, Err(ParsingUpstreamError::Unavailable(secs)) if secs == cooldown_secs)
+        );
+        assert_eq!(client.requested().len(), calls);
+
+        clock.advance(Duration::from_secs(cooldown_secs as u64));
+        let res = block_on(service.get_raw_stops_arrivals("101"));
+        assert!(matches!(res, Err(ParsingUpstreamError::Http(_))));
+        assert_eq!(client.requested().len(), calls + 1);
+        let res = block_on(service.get_raw_stops_arrivals("101"));
+        assert!(matches!(res, Err(ParsingUpstreamError::Unavailable(_))));
+    }
+
+    #[test]
+    fn escapes_like_wildcards_of_search_terms() {
+        assert_eq!(like_escaped("viru"), "viru");
+        assert_eq!(like_escaped("%"), "\\%");
+        assert_eq!(like_escaped("a_b\\c"), "a\\_b\\\\c");
+    }
+}
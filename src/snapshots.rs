@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use futures::future::LocalBoxFuture;
+use worker::{Bucket, Env};
+
+use crate::caches::now_secs;
+use crate::logging::{log_error, log_warn};
+use crate::str_utils::fnv1a_64;
+
+/// R2 bucket keeping versioned copies of the upstream data files, optional.
+pub const SNAPSHOTS_BINDING: &str = "SNAPSHOTS";
+
+pub const ROUTES_FILE: &str = "routes";
+pub const STOPS_FILE: &str = "stops";
+
+const HASH_METADATA: &str = "hash";
+
+/// Versioned copies of routes.txt/stops.txt, written as `<file>/<unix secs>-<hash>.txt` whenever
+/// the content changes, with `<file>/latest.txt` always mirroring the newest one so the service
+/// can keep answering from it while transport.tallinn.ee is down.
+pub struct UpstreamSnapshots;
+
+impl UpstreamSnapshots {
+    fn bucket(env: &Env) -> Option<Bucket> {
+        env.bucket(SNAPSHOTS_BINDING).ok()
+    }
+
+    fn latest_key(file: &str) -> String {
+        format!("{}/latest.txt", file)
+    }
+
+    /// Content of the newest snapshot of `file`, `None` when unbound, missing or unreadable.
+    pub async fn latest(env: &Env, file: &str) -> Option<Vec<u8>> {
+        let bucket = Self::bucket(env)?;
+        let object = bucket.get(Self::latest_key(file)).execute().await.ok()??;
+        let bytes = object.body()?.bytes().await.ok()?;
+        log_warn!("serving {} from R2 snapshot", file);
+        Some(bytes)
+    }
+
+    /// Writes a new version of `file` unless the latest snapshot already has the same content.
+    /// Failures are logged only, snapshots must never fail the request that refreshed the data.
+    pub async fn store_if_changed(env: &Env, file: &str, buf: &[u8]) {
+        let Some(bucket) = Self::bucket(env) else {
+            return;
+        };
+        if let Err(err) = Self::put_if_changed(&bucket, file, buf).await {
+            log_error!("failed to snapshot {}: {}", file, err);
+        }
+    }
+
+    async fn put_if_changed(bucket: &Bucket, file: &str, buf: &[u8]) -> worker::Result<()> {
+        let hash = format!("{:016x}", fnv1a_64(buf));
+        let latest_key = Self::latest_key(file);
+        if let Some(latest) = bucket.head(&latest_key).await?
+            && latest.custom_metadata()?.get(HASH_METADATA) == Some(&hash)
+        {
+            return Ok(());
+        }
+
+        let metadata = HashMap::from([(HASH_METADATA.to_string(), hash.clone())]);
+        bucket
+            .put(
+                format!("{}/{}-{}.txt", file, now_secs(), hash),
+                buf.to_vec(),
+            )
+            .custom_metadata(metadata.clone())
+            .execute()
+            .await?;
+        bucket
+            .put(latest_key, buf.to_vec())
+            .custom_metadata(metadata)
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Where the service finds and keeps the upstream snapshots: [`UpstreamSnapshots`] in the bucket
+/// bound to the worker's [`Env`], anything else on the host.
+pub trait SnapshotStore {
+    fn latest<'a>(&'a self, file: &'a str) -> LocalBoxFuture<'a, Option<Vec<u8>>>;
+
+    fn store_if_changed<'a>(&'a self, file: &'a str, buf: &'a [u8]) -> LocalBoxFuture<'a, ()>;
+}
+
+impl SnapshotStore for Env {
+    fn latest<'a>(&'a self, file: &'a str) -> LocalBoxFuture<'a, Option<Vec<u8>>> {
+        Box::pin(UpstreamSnapshots::latest(self, file))
+    }
+
+    fn store_if_changed<'a>(&'a self, file: &'a str, buf: &'a [u8]) -> LocalBoxFuture<'a, ()> {
+        Box::pin(UpstreamSnapshots::store_if_changed(self, file, buf))
+    }
+}
+
+/// Snapshots kept in memory, for the host tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySnapshots {
+    pub files: std::cell::RefCell<HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl SnapshotStore for MemorySnapshots {
+    fn latest<'a>(&'a self, file: &'a str) -> LocalBoxFuture<'a, Option<Vec<u8>>> {
+        Box::pin(futures::future::ready(
+            self.files.borrow().get(file).cloned(),
+        ))
+    }
+
+    fn store_if_changed<'a>(&'a self, file: &'a str, buf: &'a [u8]) -> LocalBoxFuture<'a, ()> {
+        self.files
+            .borrow_mut()
+            .insert(file.to_string(), buf.to_vec());
+        Box::pin(futures::future::ready(()))
+    }
+}
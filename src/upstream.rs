@@ -0,0 +1,264 @@
+//! Upstream HTTP access behind [`UpstreamClient`]. The worker sends through [`FetchClient`], the
+//! runtime's `fetch`, while [`FixtureClient`] answers from fixture bytes so the parsers and
+//! services can be driven on the host without the Workers runtime.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
+use futures::{StreamExt, TryStreamExt};
+use worker::{Method, Result};
+
+use crate::clock;
+use crate::logging::{self, log_debug};
+use crate::metrics::Metrics;
+
+/// Body of an upstream response, in the chunks it arrived in.
+pub type BodyStream = LocalBoxStream<'static, Result<Vec<u8>>>;
+
+/// An upstream request, without the URI so the same request can go to the mirror.
+pub struct UpstreamRequest {
+    pub method: Method,
+    pub headers: Vec<(&'static str, String)>,
+    /// Edge cache TTL of the answer, `Some(0)` to go past the edge cache.
+    pub cache_ttl: Option<u32>,
+}
+
+impl UpstreamRequest {
+    pub fn new(method: Method) -> Self {
+        Self {
+            method,
+            headers: Vec::new(),
+            cache_ttl: None,
+        }
+    }
+
+    pub fn get() -> Self {
+        Self::new(Method::Get)
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, secs: u32) -> Self {
+        self.cache_ttl = Some(secs);
+        self
+    }
+}
+
+pub struct UpstreamResponse {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    body: BodyStream,
+}
+
+impl UpstreamResponse {
+    pub fn new(status: u16, headers: Vec<(String, String)>, body: BodyStream) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// The header's value, names compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn stream(self) -> BodyStream {
+        self.body
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>> {
+        self.body.try_concat().await
+    }
+
+    /// The body as text, invalid UTF-8 replaced as `Response.text()` does.
+    pub async fn text(self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).into_owned())
+    }
+}
+
+/// Sends upstream requests, object-safe so the service can hold any client.
+pub trait UpstreamClient {
+    fn send<'a>(
+        &'a self,
+        uri: &'a str,
+        req: &'a UpstreamRequest,
+    ) -> LocalBoxFuture<'a, Result<UpstreamResponse>>;
+}
+
+/// A shared client, so the caller can keep looking at the one the service was given.
+impl<C: UpstreamClient + ?Sized> UpstreamClient for Rc<C> {
+    fn send<'a>(
+        &'a self,
+        uri: &'a str,
+        req: &'a UpstreamRequest,
+    ) -> LocalBoxFuture<'a, Result<UpstreamResponse>> {
+        (**self).send(uri, req)
+    }
+}
+
+/// The runtime's `fetch`, each request in the trace of the incoming one and counted and timed
+/// for its log line and the metrics.
+pub struct FetchClient;
+
+impl FetchClient {
+    async fn fetch(uri: &str, req: &UpstreamRequest) -> Result<UpstreamResponse> {
+        let headers = worker::Headers::new();
+        for (name, value) in &req.headers {
+            headers.set(name, value)?;
+        }
+        logging::propagate_trace(&headers)?;
+        let req_init = worker::RequestInit {
+            method: req.method.clone(),
+            headers,
+            cf: worker::CfProperties {
+                cache_ttl: req.cache_ttl,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let req = worker::Request::new_with_init(uri, &req_init)?;
+        let started_ms = clock::now_ms();
+        let res = worker::Fetch::Request(req).send().await;
+        let duration_ms = clock::now_ms() - started_ms;
+        logging::record_upstream_call(duration_ms);
+        let failed = res.as_ref().map_or(true, |res| res.status_code() >= 500);
+        Metrics::get().record_upstream(uri, duration_ms, failed);
+        let mut res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                log_debug!("{} failed in {}ms: {}", uri, duration_ms, err);
+                return Err(err);
+            }
+        };
+        log_debug!(
+            "{} answered {} in {}ms",
+            uri,
+            res.status_code(),
+            duration_ms
+        );
+        let headers = res.headers().entries().collect();
+        // HEAD and 304 answers come without a body to stream.
+        let body: BodyStream = match res.stream() {
+            Ok(stream) => stream.boxed_local(),
+            Err(_) => futures::stream::empty().boxed_local(),
+        };
+        Ok(UpstreamResponse::new(res.status_code(), headers, body))
+    }
+}
+
+impl UpstreamClient for FetchClient {
+    fn send<'a>(
+        &'a self,
+        uri: &'a str,
+        req: &'a UpstreamRequest,
+    ) -> LocalBoxFuture<'a, Result<UpstreamResponse>> {
+        Box::pin(Self::fetch(uri, req))
+    }
+}
+
+struct Fixture {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Answers from fixture bytes keyed by the full URI, 404 for any other URI. Bodies are streamed
+/// in `chunk_size` pieces so the parsers meet chunk boundaries as with a real download. The
+/// URIs asked for are kept in order for [`FixtureClient::requested`].
+pub struct FixtureClient {
+    fixtures: HashMap<String, Fixture>,
+    chunk_size: usize,
+    requested: RefCell<Vec<String>>,
+}
+
+impl Default for FixtureClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FixtureClient {
+    pub fn new() -> Self {
+        Self {
+            fixtures: HashMap::new(),
+            chunk_size: 16 * 1024,
+            requested: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// A 200 answer of `body` for `uri`.
+    pub fn with_body(self, uri: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+        self.with_response(uri, 200, &[], body)
+    }
+
+    pub fn with_response(
+        mut self,
+        uri: impl Into<String>,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: impl Into<Vec<u8>>,
+    ) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.fixtures.insert(
+            uri.into(),
+            Fixture {
+                status,
+                headers,
+                body: body.into(),
+            },
+        );
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn requested(&self) -> Vec<String> {
+        self.requested.borrow().clone()
+    }
+}
+
+impl UpstreamClient for FixtureClient {
+    fn send<'a>(
+        &'a self,
+        uri: &'a str,
+        req: &'a UpstreamRequest,
+    ) -> LocalBoxFuture<'a, Result<UpstreamResponse>> {
+        self.requested.borrow_mut().push(uri.to_string());
+        let res = match self.fixtures.get(uri) {
+            Some(fixture) => {
+                let chunks = match req.method {
+                    Method::Head => Vec::new(),
+                    _ => fixture
+                        .body
+                        .chunks(self.chunk_size)
+                        .map(|chunk| Ok(chunk.to_vec()))
+                        .collect(),
+                };
+                UpstreamResponse::new(
+                    fixture.status,
+                    fixture.headers.clone(),
+                    futures::stream::iter(chunks).boxed_local(),
+                )
+            }
+            None => UpstreamResponse::new(404, Vec::new(), futures::stream::empty().boxed_local()),
+        };
+        Box::pin(futures::future::ready(Ok(res)))
+    }
+}
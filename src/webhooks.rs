@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use worker::{Env, Fetch, Headers, KvStore, Method, Request, RequestInit, Result};
+
+use crate::caches::now_secs;
+use crate::crypto::{hmac_sha256, random_id};
+use crate::handlers::arrivals::{ArrivalsLookup, lookup_stop_arrivals};
+use crate::logging::{log_error, log_warn};
+use crate::models::{Arrival, StopArrivalsStatus, TransportType};
+use crate::services::kv_list_json;
+
+/// KV namespace of the registered webhooks as `webhook:<id>` JSON, optional.
+pub const WEBHOOKS_BINDING: &str = "WEBHOOKS";
+const WEBHOOK_PREFIX: &str = "webhook:";
+
+/// "Call me when route 17 is due at stop X within N minutes."
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookCondition {
+    /// HTTPS URL receiving the `POST`
+    #[schema(example = "https://example.com/hooks/tlt")]
+    pub url: String,
+    /// Stop ID or SIRI ID
+    #[schema(example = "1001")]
+    pub stop_id: String,
+    pub r#type: TransportType,
+    #[schema(example = "17")]
+    pub number: String,
+    /// Fires for every arrival reaching this many minutes away (1-60)
+    #[schema(example = 5)]
+    pub within_minutes: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Webhook {
+    #[serde(flatten)]
+    condition: WebhookCondition,
+    secret: String,
+    /// Arrival time last fired for, so one arrival fires once
+    #[serde(default)]
+    last_fired_time: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WebhookCreated {
+    #[schema(example = "q1w2e3r4t5y6u7i8o9p0aA")]
+    pub id: String,
+    /// Key of the `X-Webhook-Signature` HMAC, only shown here
+    #[schema(example = "Zx9Yw8Xv7Wu6Vt5Us4Tr3Q")]
+    pub secret: String,
+}
+
+/// Body of the webhook calls.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEvent<'a> {
+    #[schema(example = "q1w2e3r4t5y6u7i8o9p0aA")]
+    pub webhook_id: &'a str,
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub condition: &'a WebhookCondition,
+    pub arrival: &'a Arrival,
+}
+
+pub fn webhooks_store(env: &Env) -> Option<KvStore> {
+    env.kv(WEBHOOKS_BINDING).ok()
+}
+
+pub async fn create_webhook(kv: &KvStore, condition: WebhookCondition) -> Result<WebhookCreated> {
+    let (id, secret) = (random_id()?, random_id()?);
+    let webhook = Webhook {
+        condition,
+        secret: secret.clone(),
+        last_fired_time: None,
+    };
+    kv.put(
+        &format!("{}{}", WEBHOOK_PREFIX, id),
+        serde_json::to_string(&webhook)?.as_str(),
+    )?
+    .execute()
+    .await?;
+    Ok(WebhookCreated { id, secret })
+}
+
+/// Whether a webhook with that id existed.
+pub async fn delete_webhook(kv: &KvStore, id: &str) -> Result<bool> {
+    let key = format!("{}{}", WEBHOOK_PREFIX, id);
+    if kv.get(&key).text().await?.is_none() {
+        return Ok(false);
+    }
+    kv.delete(&key).await?;
+    Ok(true)
+}
+
+/// `POST`s the event signed with `X-Webhook-Signature: sha256=<hex HMAC of "<timestamp>.<body>">`
+/// and the `X-Webhook-Timestamp` it covers, answering the callback's status.
+async fn send(id: &str, webhook: &Webhook, arrival: &Arrival) -> Result<u16> {
+    let body = serde_json::to_string(&WebhookEvent {
+        webhook_id: id,
+        condition: &webhook.condition,
+        arrival,
+    })?;
+    let timestamp = now_secs().to_string();
+    let signature = hmac_sha256(
+        webhook.secret.as_bytes(),
+        format!("{}.{}", timestamp, body).as_bytes(),
+    )
+    .await?;
+    let signature = signature
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("X-Webhook-Timestamp", &timestamp)?;
+    headers.set("X-Webhook-Signature", &format!("sha256={}", signature))?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+    let request = Request::new_with_init(&webhook.condition.url, &init)?;
+    Ok(Fetch::Request(request).send().await?.status_code())
+}
+
+/// Fires the webhooks whose route became due at the stop, run every minute by the scheduled
+/// handler. A failed call is retried with the next run while the arrival is still due.
+pub async fn check_webhooks(env: &Env) -> Result<()> {
+    let Some(kv) = webhooks_store(env) else {
+        return Ok(());
+    };
+    let webhooks = kv_list_json::<Webhook>(&kv, WEBHOOK_PREFIX).await?;
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+    let mut stop_ids = webhooks
+        .iter()
+        .map(|(_, webhook)| webhook.condition.stop_id.clone())
+        .collect::<Vec<String>>();
+    stop_ids.sort_unstable();
+    stop_ids.dedup();
+    let ArrivalsLookup::Found { stops, .. } = lookup_stop_arrivals(env, stop_ids).await? else {
+        return Ok(());
+    };
+    let stops = stops
+        .into_iter()
+        .map(|stop| (stop.id.clone(), stop))
+        .collect::<HashMap<_, _>>();
+    let now = chrono::Utc::now();
+    for (key, mut webhook) in webhooks {
+        let condition = &webhook.condition;
+        let Some(stop) = stops.get(&condition.stop_id) else {
+            continue;
+        };
+        if stop.status != StopArrivalsStatus::Ok {
+            continue;
+        }
+        let Some(arrival) = stop.data.as_ref().and_then(|data| {
+            data.next_arrival_within(
+                condition.r#type.as_str(),
+                &condition.number,
+                condition.within_minutes,
+                now,
+            )
+        }) else {
+            continue;
+        };
+        if webhook.last_fired_time.as_deref() == Some(arrival.time()) {
+            continue;
+        }
+        let id = key.trim_start_matches(WEBHOOK_PREFIX);
+        match send(id, &webhook, arrival).await {
+            Ok(status) if (200..300).contains(&status) => {
+                webhook.last_fired_time = Some(arrival.time().to_string());
+                kv.put(&key, serde_json::to_string(&webhook)?.as_str())?
+                    .execute()
+                    .await?;
+            }
+            Ok(status) => log_warn!("webhook {} answered {}", id, status),
+            Err(err) => log_error!("webhook {} failed: {}", id, err),
+        }
+    }
+    Ok(())
+}
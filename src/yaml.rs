@@ -0,0 +1,87 @@
+//! YAML rendering of JSON values in block style, for the OpenAPI spec. Strings are written as
+//! JSON strings, which YAML reads as double-quoted scalars.
+
+use serde_json::{Map, Value};
+
+pub fn to_string(value: &Value) -> String {
+    match value {
+        Value::Object(map) if !map.is_empty() => object(map, 0),
+        Value::Array(items) if !items.is_empty() => array(items, 0),
+        scalar => format!("{}\n", inline(scalar)),
+    }
+}
+
+/// Scalars and empty collections, written after their key or dash.
+fn inline(value: &Value) -> String {
+    match value {
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        scalar => scalar.to_string(),
+    }
+}
+
+/// Keys YAML reads back as the same string unquoted: no numbers, booleans or nulls.
+fn key(key: &str) -> String {
+    let plain = key
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_' || first == '/')
+        && key
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || "_-./{}".contains(char))
+        && !matches!(
+            key.to_ascii_lowercase().as_str(),
+            "true" | "false" | "null" | "yes" | "no" | "on" | "off" | "y" | "n"
+        );
+    if plain {
+        key.to_string()
+    } else {
+        Value::from(key).to_string()
+    }
+}
+
+fn nested(value: &Value, indent: usize) -> Option<String> {
+    match value {
+        Value::Object(map) if !map.is_empty() => Some(object(map, indent)),
+        Value::Array(items) if !items.is_empty() => Some(array(items, indent)),
+        _ => None,
+    }
+}
+
+fn object(map: &Map<String, Value>, indent: usize) -> String {
+    let mut out = String::new();
+    for (name, value) in map {
+        out.push_str(&" ".repeat(indent));
+        out.push_str(&key(name));
+        out.push(':');
+        match nested(value, indent + 2) {
+            Some(block) => {
+                out.push('\n');
+                out.push_str(&block);
+            }
+            None => {
+                out.push(' ');
+                out.push_str(&inline(value));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn array(items: &[Value], indent: usize) -> String {
+    let mut out = String::new();
+    for item in items {
+        out.push_str(&" ".repeat(indent));
+        out.push_str("- ");
+        match nested(item, indent + 2) {
+            // The block's first line goes on the dash's line.
+            Some(block) => out.push_str(&block[indent + 2..]),
+            None => {
+                out.push_str(&inline(item));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}